@@ -0,0 +1,398 @@
+//! Local API for other applications, as JSON over the named pipe `\\.\pipe\kdeconnect-rs`.
+//!
+//! Every line is one message. Requests carry an `id` chosen by the client and a `method`, and
+//! are answered by a response with the same `id`, which has either a `result` or an `error`:
+//!
+//! ```text
+//! > {"id":1,"method":"listDevices"}
+//! < {"type":"response","id":1,"result":[{"id":"abc","name":"Pixel","address":"192.168.1.2"}]}
+//! > {"id":2,"method":"ping","device":"abc","message":"Hello"}
+//! < {"type":"response","id":2,"result":null}
+//! > {"id":3,"method":"subscribe","topics":["clipboard","power"]}
+//! < {"type":"response","id":3,"result":null}
+//! < {"type":"event","event":"ClipboardUpdated"}
+//! ```
+//!
+//! Methods:
+//! - `listDevices`: the connected devices.
+//! - `ping`: `device`, and an optional `message`.
+//! - `shareText`: `device` and `text`, which the device puts on its clipboard.
+//! - `shareUrl`: `device` and `url`, which the device opens.
+//! - `sendClipboard`: `device` and `text`, like a clipboard change on this computer.
+//! - `devicePacketLog`: `device`. The packets recorded for it, if the event log plugin is
+//!   enabled in its settings. Their bodies are left out, as they hold notifications,
+//!   clipboard contents and the like.
+//! - `subscribe`: optional `topics` (`clipboard`, `power`, `media`, `tray`, `hotkey`, all if
+//!   omitted). Events of these topics are sent to the client from then on.
+//!
+//! Only the user running KDE Connect (or, for the service, the user at the console) can open
+//! the pipe.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{io::BufReader, net::windows::named_pipe::NamedPipeServer, task::JoinSet};
+
+use crate::{
+    context::AppContextRef,
+    device::packet_log::PacketRecord,
+    event::{EventSubscription, EventTopic, SystemEvent},
+    ipc,
+    plugin::{clipboard, ping, share},
+    service::session,
+};
+
+/// Full access for the system and the user that created the pipe.
+const USER_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;OW)";
+
+/// How often the service checks whether another user is at the console.
+const CONSOLE_USER_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    id: u64,
+    #[serde(flatten)]
+    method: Method,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase")]
+enum Method {
+    ListDevices,
+    Ping {
+        device: String,
+        message: Option<String>,
+    },
+    ShareText {
+        device: String,
+        text: String,
+    },
+    ShareUrl {
+        device: String,
+        url: String,
+    },
+    SendClipboard {
+        device: String,
+        text: String,
+    },
+    DeviceStats {
+        device: String,
+    },
+    DevicePacketLog {
+        device: String,
+    },
+    Subscribe {
+        topics: Option<Vec<EventTopic>>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Reply {
+    Response {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Event {
+        event: SystemEvent,
+    },
+}
+
+impl Reply {
+    fn response(id: u64, result: Result<Value>) -> Self {
+        match result {
+            Ok(result) => Reply::Response {
+                id,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => Reply::Response {
+                id,
+                result: None,
+                error: Some(format!("{:#}", e)),
+            },
+        }
+    }
+}
+
+/// Accept clients until the pipe fails.
+pub async fn serve(ctx: AppContextRef, run_as_service: bool) -> Result<()> {
+    if !run_as_service {
+        return accept_clients(ipc::API_PIPE, USER_SDDL, &ctx).await;
+    }
+
+    // The security descriptor of a pipe is that of its first instance, so when someone else
+    // gets to the console, the pipe is closed along with its clients and created again.
+    loop {
+        if let Some(user) = console_user() {
+            let sddl = session::user_pipe_sddl(&user);
+            tokio::select! {
+                r = accept_clients(ipc::API_PIPE, &sddl, &ctx) => {
+                    if let Err(e) = r {
+                        log::warn!("API listener failed: {:?}", e);
+                    }
+                }
+                _ = console_user_changed(&user) => log::info!("Console user changed"),
+            }
+        }
+        tokio::time::sleep(CONSOLE_USER_INTERVAL).await;
+    }
+}
+
+/// The SID of the user logged on to the session attached to the console.
+fn console_user() -> Option<String> {
+    session::user_sid(session::active_console_session()?).ok()
+}
+
+async fn console_user_changed(user: &str) {
+    loop {
+        tokio::time::sleep(CONSOLE_USER_INTERVAL).await;
+        if console_user().as_deref() != Some(user) {
+            return;
+        }
+    }
+}
+
+/// Accept clients until the pipe fails. Dropping the future disconnects the clients.
+async fn accept_clients(name: &str, sddl: &str, ctx: &AppContextRef) -> Result<()> {
+    let mut clients = JoinSet::new();
+    let mut server = ipc::create_server(name, sddl, true)?;
+
+    loop {
+        tokio::select! {
+            r = server.connect() => r?,
+            // Forget the clients that are done.
+            Some(_) = clients.join_next() => continue,
+        }
+        let pipe = std::mem::replace(&mut server, ipc::create_server(name, sddl, false)?);
+
+        let ctx = ctx.clone();
+        clients.spawn(async move {
+            if let Err(e) = handle_client(pipe, &ctx).await {
+                log::warn!("API client failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_client(pipe: NamedPipeServer, ctx: &AppContextRef) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(pipe);
+    let mut reader = BufReader::new(reader);
+    let mut events: Option<EventSubscription> = None;
+
+    loop {
+        tokio::select! {
+            Some(event) = async {
+                match events.as_mut() {
+                    Some(events) => events.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                ipc::write_message(&mut writer, &Reply::Event { event }).await?;
+            }
+            request = ipc::read_message::<Value>(&mut reader) => {
+                let request = match request? {
+                    Some(request) => request,
+                    None => return Ok(()),
+                };
+
+                // Answer malformed requests instead of dropping the client, with the id if
+                // there is one.
+                let id = request.get("id").and_then(Value::as_u64).unwrap_or_default();
+                let result = match serde_json::from_value::<Request>(request) {
+                    Ok(Request {
+                        id,
+                        method: Method::Subscribe { topics },
+                    }) => {
+                        let topics = topics.as_deref().unwrap_or(EventTopic::ALL);
+                        events = Some(ctx.event_bus().subscribe(topics));
+                        Reply::response(id, Ok(Value::Null))
+                    }
+                    Ok(Request { id, method }) => Reply::response(id, call(method, ctx).await),
+                    Err(e) => Reply::response(id, Err(anyhow::anyhow!("Invalid request: {}", e))),
+                };
+                ipc::write_message(&mut writer, &result).await?;
+            }
+        }
+    }
+}
+
+async fn call(method: Method, ctx: &AppContextRef) -> Result<Value> {
+    let devices = ctx.device_manager();
+
+    match method {
+        Method::ListDevices => Ok(serde_json::to_value(devices.list_devices().await?)?),
+        Method::Ping { device, message } => {
+            devices
+                .send_packet_with_ack(&device, ping::ping_packet(message))
+                .await?;
+            Ok(Value::Null)
+        }
+        Method::ShareText { device, text } => {
+            devices
+                .send_packet_with_ack(&device, share::text_packet(text))
+                .await?;
+            Ok(Value::Null)
+        }
+        Method::ShareUrl { device, url } => {
+            devices
+                .send_packet_with_ack(&device, share::url_packet(url))
+                .await?;
+            Ok(Value::Null)
+        }
+        Method::SendClipboard { device, text } => {
+            devices
+                .send_packet_with_ack(&device, clipboard::clipboard_packet(text))
+                .await?;
+            Ok(Value::Null)
+        }
+        Method::DeviceStats { device } => {
+            let stats = devices
+                .query_stats(&device)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Device {} is not connected", device))?;
+            Ok(serde_json::to_value(stats)?)
+        }
+        Method::DevicePacketLog { device } => {
+            let records = devices
+                .query_packet_log(&device)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Device {} is not connected", device))?;
+            Ok(serde_json::to_value(without_bodies(records))?)
+        }
+        // Changes the state of the connection, which is up to the caller.
+        Method::Subscribe { .. } => Err(anyhow::anyhow!("Subscribe is handled by the connection")),
+    }
+}
+
+/// The records of a packet log without the bodies recorded in verbose mode, which only leave
+/// the computer when the user saves the log from the tray.
+fn without_bodies(records: Vec<PacketRecord>) -> Vec<PacketRecord> {
+    records
+        .into_iter()
+        .map(|record| PacketRecord {
+            body: None,
+            ..record
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::windows::named_pipe::NamedPipeClient};
+
+    use super::*;
+    use crate::{
+        device::packet_log::PacketLog, packet::NetworkPacket, testing::TestContext,
+        transfer::Direction,
+    };
+
+    fn parse(request: &str) -> Result<Request, serde_json::Error> {
+        serde_json::from_str(request)
+    }
+
+    #[test]
+    fn parses_methods() {
+        let request = parse(r#"{"id":1,"method":"ping","device":"abc"}"#).unwrap();
+        assert_eq!(request.id, 1);
+        assert!(matches!(
+            request.method,
+            Method::Ping { device, message: None } if device == "abc"
+        ));
+
+        let request =
+            parse(r#"{"id":2,"method":"shareUrl","device":"abc","url":"https://kde.org"}"#)
+                .unwrap();
+        assert!(matches!(
+            request.method,
+            Method::ShareUrl { device, url } if device == "abc" && url == "https://kde.org"
+        ));
+
+        let request = parse(r#"{"id":3,"method":"subscribe","topics":["clipboard"]}"#).unwrap();
+        assert!(matches!(
+            request.method,
+            Method::Subscribe { topics: Some(topics) } if topics == [EventTopic::Clipboard]
+        ));
+        let request = parse(r#"{"id":4,"method":"subscribe"}"#).unwrap();
+        assert!(matches!(request.method, Method::Subscribe { topics: None }));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert!(parse(r#"{"id":1,"method":"format"}"#).is_err());
+        assert!(parse(r#"{"id":1,"method":"shareText","device":"abc"}"#).is_err());
+        assert!(parse(r#"{"method":"listDevices"}"#).is_err());
+    }
+
+    #[test]
+    fn packet_log_bodies_are_left_out() {
+        let log = PacketLog::default();
+        log.configure(10, true);
+        let packet = NetworkPacket::new(
+            "kdeconnect.clipboard",
+            serde_json::json!({ "content": "secret" }),
+        );
+        log.record(Direction::Incoming, &packet, 42);
+
+        let records = without_bodies(log.records());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].typ, "kdeconnect.clipboard");
+        assert_eq!(records[0].size, 42);
+        assert!(!serde_json::to_string(&records).unwrap().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn subscribe_is_not_dispatched() {
+        let context = TestContext::new().unwrap();
+        let method = Method::Subscribe { topics: None };
+        assert!(call(method, &context.ctx).await.is_err());
+    }
+
+    async fn ask(pipe: &mut BufReader<NamedPipeClient>, request: &str) -> Value {
+        pipe.write_all(request.as_bytes()).await.unwrap();
+        pipe.write_all(b"\n").await.unwrap();
+        ipc::read_message(pipe).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_requests_on_the_pipe() {
+        let context = TestContext::new().unwrap();
+        let name = format!(r"\\.\pipe\kdeconnect-test-{}", uuid::Uuid::new_v4());
+        let ctx = context.ctx.clone();
+        let server_name = name.clone();
+        tokio::spawn(async move { accept_clients(&server_name, USER_SDDL, &ctx).await });
+        // Lets the listener create the pipe.
+        tokio::task::yield_now().await;
+
+        let mut pipe = BufReader::new(ipc::connect(&name).await.unwrap());
+
+        let reply = ask(&mut pipe, r#"{"id":1,"method":"listDevices"}"#).await;
+        assert_eq!(reply["type"], "response");
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["result"], serde_json::json!([]));
+
+        let reply = ask(&mut pipe, r#"{"id":2,"method":"ping","device":"missing"}"#).await;
+        assert_eq!(reply["id"], 2);
+        assert!(reply["error"].is_string());
+
+        let reply = ask(&mut pipe, r#"{"id":3,"method":"format"}"#).await;
+        assert_eq!(reply["id"], 3);
+        assert!(reply["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid request"));
+
+        let reply = ask(
+            &mut pipe,
+            r#"{"id":4,"method":"subscribe","topics":["tray"]}"#,
+        )
+        .await;
+        assert_eq!(reply["id"], 4);
+        assert_eq!(reply["result"], Value::Null);
+    }
+}
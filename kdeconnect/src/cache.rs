@@ -4,7 +4,9 @@ use anyhow::Result;
 use lru_cache::LruCache;
 use tokio::sync::Mutex;
 
-type Cache = LruCache<String, Arc<Vec<u8>>>;
+use crate::utils::hash::ContentHash;
+
+type Cache = LruCache<CacheKey, Arc<Vec<u8>>>;
 
 lazy_static::lazy_static! {
     pub static ref PAYLOAD_CACHE: PayloadCache = {
@@ -12,6 +14,61 @@ lazy_static::lazy_static! {
     };
 }
 
+/// What a cached file holds, which decides its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// A notification icon, converted to PNG.
+    NotificationIcon,
+    /// A cover, re-encoded as JPEG.
+    AlbumArt,
+    /// Shared text, opened with the default editor.
+    Text,
+}
+
+impl CacheKind {
+    const ALL: [CacheKind; 3] = [Self::NotificationIcon, Self::AlbumArt, Self::Text];
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Self::NotificationIcon => ".icon.png",
+            Self::AlbumArt => ".jpg",
+            Self::Text => ".txt",
+        }
+    }
+}
+
+/// Identifies a file in the [`PayloadCache`] by its kind and the hash of its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub kind: CacheKind,
+    pub hash: ContentHash,
+}
+
+impl CacheKey {
+    pub fn new(kind: CacheKind, hash: ContentHash) -> Self {
+        Self { kind, hash }
+    }
+
+    /// Name of the file in the cache directory.
+    pub fn file_name(&self) -> String {
+        format!("{}{}", self.hash, self.kind.suffix())
+    }
+
+    /// Parse a name returned by [`CacheKey::file_name`], e.g. from a URL given to a device.
+    ///
+    /// Anything else is rejected, so that the name cannot point outside of the cache.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        CacheKind::ALL
+            .into_iter()
+            .find_map(|kind| {
+                let hash = file_name.strip_suffix(kind.suffix())?;
+                Some(Self::new(kind, ContentHash::parse(hash)?))
+            })
+            // Only the exact name we would give the file, e.g. not in upper case.
+            .filter(|key| key.file_name() == file_name)
+    }
+}
+
 pub struct PayloadCache {
     cache: Mutex<Cache>,
     cache_path: PathBuf,
@@ -29,16 +86,20 @@ impl PayloadCache {
         })
     }
 
-    async fn get_internal(&self, cache: &mut Cache, name: &str) -> Result<Option<Arc<Vec<u8>>>> {
-        if let Some(cached) = cache.get_mut(name) {
+    async fn get_internal(
+        &self,
+        cache: &mut Cache,
+        key: &CacheKey,
+    ) -> Result<Option<Arc<Vec<u8>>>> {
+        if let Some(cached) = cache.get_mut(key) {
             return Ok(Some(Arc::clone(cached)));
         };
 
-        let path = self.cache_path.join(name);
+        let path = self.cache_path.join(key.file_name());
         match tokio::fs::read(&path).await {
             Ok(data) => {
                 let a = Arc::new(data);
-                cache.insert(name.to_string(), a.clone());
+                cache.insert(*key, a.clone());
                 Ok(Some(a))
             }
             Err(e) => match e.kind() {
@@ -48,13 +109,13 @@ impl PayloadCache {
         }
     }
 
-    pub async fn get(&self, name: &str) -> Result<Option<Arc<Vec<u8>>>> {
+    pub async fn get(&self, key: &CacheKey) -> Result<Option<Arc<Vec<u8>>>> {
         let mut cache = self.cache.lock().await;
-        self.get_internal(&mut cache, name).await
+        self.get_internal(&mut cache, key).await
     }
 
-    pub async fn get_path(&self, name: &str) -> Result<Option<PathBuf>> {
-        let path = self.cache_path.join(name);
+    pub async fn get_path(&self, key: &CacheKey) -> Result<Option<PathBuf>> {
+        let path = self.cache_path.join(key.file_name());
 
         match tokio::fs::metadata(&path).await {
             Ok(_) => Ok(Some(path)),
@@ -65,17 +126,17 @@ impl PayloadCache {
         }
     }
 
-    pub async fn put(&self, name: &str, data: Vec<u8>) -> Result<()> {
+    pub async fn put(&self, key: &CacheKey, data: Vec<u8>) -> Result<()> {
         let mut cache = self.cache.lock().await;
 
-        if self.get_internal(&mut cache, name).await?.is_some() {
+        if self.get_internal(&mut cache, key).await?.is_some() {
             return Ok(());
         }
 
         let data = Arc::new(data);
-        cache.insert(name.to_string(), data.clone());
+        cache.insert(*key, data.clone());
 
-        let path = self.cache_path.join(name);
+        let path = self.cache_path.join(key.file_name());
         tokio::fs::write(&path, data.as_slice()).await?;
 
         Ok(())
@@ -84,8 +145,61 @@ impl PayloadCache {
 
 impl Debug for PayloadCache {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("AlbumArtCache")
+        f.debug_struct("PayloadCache")
             .field("cache_path", &self.cache_path)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_its_own_file_names() {
+        for kind in CacheKind::ALL {
+            for hash in [ContentHash::md5(b"data"), ContentHash::sha256(b"data")] {
+                let key = CacheKey::new(kind, hash);
+                assert_eq!(CacheKey::parse(&key.file_name()), Some(key));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_names_outside_of_the_cache() {
+        let hash = ContentHash::md5(b"data").to_string();
+        for name in [
+            "..".to_string(),
+            "../secret.txt".to_string(),
+            format!("../{}.txt", hash),
+            format!("..\\{}.txt", hash),
+            format!("sub/{}.txt", hash),
+            format!("sub\\{}.txt", hash),
+            format!("/{}.txt", hash),
+            format!("C:\\{}.txt", hash),
+            format!("C:{}.txt", hash),
+            format!("\\\\?\\C:\\{}.txt", hash),
+            format!("{}/../../{}.txt", hash, hash),
+            format!("..{}.txt", &hash[2..]),
+        ] {
+            assert_eq!(CacheKey::parse(&name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_other_names() {
+        let hash = ContentHash::md5(b"data").to_string();
+        for name in [
+            String::new(),
+            hash.clone(),
+            format!("{}.exe", hash),
+            format!("{}.TXT", hash),
+            format!("{}.txt", hash.to_uppercase()),
+            format!("{}.png", hash),
+            format!("{}.txt.txt", hash),
+            format!("{}.txt\0", hash),
+        ] {
+            assert_eq!(CacheKey::parse(&name), None, "{:?}", name);
+        }
+    }
+}
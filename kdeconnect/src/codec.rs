@@ -0,0 +1,244 @@
+//! How packets are encoded on a connection.
+//!
+//! Packets are JSON objects ending with a newline by default. Other encodings are experiments
+//! that are not part of the standard protocol: they are only used if enabled in the network
+//! settings, and with devices that advertise them in the incoming capabilities of the identity
+//! sent over TLS, e.g. "kdeconnect.codec.cbor". Both devices then switch to it for every packet
+//! after the identities.
+
+use std::{fmt::Debug, io};
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::packet::NetworkPacket;
+
+/// Frames and serializes the packets of one connection.
+pub trait Codec: Debug + Send + Sync {
+    /// Name in the network settings, e.g. "cbor".
+    fn name(&self) -> &'static str;
+
+    /// Append the packet to `buf`, framed so that [`Codec::decode`] can find where it ends.
+    fn encode(&mut self, packet: &NetworkPacket, buf: &mut BytesMut) -> Result<()>;
+
+    /// Take the next packet from the start of `buf`, or `None` if it has not been received
+    /// completely yet.
+    ///
+    /// The outer error means that the connection cannot be read any further, e.g. because the
+    /// packet exceeds `limit` bytes. The inner one only concerns this packet, which is removed
+    /// from `buf` anyway.
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+        limit: usize,
+    ) -> io::Result<Option<Result<NetworkPacket>>>;
+}
+
+/// The standard encoding: a JSON object per line.
+#[derive(Debug, Default)]
+pub struct JsonLineCodec {
+    /// How much of the buffer is known not to contain a newline.
+    scanned: usize,
+}
+
+impl Codec for JsonLineCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&mut self, packet: &NetworkPacket, buf: &mut BytesMut) -> Result<()> {
+        packet.encode(buf)?;
+        Ok(())
+    }
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+        limit: usize,
+    ) -> io::Result<Option<Result<NetworkPacket>>> {
+        let newline = buf[self.scanned..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|i| self.scanned + i);
+        let len = newline.unwrap_or(buf.len());
+        if len > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packet exceeds {} bytes", limit),
+            ));
+        }
+
+        match newline {
+            Some(i) => {
+                let line = buf.split_to(i + 1);
+                self.scanned = 0;
+                Ok(Some(NetworkPacket::parse(&line[..i])))
+            }
+            None => {
+                self.scanned = buf.len();
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// CBOR, preceded by its length as a big-endian `u32`.
+#[derive(Debug, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&mut self, packet: &NetworkPacket, buf: &mut BytesMut) -> Result<()> {
+        let start = buf.len();
+        buf.put_u32(0);
+        ciborium::ser::into_writer(packet, buf.writer())?;
+        let len = u32::try_from(buf.len() - start - 4)?;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+        limit: usize,
+    ) -> io::Result<Option<Result<NetworkPacket>>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Packet exceeds {} bytes", limit),
+            ));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+
+        buf.advance(4);
+        let frame = buf.split_to(len);
+        Ok(Some(parse_cbor(&frame)))
+    }
+}
+
+fn parse_cbor(frame: &[u8]) -> Result<NetworkPacket> {
+    let packet: NetworkPacket = match ciborium::de::from_reader(frame) {
+        Ok(packet) => packet,
+        Err(e) => bail!("Invalid CBOR packet: {}", e),
+    };
+    packet.validate()?;
+    Ok(packet)
+}
+
+/// The experimental codecs, in the order they are preferred when both devices have several
+/// of them enabled.
+const EXPERIMENTAL: &[&str] = &["cbor"];
+
+fn by_name(name: &str) -> Option<Box<dyn Codec>> {
+    match name {
+        "cbor" => Some(Box::new(CborCodec)),
+        _ => None,
+    }
+}
+
+/// The capability advertising a codec in identities.
+fn capability(name: &str) -> String {
+    format!("kdeconnect.codec.{}", name)
+}
+
+/// Capabilities to add to our identity for the codecs enabled in the settings.
+pub fn capabilities(enabled: &[String]) -> Vec<String> {
+    EXPERIMENTAL
+        .iter()
+        .filter(|name| enabled.iter().any(|e| e == *name))
+        .map(|name| capability(name))
+        .collect()
+}
+
+/// The codec to use with a device that advertises `remote_capabilities`, the same one the device
+/// picks as long as it follows the same order.
+pub fn negotiate(enabled: &[String], remote_capabilities: &[String]) -> Box<dyn Codec> {
+    EXPERIMENTAL
+        .iter()
+        .filter(|name| enabled.iter().any(|e| e == *name))
+        .find(|name| remote_capabilities.contains(&capability(name)))
+        .and_then(|name| by_name(name))
+        .unwrap_or_else(|| Box::<JsonLineCodec>::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(message: &str) -> NetworkPacket {
+        NetworkPacket::new("kdeconnect.ping", serde_json::json!({ "message": message }))
+    }
+
+    /// Encode two packets and decode them again, fed one byte at a time.
+    fn round_trip(codec: &mut dyn Codec) {
+        let mut wire = BytesMut::new();
+        codec.encode(&ping("first"), &mut wire).unwrap();
+        codec.encode(&ping("second"), &mut wire).unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut decoded = vec![];
+        for &b in wire.iter() {
+            buf.put_u8(b);
+            if let Some(packet) = codec.decode(&mut buf, 1024).unwrap() {
+                decoded.push(packet.unwrap().body["message"].clone());
+            }
+        }
+        assert_eq!(decoded, ["first", "second"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        round_trip(&mut JsonLineCodec::default());
+        round_trip(&mut CborCodec);
+    }
+
+    #[test]
+    fn json_skips_invalid_lines() {
+        let mut codec = JsonLineCodec::default();
+        let mut buf = BytesMut::from(&b"not json\n{}\n"[..]);
+        assert!(codec.decode(&mut buf, 100).unwrap().unwrap().is_err());
+        assert!(codec.decode(&mut buf, 100).unwrap().unwrap().is_err());
+        assert!(codec.decode(&mut buf, 100).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_enforces_limit() {
+        let mut buf = BytesMut::from(&[b'a'; 101][..]);
+        assert!(JsonLineCodec::default().decode(&mut buf, 100).is_err());
+
+        let mut buf = BytesMut::new();
+        CborCodec.encode(&ping(&"a".repeat(100)), &mut buf).unwrap();
+        buf.truncate(4);
+        assert!(CborCodec.decode(&mut buf, 100).is_err());
+    }
+
+    #[test]
+    fn cbor_validates_packets() {
+        let mut buf = BytesMut::new();
+        CborCodec
+            .encode(&NetworkPacket::new("ping", serde_json::json!({})), &mut buf)
+            .unwrap();
+        assert!(CborCodec.decode(&mut buf, 1024).unwrap().unwrap().is_err());
+    }
+
+    #[test]
+    fn negotiates_codecs_enabled_on_both_sides() {
+        let enabled = vec!["cbor".to_string()];
+        let remote = capabilities(&enabled);
+
+        assert_eq!(negotiate(&enabled, &remote).name(), "cbor");
+        assert_eq!(negotiate(&[], &remote).name(), "json");
+        assert_eq!(negotiate(&enabled, &[]).name(), "json");
+        assert!(capabilities(&["unknown".to_string()]).is_empty());
+    }
+}
@@ -1,4 +1,16 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs::File,
+    io::BufReader,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
@@ -80,3 +92,850 @@ impl TryFrom<EncodedConfig> for Config {
         })
     }
 }
+
+/// User-editable settings, stored as TOML next to the identity config.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(default)]
+    pub discovery: DiscoverySettings,
+    #[serde(default)]
+    pub network: NetworkSettings,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsSettings,
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceSettings>,
+}
+
+/// How we advertise ourselves to devices on the network.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiscoverySettings {
+    /// Seconds between broadcasts while no device is connected.
+    #[serde(default = "default_discovery_interval")]
+    pub interval: u64,
+    /// Seconds between broadcasts while some devices are connected, so that others can still
+    /// find us. Broadcasting stops while connected if 0.
+    #[serde(default = "default_discovery_connected_interval")]
+    pub connected_interval: u64,
+    /// Also broadcast to the subnet of each network interface, for networks that drop
+    /// packets sent to 255.255.255.255.
+    #[serde(default = "default_directed_broadcasts")]
+    pub directed_broadcasts: bool,
+    /// Addresses of devices which broadcasts do not reach, e.g. on another subnet or over a
+    /// VPN. The identity is sent to each of them directly.
+    #[serde(default)]
+    pub static_addresses: Vec<Ipv4Addr>,
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            interval: default_discovery_interval(),
+            connected_interval: default_discovery_connected_interval(),
+            directed_broadcasts: default_directed_broadcasts(),
+            static_addresses: vec![],
+        }
+    }
+}
+
+fn default_discovery_interval() -> u64 {
+    5
+}
+
+fn default_discovery_connected_interval() -> u64 {
+    60
+}
+
+fn default_directed_broadcasts() -> bool {
+    true
+}
+
+/// A range of ports, both ends included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl Display for PortRange {
+    /// As understood by the firewall, e.g. "1716-1764".
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Where devices send discovery packets, which cannot be changed.
+pub const DISCOVERY_UDP_PORT: u16 = 1716;
+
+/// The TCP ports we listen on.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkSettings {
+    /// The TCP port devices connect to is the first free one in this range. It is announced in
+    /// our identity.
+    #[serde(default = "default_discovery_ports")]
+    pub discovery_ports: PortRange,
+    /// Each payload sent to a device is served on the first free port in this range.
+    #[serde(default = "default_transfer_ports")]
+    pub transfer_ports: PortRange,
+    /// Check on startup whether the firewall blocks incoming connections, and offer to allow
+    /// them. Turned off once the user has answered.
+    #[serde(default = "default_firewall_check")]
+    pub firewall_check: bool,
+    /// Caps the bandwidth of payload transfers with all devices, unless a device has its own.
+    #[serde(default)]
+    pub bandwidth: BandwidthSettings,
+    /// Experimental packet encodings used instead of JSON with devices that support them, e.g.
+    /// "cbor". See [`crate::codec`].
+    #[serde(default)]
+    pub experimental_codecs: Vec<String>,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            discovery_ports: default_discovery_ports(),
+            transfer_ports: default_transfer_ports(),
+            firewall_check: default_firewall_check(),
+            bandwidth: BandwidthSettings::default(),
+            experimental_codecs: vec![],
+        }
+    }
+}
+
+fn default_discovery_ports() -> PortRange {
+    PortRange {
+        start: 1716,
+        end: 1764,
+    }
+}
+
+fn default_transfer_ports() -> PortRange {
+    PortRange {
+        start: 1765,
+        end: 1864,
+    }
+}
+
+fn default_firewall_check() -> bool {
+    true
+}
+
+/// Bandwidth caps for payload transfers, e.g. so that syncing large files does not saturate the
+/// uplink during a video call.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BandwidthSettings {
+    /// In KiB per second, unlimited if 0.
+    #[serde(default)]
+    pub max_upload: u64,
+    /// In KiB per second, unlimited if 0.
+    #[serde(default)]
+    pub max_download: u64,
+}
+
+/// What is recorded to help with bug reports.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiagnosticsSettings {
+    /// Write a report to the data directory when the application crashes, with the backtrace,
+    /// the known devices and the last lines logged.
+    #[serde(default = "default_crash_reports")]
+    pub crash_reports: bool,
+}
+
+impl Default for DiagnosticsSettings {
+    fn default() -> Self {
+        Self {
+            crash_reports: default_crash_reports(),
+        }
+    }
+}
+
+fn default_crash_reports() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DeviceSettings {
+    pub id: String,
+    /// Last known name, for menus shown while the device is not connected.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Replaces the caps in the network settings for this device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<BandwidthSettings>,
+    /// What the device may share without asking first. New devices get the default, devices
+    /// saved before this setting existed keep saving files and opening links right away.
+    #[serde(default = "default_saved_device_trust")]
+    pub trust: TrustLevel,
+    #[serde(default)]
+    pub plugins: PluginSettings,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrustLevel {
+    /// Ask before saving files or opening links.
+    Ask,
+    /// Save files right away, ask before opening links.
+    #[default]
+    AcceptFiles,
+    /// Save files and open links right away, unless confirm-urls is on.
+    AcceptEverything,
+}
+
+impl TrustLevel {
+    pub const ALL: [TrustLevel; 3] = [Self::Ask, Self::AcceptFiles, Self::AcceptEverything];
+
+    /// Whether shared files are saved without asking first.
+    pub fn accepts_files(self) -> bool {
+        self != Self::Ask
+    }
+
+    /// Whether shared links are opened without asking first, given the confirm-urls setting.
+    pub fn opens_urls(self, confirm_urls: bool) -> bool {
+        self == Self::AcceptEverything && !confirm_urls
+    }
+}
+
+fn default_saved_device_trust() -> TrustLevel {
+    TrustLevel::AcceptEverything
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PluginSettings {
+    /// Plugins not loaded for the device, by name, e.g. "ping" or "mpris-remote".
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    #[serde(default)]
+    pub battery: BatterySettings,
+    #[serde(default)]
+    pub clipboard: ClipboardSettings,
+    #[serde(default)]
+    pub notification_receive: NotificationReceiveSettings,
+    #[serde(default)]
+    pub share: ShareSettings,
+    #[serde(default)]
+    pub mpris: MprisSettings,
+    #[serde(default)]
+    pub system_stats: SystemStatsSettings,
+    #[serde(default)]
+    pub wake_on_lan: WakeOnLanSettings,
+    #[serde(default)]
+    pub telephony: TelephonySettings,
+    #[serde(default)]
+    pub input_receive: InputReceiveSettings,
+    #[serde(default)]
+    pub system_volume: SystemVolumeSettings,
+    #[serde(default)]
+    pub event_log: EventLogSettings,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BatterySettings {
+    /// Minutes between two requests for the battery of the device, in case it does not report
+    /// changes by itself. It is only requested on connect if 0.
+    #[serde(default = "default_battery_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+impl Default for BatterySettings {
+    fn default() -> Self {
+        Self {
+            refresh_interval: default_battery_refresh_interval(),
+        }
+    }
+}
+
+fn default_battery_refresh_interval() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClipboardSettings {
+    /// Number of text entries kept in the history menu, none if 0.
+    #[serde(default)]
+    pub history_size: usize,
+    /// Only send the clipboard from the tray menu, instead of on every change. On by default,
+    /// so that nothing copied leaves the computer unless the user asks for it.
+    #[serde(default = "default_clipboard_manual_sync")]
+    pub manual_sync: bool,
+    /// Show a notification when content marked as sensitive (e.g. a password) is not sent.
+    #[serde(default)]
+    pub notify_sensitive: bool,
+    /// Text larger than this (in bytes) is shared as a file, as large packets hold up the
+    /// connection.
+    #[serde(default = "default_clipboard_inline_limit")]
+    pub inline_limit: usize,
+    /// Text larger than this (in bytes) is not sent at all.
+    #[serde(default = "default_clipboard_max_size")]
+    pub max_size: usize,
+}
+
+fn default_clipboard_manual_sync() -> bool {
+    true
+}
+
+impl Default for ClipboardSettings {
+    fn default() -> Self {
+        Self {
+            history_size: 0,
+            manual_sync: default_clipboard_manual_sync(),
+            notify_sensitive: false,
+            inline_limit: default_clipboard_inline_limit(),
+            max_size: default_clipboard_max_size(),
+        }
+    }
+}
+
+fn default_clipboard_inline_limit() -> usize {
+    256 * 1024
+}
+
+fn default_clipboard_max_size() -> usize {
+    16 * 1024 * 1024
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotificationReceiveSettings {
+    /// If not empty, only notifications from these apps are shown.
+    #[serde(default)]
+    pub allowed_apps: Vec<String>,
+    /// Notifications from these apps are never shown.
+    #[serde(default)]
+    pub blocked_apps: Vec<String>,
+    /// Notifications with a title matching any of these regexes are never shown.
+    #[serde(default)]
+    pub blocked_titles: Vec<String>,
+    /// Show notifications even if Focus Assist (or a full screen app) is active.
+    #[serde(default)]
+    pub ignore_focus_assist: bool,
+    /// Put all notifications in the Action Center without showing a popup.
+    #[serde(default)]
+    pub action_center_only: bool,
+    /// How notifications get the attention of the user.
+    #[serde(default)]
+    pub alert: NotificationAlert,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationAlert {
+    /// Shown without a sound.
+    Silent,
+    /// Shown with the default sound.
+    #[default]
+    Normal,
+    /// Shown as important, and even during Focus Assist if Windows allows it.
+    Urgent,
+}
+
+impl NotificationAlert {
+    pub const ALL: [NotificationAlert; 3] = [Self::Silent, Self::Normal, Self::Urgent];
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShareSettings {
+    /// Where received files are saved, instead of "Downloads/KDE Connect/<device name>".
+    #[serde(default)]
+    pub download_dir: Option<PathBuf>,
+    /// Ask before opening a shared URL, even if the device is trusted with everything.
+    #[serde(default)]
+    pub confirm_urls: bool,
+    /// Shared URLs with other schemes are never opened, as they may start any registered
+    /// application.
+    #[serde(default = "default_share_url_schemes")]
+    pub url_schemes: Vec<String>,
+    /// What to do with shared text.
+    #[serde(default)]
+    pub text_mode: TextShareMode,
+}
+
+impl Default for ShareSettings {
+    fn default() -> Self {
+        Self {
+            download_dir: None,
+            confirm_urls: false,
+            url_schemes: default_share_url_schemes(),
+            text_mode: TextShareMode::default(),
+        }
+    }
+}
+
+fn default_share_url_schemes() -> Vec<String> {
+    vec!["http".into(), "https".into()]
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextShareMode {
+    /// Copy the text to the clipboard.
+    #[default]
+    Clipboard,
+    /// Open the text in the default text editor.
+    Editor,
+    /// Copy the text to the clipboard, and open it in the default text editor.
+    Both,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MprisSettings {
+    /// Players that are not shown on the device, by name or AppUserModelID.
+    #[serde(default)]
+    pub hidden_players: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EventLogSettings {
+    /// Off by default, it is only meant for debugging.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Packets kept, the oldest ones are dropped.
+    #[serde(default = "default_event_log_capacity")]
+    pub capacity: usize,
+    /// Also keep the bodies of the packets, which contain notifications, clipboard contents
+    /// and the like.
+    #[serde(default)]
+    pub verbose: bool,
+}
+
+impl Default for EventLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: default_event_log_capacity(),
+            verbose: false,
+        }
+    }
+}
+
+fn default_event_log_capacity() -> usize {
+    500
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SystemStatsSettings {
+    /// Off by default, as it sends a packet to the device every `interval`.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds between two reports.
+    #[serde(default = "default_system_stats_interval")]
+    pub interval: u64,
+    #[serde(default = "default_system_stats_metrics")]
+    pub metrics: Vec<SystemStatsMetric>,
+}
+
+impl Default for SystemStatsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: default_system_stats_interval(),
+            metrics: default_system_stats_metrics(),
+        }
+    }
+}
+
+fn default_system_stats_interval() -> u64 {
+    5
+}
+
+fn default_system_stats_metrics() -> Vec<SystemStatsMetric> {
+    vec![
+        SystemStatsMetric::Cpu,
+        SystemStatsMetric::Memory,
+        SystemStatsMetric::Network,
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemStatsMetric {
+    Cpu,
+    Memory,
+    Network,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WakeOnLanSettings {
+    /// Learned from the ARP table while the device is connected, e.g. "01:23:45:67:89:ab".
+    #[serde(default)]
+    pub mac_address: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TelephonySettings {
+    /// Pause the players of this computer while the phone rings or a call is active.
+    #[serde(default = "default_telephony_pause_media")]
+    pub pause_media: bool,
+    /// Lower the volume of the default output to this (0-100) during calls, unchanged if unset.
+    #[serde(default)]
+    pub call_volume: Option<u8>,
+}
+
+impl Default for TelephonySettings {
+    fn default() -> Self {
+        Self {
+            pause_media: default_telephony_pause_media(),
+            call_volume: None,
+        }
+    }
+}
+
+fn default_telephony_pause_media() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InputReceiveSettings {
+    /// Number of the display the pointer is kept on, from its device name `\\.\DISPLAY<number>`.
+    /// It is usually, but not always, the number shown in the display settings of Windows, and
+    /// the names are logged if the display is not found. The pointer moves across all displays
+    /// if unset.
+    #[serde(default)]
+    pub monitor: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SystemVolumeSettings {
+    /// Show a toast with the new volume when the device changes it.
+    #[serde(default = "default_system_volume_show_toast")]
+    pub show_toast: bool,
+}
+
+impl Default for SystemVolumeSettings {
+    fn default() -> Self {
+        Self {
+            show_toast: default_system_volume_show_toast(),
+        }
+    }
+}
+
+fn default_system_volume_show_toast() -> bool {
+    true
+}
+
+/// Keys of the settings file that [`Settings`] does not know, e.g. added by a newer version or
+/// by hand, so that saving the settings does not drop them.
+///
+/// Keys inside arrays are not kept.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct UnknownKeys(BTreeMap<String, UnknownKey>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum UnknownKey {
+    /// The whole value is unknown.
+    Value(toml::Value),
+    /// A known table, with some unknown keys inside.
+    Table(UnknownKeys),
+}
+
+impl UnknownKeys {
+    /// Keys of `raw` missing from `known`, which is `raw` parsed and serialized again.
+    fn diff(raw: &toml::value::Table, known: &toml::value::Table) -> Self {
+        let mut keys = BTreeMap::new();
+        for (key, value) in raw {
+            match (value, known.get(key)) {
+                (_, None) => {
+                    keys.insert(key.clone(), UnknownKey::Value(value.clone()));
+                }
+                (toml::Value::Table(raw), Some(toml::Value::Table(known))) => {
+                    let inner = Self::diff(raw, known);
+                    if !inner.0.is_empty() {
+                        keys.insert(key.clone(), UnknownKey::Table(inner));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self(keys)
+    }
+
+    /// Add the keys back to `table`, without replacing known ones.
+    ///
+    /// Unknown keys of a known table are dropped with it, e.g. when a device is removed.
+    fn restore(&self, table: &mut toml::value::Table) {
+        for (key, unknown) in &self.0 {
+            match unknown {
+                UnknownKey::Value(value) => {
+                    table.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+                UnknownKey::Table(inner) => {
+                    if let Some(toml::Value::Table(table)) = table.get_mut(key) {
+                        inner.restore(table);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Settings shared by the whole application, saved to disk on every update.
+#[derive(Debug)]
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: RwLock<Settings>,
+    unknown: UnknownKeys,
+    /// Incremented on every update, see [`Self::revision`].
+    revision: AtomicU64,
+}
+
+impl SettingsStore {
+    /// Loads settings from a file, or uses the defaults if it doesn't exist.
+    pub fn load_or_default(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let (settings, unknown) = if path.exists() {
+            let raw: toml::value::Table = toml::from_str(&std::fs::read_to_string(path)?)?;
+            let settings: Settings = toml::Value::Table(raw.clone()).try_into()?;
+            let unknown = toml::Value::try_from(&settings)?
+                .as_table()
+                .map(|known| UnknownKeys::diff(&raw, known))
+                .unwrap_or_default();
+            (settings, unknown)
+        } else {
+            (Settings::default(), UnknownKeys::default())
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            settings: RwLock::new(settings),
+            unknown,
+            revision: AtomicU64::new(0),
+        })
+    }
+
+    /// Changes whenever the settings are updated, so that values read often can be kept until
+    /// then instead of being copied every time.
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Acquire)
+    }
+
+    fn save(&self, settings: &Settings) -> Result<()> {
+        self.revision.fetch_add(1, Ordering::Release);
+
+        let mut doc = toml::Value::try_from(settings)?;
+        if let toml::Value::Table(table) = &mut doc {
+            self.unknown.restore(table);
+        }
+        std::fs::write(&self.path, toml::to_string(&doc)?)?;
+        Ok(())
+    }
+
+    /// Get a copy of the discovery settings.
+    pub fn discovery(&self) -> DiscoverySettings {
+        self.settings.read().unwrap().discovery.clone()
+    }
+
+    /// Get a copy of the network settings.
+    pub fn network(&self) -> NetworkSettings {
+        self.settings.read().unwrap().network.clone()
+    }
+
+    /// Modify the network settings and save them to disk.
+    pub fn update_network<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut NetworkSettings),
+    {
+        let mut settings = self.settings.write().unwrap();
+        f(&mut settings.network);
+
+        self.save(&settings)
+    }
+
+    /// Get a copy of the diagnostics settings.
+    pub fn diagnostics(&self) -> DiagnosticsSettings {
+        self.settings.read().unwrap().diagnostics.clone()
+    }
+
+    /// Get a copy of the settings for a device, or the defaults if it has none.
+    pub fn device(&self, device_id: &str) -> DeviceSettings {
+        let settings = self.settings.read().unwrap();
+
+        settings
+            .devices
+            .values()
+            .find(|d| d.id == device_id)
+            .cloned()
+            .unwrap_or_else(|| DeviceSettings {
+                id: device_id.to_string(),
+                ..Default::default()
+            })
+    }
+
+    /// Get a copy of the settings of all devices that have any.
+    pub fn devices(&self) -> Vec<DeviceSettings> {
+        let settings = self.settings.read().unwrap();
+        settings.devices.values().cloned().collect()
+    }
+
+    /// Like [`Self::devices`], but `None` instead of waiting while the settings are modified.
+    pub fn try_devices(&self) -> Option<Vec<DeviceSettings>> {
+        let settings = self.settings.try_read().ok()?;
+        Some(settings.devices.values().cloned().collect())
+    }
+
+    /// Modify the settings of a device and save them to disk.
+    pub fn update_device<F>(&self, device_id: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut DeviceSettings),
+    {
+        let mut settings = self.settings.write().unwrap();
+
+        let key = settings
+            .devices
+            .iter()
+            .find(|(_, d)| d.id == device_id)
+            .map(|(k, _)| k.clone())
+            .unwrap_or_else(|| device_id.to_string());
+
+        let device = settings
+            .devices
+            .entry(key)
+            .or_insert_with(|| DeviceSettings {
+                id: device_id.to_string(),
+                ..Default::default()
+            });
+        f(device);
+
+        self.save(&settings)
+    }
+
+    /// Delete the settings of a device and save the rest to disk.
+    pub fn remove_device(&self, device_id: &str) -> Result<()> {
+        let mut settings = self.settings.write().unwrap();
+        settings.devices.retain(|_, d| d.id != device_id);
+
+        self.save(&settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_keep_unknown_keys() {
+        let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+from-the-future = 1
+
+[network]
+firewall-check = false
+unknown-network-key = "kept"
+
+[devices.phone]
+id = "phone"
+
+[devices.phone.plugins.share]
+unknown-share-key = true
+
+[devices.tablet]
+id = "tablet"
+unknown-device-key = 2
+"#,
+        )
+        .unwrap();
+
+        let store = SettingsStore::load_or_default(&path).unwrap();
+        store
+            .update_device("phone", |d| d.name = Some("Phone".into()))
+            .unwrap();
+        store.remove_device("tablet").unwrap();
+        store.update_network(|n| n.firewall_check = true).unwrap();
+
+        let saved: toml::Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(saved["from-the-future"].as_integer(), Some(1));
+        assert_eq!(
+            saved["network"]["unknown-network-key"].as_str(),
+            Some("kept")
+        );
+        assert_eq!(saved["network"]["firewall-check"].as_bool(), Some(true));
+        let phone = &saved["devices"]["phone"];
+        assert_eq!(phone["name"].as_str(), Some("Phone"));
+        assert_eq!(
+            phone["plugins"]["share"]["unknown-share-key"].as_bool(),
+            Some(true)
+        );
+        // Removed devices do not come back through their unknown keys.
+        assert!(saved["devices"].get("tablet").is_none());
+    }
+
+    #[test]
+    fn devices_saved_before_trust_levels_keep_opening_links() {
+        let settings: Settings = toml::from_str(
+            r#"
+[devices.phone]
+id = "phone"
+
+[devices.tablet]
+id = "tablet"
+trust = "ask"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.devices["phone"].trust,
+            TrustLevel::AcceptEverything
+        );
+        assert_eq!(settings.devices["tablet"].trust, TrustLevel::Ask);
+    }
+
+    #[test]
+    fn new_devices_only_accept_files() {
+        let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+
+        let store = SettingsStore::load_or_default(&path).unwrap();
+        assert_eq!(store.device("phone").trust, TrustLevel::AcceptFiles);
+        store
+            .update_device("phone", |d| d.name = Some("Phone".into()))
+            .unwrap();
+        let reloaded = SettingsStore::load_or_default(&path).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(reloaded.device("phone").trust, TrustLevel::AcceptFiles);
+    }
+
+    #[test]
+    fn trust_levels_decide_what_is_asked() {
+        assert!(!TrustLevel::Ask.accepts_files());
+        assert!(TrustLevel::AcceptFiles.accepts_files());
+        assert!(TrustLevel::AcceptEverything.accepts_files());
+
+        assert!(!TrustLevel::Ask.opens_urls(false));
+        assert!(!TrustLevel::AcceptFiles.opens_urls(false));
+        assert!(TrustLevel::AcceptEverything.opens_urls(false));
+        assert!(!TrustLevel::AcceptEverything.opens_urls(true));
+    }
+}
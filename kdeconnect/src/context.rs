@@ -1,23 +1,69 @@
-use crate::{config::Config, device::DeviceManagerHandle, CustomWindowEvent};
-use anyhow::Result;
-use once_cell::sync::OnceCell;
-use std::{fmt::Debug, sync::Arc};
-use tao::{event_loop::EventLoopProxy, global_shortcut::ShortcutManager};
-use tokio::{
-    net::{TcpStream, ToSocketAddrs},
-    sync::Mutex,
+use crate::{
+    config::{Config, SettingsStore},
+    device::DeviceManagerHandle,
+    event::EventBus,
+    service::{self, ServiceMessage},
+    transfer::TransferManager,
+    CustomWindowEvent,
 };
+use anyhow::{Context, Result};
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use tao::{
+    accelerator::Accelerator,
+    event_loop::EventLoopProxy,
+    global_shortcut::{GlobalShortcut, ShortcutManager},
+};
+use tokio::{net::TcpStream, sync::Mutex};
 use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
 
 pub type AppContextRef = Arc<ApplicationContext>;
 
+/// TLS configuration for connections to devices and payload transfers.
+#[async_trait::async_trait]
+pub trait TlsProvider: Send + Sync {
+    fn tls_acceptor(&self) -> Result<TlsAcceptor>;
+
+    fn tls_connector(&self) -> Result<TlsConnector>;
+
+    /// Connect to a device, e.g. to download a payload.
+    async fn tls_connect(&self, addr: SocketAddr) -> Result<TlsStream<TcpStream>> {
+        let connector = self.tls_connector()?;
+        let stream = TcpStream::connect(addr).await?;
+        let tls_stream = connector
+            .connect(
+                tokio_rustls::rustls::ServerName::IpAddress(addr.ip()),
+                stream,
+            )
+            .await?;
+
+        Ok(tls_stream)
+    }
+}
+
+/// Rebuilds the tray menu, after something shown in it changed.
+#[async_trait::async_trait]
+pub trait TrayUpdater: Send + Sync {
+    async fn update_tray(&self);
+}
+
+/// Registers global hotkeys, which are only available when the process owns the event loop.
+#[async_trait::async_trait]
+pub trait HotkeyRegistrar: Send + Sync {
+    async fn register_hotkey(&self, accelerator: Accelerator) -> Result<GlobalShortcut>;
+
+    async fn unregister_hotkey(&self, shortcut: GlobalShortcut) -> Result<()>;
+}
+
 pub struct ApplicationContext {
-    pub device_manager: DeviceManagerHandle,
-    pub config: Config,
-    pub tls_acceptor: OnceCell<TlsAcceptor>,
-    pub tls_connector: OnceCell<TlsConnector>,
-    pub event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
-    pub hotkey_manager: Mutex<ShortcutManager>,
+    device_manager: DeviceManagerHandle,
+    config: Config,
+    settings: SettingsStore,
+    tls: Option<(TlsAcceptor, TlsConnector)>,
+    /// `None` when running as a service, where the UI helper owns the event loop.
+    event_loop_proxy: Option<EventLoopProxy<CustomWindowEvent>>,
+    event_bus: EventBus,
+    hotkey_manager: Option<Mutex<ShortcutManager>>,
+    transfers: TransferManager,
 }
 
 impl Debug for ApplicationContext {
@@ -26,59 +72,141 @@ impl Debug for ApplicationContext {
     }
 }
 
-impl ApplicationContext {
-    pub async fn new(
-        config: Config,
-        event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
-        hotkey_manager: ShortcutManager,
-    ) -> Result<Arc<Self>> {
+/// Collects the parts of an [`ApplicationContext`], see [`ApplicationContext::builder`].
+pub struct ContextBuilder {
+    config: Config,
+    settings: SettingsStore,
+    tls: Option<(TlsAcceptor, TlsConnector)>,
+    event_loop_proxy: Option<EventLoopProxy<CustomWindowEvent>>,
+    event_bus: Option<EventBus>,
+    hotkey_manager: Option<ShortcutManager>,
+}
+
+impl ContextBuilder {
+    pub fn tls(mut self, acceptor: TlsAcceptor, connector: TlsConnector) -> Self {
+        self.tls = Some((acceptor, connector));
+        self
+    }
+
+    /// Leave it unset when running as a service, window events are then sent to the UI helper.
+    pub fn event_loop_proxy(mut self, proxy: EventLoopProxy<CustomWindowEvent>) -> Self {
+        self.event_loop_proxy = Some(proxy);
+        self
+    }
+
+    pub fn event_bus(mut self, event_bus: EventBus) -> Self {
+        self.event_bus = Some(event_bus);
+        self
+    }
+
+    pub fn hotkey_manager(mut self, hotkey_manager: ShortcutManager) -> Self {
+        self.hotkey_manager = Some(hotkey_manager);
+        self
+    }
+
+    /// Create the context and start the device manager.
+    pub fn build(self) -> AppContextRef {
         let (device_manager_actor, device_manager) = crate::device::DeviceManagerActor::new();
 
-        let this = Arc::new(Self {
+        let this = Arc::new(ApplicationContext {
             device_manager,
-            config,
-            tls_acceptor: OnceCell::new(),
-            tls_connector: OnceCell::new(),
-            event_loop_proxy,
-            hotkey_manager: Mutex::new(hotkey_manager),
+            config: self.config,
+            settings: self.settings,
+            tls: self.tls,
+            event_loop_proxy: self.event_loop_proxy,
+            event_bus: self.event_bus.unwrap_or_else(EventBus::new),
+            hotkey_manager: self.hotkey_manager.map(Mutex::new),
+            transfers: TransferManager::default(),
         });
 
         device_manager_actor.run(this.clone());
 
-        Ok(this)
+        this
     }
+}
 
-    pub fn setup_tls(&self, acceptor: TlsAcceptor, connector: TlsConnector) {
-        self.tls_acceptor.set(acceptor).ok();
-        self.tls_connector.set(connector).ok();
+impl ApplicationContext {
+    pub fn builder(config: Config, settings: SettingsStore) -> ContextBuilder {
+        ContextBuilder {
+            config,
+            settings,
+            tls: None,
+            event_loop_proxy: None,
+            event_bus: None,
+            hotkey_manager: None,
+        }
     }
 
-    pub fn tls_acceptor(&self) -> TlsAcceptor {
-        self.tls_acceptor.get().unwrap().clone()
+    pub fn device_manager(&self) -> &DeviceManagerHandle {
+        &self.device_manager
     }
 
-    pub fn tls_connector(&self) -> TlsConnector {
-        self.tls_connector.get().unwrap().clone()
+    pub fn config(&self) -> &Config {
+        &self.config
     }
 
-    pub async fn tls_connect(
-        &self,
-        addr: impl ToSocketAddrs,
-    ) -> std::io::Result<TlsStream<TcpStream>> {
-        let stream = tokio::net::TcpStream::connect(addr).await?;
-        let peer = stream.peer_addr()?;
-        let tls_stream = self
-            .tls_connector()
-            .connect(
-                tokio_rustls::rustls::ServerName::IpAddress(peer.ip()),
-                stream,
-            )
-            .await?;
+    pub fn settings(&self) -> &SettingsStore {
+        &self.settings
+    }
 
-        Ok(tls_stream)
+    /// Payload transfers in progress and recently finished.
+    pub fn transfers(&self) -> &TransferManager {
+        &self.transfers
+    }
+
+    pub fn event_bus(&self) -> &EventBus {
+        &self.event_bus
     }
 
-    pub async fn update_tray(&self) {
+    /// Send an event to the event loop, or to the UI helper when running as a service.
+    pub fn send_window_event(&self, event: CustomWindowEvent) {
+        match &self.event_loop_proxy {
+            Some(proxy) => {
+                proxy.send_event(event).ok();
+            }
+            None => {
+                if let Some(link) = service::link::get() {
+                    link.send(ServiceMessage::Window { event });
+                }
+            }
+        }
+    }
+}
+
+impl TlsProvider for ApplicationContext {
+    fn tls_acceptor(&self) -> Result<TlsAcceptor> {
+        let (acceptor, _) = self.tls.as_ref().context("TLS is not set up")?;
+        Ok(acceptor.clone())
+    }
+
+    fn tls_connector(&self) -> Result<TlsConnector> {
+        let (_, connector) = self.tls.as_ref().context("TLS is not set up")?;
+        Ok(connector.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl TrayUpdater for ApplicationContext {
+    async fn update_tray(&self) {
         self.device_manager.update_tray().await;
     }
 }
+
+#[async_trait::async_trait]
+impl HotkeyRegistrar for ApplicationContext {
+    async fn register_hotkey(&self, accelerator: Accelerator) -> Result<GlobalShortcut> {
+        let manager = self
+            .hotkey_manager
+            .as_ref()
+            .context("Global hotkeys are not available")?;
+        Ok(manager.lock().await.register(accelerator)?)
+    }
+
+    async fn unregister_hotkey(&self, shortcut: GlobalShortcut) -> Result<()> {
+        let manager = self
+            .hotkey_manager
+            .as_ref()
+            .context("Global hotkeys are not available")?;
+        Ok(manager.lock().await.unregister(shortcut)?)
+    }
+}
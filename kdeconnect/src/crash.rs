@@ -0,0 +1,205 @@
+//! Reporting panics: they are logged with a backtrace, and those that are not caught by the
+//! plugin isolation also show a toast and write a crash report for bug reports.
+
+use std::{
+    backtrace::Backtrace,
+    cell::Cell,
+    fmt::Write as _,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use once_cell::sync::OnceCell;
+use winrt_toast::Toast;
+
+use crate::{
+    context::AppContextRef,
+    logging,
+    service::{self, ServiceMessage},
+    utils,
+};
+
+/// Set once the server is running, for the device summary and the settings.
+static CONTEXT: OnceCell<CrashContext> = OnceCell::new();
+
+/// Only the first panic shows a toast, a broken task may panic over and over.
+static TOAST_SHOWN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Number of [`ExpectPanics`] futures being polled on this thread.
+    static EXPECTED: Cell<usize> = const { Cell::new(0) };
+}
+
+struct CrashContext {
+    ctx: AppContextRef,
+    /// Read once, the settings may be locked by the code that panicked.
+    write_reports: bool,
+}
+
+/// Report panics, with reports written to `data_dir`.
+pub fn install_hook(data_dir: PathBuf) {
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::force_capture();
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("unnamed");
+
+        log::error!("Panic in thread {}: {}\n{}", thread, info, backtrace);
+
+        if EXPECTED.with(|e| e.get()) > 0 {
+            // Caught and reported by the plugin isolation.
+            return;
+        }
+
+        let report = match CONTEXT.get() {
+            Some(c) if !c.write_reports => None,
+            context => {
+                let content = crash_report(info, thread, &backtrace, context.map(|c| &c.ctx));
+                match write_report(&data_dir, &content) {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        log::error!("Failed to write crash report: {:?}", e);
+                        None
+                    }
+                }
+            }
+        };
+
+        if !TOAST_SHOWN.swap(true, Ordering::Relaxed) {
+            show_toast(report.as_deref());
+        }
+    }));
+}
+
+/// Fill the crash reports with what the server knows.
+pub fn set_context(ctx: AppContextRef) {
+    let write_reports = ctx.settings().diagnostics().crash_reports;
+    CONTEXT.set(CrashContext { ctx, write_reports }).ok();
+}
+
+/// Mark panics in `fut` as caught, so that they are only logged.
+pub fn expect_panics<F: Future>(fut: F) -> ExpectPanics<F> {
+    ExpectPanics {
+        inner: Box::pin(fut),
+    }
+}
+
+pub struct ExpectPanics<F> {
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for ExpectPanics<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        /// Decrements even when the poll panics.
+        struct Guard;
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                EXPECTED.with(|e| e.set(e.get() - 1));
+            }
+        }
+
+        EXPECTED.with(|e| e.set(e.get() + 1));
+        let _guard = Guard;
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+fn crash_report(
+    info: &dyn std::fmt::Display,
+    thread: &str,
+    backtrace: &Backtrace,
+    ctx: Option<&AppContextRef>,
+) -> String {
+    let mut report = String::new();
+    writeln!(
+        report,
+        "KDE Connect {} crash report",
+        env!("CARGO_PKG_VERSION")
+    )
+    .ok();
+    writeln!(report, "Time: {}", time::OffsetDateTime::now_utc()).ok();
+    writeln!(report, "Thread: {}", thread).ok();
+    writeln!(report, "Panic: {}", info).ok();
+    writeln!(report, "\nBacktrace:\n{}", backtrace).ok();
+
+    writeln!(report, "\nDevices:").ok();
+    match ctx {
+        Some(ctx) => report.push_str(&device_summary(ctx)),
+        None => report.push_str("  (server not started)\n"),
+    }
+
+    writeln!(report, "\nLast log lines:").ok();
+    for line in logging::recent_lines() {
+        writeln!(report, "{}", line).ok();
+    }
+
+    report
+}
+
+/// The known devices, and whether they are connected.
+fn device_summary(ctx: &AppContextRef) -> String {
+    let (known, connected) = match (
+        ctx.settings().try_devices(),
+        ctx.device_manager().try_connected_devices(),
+    ) {
+        (Some(known), Some(connected)) => (known, connected),
+        _ => return "  (unavailable)\n".to_string(),
+    };
+
+    let mut summary = String::new();
+    for device in &known {
+        let state = if connected.contains(&device.id) {
+            "connected"
+        } else {
+            "not connected"
+        };
+        let name = device.name.as_deref().unwrap_or("unknown");
+        writeln!(summary, "  {} ({}): {}", name, device.id, state).ok();
+    }
+    for id in connected
+        .iter()
+        .filter(|id| !known.iter().any(|d| &d.id == *id))
+    {
+        writeln!(summary, "  {}: connected, no settings", id).ok();
+    }
+    if summary.is_empty() {
+        summary.push_str("  (none)\n");
+    }
+    summary
+}
+
+fn write_report(data_dir: &Path, content: &str) -> std::io::Result<PathBuf> {
+    let dir = data_dir.join("crash-reports");
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("crash-{}.txt", utils::unix_ts_ms()));
+    std::fs::write(&path, content)?;
+    Ok(path)
+}
+
+fn show_toast(report: Option<&Path>) {
+    let title = "KDE Connect ran into a problem";
+    let content = match report {
+        Some(path) => format!("A crash report was saved to {}", path.display()),
+        None => "Details were written to the log.".to_string(),
+    };
+
+    if let Some(link) = service::link::get() {
+        link.send(ServiceMessage::Toast {
+            title: title.into(),
+            content: Some(content),
+            attribution: None,
+        });
+        return;
+    }
+
+    let mut toast = Toast::new();
+    toast.text1(title).text2(content);
+    // The toast thread may be the one crashing.
+    if let Err(e) = utils::toast::new_manager().and_then(|manager| manager.show(&toast)) {
+        log::error!("Failed to show crash toast: {:?}", e);
+    }
+}
@@ -0,0 +1,1416 @@
+//! A connection to a device: handshake, packet framing and the send/receive loop.
+
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use socket2::Socket;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+    time::Instant,
+};
+use tokio_rustls::{
+    rustls::{CipherSuite, CommonState, ProtocolVersion, ServerName},
+    TlsAcceptor, TlsStream,
+};
+
+use crate::{
+    codec::{self, Codec, JsonLineCodec},
+    config::{NetworkSettings, PortRange},
+    context::{AppContextRef, TlsProvider},
+    packet::{self, IdentityPacket, NetworkPacket, NetworkPacketWithPayload, PairPacket},
+    plugin::{packets::PacketKind, Capabilities},
+    tls,
+    transfer::{Direction, PayloadProgress, TransferState},
+    utils,
+};
+
+use super::{
+    packet_log::PacketLog,
+    queue::{OutgoingReceiver, QueuedPacket},
+    stats::TrafficStats,
+};
+
+/// How long the remote device has to send its identity.
+const IDENTITY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long writing a single packet may take before the connection is considered dead.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a payload is available for download, once a transfer has started it may take longer.
+const PAYLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+/// Payloads are written in chunks of this size, to report progress and notice cancellation.
+const PAYLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// Probe the connection after receiving nothing for this long.
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(2 * 60);
+/// How long a device has to answer a probe.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// TCP keepalive probes start after the connection is idle this long. Windows gives up after 10
+/// unanswered ones, which fails reads from the connection.
+const TCP_KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// How far the timestamp of a pairing request may be from our clock.
+const PAIR_TIMESTAMP_TOLERANCE: Duration = Duration::from_secs(30 * 60);
+/// How much is read from the connection at once.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+/// Capacity of the send buffer kept between packets. A larger one, grown by an unusually large
+/// packet, is released after sending it.
+const WRITE_BUFFER_RETAINED: usize = 64 * 1024;
+
+/// Which side of the TCP connection we are.
+#[derive(Debug)]
+pub enum Role {
+    /// The device connected to us.
+    Server,
+    /// We connected to the device after receiving its identity over UDP.
+    Client { remote_identity: IdentityPacket },
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::Client { .. } => "client",
+        }
+    }
+}
+
+/// Application-level keepalive, which notices devices whose connection is still up but that
+/// stopped handling packets.
+///
+/// After `idle` without incoming traffic a probe is sent, which the device must answer within
+/// `timeout`. Devices that ignore the first probe, like the official clients, are not probed
+/// again: dead connections to them are noticed by TCP keepalive, which the device answers from
+/// its network stack, even while asleep. A device that disappears is dropped within
+/// about a minute that way.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: KEEPALIVE_IDLE,
+            timeout: KEEPALIVE_TIMEOUT,
+        }
+    }
+}
+
+/// Behavior that changed with the protocol version of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolFeatures {
+    /// Identities are sent again once the connection is encrypted, and only those are trusted.
+    pub identity_over_tls: bool,
+    /// Pairing requests carry a timestamp, which is hashed into the verification key.
+    pub pair_timestamp: bool,
+}
+
+impl ProtocolFeatures {
+    pub fn for_version(version: u8) -> Self {
+        Self {
+            identity_over_tls: version >= 8,
+            pair_timestamp: version >= 8,
+        }
+    }
+}
+
+/// Outcome of a successful handshake.
+pub struct Handshake {
+    pub conn: DeviceConnection<TlsStream<TcpStream>>,
+    pub remote_identity: IdentityPacket,
+    /// Capabilities the device has been told about.
+    pub advertised_caps: Capabilities,
+    pub info: ConnectionInfo,
+}
+
+/// Details of an established connection, shown in the tray for debugging.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The older of our protocol version and the one of the device.
+    pub protocol_version: u8,
+    pub features: ProtocolFeatures,
+    pub tls_version: Option<ProtocolVersion>,
+    pub cipher_suite: Option<CipherSuite>,
+    /// DER encoded certificate of the device.
+    pub certificate: Option<Vec<u8>>,
+    /// Derived from both certificates, see [`tls::verification_key`]. From protocol version 8
+    /// it depends on the pairing request, see [`ConnectionInfo::pairing_verification_key`].
+    pub verification_key: Option<String>,
+    /// The device exchanges which plugins are enabled, which is not part of the standard
+    /// protocol and only advertised by devices running this app.
+    pub plugin_state: bool,
+}
+
+impl ConnectionInfo {
+    fn new(tls: &CommonState, remote_identity: &IdentityPacket, local_cert: &[u8]) -> Self {
+        let protocol_version = remote_identity
+            .protocol_version
+            .min(packet::PROTOCOL_VERSION);
+        let features = ProtocolFeatures::for_version(protocol_version);
+        let certificate = tls
+            .peer_certificates()
+            .and_then(|c| c.first())
+            .map(|c| c.0.clone());
+        let verification_key = match &certificate {
+            Some(cert) if !features.pair_timestamp => {
+                compute_verification_key(local_cert, cert, None)
+            }
+            _ => None,
+        };
+
+        Self {
+            protocol_version,
+            features,
+            tls_version: tls.protocol_version(),
+            cipher_suite: tls.negotiated_cipher_suite().map(|s| s.suite()),
+            certificate,
+            verification_key,
+            plugin_state: accepts_plugin_state(remote_identity),
+        }
+    }
+
+    /// The verification key of a pairing request made at `timestamp`, if the device sent one.
+    pub fn pairing_verification_key(
+        &self,
+        local_cert: &[u8],
+        timestamp: Option<u64>,
+    ) -> Option<String> {
+        match (timestamp, &self.certificate) {
+            (Some(timestamp), Some(cert)) => {
+                compute_verification_key(local_cert, cert, Some(timestamp))
+            }
+            _ => self.verification_key.clone(),
+        }
+    }
+
+    /// SHA-256 fingerprint of the certificate of the device.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.certificate.as_deref().map(tls::fingerprint)
+    }
+}
+
+fn compute_verification_key(
+    local_cert: &[u8],
+    remote_cert: &[u8],
+    timestamp: Option<u64>,
+) -> Option<String> {
+    tls::verification_key(local_cert, remote_cert, timestamp)
+        .map_err(|e| log::warn!("Failed to compute verification key: {:?}", e))
+        .ok()
+}
+
+fn enable_keepalive(stream: TcpStream) -> Result<TcpStream> {
+    let s2_socket = Socket::from(stream.into_std()?);
+    s2_socket.set_keepalive(true)?;
+    s2_socket.set_tcp_keepalive(
+        &socket2::TcpKeepalive::new()
+            .with_time(TCP_KEEPALIVE_TIME)
+            .with_interval(TCP_KEEPALIVE_INTERVAL),
+    )?;
+    Ok(TcpStream::from_std(s2_socket.into())?)
+}
+
+/// Exchange identities and upgrade the connection to TLS.
+pub async fn handshake(
+    role: Role,
+    stream: TcpStream,
+    ip: IpAddr,
+    ctx: &AppContextRef,
+) -> Result<Handshake> {
+    let mut stream = enable_keepalive(stream)?;
+
+    let (mut stream, remote_identity, advertised_caps, mut info) = match role {
+        Role::Server => {
+            let line =
+                tokio::time::timeout(IDENTITY_TIMEOUT, packet::read_identity_line(&mut stream))
+                    .await
+                    .context("Timed out waiting for identity")??;
+            let remote_identity = NetworkPacket::parse_identity(&line)?;
+
+            let started = Instant::now();
+            let stream = ctx
+                .tls_connector()?
+                .connect(ServerName::IpAddress(ip), stream)
+                .await
+                .context("TLS connect")?;
+            log::debug!("TLS handshake with {} took {:?}", ip, started.elapsed());
+            let info =
+                ConnectionInfo::new(stream.get_ref().1, &remote_identity, &ctx.config().tls_cert);
+
+            (
+                TlsStream::from(stream),
+                remote_identity,
+                // The remote device got our identity from the UDP broadcast.
+                Capabilities::all(),
+                info,
+            )
+        }
+        Role::Client { remote_identity } => {
+            // Prefer the plugins already loaded for this device if it is still known.
+            let caps = ctx
+                .device_manager()
+                .query_capabilities(&remote_identity.device_id)
+                .await?
+                .unwrap_or_else(Capabilities::all);
+            let local_identity_packet = NetworkPacket::new_identity(
+                None,
+                caps.incoming.iter().cloned(),
+                caps.outgoing.iter().cloned(),
+                ctx.config(),
+            );
+            local_identity_packet.write_to_conn(&mut stream).await?;
+
+            let started = Instant::now();
+            let stream = ctx
+                .tls_acceptor()?
+                .accept(stream)
+                .await
+                .context("TLS accept")?;
+            log::debug!("TLS handshake with {} took {:?}", ip, started.elapsed());
+            let info =
+                ConnectionInfo::new(stream.get_ref().1, &remote_identity, &ctx.config().tls_cert);
+
+            (TlsStream::from(stream), remote_identity, caps, info)
+        }
+    };
+
+    let mut codec: Box<dyn Codec> = Box::<JsonLineCodec>::default();
+    let remote_identity = if info.features.identity_over_tls {
+        // Other encodings are only offered in the identity that cannot be tampered with.
+        let codecs = ctx.settings().network().experimental_codecs;
+        let local_identity_packet = NetworkPacket::new_identity(
+            None,
+            advertised_caps
+                .incoming
+                .iter()
+                .cloned()
+                .chain(codec::capabilities(&codecs)),
+            advertised_caps.outgoing.iter().cloned(),
+            ctx.config(),
+        );
+        let identity =
+            exchange_identities(&mut stream, &local_identity_packet, &remote_identity).await?;
+        codec = codec::negotiate(&codecs, &identity.incoming_capabilities);
+        info.plugin_state = accepts_plugin_state(&identity);
+        identity
+    } else {
+        remote_identity
+    };
+    if codec.name() != "json" {
+        log::info!(
+            "Using the experimental {} encoding with {}",
+            codec.name(),
+            ip
+        );
+    }
+
+    Ok(Handshake {
+        conn: DeviceConnection::new(stream)
+            .with_payload_acceptor(ctx.tls_acceptor()?)
+            .with_peer_ip(ip)
+            .with_keepalive(Keepalive::default())
+            .with_features(info.features)
+            .with_codec(codec)
+            .with_payload_ports(ctx.settings().network().transfer_ports),
+        remote_identity,
+        advertised_caps,
+        info,
+    })
+}
+
+fn accepts_plugin_state(identity: &IdentityPacket) -> bool {
+    identity
+        .incoming_capabilities
+        .iter()
+        .any(|typ| typ == PacketKind::Plugins.as_str())
+}
+
+/// Send our identity again over the encrypted connection and read the one of the device, which
+/// replaces the one it sent in plain text. Both must have the same device id.
+async fn exchange_identities<S>(
+    stream: &mut S,
+    local_identity: &NetworkPacket,
+    plain_identity: &IdentityPacket,
+) -> Result<IdentityPacket>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    local_identity.write_to_conn(&mut *stream).await?;
+
+    let line = tokio::time::timeout(IDENTITY_TIMEOUT, packet::read_identity_line(stream))
+        .await
+        .context("Timed out waiting for encrypted identity")??;
+    let identity = NetworkPacket::parse_identity(&line)?;
+    if identity.device_id != plain_identity.device_id {
+        bail!(
+            "Device id changed from {} to {} after TLS",
+            plain_identity.device_id,
+            identity.device_id
+        );
+    }
+
+    Ok(identity)
+}
+
+/// Packet framing and the send/receive loop over an established connection.
+pub struct DeviceConnection<S> {
+    stream: BufStream<S>,
+    codec: Box<dyn Codec>,
+    /// Received data that has not been decoded yet, kept across calls to `recv`.
+    read_buf: BytesMut,
+    /// Serialized packet being sent, reused so that sending does not allocate.
+    write_buf: BytesMut,
+    /// Received packets larger than this close the connection.
+    max_packet_size: usize,
+    write_timeout: Duration,
+    /// Used to serve payloads; packets with payloads fail to send without it.
+    payload_acceptor: Option<TlsAcceptor>,
+    payload_ports: PortRange,
+    /// The address of the device, the only one allowed to download payloads if set.
+    peer_ip: Option<IpAddr>,
+    keepalive: Option<Keepalive>,
+    /// Counts the packets and served payloads, once the connection belongs to a device.
+    stats: Option<Arc<TrafficStats>>,
+    /// Records the packets, once the connection belongs to a device.
+    packet_log: Option<Arc<PacketLog>>,
+    /// Payloads being served, which are stopped when the connection is dropped.
+    payload_servers: Vec<JoinHandle<()>>,
+    features: ProtocolFeatures,
+    /// Called after a pairing request has been accepted, with its timestamp if it had one.
+    on_pair: Option<Box<dyn Fn(Option<u64>) + Send + Sync>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> DeviceConnection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufStream::new(stream),
+            codec: Box::<JsonLineCodec>::default(),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            max_packet_size: packet::MAX_PACKET_SIZE,
+            write_timeout: WRITE_TIMEOUT,
+            payload_acceptor: None,
+            payload_ports: NetworkSettings::default().transfer_ports,
+            peer_ip: None,
+            keepalive: None,
+            stats: None,
+            packet_log: None,
+            payload_servers: Vec::new(),
+            features: ProtocolFeatures::for_version(7),
+            on_pair: None,
+        }
+    }
+
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    pub fn with_codec(mut self, codec: Box<dyn Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    pub fn with_payload_acceptor(mut self, acceptor: TlsAcceptor) -> Self {
+        self.payload_acceptor = Some(acceptor);
+        self
+    }
+
+    pub fn with_payload_ports(mut self, ports: PortRange) -> Self {
+        self.payload_ports = ports;
+        self
+    }
+
+    pub fn with_peer_ip(mut self, ip: IpAddr) -> Self {
+        self.peer_ip = Some(ip);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<TrafficStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn with_packet_log(mut self, packet_log: Arc<PacketLog>) -> Self {
+        self.packet_log = Some(packet_log);
+        self
+    }
+
+    pub fn with_features(mut self, features: ProtocolFeatures) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn on_pair(mut self, f: impl Fn(Option<u64>) + Send + Sync + 'static) -> Self {
+        self.on_pair = Some(Box::new(f));
+        self
+    }
+
+    /// Receive the next valid packet, or `None` if the connection was closed.
+    ///
+    /// Packets that fail to parse are logged and skipped, while a packet exceeding the maximum
+    /// size is an error. This is cancellation safe.
+    pub async fn recv(&mut self) -> Result<Option<NetworkPacket>> {
+        loop {
+            let buffered = self.read_buf.len();
+            if let Some(parsed) = self
+                .codec
+                .decode(&mut self.read_buf, self.max_packet_size)?
+            {
+                // Including the framing.
+                let size = buffered - self.read_buf.len();
+                if let Some(stats) = &self.stats {
+                    stats.add_received(size);
+                }
+                match parsed {
+                    Ok(packet) => {
+                        if let Some(log) = &self.packet_log {
+                            log.record(Direction::Incoming, &packet, size);
+                        }
+                        return Ok(Some(packet));
+                    }
+                    Err(err) => {
+                        log::error!("Failed to parse packet: {:?}", err);
+                    }
+                }
+                continue;
+            }
+
+            self.read_buf.reserve(READ_CHUNK_SIZE);
+            if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+                if self.read_buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed in the middle of a packet",
+                )
+                .into());
+            }
+        }
+    }
+
+    /// Write a packet, serving its payload if there is one.
+    pub async fn send(&mut self, mut packet: NetworkPacketWithPayload) -> Result<()> {
+        if let Some(payload) = packet.payload.take() {
+            let acceptor = self
+                .payload_acceptor
+                .clone()
+                .context("Payloads are not supported on this connection")?;
+
+            match open_payload_tcp_server(self.payload_ports).await {
+                Ok((payload_server, payload_port)) => {
+                    packet.packet.set_payload(payload.len() as _, payload_port);
+
+                    log::info!(
+                        "Serving a payload of {} bytes on {}",
+                        payload.len(),
+                        payload_port
+                    );
+
+                    let server = PayloadServer {
+                        listener: payload_server,
+                        acceptor,
+                        peer_ip: self.peer_ip,
+                        consumers: packet.consumers().get(),
+                        stats: self.stats.clone(),
+                        progress: packet.progress.take(),
+                    };
+                    self.payload_servers.retain(|task| !task.is_finished());
+                    self.payload_servers
+                        .push(tokio::spawn(async move { server.serve(payload).await }));
+                }
+                Err(e) => {
+                    log::error!("Failed to start payload server: {:?}", e);
+                }
+            }
+        }
+
+        self.write_buf.clear();
+        self.codec
+            .encode(&packet.packet, &mut self.write_buf)
+            .context("Serialize packet")?;
+
+        let write = async {
+            self.stream
+                .write_all(&self.write_buf)
+                .await
+                .context("Write to connection")?;
+            self.stream.flush().await.context("Flush connection")
+        };
+        let result = tokio::time::timeout(self.write_timeout, write)
+            .await
+            .context("Timed out writing to connection")?;
+        if result.is_ok() {
+            if let Some(stats) = &self.stats {
+                stats.add_sent(self.write_buf.len());
+            }
+            if let Some(log) = &self.packet_log {
+                log.record(Direction::Outgoing, &packet.packet, self.write_buf.len());
+            }
+        }
+
+        if self.write_buf.capacity() > WRITE_BUFFER_RETAINED {
+            self.write_buf = BytesMut::new();
+        }
+        result
+    }
+
+    async fn handle_pair(&mut self, packet: NetworkPacket) -> Result<()> {
+        let timestamp = packet
+            .into_body::<PairPacket>()
+            .ok()
+            .and_then(|p| p.timestamp);
+
+        if self.features.pair_timestamp {
+            let now = utils::unix_ts_ms() / 1000;
+            match timestamp {
+                Some(ts) if ts.abs_diff(now) <= PAIR_TIMESTAMP_TOLERANCE.as_secs() => {}
+                Some(ts) => {
+                    log::warn!(
+                        "Rejected pairing request, the clocks differ by {} seconds",
+                        ts.abs_diff(now)
+                    );
+                    return self.send(NetworkPacket::new_pair(false).into()).await;
+                }
+                None => {
+                    log::warn!("Rejected pairing request without timestamp");
+                    return self.send(NetworkPacket::new_pair(false).into()).await;
+                }
+            }
+        }
+
+        self.send(NetworkPacket::new_pair(true).into()).await?;
+        log::info!("Accepted pairing request");
+        if let Some(on_pair) = &self.on_pair {
+            on_pair(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Send queued packets and pass received ones to `dispatch`, until either side hangs up.
+    ///
+    /// Pairing requests are accepted directly, unless their timestamp is required and too far
+    /// from our clock.
+    pub async fn run<F, Fut>(
+        &mut self,
+        outgoing: &mut OutgoingReceiver,
+        mut dispatch: F,
+    ) -> Result<()>
+    where
+        F: FnMut(NetworkPacket) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut last_received = Instant::now();
+        // When the last unanswered probe was sent
+        let mut probe_sent: Option<Instant> = None;
+        let mut answers_probes = false;
+        // Set once the device ignored a probe, it is left to TCP keepalive then.
+        let mut ignores_probes = false;
+
+        loop {
+            let keepalive_deadline =
+                self.keepalive
+                    .filter(|_| !ignores_probes)
+                    .map(|k| match probe_sent {
+                        Some(sent) => sent + k.timeout,
+                        None => last_received + k.idle,
+                    });
+
+            tokio::select! {
+                queued = outgoing.recv() => {
+                    let (packet, ack) = match queued {
+                        Some(QueuedPacket { packet, ack }) => (packet, ack),
+                        None => {
+                            log::info!("Packet sender disconnected");
+                            return Ok(());
+                        }
+                    };
+
+                    // A probe requested from outside, e.g. after the computer woke up.
+                    if packet.packet.typ == packet::PACKET_TYPE_KEEPALIVE && probe_sent.is_none() {
+                        probe_sent = Some(Instant::now());
+                    }
+
+                    let result = self.send(packet).await;
+                    let error = result.as_ref().err().map(|e| format!("{:#}", e));
+                    if let Some(ack) = ack {
+                        ack.send(result).ok();
+                    }
+                    if let Some(e) = error {
+                        anyhow::bail!("Failed to send packet: {}", e);
+                    }
+                }
+
+                received = self.recv() => {
+                    let packet = match received.context("Failed to read from connection")? {
+                        Some(packet) => packet,
+                        None => {
+                            log::warn!("Connection closed (EOF)");
+                            return Ok(());
+                        }
+                    };
+
+                    last_received = Instant::now();
+                    probe_sent = None;
+
+                    match packet.typ.as_str() {
+                        packet::PACKET_TYPE_KEEPALIVE => {
+                            // Devices that send probes answer them too.
+                            answers_probes = true;
+                            ignores_probes = false;
+                            if packet.body["reply"] != true {
+                                self.send(NetworkPacket::new_keepalive(true).into()).await?;
+                            }
+                        }
+                        packet::PACKET_TYPE_PAIR => {
+                            self.handle_pair(packet).await?;
+                        }
+                        _ => {
+                            dispatch(packet).await;
+                        }
+                    }
+                }
+
+                _ = tokio::time::sleep_until(keepalive_deadline.unwrap_or_else(Instant::now)),
+                    if keepalive_deadline.is_some() =>
+                {
+                    if probe_sent.is_some() {
+                        if answers_probes {
+                            anyhow::bail!("No answer to keepalive probe");
+                        }
+                        log::debug!("Device ignores keepalive probes, relying on TCP keepalive");
+                        ignores_probes = true;
+                        continue;
+                    }
+
+                    log::debug!("Connection idle, sending keepalive probe");
+                    self.send(NetworkPacket::new_keepalive(false).into())
+                        .await
+                        .context("Failed to send keepalive probe")?;
+                    probe_sent = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+impl<S> Drop for DeviceConnection<S> {
+    fn drop(&mut self) {
+        // Nobody is going to download them once the device is gone.
+        for task in &self.payload_servers {
+            task.abort();
+        }
+    }
+}
+
+/// Opens a TCP listener on an empty port in `ports` for payload serving.
+async fn open_payload_tcp_server(ports: PortRange) -> Result<(TcpListener, u16)> {
+    let mut last_error = None;
+
+    for port in ports.ports() {
+        let addr = (Ipv4Addr::UNSPECIFIED, port);
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok((listener, port)),
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    match last_error {
+        Some(err) => Err(err).with_context(|| format!("No free transfer port in {}", ports)),
+        None => bail!("Empty transfer port range {}", ports),
+    }
+}
+
+/// Compare addresses regardless of IPv4 addresses being mapped to IPv6.
+fn same_ip(a: IpAddr, b: IpAddr) -> bool {
+    fn canonical(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        }
+    }
+    canonical(a) == canonical(b)
+}
+
+/// Serves a payload on its own port.
+struct PayloadServer {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    /// Connections from other addresses are closed, as the port is open to the whole network.
+    peer_ip: Option<IpAddr>,
+    /// Stop accepting connections after this many complete downloads.
+    consumers: usize,
+    stats: Option<Arc<TrafficStats>>,
+    progress: Option<Arc<PayloadProgress>>,
+}
+
+impl PayloadServer {
+    fn is_cancelled(&self) -> bool {
+        self.progress
+            .as_ref()
+            .is_some_and(|p| p.progress().is_cancelled())
+    }
+
+    /// Wait until the transfer is cancelled, forever if it cannot be.
+    async fn cancelled(progress: &Option<Arc<PayloadProgress>>) {
+        match progress {
+            Some(progress) => progress.progress().cancelled().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// The device has to connect to us for every payload, as stock clients cannot be asked to
+    /// serve it in the other direction. When it never does, a firewall is the usual suspect.
+    fn log_unreached(&self) {
+        let port = self
+            .listener
+            .local_addr()
+            .map(|a| a.port())
+            .unwrap_or_default();
+        log::warn!(
+            "The device did not connect to payload port {} within {:?}, check that the firewall \
+             allows inbound connections to the transfer ports",
+            port,
+            PAYLOAD_TIMEOUT
+        );
+    }
+
+    /// Serve `data` to concurrent or successive downloads, until `consumers` of them have
+    /// completed or no new one has started for `PAYLOAD_TIMEOUT`, and the started ones are
+    /// done.
+    ///
+    /// Transfers in progress are aborted when this future is dropped.
+    async fn serve(self, data: Arc<Vec<u8>>) {
+        let deadline = Instant::now() + PAYLOAD_TIMEOUT;
+        let mut transfers = JoinSet::new();
+        let mut completed = 0;
+        let mut reached = false;
+
+        while completed < self.consumers && !self.is_cancelled() {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                Some(res) = transfers.join_next(), if !transfers.is_empty() => {
+                    if matches!(res, Ok(true)) {
+                        completed += 1;
+                    }
+                    continue;
+                }
+                _ = Self::cancelled(&self.progress) => {
+                    log::info!("Payload cancelled, closing its port");
+                    break;
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    if !reached {
+                        self.log_unreached();
+                    }
+                    break;
+                }
+            };
+            let (stream, addr) = match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    log::error!("Error accepting payload connection: {:?}", e);
+                    break;
+                }
+            };
+
+            if let Some(peer_ip) = self.peer_ip {
+                if !same_ip(addr.ip(), peer_ip) {
+                    log::warn!("Rejected payload connection from {}", addr);
+                    continue;
+                }
+            }
+
+            reached = true;
+            log::info!("Payload connection from {}", addr);
+            let data = data.clone();
+            let acceptor = self.acceptor.clone();
+            let stats = self.stats.clone();
+            let progress = self.progress.clone();
+
+            transfers.spawn(async move {
+                let mut stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::error!("Failed to accept payload TLS connection: {}", e);
+                        return false;
+                    }
+                };
+
+                let payload = progress;
+                let progress = payload.as_deref().map(PayloadProgress::progress);
+                if let Some(progress) = progress {
+                    progress.reset();
+                }
+                for chunk in data.chunks(PAYLOAD_CHUNK_SIZE) {
+                    if let Some(payload) = &payload {
+                        payload.throttle(chunk.len()).await;
+                    }
+                    if progress.is_some_and(|p| p.is_cancelled()) {
+                        log::info!("Payload transfer to {} cancelled", addr);
+                        return false;
+                    }
+                    if let Err(err) = stream.write_all(chunk).await {
+                        log::error!("Error writing payload to {}: {:?}", addr, err);
+                        return false;
+                    }
+                    if let Some(progress) = progress {
+                        progress.add(chunk.len());
+                    }
+                }
+
+                if let Err(e) = stream.flush().await {
+                    log::error!("Error flushing payload to {}: {:?}", addr, e);
+                    return false;
+                }
+                if let Some(stats) = stats {
+                    stats.add_sent(data.len());
+                }
+                if let Some(progress) = progress {
+                    progress.finish(TransferState::Completed);
+                }
+                true
+            });
+        }
+
+        // Stop accepting, but let the started transfers finish.
+        drop(self.listener);
+        while transfers.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream},
+        sync::{oneshot, Mutex},
+    };
+
+    use super::*;
+    use crate::{device::queue::outgoing_queue, transfer::Progress, utils::bandwidth::Throttle};
+
+    fn ping(message: &str) -> NetworkPacket {
+        NetworkPacket::new("kdeconnect.ping", serde_json::json!({ "message": message }))
+    }
+
+    async fn read_packet(remote: &mut BufReader<DuplexStream>) -> NetworkPacket {
+        let mut line = String::new();
+        remote.read_line(&mut line).await.unwrap();
+        NetworkPacket::parse(line.trim_end().as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn recv_frames_split_packets() {
+        let (local, mut remote) = duplex(1024);
+        let mut conn = DeviceConnection::new(local);
+
+        let first = ping("first").to_vec().unwrap();
+        let second = ping("second").to_vec().unwrap();
+        let (head, tail) = first.split_at(10);
+
+        remote.write_all(head).await.unwrap();
+        // Nothing complete yet, and cancelling must not lose the partial packet.
+        assert!(tokio::time::timeout(Duration::from_millis(50), conn.recv())
+            .await
+            .is_err());
+
+        remote.write_all(tail).await.unwrap();
+        remote.write_all(b"\n").await.unwrap();
+        remote.write_all(&second).await.unwrap();
+        remote.write_all(b"\n").await.unwrap();
+        drop(remote);
+
+        let p = conn.recv().await.unwrap().unwrap();
+        assert_eq!(p.body["message"], "first");
+        let p = conn.recv().await.unwrap().unwrap();
+        assert_eq!(p.body["message"], "second");
+        assert!(conn.recv().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn recv_skips_invalid_packets() {
+        let (local, mut remote) = duplex(1024);
+        let mut conn = DeviceConnection::new(local);
+
+        remote.write_all(b"not json\n{}\n").await.unwrap();
+        ping("valid").write_to_conn(&mut remote).await.unwrap();
+
+        let p = conn.recv().await.unwrap().unwrap();
+        assert_eq!(p.body["message"], "valid");
+    }
+
+    #[tokio::test]
+    async fn recv_rejects_oversized_packets() {
+        let (local, mut remote) = duplex(64 * 1024);
+        let mut conn = DeviceConnection::new(local);
+
+        tokio::spawn(async move {
+            let chunk = vec![b'a'; 64 * 1024];
+            for _ in 0..(packet::MAX_PACKET_SIZE / chunk.len() + 2) {
+                if remote.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        assert!(conn.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn recv_uses_configured_max_packet_size() {
+        let (local, mut remote) = duplex(1024);
+        let mut conn = DeviceConnection::new(local).with_max_packet_size(100);
+
+        ping("small").write_to_conn(&mut remote).await.unwrap();
+        ping(&"a".repeat(100))
+            .write_to_conn(&mut remote)
+            .await
+            .unwrap();
+
+        let p = conn.recv().await.unwrap().unwrap();
+        assert_eq!(p.body["message"], "small");
+        assert!(conn.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_times_out_when_remote_stalls() {
+        let (local, _remote) = duplex(16);
+        let mut conn = DeviceConnection::new(local).with_write_timeout(Duration::from_millis(50));
+
+        let big = NetworkPacket::new(
+            "kdeconnect.clipboard",
+            serde_json::json!({ "content": "a".repeat(64 * 1024) }),
+        );
+        assert!(conn.send(big.into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_reuses_write_buffer() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut conn = DeviceConnection::new(local);
+        let mut remote = BufReader::new(remote);
+
+        // A flood of small packets, like the input events of a touchpad.
+        let reader = tokio::spawn(async move {
+            for i in 0..10_000 {
+                let p = read_packet(&mut remote).await;
+                assert_eq!(p.body["dx"], i);
+            }
+            remote
+        });
+        for i in 0..10_000 {
+            let packet = NetworkPacket::new(
+                "kdeconnect.mousepad.request",
+                serde_json::json!({ "dx": i, "dy": 0 }),
+            );
+            conn.send(packet.into()).await.unwrap();
+        }
+        let capacity = conn.write_buf.capacity();
+        assert!(capacity > 0 && capacity <= WRITE_BUFFER_RETAINED);
+        let mut remote = reader.await.unwrap();
+
+        // The buffer grown by a large packet is not kept around.
+        let big = NetworkPacket::new(
+            "kdeconnect.clipboard",
+            serde_json::json!({ "content": "a".repeat(2 * WRITE_BUFFER_RETAINED) }),
+        );
+        let reader = tokio::spawn(async move { read_packet(&mut remote).await });
+        conn.send(big.into()).await.unwrap();
+        assert_eq!(conn.write_buf.capacity(), 0);
+        assert_eq!(reader.await.unwrap().typ, "kdeconnect.clipboard");
+    }
+
+    #[tokio::test]
+    async fn send_payload_requires_acceptor() {
+        let (local, _remote) = duplex(1024);
+        let mut conn = DeviceConnection::new(local);
+
+        let packet = NetworkPacketWithPayload::new(ping("payload"), Arc::new(vec![0; 16]));
+        assert!(conn.send(packet).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_stops_payload_servers() {
+        let config = crate::config::Config::init().unwrap();
+        let (acceptor, _) = crate::tls::build_tls(&config).unwrap();
+        let (local, remote) = duplex(1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local).with_payload_acceptor(acceptor);
+
+        let packet = NetworkPacketWithPayload::new(ping("payload"), Arc::new(vec![0; 16]));
+        conn.send(packet).await.unwrap();
+        let info = read_packet(&mut remote).await.payload_transfer_info;
+        let port = info.unwrap().port;
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_ok());
+
+        drop(conn);
+        // The listener is closed once the aborted task is dropped by the runtime.
+        for _ in 0..100 {
+            if TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Payload server still listening on {}", port);
+    }
+
+    #[tokio::test]
+    async fn payload_server_rejects_other_hosts() {
+        let config = crate::config::Config::init().unwrap();
+        let (acceptor, _) = crate::tls::build_tls(&config).unwrap();
+        let (local, remote) = duplex(1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local)
+            .with_payload_acceptor(acceptor)
+            .with_peer_ip(Ipv4Addr::LOCALHOST.into());
+
+        let packet = NetworkPacketWithPayload::new(ping("payload"), Arc::new(vec![0; 16]));
+        conn.send(packet).await.unwrap();
+        let info = read_packet(&mut remote).await.payload_transfer_info;
+        let port = info.unwrap().port;
+
+        // Another address of the loopback network, standing in for another host.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket
+            .bind((Ipv4Addr::new(127, 0, 0, 2), 0).into())
+            .unwrap();
+        let mut stream = socket
+            .connect((Ipv4Addr::LOCALHOST, port).into())
+            .await
+            .unwrap();
+
+        // Closed without a TLS handshake, let alone the payload.
+        let mut buf = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf));
+        assert!(matches!(read.await, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn payload_server_stops_after_consumers() {
+        let config = crate::config::Config::init().unwrap();
+        let (acceptor, connector) = crate::tls::build_tls(&config).unwrap();
+        let (listener, port) = open_payload_tcp_server(NetworkSettings::default().transfer_ports)
+            .await
+            .unwrap();
+        let server = PayloadServer {
+            listener,
+            acceptor,
+            peer_ip: None,
+            consumers: 2,
+            stats: None,
+            progress: None,
+        };
+        let task = tokio::spawn(server.serve(Arc::new(vec![7; 1000])));
+
+        for _ in 0..2 {
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+                .await
+                .unwrap();
+            let name = ServerName::IpAddress(Ipv4Addr::LOCALHOST.into());
+            let mut stream = connector.connect(name, stream).await.unwrap();
+            let mut buf = vec![0; 1000];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, vec![7; 1000]);
+        }
+
+        // Long before the payload times out.
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn payload_server_stops_when_cancelled() {
+        let context = crate::testing::TestContext::new().unwrap();
+        let config = crate::config::Config::init().unwrap();
+        let (acceptor, _) = crate::tls::build_tls(&config).unwrap();
+        let (listener, port) = open_payload_tcp_server(NetworkSettings::default().transfer_ports)
+            .await
+            .unwrap();
+        let progress = Arc::new(Progress::default());
+        let throttle = Throttle::new(&context.ctx, "test-device", Direction::Outgoing);
+        let server = PayloadServer {
+            listener,
+            acceptor,
+            peer_ip: None,
+            consumers: 1,
+            stats: None,
+            progress: Some(Arc::new(PayloadProgress::new(
+                progress.clone(),
+                Arc::new(throttle),
+            ))),
+        };
+        let task = tokio::spawn(server.serve(Arc::new(vec![7; 1000])));
+
+        // Waiting for a download.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        progress.cancel();
+
+        // Long before the payload times out.
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_err());
+        assert_eq!(progress.state(), TransferState::Cancelled);
+    }
+
+    #[test]
+    fn same_ip_ignores_ipv4_mapping() {
+        let v4 = IpAddr::from(Ipv4Addr::new(192, 168, 1, 2));
+        let mapped = IpAddr::from(Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped());
+        assert!(same_ip(v4, mapped));
+        assert!(!same_ip(v4, Ipv4Addr::new(192, 168, 1, 3).into()));
+    }
+
+    #[tokio::test]
+    async fn run_sends_control_packets_first() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local);
+        let (tx, mut rx) = outgoing_queue();
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        tx.send(QueuedPacket::new(
+            NetworkPacket::new("kdeconnect.battery", serde_json::json!({})).into(),
+            None,
+        ));
+        tx.send(QueuedPacket::new(ping("control").into(), Some(ack_tx)));
+
+        let task = tokio::spawn(async move { conn.run(&mut rx, |_| async {}).await });
+
+        assert_eq!(read_packet(&mut remote).await.typ, "kdeconnect.ping");
+        assert_eq!(read_packet(&mut remote).await.typ, "kdeconnect.battery");
+        ack_rx.await.unwrap().unwrap();
+
+        // Dropping the sender ends the loop cleanly.
+        drop(tx);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_answers_keepalive_probes() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local);
+        let (_tx, mut rx) = outgoing_queue();
+
+        let task = tokio::spawn(async move { conn.run(&mut rx, |_| async {}).await });
+
+        NetworkPacket::new_keepalive(false)
+            .write_to_conn(remote.get_mut())
+            .await
+            .unwrap();
+        let reply = read_packet(&mut remote).await;
+        assert_eq!(reply.typ, packet::PACKET_TYPE_KEEPALIVE);
+        assert_eq!(reply.body["reply"], true);
+
+        drop(remote);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_drops_connection_without_keepalive_answer() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local).with_keepalive(Keepalive {
+            idle: Duration::from_millis(50),
+            timeout: Duration::from_millis(50),
+        });
+        let (_tx, mut rx) = outgoing_queue();
+
+        let task = tokio::spawn(async move { conn.run(&mut rx, |_| async {}).await });
+
+        // Answer the first probe, so that the next one is expected to be answered too.
+        let probe = read_packet(&mut remote).await;
+        assert_eq!(probe.typ, packet::PACKET_TYPE_KEEPALIVE);
+        NetworkPacket::new_keepalive(true)
+            .write_to_conn(remote.get_mut())
+            .await
+            .unwrap();
+
+        let probe = read_packet(&mut remote).await;
+        assert_eq!(probe.typ, packet::PACKET_TYPE_KEEPALIVE);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn run_keeps_idle_connection_to_silent_device() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local).with_keepalive(Keepalive {
+            idle: Duration::from_millis(20),
+            timeout: Duration::from_millis(20),
+        });
+        let (_tx, mut rx) = outgoing_queue();
+
+        let task = tokio::spawn(async move { conn.run(&mut rx, |_| async {}).await });
+
+        // Like a phone, never answer: a single probe is sent, and the connection stays up.
+        let probe = read_packet(&mut remote).await;
+        assert_eq!(probe.typ, packet::PACKET_TYPE_KEEPALIVE);
+        let next = tokio::time::timeout(Duration::from_millis(200), read_packet(&mut remote)).await;
+        assert!(next.is_err());
+        assert!(!task.is_finished());
+
+        drop(remote);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_accepts_pairing_and_dispatches_packets() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let mut conn = DeviceConnection::new(local);
+        let (_tx, mut rx) = outgoing_queue();
+
+        let dispatched = Arc::new(Mutex::new(vec![]));
+        let d = dispatched.clone();
+        let task = tokio::spawn(async move {
+            conn.run(&mut rx, move |packet| {
+                let d = d.clone();
+                async move { d.lock().await.push(packet) }
+            })
+            .await
+        });
+
+        NetworkPacket::new_pair(true)
+            .write_to_conn(remote.get_mut())
+            .await
+            .unwrap();
+        let reply = read_packet(&mut remote).await;
+        assert_eq!(reply.typ, packet::PACKET_TYPE_PAIR);
+        assert_eq!(reply.body["pair"], true);
+
+        ping("hello").write_to_conn(remote.get_mut()).await.unwrap();
+
+        // EOF ends the loop cleanly.
+        drop(remote);
+        task.await.unwrap().unwrap();
+
+        let dispatched = dispatched.lock().await;
+        assert_eq!(dispatched.len(), 1);
+        assert_eq!(dispatched[0].body["message"], "hello");
+    }
+
+    fn pair_request(timestamp: Option<u64>) -> NetworkPacket {
+        NetworkPacket::new(
+            packet::PACKET_TYPE_PAIR,
+            PairPacket {
+                pair: true,
+                timestamp,
+            },
+        )
+    }
+
+    /// Run a protocol version 8 connection, and answer `request`.
+    async fn pair_v8(request: NetworkPacket) -> (NetworkPacket, Option<Option<u64>>) {
+        let (local, remote) = duplex(64 * 1024);
+        let mut remote = BufReader::new(remote);
+        let paired = Arc::new(std::sync::Mutex::new(None));
+        let p = paired.clone();
+        let mut conn = DeviceConnection::new(local)
+            .with_features(ProtocolFeatures::for_version(8))
+            .on_pair(move |timestamp| *p.lock().unwrap() = Some(timestamp));
+        let (_tx, mut rx) = outgoing_queue();
+
+        let task = tokio::spawn(async move { conn.run(&mut rx, |_| async {}).await });
+
+        request.write_to_conn(remote.get_mut()).await.unwrap();
+        let reply = read_packet(&mut remote).await;
+
+        drop(remote);
+        task.await.unwrap().unwrap();
+
+        let paired = *paired.lock().unwrap();
+        (reply, paired)
+    }
+
+    #[tokio::test]
+    async fn run_accepts_pairing_with_current_timestamp() {
+        let now = utils::unix_ts_ms() / 1000;
+        let (reply, paired) = pair_v8(pair_request(Some(now - 60))).await;
+
+        assert_eq!(reply.body["pair"], true);
+        assert_eq!(paired, Some(Some(now - 60)));
+    }
+
+    #[tokio::test]
+    async fn run_rejects_pairing_without_valid_timestamp() {
+        let stale = utils::unix_ts_ms() / 1000 - 2 * PAIR_TIMESTAMP_TOLERANCE.as_secs();
+
+        for request in [pair_request(None), pair_request(Some(stale))] {
+            let (reply, paired) = pair_v8(request).await;
+
+            assert_eq!(reply.body["pair"], false);
+            assert_eq!(paired, None);
+        }
+    }
+
+    fn identity(device_id: &str) -> NetworkPacket {
+        NetworkPacket::new(
+            packet::PACKET_TYPE_IDENTITY,
+            IdentityPacket {
+                device_id: device_id.into(),
+                device_name: "Phone".into(),
+                protocol_version: 8,
+                device_type: "phone".into(),
+                incoming_capabilities: vec![],
+                outgoing_capabilities: vec![],
+                tcp_port: None,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn exchange_identities_checks_device_id() {
+        let plain = identity("phone_1").into_body::<IdentityPacket>().unwrap();
+
+        for (sent_id, ok) in [("phone_1", true), ("phone_2", false)] {
+            let (mut local, remote) = duplex(64 * 1024);
+            let mut remote = BufReader::new(remote);
+            identity(sent_id)
+                .write_to_conn(remote.get_mut())
+                .await
+                .unwrap();
+
+            let result = exchange_identities(&mut local, &identity("desktop"), &plain).await;
+            assert_eq!(result.is_ok(), ok, "{:?}", result);
+
+            // Our identity was sent either way.
+            let ours = read_packet(&mut remote).await;
+            assert_eq!(ours.body["deviceId"], "desktop");
+        }
+    }
+}
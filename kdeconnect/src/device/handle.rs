@@ -1,15 +1,24 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::oneshot;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+use tokio::{net::TcpStream, sync::oneshot};
+use tokio_rustls::TlsStream;
 
 use crate::packet::{NetworkPacket, NetworkPacketWithPayload};
 
-use super::{DeviceManagerHandle, Message};
+use super::{packet_log::PacketLog, stats::TrafficStats, DeviceManagerHandle, Message};
 
 #[derive(Clone)]
 pub struct DeviceHandle {
     pub(super) device_id: Arc<String>,
-    pub(super) device_name: Arc<String>,
+    /// Updated when the device is renamed.
+    pub(super) device_name: Arc<RwLock<String>>,
+    pub(super) stats: Arc<TrafficStats>,
+    pub(super) packet_log: Arc<PacketLog>,
+    /// Packet types the device does not accept, see [`set_rejected_types`](Self::set_rejected_types).
+    pub(super) rejected_types: Arc<RwLock<HashSet<String>>>,
     pub(super) manager_handle: DeviceManagerHandle,
 }
 
@@ -26,35 +35,81 @@ impl DeviceHandle {
         &self.device_id
     }
 
-    pub fn device_name(&self) -> &str {
-        &self.device_name
+    /// The current name of the device, which changes if it is renamed while connected.
+    pub fn device_name(&self) -> String {
+        self.device_name.read().unwrap().clone()
+    }
+
+    /// Traffic counters, which payload transfers done outside of the device manager should
+    /// update.
+    pub fn stats(&self) -> &Arc<TrafficStats> {
+        &self.stats
+    }
+
+    /// The recent packets of the device, which the connection records once it is configured.
+    pub fn packet_log(&self) -> &Arc<PacketLog> {
+        &self.packet_log
+    }
+
+    /// Stop sending packets of these types, e.g. because the plugin handling them is disabled
+    /// on the device. Replaces the previous set.
+    pub fn set_rejected_types(&self, types: HashSet<String>) {
+        *self.rejected_types.write().unwrap() = types;
+    }
+
+    fn is_rejected(&self, packet: &NetworkPacketWithPayload) -> bool {
+        self.rejected_types
+            .read()
+            .unwrap()
+            .contains(&packet.packet.typ)
     }
 
     /// Send packet to device
     pub async fn send_packet(&self, packet: impl Into<NetworkPacketWithPayload>) {
+        let packet = packet.into();
+        if self.is_rejected(&packet) {
+            log::debug!(
+                "Not sending {} to {}: disabled on the device",
+                packet.packet.typ,
+                self.device_id
+            );
+            return;
+        }
         self.manager_handle
             .send_packet(self.device_id(), packet)
             .await;
     }
 
+    /// Send packet to device, waiting until it has been written to the connection
+    pub async fn send_packet_with_ack(
+        &self,
+        packet: impl Into<NetworkPacketWithPayload>,
+    ) -> Result<()> {
+        let packet = packet.into();
+        if self.is_rejected(&packet) {
+            anyhow::bail!("{} is disabled on the device", packet.packet.typ);
+        }
+        self.manager_handle
+            .send_packet_with_ack(self.device_id(), packet)
+            .await
+    }
+
     /// Dispatch received packet from the device to plugins
     pub async fn dispatch_packet(&self, packet: impl Into<NetworkPacket>) {
         self.manager_handle
-            .send_message(Message::Packet {
-                device_id: self.device_id.to_string(),
-                packet: packet.into(),
-            })
+            .dispatch_packet(&self.device_id, packet.into())
             .await;
     }
 
-    pub async fn fetch_payload(&self, port: u16, size: usize) -> Result<Vec<u8>> {
+    /// Connect to the payload server of the device, so that the payload can be read
+    /// incrementally.
+    pub async fn connect_payload(&self, port: u16) -> Result<TlsStream<TcpStream>> {
         let (tx, rx) = oneshot::channel();
 
         self.manager_handle
-            .send_message(Message::FetchPayload {
+            .send_message(Message::ConnectPayload {
                 device_id: self.device_id.to_string(),
                 port,
-                size,
                 reply: tx,
             })
             .await;
@@ -1,69 +1,120 @@
 use anyhow::Result;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::IpAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex, RwLock,
     },
+    time::{Duration, Instant},
 };
-use tao::menu::{ContextMenu, MenuItem, MenuItemAttributes};
+use tao::menu::MenuId;
 use tracing::{Instrument, Span};
 
-use tokio::{
-    io::AsyncReadExt,
-    sync::{mpsc, oneshot},
-};
+use tokio::sync::{mpsc, oneshot};
+use winrt_toast::{Action, Text, Toast};
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent,
-    packet::NetworkPacketWithPayload, plugin::PluginRepository, CustomWindowEvent,
+    context::{AppContextRef, TlsProvider},
+    device::DeviceHandle,
+    diagnostics,
+    event::SystemEvent,
+    packet::{IdentityPacket, NetworkPacket, NetworkPacketWithPayload, PACKET_TYPE_IDENTITY},
+    plugin::{battery::BatteryHistory, wake_on_lan, Capabilities, PluginRepository},
+    transfer,
+    tray::{self, DeviceMenu, IconState, TrayMenu},
+    utils::{
+        self,
+        rate_limit::{Rate, RateLimiter},
+        toast::{ToastCallbacks, TOASTS},
+    },
+    CustomWindowEvent,
 };
 
-use super::Message;
+use super::{
+    connection::ConnectionInfo,
+    packet_log::{PacketLog, PacketRecord},
+    queue::{self, Ack, OutgoingReceiver, OutgoingSender, QueuedPacket},
+    stats::{TrafficSnapshot, TrafficStats},
+    DeviceInfo, Message,
+};
 
 static NEXT_CONN_ID: AtomicUsize = AtomicUsize::new(0);
 
-fn load_png_icon(buf: &[u8]) -> tao::system_tray::Icon {
-    let (icon_rgba, icon_width, icon_height) = {
-        let image = image::load_from_memory(buf).unwrap().into_rgba8();
-        let (width, height) = image.dimensions();
-        let rgba = image.into_raw();
-        (rgba, width, height)
-    };
-    tao::system_tray::Icon::from_rgba(icon_rgba, icon_width, icon_height).unwrap()
-}
+/// How long to wait for a packet to be written to the connection.
+const SEND_ACK_TIMEOUT: Duration = Duration::from_secs(30);
 
-lazy_static::lazy_static! {
-    static ref ICON_CELLPHONE: tao::system_tray::Icon = {
-        load_png_icon(include_bytes!("../icons/cellphone.png"))
-    };
-    static ref ICON_CELLPHONE_OFF: tao::system_tray::Icon = {
-        load_png_icon(include_bytes!("../icons/cellphone-off.png"))
-    };
-}
+const ACTION_FORGET: &str = "forget";
+
+/// A device connecting again this soon after losing its connection has reconnected.
+const RECONNECT_GRACE: Duration = Duration::from_secs(30);
+/// Reconnects allowed in `UNSTABLE_WINDOW` before the user is told the connection is unstable.
+const UNSTABLE_RECONNECTS: u32 = 3;
+const UNSTABLE_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How long a device the user disconnected from is refused, so that it does not come back with
+/// its next broadcast.
+const DISCONNECT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConnectionId(usize);
 
+/// What is needed to exchange packets with a connected device.
+#[derive(Debug, Clone)]
+struct Route {
+    tx: Arc<OutgoingSender>,
+    plugin_repo: Arc<PluginRepository>,
+}
+
+/// Routes of the connected devices, by device id. Only the actor changes them, while sending
+/// and dispatching packets read them directly instead of waiting for the actor.
+type Routes = Arc<RwLock<HashMap<String, Route>>>;
+
+/// Devices whose connections are refused, by device id.
+#[derive(Debug, Default)]
+struct RefusedDevices(HashMap<String, Option<Instant>>);
+
+impl RefusedDevices {
+    /// Refuse the device until `until`, or until the application restarts if `None`.
+    fn refuse(&mut self, id: &str, until: Option<Instant>) {
+        self.0.insert(id.to_string(), until);
+    }
+
+    fn refuses(&mut self, id: &str, now: Instant) -> bool {
+        match self.0.get(id) {
+            Some(None) => true,
+            Some(Some(until)) if now < *until => true,
+            Some(Some(_)) => {
+                self.0.remove(id);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceManagerHandle {
     sender: mpsc::Sender<(Message, Span)>,
     active_device_count: Arc<AtomicUsize>,
+    routes: Routes,
+    refused: Arc<Mutex<RefusedDevices>>,
 }
 
 impl DeviceManagerHandle {
+    /// Whether connections from the device are refused, because the user recently disconnected
+    /// from it or forgot it.
+    pub fn refuses(&self, id: &str) -> bool {
+        self.refused.lock().unwrap().refuses(id, Instant::now())
+    }
+
     pub async fn add_device(
         &self,
         id: impl Into<String>,
         name: impl Into<String>,
         ip: IpAddr,
-    ) -> Result<(
-        ConnectionId,
-        mpsc::Receiver<NetworkPacketWithPayload>,
-        DeviceHandle,
-    )> {
-        let (tx, rx) = mpsc::channel(1);
+        info: ConnectionInfo,
+    ) -> Result<(ConnectionId, OutgoingReceiver, DeviceHandle)> {
+        let (tx, rx) = queue::outgoing_queue();
         let conn_id = ConnectionId(NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed));
 
         let (reply_tx, reply_rx) = oneshot::channel();
@@ -72,6 +123,7 @@ impl DeviceManagerHandle {
             id: id.into(),
             name: name.into(),
             ip,
+            info,
             conn_id,
             tx,
             reply: reply_tx,
@@ -87,19 +139,76 @@ impl DeviceManagerHandle {
         ))
     }
 
+    /// Whether the device is connected.
     pub async fn query_device(&self, id: impl Into<String>) -> Result<bool> {
+        Ok(self.route(&id.into()).is_some())
+    }
+
+    /// Ids of the connected devices, `None` instead of waiting while they are modified.
+    pub fn try_connected_devices(&self) -> Option<Vec<String>> {
+        let routes = self.routes.try_read().ok()?;
+        Some(routes.keys().cloned().collect())
+    }
+
+    fn route(&self, device_id: &str) -> Option<Route> {
+        self.routes.read().unwrap().get(device_id).cloned()
+    }
+
+    /// All connected devices, sorted by name.
+    pub async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
         let (reply_tx, reply_rx) = oneshot::channel();
-        let msg = Message::QueryDevice {
+        self.send_message(Message::ListDevices { reply: reply_tx })
+            .await;
+
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))
+    }
+
+    /// Capabilities of the plugins loaded for a device, or `None` if the device has not been
+    /// seen in this session yet.
+    pub async fn query_capabilities(&self, id: impl Into<String>) -> Result<Option<Capabilities>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let msg = Message::QueryCapabilities {
             id: id.into(),
             reply: reply_tx,
         };
         self.send_message(msg).await;
 
-        let result = reply_rx
+        reply_rx
             .await
-            .map_err(|_| anyhow::anyhow!("Failed to get response"))?;
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))
+    }
+
+    /// Packets recorded for a device, or `None` if it is not connected.
+    pub async fn query_packet_log(
+        &self,
+        id: impl Into<String>,
+    ) -> Result<Option<Vec<PacketRecord>>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let msg = Message::QueryPacketLog {
+            id: id.into(),
+            reply: reply_tx,
+        };
+        self.send_message(msg).await;
+
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))
+    }
+
+    /// Traffic of a device, or `None` if it is not connected.
+    pub async fn query_stats(&self, id: impl Into<String>) -> Result<Option<TrafficSnapshot>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let msg = Message::QueryStats {
+            id: id.into(),
+            reply: reply_tx,
+        };
+        self.send_message(msg).await;
 
-        Ok(result)
+        reply_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to get response"))
     }
 
     pub async fn remove_device(&self, id: impl Into<String>, conn_id: ConnectionId) {
@@ -110,6 +219,17 @@ impl DeviceManagerHandle {
         self.send_message(msg).await;
     }
 
+    /// Close the connection to a device. It may connect again when it is discovered next time.
+    pub async fn disconnect(&self, id: impl Into<String>) {
+        self.send_message(Message::Disconnect { id: id.into() })
+            .await;
+    }
+
+    /// Unpair a device and delete its settings.
+    pub async fn forget(&self, id: impl Into<String>) {
+        self.send_message(Message::Forget { id: id.into() }).await;
+    }
+
     pub(super) async fn send_message(&self, msg: Message) {
         self.sender
             .send((msg, tracing::Span::current()))
@@ -131,14 +251,156 @@ impl DeviceManagerHandle {
         self.send_message(Message::UpdateTray).await;
     }
 
+    /// Probe all connections now instead of waiting for them to be idle, so that dead ones are
+    /// noticed sooner.
+    pub async fn check_connections(&self) {
+        let packet: NetworkPacketWithPayload = NetworkPacket::new_keepalive(false).into();
+        log::debug!("Broadcasting {:?}", packet);
+
+        for route in self.routes.read().unwrap().values() {
+            route.tx.send(QueuedPacket::new(packet.clone(), None));
+        }
+    }
+
+    /// Stop all plugins and remove all devices, waiting for the plugins to be disposed.
+    pub async fn shutdown(&self) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send_message(Message::Shutdown { reply: reply_tx })
+            .await;
+        reply_rx.await.ok();
+    }
+
     pub async fn send_packet(&self, device_id: &str, packet: impl Into<NetworkPacketWithPayload>) {
-        let packet: NetworkPacketWithPayload = packet.into();
+        self.queue_packet(device_id, packet.into(), None);
+    }
+
+    fn queue_packet(&self, device_id: &str, packet: NetworkPacketWithPayload, ack: Option<Ack>) {
+        log::debug!("Sending {:?} to {}", packet, device_id);
+
+        if let Some(route) = self.route(device_id) {
+            route.tx.send(QueuedPacket::new(packet, ack));
+        } else if let Some(ack) = ack {
+            ack.send(Err(anyhow::anyhow!("Device {} not found", device_id)))
+                .ok();
+        }
+    }
+
+    /// Send a packet and wait until it has been written to the connection.
+    pub async fn send_packet_with_ack(
+        &self,
+        device_id: &str,
+        packet: impl Into<NetworkPacketWithPayload>,
+    ) -> Result<()> {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.queue_packet(device_id, packet.into(), Some(ack_tx));
+
+        match tokio::time::timeout(SEND_ACK_TIMEOUT, ack_rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(anyhow::anyhow!("Device disconnected")),
+            Err(_) => Err(anyhow::anyhow!("Timed out sending packet")),
+        }
+    }
+
+    /// Hand a packet received from the device to its plugins.
+    pub async fn dispatch_packet(&self, device_id: &str, packet: NetworkPacket) {
+        if packet.typ == PACKET_TYPE_IDENTITY {
+            self.receive_identity(device_id, packet).await;
+            return;
+        }
 
-        let msg = Message::SendPacket {
-            device_id: Some(device_id.into()),
-            packet,
+        let span = tracing::info_span!(
+            "Packet",
+            device = device_id,
+            packet.id = packet.id,
+            packet.typ = packet.typ,
+        );
+        let _enter = span.enter();
+
+        let pr = if let Some(route) = self.route(device_id) {
+            route.plugin_repo
+        } else {
+            tracing::warn!("Device {} not found", device_id);
+            return;
         };
-        self.send_message(msg).await;
+
+        tokio::spawn(
+            async move {
+                if let Err(e) = pr.handle_packet(packet).await {
+                    tracing::error!("Failed to handle packet: {:?}", e);
+                }
+            }
+            .instrument(span.clone()),
+        );
+    }
+
+    /// Devices send their identity again on the connection when they are renamed.
+    async fn receive_identity(&self, device_id: &str, packet: NetworkPacket) {
+        let identity = match packet
+            .into_body::<IdentityPacket>()
+            .map_err(anyhow::Error::from)
+            .and_then(|identity| identity.validate().map(|_| identity))
+        {
+            Ok(identity) => identity,
+            Err(e) => {
+                log::warn!("Invalid identity from {}: {:?}", device_id, e);
+                return;
+            }
+        };
+        if identity.device_id != device_id {
+            log::warn!(
+                "Device {} sent the identity of {}, ignoring it",
+                device_id,
+                identity.device_id
+            );
+            return;
+        }
+
+        self.send_message(Message::Rename {
+            id: identity.device_id,
+            name: identity.device_name,
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+impl DeviceManagerHandle {
+    /// A device that is neither connected nor known to the actor, for testing plugins.
+    ///
+    /// Packets sent to it end up in the returned queue. Messages for the actor are dropped,
+    /// so e.g. fetching payloads fails.
+    pub(crate) fn mock_device(device_id: &str) -> (DeviceHandle, OutgoingReceiver) {
+        let (sender, mut receiver) = mpsc::channel(100);
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+
+        let handle = Self {
+            sender,
+            active_device_count: Arc::new(AtomicUsize::new(1)),
+            routes: Routes::default(),
+            refused: Default::default(),
+        };
+        let dev = DeviceHandle {
+            device_id: Arc::new(device_id.into()),
+            device_name: Arc::new(RwLock::new(device_id.into())),
+            stats: Default::default(),
+            packet_log: Default::default(),
+            rejected_types: Default::default(),
+            manager_handle: handle.clone(),
+        };
+
+        let (tx, rx) = queue::outgoing_queue();
+        let route = Route {
+            tx: Arc::new(tx),
+            // Packets are handed to the plugins under test directly.
+            plugin_repo: Arc::new(PluginRepository::empty(dev.clone())),
+        };
+        handle
+            .routes
+            .write()
+            .unwrap()
+            .insert(device_id.into(), route);
+
+        (dev, rx)
     }
 }
 
@@ -147,33 +409,78 @@ impl DeviceManagerHandle {
 struct Device {
     name: String,
     remote_ip: IpAddr,
+    info: ConnectionInfo,
     conn_id: ConnectionId,
-    tx: mpsc::Sender<NetworkPacketWithPayload>,
+    tx: Arc<OutgoingSender>,
     plugin_repo: Arc<PluginRepository>,
+    stats: Arc<TrafficStats>,
+    packet_log: Arc<PacketLog>,
+    rejected_types: Arc<RwLock<HashSet<String>>>,
+    /// The name in the handles of the plugins, kept equal to `name`.
+    handle_name: Arc<RwLock<String>>,
+    disconnect_menu_id: MenuId,
+    forget_menu_id: MenuId,
+}
+
+/// Labels with the details of the connection, for debugging.
+fn connection_info_menu(device: &Device) -> TrayMenu {
+    let info = &device.info;
+    let unknown = || "unknown".to_string();
+
+    let mut menu = TrayMenu::new();
+    menu.add_label(format!("Address: {}", device.remote_ip));
+    menu.add_label(format!("Protocol version: {}", info.protocol_version));
+    menu.add_label(format!(
+        "TLS version: {}",
+        info.tls_version
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_else(unknown)
+    ));
+    menu.add_label(format!(
+        "Cipher suite: {}",
+        info.cipher_suite
+            .map(|s| format!("{:?}", s))
+            .unwrap_or_else(unknown)
+    ));
+    menu.add_label(format!(
+        "Certificate SHA-256: {}",
+        info.fingerprint().unwrap_or_else(unknown)
+    ));
+    menu
 }
 
 pub struct DeviceManagerActor {
     receiver: mpsc::Receiver<(Message, Span)>,
     devices: HashMap<String, Device>,
     active_device_count: Arc<AtomicUsize>,
+    routes: Routes,
     handle: DeviceManagerHandle,
+    /// When devices lost their connection, to notice them reconnecting.
+    disconnected_at: HashMap<String, Instant>,
+    reconnects: RateLimiter<String>,
 }
 
 impl DeviceManagerActor {
     pub fn new() -> (Self, DeviceManagerHandle) {
         let (sender, receiver) = mpsc::channel(100);
         let active_device_count = Arc::new(AtomicUsize::new(0));
+        let routes = Routes::default();
 
         let handle = DeviceManagerHandle {
             sender,
             active_device_count: active_device_count.clone(),
+            routes: routes.clone(),
+            refused: Default::default(),
         };
 
         let actor = Self {
             receiver,
             devices: HashMap::new(),
             active_device_count,
+            routes,
             handle: handle.clone(),
+            disconnected_at: HashMap::new(),
+            reconnects: RateLimiter::new(UNSTABLE_RECONNECTS, UNSTABLE_WINDOW),
         };
 
         (actor, handle)
@@ -187,40 +494,102 @@ impl DeviceManagerActor {
                 id,
                 name,
                 ip,
+                info,
                 conn_id,
                 tx,
                 reply,
             } => {
+                // Counting continues if the device replaces its connection.
+                let stats = self
+                    .devices
+                    .get(&id)
+                    .map(|d| d.stats.clone())
+                    .unwrap_or_default();
+                let packet_log = self
+                    .devices
+                    .get(&id)
+                    .map(|d| d.packet_log.clone())
+                    .unwrap_or_default();
+                // Shared with the handles the plugins already have. The device reports which
+                // plugins it has enabled again on the new connection.
+                let rejected_types = self
+                    .devices
+                    .get(&id)
+                    .map(|d| d.rejected_types.clone())
+                    .unwrap_or_default();
+                rejected_types.write().unwrap().clear();
+                // Updated below if the device was renamed while disconnected from it.
+                let handle_name = self
+                    .devices
+                    .get(&id)
+                    .map(|d| d.handle_name.clone())
+                    .unwrap_or_else(|| Arc::new(RwLock::new(name.clone())));
                 let dh = DeviceHandle {
                     device_id: Arc::new(id.clone()),
-                    device_name: Arc::new(name.clone()),
+                    device_name: handle_name.clone(),
+                    stats: stats.clone(),
+                    packet_log: packet_log.clone(),
+                    rejected_types: rejected_types.clone(),
                     manager_handle: self.handle.clone(),
                 };
 
                 log::info!("Adding device: {}", id);
 
+                // Replacing a connection that is still open counts as well.
+                let reconnected = self.devices.contains_key(&id)
+                    || self
+                        .disconnected_at
+                        .remove(&id)
+                        .is_some_and(|t| t.elapsed() < RECONNECT_GRACE);
+                if reconnected && self.reconnects.check(id.clone()) == Rate::Exceeded {
+                    log::warn!("Connection to {} is unstable", id);
+                    utils::simple_toast(
+                        &format!("Connection to {} is unstable", name),
+                        Some("The device keeps reconnecting, check its network connection."),
+                        None,
+                    )
+                    .await;
+                }
+
+                save_device_name(ctx, &id, &name);
+                self.rename_device(&id, &name);
+
                 if let Some(device) = self.devices.get_mut(&id) {
                     device.remote_ip = ip;
+                    device.info = info;
                     device.conn_id = conn_id;
-                    device.tx = tx;
+                    device.tx = Arc::new(tx);
                 } else {
                     let plugin_repo = PluginRepository::new(dh.clone(), ctx.clone()).await;
+                    let disconnect_menu_id = MenuId::new(&format!("{}:disconnect", id));
+                    let forget_menu_id = MenuId::new(&format!("{}:forget", id));
                     self.devices.insert(
                         id,
                         Device {
                             name,
                             remote_ip: ip,
+                            info,
                             conn_id,
-                            tx,
+                            tx: Arc::new(tx),
                             plugin_repo: Arc::new(plugin_repo),
+                            stats,
+                            packet_log,
+                            rejected_types,
+                            handle_name,
+                            disconnect_menu_id,
+                            forget_menu_id,
                         },
                     );
                 }
 
+                self.devices_changed();
+                if let Some(device) = self.devices.get(dh.device_id()) {
+                    if device.info.plugin_state {
+                        device.plugin_repo.send_state(true).await;
+                    }
+                }
                 let _ = reply.send(dh);
 
-                self.update_active_device_count();
-
                 tray_updated = true;
             }
             Message::RemoveDevice { id, conn_id } => {
@@ -231,35 +600,100 @@ impl DeviceManagerActor {
 
                         device.plugin_repo.dispose().await;
                         self.devices.remove(&id);
-                        self.update_active_device_count();
+                        self.devices_changed();
+                        self.disconnected_at.insert(id, Instant::now());
                     }
                 }
 
                 tray_updated = true;
             }
-            Message::QueryDevice { id, reply } => {
-                let _ = reply.send(self.devices.contains_key(&id));
+            Message::Disconnect { id } => {
+                self.disconnect(&id);
+                self.refuse(&id, Some(DISCONNECT_COOLDOWN));
             }
-            Message::SendPacket { packet, device_id } => {
-                if let Some(device_id) = device_id {
-                    log::debug!("Sending {:?} to {}", packet, device_id);
-
-                    if let Some(device) = self.devices.get(&device_id) {
-                        if let Err(e) = device.tx.send(packet).await {
-                            log::error!("Failed to send packet to device {}: {}", device.name, e);
-                        }
-                    }
-                } else {
-                    log::debug!("Broadcasting {:?}", packet);
+            Message::Forget { id } => {
+                if let Some(device) = self.devices.get(&id) {
+                    log::info!("Forgetting device: {}", id);
 
-                    for device in self.devices.values() {
-                        if let Err(e) = device.tx.send(packet.clone()).await {
-                            log::error!("Failed to send packet to device {}: {}", device.name, e);
-                        };
-                    }
+                    // Queued before closing, so it is still sent.
+                    device.tx.send(QueuedPacket::new(
+                        NetworkPacket::new_pair(false).into(),
+                        None,
+                    ));
+                    self.disconnect(&id);
+                }
+                // Until pairing can be undone on our side, this is the closest to unpaired.
+                self.refuse(&id, None);
+
+                if let Err(e) = ctx.settings().remove_device(&id) {
+                    log::error!("Failed to remove settings of {}: {:?}", id, e);
+                }
+                utils::remove_device_toasts(&id).await;
+                BatteryHistory::remove(&id).await;
+            }
+            Message::ListDevices { reply } => {
+                let mut devices: Vec<_> = self
+                    .devices
+                    .iter()
+                    .map(|(id, device)| DeviceInfo {
+                        id: id.clone(),
+                        name: device.name.clone(),
+                        address: device.remote_ip,
+                    })
+                    .collect();
+                devices.sort_by(|a, b| a.name.cmp(&b.name));
+                let _ = reply.send(devices);
+            }
+            Message::QueryCapabilities { id, reply } => {
+                let caps = self.devices.get(&id).map(|d| d.plugin_repo.capabilities());
+                let _ = reply.send(caps);
+            }
+            Message::QueryPacketLog { id, reply } => {
+                let _ = reply.send(self.devices.get(&id).map(|d| d.packet_log.records()));
+            }
+            Message::QueryStats { id, reply } => {
+                let _ = reply.send(self.devices.get(&id).map(|d| d.stats.snapshot()));
+            }
+            Message::Rename { id, name } => {
+                if self.rename_device(&id, &name) {
+                    save_device_name(ctx, &id, &name);
+                    tray_updated = true;
                 }
             }
             Message::Event(event) => {
+                for (id, device) in &self.devices {
+                    if event.is_menu_clicked(device.disconnect_menu_id) {
+                        self.disconnect(id);
+                        self.refuse(id, Some(DISCONNECT_COOLDOWN));
+                        utils::simple_toast(
+                            &format!("Disconnected from {}", device.name),
+                            Some(&format!(
+                                "It can connect again in {} minutes.",
+                                DISCONNECT_COOLDOWN.as_secs() / 60
+                            )),
+                            None,
+                        )
+                        .await;
+                    } else if event.is_menu_clicked(device.forget_menu_id) {
+                        confirm_forget(&device.name, id, self.handle.clone()).await;
+                    }
+                }
+
+                let wctx = ctx.clone();
+                tokio::spawn(async move {
+                    wake_on_lan::handle_event(event, &wctx).await;
+                });
+
+                let dctx = ctx.clone();
+                tokio::spawn(async move {
+                    diagnostics::handle_event(event, &dctx).await;
+                });
+
+                let tctx = ctx.clone();
+                tokio::spawn(async move {
+                    transfer::handle_event(event, &tctx).await;
+                });
+
                 for device in self.devices.values() {
                     let pr = device.plugin_repo.clone();
 
@@ -268,39 +702,12 @@ impl DeviceManagerActor {
                     });
                 }
             }
-            Message::Packet { device_id, packet } => {
-                let span = tracing::info_span!(
-                    "Packet",
-                    device = device_id,
-                    packet.id = packet.id,
-                    packet.typ = packet.typ,
-                );
-                let _enter = span.enter();
-
-                let device = if let Some(device) = self.devices.get_mut(&device_id) {
-                    device
-                } else {
-                    tracing::warn!("Device {} not found", device_id);
-                    return;
-                };
-                let pr = device.plugin_repo.clone();
-
-                tokio::spawn(
-                    async move {
-                        if let Err(e) = pr.handle_packet(packet).await {
-                            tracing::error!("Failed to handle packet: {:?}", e);
-                        }
-                    }
-                    .instrument(span.clone()),
-                );
-            }
-            Message::FetchPayload {
+            Message::ConnectPayload {
                 device_id,
                 port,
-                size,
                 reply,
             } => {
-                let device = if let Some(device) = self.devices.get_mut(&device_id) {
+                let device = if let Some(device) = self.devices.get(&device_id) {
                     device
                 } else {
                     let _ = reply.send(Err(anyhow::anyhow!("Device {} not found", device_id)));
@@ -310,27 +717,23 @@ impl DeviceManagerActor {
                 let ctx = ctx.clone();
 
                 tokio::spawn(async move {
-                    let task = async {
-                        let mut conn = ctx.tls_connect((remote_ip, port)).await?;
-                        let mut buf = Vec::with_capacity(size as usize);
-                        conn.read_to_end(&mut buf).await?;
-
-                        if buf.len() == size {
-                            Ok(buf)
-                        } else {
-                            Err(anyhow::anyhow!(
-                                "Payload size mismatch: {} (fetched) != {} (requested)",
-                                buf.len(),
-                                size
-                            ))
-                        }
-                    };
-                    let _ = reply.send(task.await);
+                    let conn = ctx.tls_connect((remote_ip, port).into()).await;
+                    let _ = reply.send(conn.map(Into::into));
                 });
             }
             Message::UpdateTray => {
                 tray_updated = true;
             }
+            Message::Shutdown { reply } => {
+                log::info!("Disposing plugins of {} device(s)", self.devices.len());
+
+                for (_, device) in self.devices.drain() {
+                    device.plugin_repo.dispose().await;
+                }
+                self.devices_changed();
+
+                let _ = reply.send(());
+            }
         }
 
         if tray_updated {
@@ -338,45 +741,138 @@ impl DeviceManagerActor {
         }
     }
 
-    fn update_active_device_count(&self) {
+    /// Use the new name of a connected device everywhere, returning whether it changed.
+    fn rename_device(&mut self, id: &str, name: &str) -> bool {
+        let device = match self.devices.get_mut(id) {
+            Some(device) if device.name != name => device,
+            _ => return false,
+        };
+
+        log::info!("Device {} renamed from {:?} to {:?}", id, device.name, name);
+        device.name = name.to_string();
+        *device.handle_name.write().unwrap() = name.to_string();
+
+        let pr = device.plugin_repo.clone();
+        tokio::spawn(async move {
+            pr.handle_event(SystemEvent::DeviceRenamed).await;
+        });
+        true
+    }
+
+    /// Close the connection to the device. It is removed by `handle_conn` once the connection
+    /// has ended.
+    fn disconnect(&self, id: &str) {
+        if let Some(device) = self.devices.get(id) {
+            log::info!("Disconnecting device: {}", id);
+            device.tx.close();
+        }
+    }
+
+    /// Refuse connections from the device for `duration`, or until the application restarts.
+    fn refuse(&self, id: &str, duration: Option<Duration>) {
+        match duration {
+            Some(duration) => log::info!("Refusing connections from {} for {:?}", id, duration),
+            None => log::info!("Refusing connections from {} until restarted", id),
+        }
+        let until = duration.map(|d| Instant::now() + d);
+        self.handle.refused.lock().unwrap().refuse(id, until);
+    }
+
+    /// Update the state shared with the handles after devices were added or removed.
+    fn devices_changed(&self) {
         let count = self.devices.len();
         self.active_device_count
             .store(count, std::sync::atomic::Ordering::Relaxed);
+
+        *self.routes.write().unwrap() = self
+            .devices
+            .iter()
+            .map(|(id, device)| {
+                let route = Route {
+                    tx: device.tx.clone(),
+                    plugin_repo: device.plugin_repo.clone(),
+                };
+                (id.clone(), route)
+            })
+            .collect();
     }
 
     async fn update_tray(&self, ctx: &AppContextRef) {
-        let mut menu = ContextMenu::new();
+        let mut menu = TrayMenu::new();
+        let mut battery = None;
+        let mut tooltip_lines = vec![];
 
         if self.devices.is_empty() {
-            menu.add_item(MenuItemAttributes::new("No device connected").with_enabled(false));
-            menu.add_native_item(MenuItem::Separator);
+            menu.add_label("No device connected");
         } else {
             for device in self.devices.values() {
-                menu.add_item(MenuItemAttributes::new(&format!(
-                    "{}\t\t\t  {}",
-                    device.name, device.remote_ip
-                )));
+                let mut device_menu = DeviceMenu::default();
+                device.plugin_repo.create_tray_menu(&mut device_menu).await;
+                battery = device_menu.battery;
+                tooltip_lines.extend(device_menu.tooltip_line(&device.name));
 
-                device.plugin_repo.create_tray_menu(&mut menu).await;
+                let stats = device.stats.snapshot();
+                device_menu.status.add_label(format!(
+                    "Sent {}, received {}",
+                    utils::format_size(stats.sent),
+                    utils::format_size(stats.received)
+                ));
+                if stats.send_speed > 0 || stats.receive_speed > 0 {
+                    device_menu.status.add_label(format!(
+                        "Transferring at {}/s up, {}/s down",
+                        utils::format_size(stats.send_speed),
+                        utils::format_size(stats.receive_speed)
+                    ));
+                }
+                if let Some(key) = &device.info.verification_key {
+                    device_menu
+                        .status
+                        .add_label(format!("Verification key: {}", key));
+                }
+                device_menu
+                    .status
+                    .add_submenu("Connection info", connection_info_menu(device));
+                if stats.corrupt_payloads > 0 {
+                    device_menu.status.add_label(format!(
+                        "{} corrupt payload(s) discarded",
+                        stats.corrupt_payloads
+                    ));
+                }
 
-                menu.add_native_item(MenuItem::Separator);
+                menu.add_submenu(
+                    &device.name,
+                    device_menu.into_submenu(
+                        device.remote_ip,
+                        device.disconnect_menu_id,
+                        device.forget_menu_id,
+                    ),
+                );
             }
         }
 
-        menu.add_native_item(MenuItem::Quit);
+        menu.add_separator();
+        ctx.transfers().add_menu(&mut menu);
 
-        ctx.event_loop_proxy
-            .send_event(CustomWindowEvent::SetTrayMenu(menu))
-            .ok();
+        menu.add_separator();
+        wake_on_lan::add_wake_items(&mut menu, ctx, |id| self.devices.contains_key(id));
 
-        let icon = if self.devices.is_empty() {
-            ICON_CELLPHONE_OFF.clone()
-        } else {
-            ICON_CELLPHONE.clone()
+        menu.add_separator();
+        diagnostics::add_menu_item(&mut menu);
+
+        menu.add_separator();
+        menu.add_quit();
+
+        ctx.send_window_event(CustomWindowEvent::SetTrayMenu(menu));
+
+        // The battery is only shown when there is a single device.
+        let icon = IconState {
+            device_count: self.devices.len(),
+            battery,
         };
-        ctx.event_loop_proxy
-            .send_event(CustomWindowEvent::SetTrayIcon(icon))
-            .ok();
+        ctx.send_window_event(CustomWindowEvent::SetTrayIcon(icon));
+        ctx.send_window_event(CustomWindowEvent::SetTrayTooltip(tray::tooltip(
+            tooltip_lines,
+        )));
     }
 
     /// Spawn the actor to a background task.
@@ -390,3 +886,151 @@ impl DeviceManagerActor {
         });
     }
 }
+
+/// Devices with settings are known, e.g. when cleaning up their toasts.
+fn save_device_name(ctx: &AppContextRef, id: &str, name: &str) {
+    if ctx.settings().device(id).name.as_deref() != Some(name) {
+        utils::log_if_error(
+            "Failed to save device name",
+            ctx.settings()
+                .update_device(id, |settings| settings.name = Some(name.to_string())),
+        );
+    }
+}
+
+/// Ask the user whether the device should really be forgotten, and forget it if so.
+async fn confirm_forget(name: &str, id: &str, handle: DeviceManagerHandle) {
+    let mut toast = Toast::new();
+    toast
+        .text1(format!("Forget {}?", name))
+        .text2("The device will be unpaired and its settings deleted.")
+        .text3(Text::new(name).as_attribution())
+        .action(Action::new("Forget", ACTION_FORGET, ""));
+
+    let id = id.to_string();
+    let rt_handle = tokio::runtime::Handle::current();
+    let on_activated = move |arg: winrt_toast::Result<String>| {
+        if matches!(arg.as_deref(), Ok(ACTION_FORGET)) {
+            let handle = handle.clone();
+            let id = id.clone();
+            rt_handle.spawn(async move { handle.forget(id).await });
+        }
+    };
+
+    let callbacks = ToastCallbacks::new().on_activated(on_activated);
+    if let Err(e) = TOASTS.show_with_callbacks(toast, callbacks).await {
+        log::error!("Failed to show toast: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::connection::ProtocolFeatures;
+
+    use super::*;
+
+    /// Add a connected device to the actor, returning the handle its plugins would get.
+    fn connect_mock_device(actor: &mut DeviceManagerActor, id: &str, name: &str) -> DeviceHandle {
+        let (dev, _) = DeviceManagerHandle::mock_device(id);
+        *dev.device_name.write().unwrap() = name.to_string();
+        let (tx, _) = queue::outgoing_queue();
+        let device = Device {
+            name: name.to_string(),
+            remote_ip: IpAddr::from([127, 0, 0, 1]),
+            info: ConnectionInfo {
+                protocol_version: 7,
+                features: ProtocolFeatures::for_version(7),
+                tls_version: None,
+                cipher_suite: None,
+                certificate: None,
+                verification_key: None,
+                plugin_state: false,
+            },
+            conn_id: ConnectionId(0),
+            tx: Arc::new(tx),
+            plugin_repo: Arc::new(PluginRepository::empty(dev.clone())),
+            stats: Default::default(),
+            packet_log: Default::default(),
+            rejected_types: Default::default(),
+            handle_name: dev.device_name.clone(),
+            disconnect_menu_id: MenuId::new(&format!("{}:disconnect", id)),
+            forget_menu_id: MenuId::new(&format!("{}:forget", id)),
+        };
+        actor.devices.insert(id.to_string(), device);
+        dev
+    }
+
+    fn identity(id: &str, name: &str) -> NetworkPacket {
+        NetworkPacket::new(
+            PACKET_TYPE_IDENTITY,
+            IdentityPacket {
+                device_id: id.into(),
+                device_name: name.into(),
+                protocol_version: 7,
+                device_type: "phone".into(),
+                incoming_capabilities: vec![],
+                outgoing_capabilities: vec![],
+                tcp_port: None,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn identity_renames_the_device() {
+        let (mut actor, handle) = DeviceManagerActor::new();
+        handle
+            .dispatch_packet("phone", identity("phone", "New name"))
+            .await;
+
+        match actor.receiver.try_recv() {
+            Ok((Message::Rename { id, name }, _)) => {
+                assert_eq!(id, "phone");
+                assert_eq!(name, "New name");
+            }
+            other => panic!("Expected a rename, got {:?}", other.map(|(msg, _)| msg)),
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_of_another_device_is_ignored() {
+        let (mut actor, handle) = DeviceManagerActor::new();
+        handle
+            .dispatch_packet("phone", identity("other", "New name"))
+            .await;
+        handle
+            .dispatch_packet("phone", identity("in/valid", "New name"))
+            .await;
+
+        assert!(actor.receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn renamed_device_updates_handles() {
+        let (mut actor, _handle) = DeviceManagerActor::new();
+        let dev = connect_mock_device(&mut actor, "phone", "Old name");
+
+        assert!(actor.rename_device("phone", "New name"));
+        assert_eq!(actor.devices["phone"].name, "New name");
+        assert_eq!(dev.device_name(), "New name");
+
+        // Neither the same name nor unknown devices count as renames.
+        assert!(!actor.rename_device("phone", "New name"));
+        assert!(!actor.rename_device("other", "New name"));
+    }
+
+    #[test]
+    fn refused_devices_expire() {
+        let now = Instant::now();
+        let mut refused = RefusedDevices::default();
+        refused.refuse("disconnected", Some(now + Duration::from_secs(60)));
+        refused.refuse("forgotten", None);
+
+        assert!(refused.refuses("disconnected", now));
+        assert!(refused.refuses("forgotten", now));
+        assert!(!refused.refuses("other", now));
+
+        let later = now + Duration::from_secs(61);
+        assert!(!refused.refuses("disconnected", later));
+        assert!(refused.refuses("forgotten", later));
+    }
+}
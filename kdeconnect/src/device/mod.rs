@@ -1,19 +1,34 @@
+pub mod connection;
 pub mod handle;
 pub mod manager;
+pub mod packet_log;
+pub mod queue;
+pub mod stats;
 
 use anyhow::Result;
+use serde::Serialize;
 use std::net::IpAddr;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{net::TcpStream, sync::oneshot};
+use tokio_rustls::TlsStream;
 
 pub use handle::DeviceHandle;
 pub use manager::{DeviceManagerActor, DeviceManagerHandle};
 
-use crate::{
-    event::SystemEvent,
-    packet::{NetworkPacket, NetworkPacketWithPayload},
+use crate::{event::SystemEvent, plugin::Capabilities};
+
+use self::{
+    connection::ConnectionInfo, manager::ConnectionId, packet_log::PacketRecord,
+    queue::OutgoingSender, stats::TrafficSnapshot,
 };
 
-use self::manager::ConnectionId;
+/// A connected device, as listed by [`DeviceManagerHandle::list_devices`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub address: IpAddr,
+}
 
 #[derive(Debug)]
 pub enum Message {
@@ -21,33 +36,56 @@ pub enum Message {
         id: String,
         name: String,
         ip: IpAddr,
+        info: ConnectionInfo,
         conn_id: ConnectionId,
-        tx: mpsc::Sender<NetworkPacketWithPayload>,
+        tx: OutgoingSender,
         reply: oneshot::Sender<DeviceHandle>,
     },
-    /// Whether the device is connected
-    QueryDevice {
+    ListDevices {
+        reply: oneshot::Sender<Vec<DeviceInfo>>,
+    },
+    /// Capabilities of the plugins loaded for the device, if it is known
+    QueryCapabilities {
+        id: String,
+        reply: oneshot::Sender<Option<Capabilities>>,
+    },
+    /// Traffic of the device, if it is connected
+    QueryStats {
+        id: String,
+        reply: oneshot::Sender<Option<TrafficSnapshot>>,
+    },
+    /// Packets recorded for the device, if it is connected
+    QueryPacketLog {
         id: String,
-        reply: oneshot::Sender<bool>,
+        reply: oneshot::Sender<Option<Vec<PacketRecord>>>,
     },
     RemoveDevice {
         id: String,
         conn_id: ConnectionId,
     },
-    SendPacket {
-        device_id: Option<String>,
-        packet: NetworkPacketWithPayload,
+    /// Close the connection to the device, which is then removed like any lost connection
+    Disconnect {
+        id: String,
+    },
+    /// Unpair the device, delete its settings and disconnect it
+    Forget {
+        id: String,
+    },
+    /// The device sent a new identity on its connection
+    Rename {
+        id: String,
+        name: String,
     },
     Event(SystemEvent),
     UpdateTray,
-    Packet {
-        device_id: String,
-        packet: NetworkPacket,
-    },
-    FetchPayload {
+    /// Open the payload connection, so that the payload can be read incrementally
+    ConnectPayload {
         device_id: String,
         port: u16,
-        size: usize,
-        reply: oneshot::Sender<Result<Vec<u8>>>,
+        reply: oneshot::Sender<Result<TlsStream<TcpStream>>>,
+    },
+    /// Stop and dispose the plugins of all devices
+    Shutdown {
+        reply: oneshot::Sender<()>,
     },
 }
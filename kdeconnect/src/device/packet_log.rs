@@ -0,0 +1,127 @@
+//! The most recent packets exchanged with a device, for debugging.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{packet::NetworkPacket, transfer::Direction, utils};
+
+/// A packet sent or received over the connection.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PacketRecord {
+    /// When the packet was sent or received, in milliseconds since the epoch.
+    pub timestamp: u64,
+    pub direction: Direction,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// Bytes on the connection, including the framing.
+    pub size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<u64>,
+    /// Only recorded in verbose mode, as bodies contain notifications, clipboard contents and
+    /// the like.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+/// A ring buffer of the last packets of a device, which records nothing until it is
+/// [configured](Self::configure).
+///
+/// It is kept when the device reconnects, like its [`TrafficStats`](super::stats::TrafficStats).
+#[derive(Debug, Default)]
+pub struct PacketLog {
+    /// Whether `capacity` is not 0, checked before locking for every packet.
+    enabled: AtomicBool,
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug, Default)]
+struct LogState {
+    capacity: usize,
+    verbose: bool,
+    records: VecDeque<PacketRecord>,
+}
+
+impl PacketLog {
+    /// Keep the last `capacity` packets, none if 0, with their bodies if `verbose`.
+    pub fn configure(&self, capacity: usize, verbose: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity = capacity;
+        state.verbose = verbose;
+        while state.records.len() > capacity {
+            state.records.pop_front();
+        }
+        self.enabled.store(capacity > 0, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, direction: Direction, packet: &NetworkPacket, size: usize) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.capacity == 0 {
+            return;
+        }
+        if state.records.len() >= state.capacity {
+            state.records.pop_front();
+        }
+        let body = state.verbose.then(|| packet.body.clone());
+        state.records.push_back(PacketRecord {
+            timestamp: utils::unix_ts_ms(),
+            direction,
+            typ: packet.typ.clone(),
+            size,
+            payload_size: packet.payload_size,
+            body,
+        });
+    }
+
+    /// The recorded packets, oldest first.
+    pub fn records(&self) -> Vec<PacketRecord> {
+        self.state.lock().unwrap().records.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping() -> NetworkPacket {
+        NetworkPacket::new("kdeconnect.ping", serde_json::json!({ "message": "hi" }))
+    }
+
+    #[test]
+    fn disabled_until_configured() {
+        let log = PacketLog::default();
+        log.record(Direction::Incoming, &ping(), 10);
+        assert!(log.records().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_packets() {
+        let log = PacketLog::default();
+        log.configure(2, false);
+        for size in 1..=3 {
+            log.record(Direction::Outgoing, &ping(), size);
+        }
+
+        let records = log.records();
+        assert_eq!(records.iter().map(|r| r.size).collect::<Vec<_>>(), [2, 3]);
+        assert!(records.iter().all(|r| r.body.is_none()));
+
+        log.configure(1, true);
+        log.record(Direction::Incoming, &ping(), 4);
+        let records = log.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].body, Some(ping().body));
+    }
+}
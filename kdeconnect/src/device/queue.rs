@@ -0,0 +1,340 @@
+//! Per-device queue of outgoing packets.
+//!
+//! Packets are sent in order of their priority, so that small control packets are not stuck
+//! behind large payloads. The queue is bounded, and state updates that are superseded by a newer
+//! one (e.g. clipboard content, media player status) are replaced in place instead of queued.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{oneshot, Notify};
+
+use crate::packet::{
+    NetworkPacketWithPayload, PACKET_TYPE_IDENTITY, PACKET_TYPE_KEEPALIVE, PACKET_TYPE_PAIR,
+};
+
+/// Maximum number of packets waiting to be sent to a device.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Packet types that only carry the latest state, so older queued packets can be dropped.
+const REPLACEABLE_PACKET_TYPES: &[&str] = &["kdeconnect.clipboard", "kdeconnect.mpris"];
+
+/// Packet types that are sent before anything else.
+const CONTROL_PACKET_TYPES: &[&str] = &[
+    PACKET_TYPE_IDENTITY,
+    PACKET_TYPE_PAIR,
+    PACKET_TYPE_KEEPALIVE,
+    "kdeconnect.ping",
+];
+
+/// Notified with the outcome once a packet has been written to the connection, or dropped.
+pub type Ack = oneshot::Sender<anyhow::Result<()>>;
+
+#[derive(Debug)]
+pub struct QueuedPacket {
+    pub packet: NetworkPacketWithPayload,
+    pub ack: Option<Ack>,
+}
+
+impl QueuedPacket {
+    pub fn new(packet: NetworkPacketWithPayload, ack: Option<Ack>) -> Self {
+        Self { packet, ack }
+    }
+
+    /// Tell the sender that this packet will never be sent.
+    fn reject(self, reason: &str) {
+        if let Some(ack) = self.ack {
+            ack.send(Err(anyhow::anyhow!("{}", reason))).ok();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PacketPriority {
+    /// Packets announcing a payload transfer.
+    Payload = 0,
+    /// Regular packets.
+    Data = 1,
+    /// Pairing, identity and ping.
+    Control = 2,
+}
+
+impl PacketPriority {
+    const COUNT: usize = 3;
+
+    fn of(packet: &NetworkPacketWithPayload) -> Self {
+        if CONTROL_PACKET_TYPES.contains(&packet.packet.typ.as_str()) {
+            Self::Control
+        } else if packet.payload.is_some() {
+            Self::Payload
+        } else {
+            Self::Data
+        }
+    }
+}
+
+/// Key identifying packets that supersede each other, if any.
+fn replace_key(packet: &NetworkPacketWithPayload) -> Option<(&str, Option<&str>)> {
+    let typ = packet.packet.typ.as_str();
+
+    if packet.payload.is_none() && REPLACEABLE_PACKET_TYPES.contains(&typ) {
+        // MPRIS updates are per player.
+        let player = packet.packet.body.get("player").and_then(|p| p.as_str());
+        Some((typ, player))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// One queue per priority, indexed by `PacketPriority as usize`.
+    queues: [VecDeque<QueuedPacket>; PacketPriority::COUNT],
+    closed: bool,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    fn push(&mut self, queued: QueuedPacket) {
+        let priority = PacketPriority::of(&queued.packet);
+
+        if let Some(key) = replace_key(&queued.packet) {
+            let queue = &mut self.queues[priority as usize];
+            if let Some(stale) = queue
+                .iter_mut()
+                .find(|p| replace_key(&p.packet) == Some(key))
+            {
+                log::debug!("Replacing stale {} packet", stale.packet.packet.typ);
+                std::mem::replace(stale, queued).reject("Superseded by a newer packet");
+                return;
+            }
+        }
+
+        if self.len() >= QUEUE_CAPACITY {
+            // Make room by dropping the oldest packet that is not more important than this one.
+            let victim = self.queues[..=priority as usize]
+                .iter_mut()
+                .find(|q| !q.is_empty())
+                .and_then(|q| q.pop_front());
+
+            match victim {
+                Some(dropped) => {
+                    log::warn!(
+                        "Outgoing queue full, dropping {:?}",
+                        dropped.packet.packet.typ
+                    );
+                    dropped.reject("Outgoing queue full");
+                }
+                None => {
+                    log::warn!(
+                        "Outgoing queue full, dropping {:?}",
+                        queued.packet.packet.typ
+                    );
+                    queued.reject("Outgoing queue full");
+                    return;
+                }
+            }
+        }
+
+        self.queues[priority as usize].push_back(queued);
+    }
+
+    fn pop(&mut self) -> Option<QueuedPacket> {
+        self.queues.iter_mut().rev().find_map(|q| q.pop_front())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+/// Sending half of an outgoing queue. The queue is closed when it is dropped.
+#[derive(Debug)]
+pub struct OutgoingSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of an outgoing queue, owned by the connection.
+#[derive(Debug)]
+pub struct OutgoingReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn outgoing_queue() -> (OutgoingSender, OutgoingReceiver) {
+    let shared = Arc::new(Shared::default());
+    (
+        OutgoingSender {
+            shared: shared.clone(),
+        },
+        OutgoingReceiver { shared },
+    )
+}
+
+impl OutgoingSender {
+    /// Queue a packet without waiting for the connection to catch up.
+    pub fn send(&self, packet: QueuedPacket) {
+        self.shared.state.lock().unwrap().push(packet);
+        self.shared.notify.notify_one();
+    }
+
+    /// Close the queue, which ends the connection once the queued packets have been sent.
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Drop for OutgoingSender {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl OutgoingReceiver {
+    /// Wait for the next packet to send, highest priority first.
+    ///
+    /// Returns `None` once the sender is dropped and the queue is drained.
+    pub async fn recv(&mut self) -> Option<QueuedPacket> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(packet) = state.pop() {
+                    return Some(packet);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::packet::NetworkPacket;
+
+    fn packet(typ: &str, body: serde_json::Value) -> NetworkPacketWithPayload {
+        NetworkPacket::new(typ, body).into()
+    }
+
+    fn with_payload(typ: &str) -> NetworkPacketWithPayload {
+        NetworkPacketWithPayload::new(NetworkPacket::new(typ, json!({})), Arc::new(vec![0; 4]))
+    }
+
+    fn queued(
+        packet: NetworkPacketWithPayload,
+    ) -> (QueuedPacket, oneshot::Receiver<anyhow::Result<()>>) {
+        let (ack, rx) = oneshot::channel();
+        (QueuedPacket::new(packet, Some(ack)), rx)
+    }
+
+    fn drain(state: &mut QueueState) -> Vec<String> {
+        std::iter::from_fn(|| state.pop())
+            .map(|p| p.packet.packet.typ)
+            .collect()
+    }
+
+    #[test]
+    fn pops_by_priority_then_in_order() {
+        let mut state = QueueState::default();
+        for p in [
+            with_payload("kdeconnect.share.request"),
+            packet("kdeconnect.battery", json!({})),
+            packet("kdeconnect.ping", json!({})),
+            packet("kdeconnect.notification", json!({})),
+            packet(PACKET_TYPE_PAIR, json!({})),
+        ] {
+            state.push(QueuedPacket::new(p, None));
+        }
+
+        assert_eq!(
+            drain(&mut state),
+            [
+                "kdeconnect.ping",
+                PACKET_TYPE_PAIR,
+                "kdeconnect.battery",
+                "kdeconnect.notification",
+                "kdeconnect.share.request",
+            ]
+        );
+    }
+
+    #[test]
+    fn replaces_superseded_state() {
+        let mut state = QueueState::default();
+        let (first, mut first_ack) = queued(packet("kdeconnect.mpris", json!({ "player": "a" })));
+        state.push(first);
+        state.push(QueuedPacket::new(
+            packet("kdeconnect.mpris", json!({ "player": "b" })),
+            None,
+        ));
+        state.push(QueuedPacket::new(
+            packet("kdeconnect.mpris", json!({ "player": "a", "volume": 50 })),
+            None,
+        ));
+
+        assert!(first_ack.try_recv().unwrap().is_err());
+        let players = std::iter::from_fn(|| state.pop())
+            .map(|p| p.packet.packet.body)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            players,
+            [
+                json!({ "player": "a", "volume": 50 }),
+                json!({ "player": "b" })
+            ]
+        );
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest_less_important_packet() {
+        let mut state = QueueState::default();
+        let (oldest, mut oldest_ack) = queued(packet("kdeconnect.notification", json!({})));
+        state.push(oldest);
+        for _ in 1..QUEUE_CAPACITY {
+            state.push(QueuedPacket::new(
+                packet("kdeconnect.battery", json!({})),
+                None,
+            ));
+        }
+
+        // Control packets make room.
+        state.push(QueuedPacket::new(
+            packet("kdeconnect.ping", json!({})),
+            None,
+        ));
+        assert!(oldest_ack.try_recv().unwrap().is_err());
+        assert_eq!(state.len(), QUEUE_CAPACITY);
+
+        // Payloads are less important than everything queued, so they are dropped themselves.
+        let (payload, mut payload_ack) = queued(with_payload("kdeconnect.share.request"));
+        state.push(payload);
+        assert!(payload_ack.try_recv().unwrap().is_err());
+        assert_eq!(state.len(), QUEUE_CAPACITY);
+        assert_eq!(state.pop().unwrap().packet.packet.typ, "kdeconnect.ping");
+    }
+
+    #[tokio::test]
+    async fn receiver_drains_after_close() {
+        let (sender, mut receiver) = outgoing_queue();
+        sender.send(QueuedPacket::new(
+            packet("kdeconnect.battery", json!({})),
+            None,
+        ));
+        drop(sender);
+
+        assert!(receiver.recv().await.is_some());
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -0,0 +1,204 @@
+//! Traffic counters of a connected device.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Transfer speeds are averaged over the last few complete slices of this long.
+const SPEED_SLICE: Duration = Duration::from_millis(250);
+const SPEED_SLICES: u64 = 4;
+
+/// Bytes exchanged with a device, over its connection and payload transfers.
+#[derive(Debug)]
+pub struct TrafficStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    corrupt_payloads: AtomicU64,
+    speed: Mutex<SpeedRing>,
+}
+
+/// Bytes transferred in each of the last few slices of time, so that speeds can be read
+/// without changing anything.
+#[derive(Debug)]
+struct SpeedRing {
+    start: Instant,
+    /// By slice number modulo its length, with one more for the slice still running.
+    slices: [Slice; SPEED_SLICES as usize + 1],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Slice {
+    /// Number of the slice since `start`, the others are left over from earlier ones.
+    number: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl SpeedRing {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            slices: Default::default(),
+        }
+    }
+
+    fn slice_number(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.start).as_millis() / SPEED_SLICE.as_millis()) as u64
+    }
+
+    fn add(&mut self, now: Instant, sent: u64, received: u64) {
+        let number = self.slice_number(now);
+        let slice = &mut self.slices[(number % self.slices.len() as u64) as usize];
+        if slice.number != number {
+            *slice = Slice {
+                number,
+                ..Default::default()
+            };
+        }
+        slice.sent += sent;
+        slice.received += received;
+    }
+
+    /// Bytes sent and received per second, over the complete slices before `now`.
+    fn speeds(&self, now: Instant) -> (u64, u64) {
+        let current = self.slice_number(now);
+        let recent = self
+            .slices
+            .iter()
+            .filter(|s| s.number < current && current - s.number <= SPEED_SLICES);
+
+        let (sent, received) = recent.fold((0, 0), |(sent, received), s| {
+            (sent + s.sent, received + s.received)
+        });
+        let secs = (SPEED_SLICE * SPEED_SLICES as u32).as_secs_f64();
+        ((sent as f64 / secs) as u64, (received as f64 / secs) as u64)
+    }
+}
+
+/// Traffic counters at some point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrafficSnapshot {
+    /// Total bytes sent.
+    pub sent: u64,
+    /// Total bytes received.
+    pub received: u64,
+    /// Bytes sent per second, recently.
+    pub send_speed: u64,
+    /// Bytes received per second, recently.
+    pub receive_speed: u64,
+    /// Payloads discarded because they did not match their hash.
+    pub corrupt_payloads: u64,
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            corrupt_payloads: AtomicU64::new(0),
+            speed: Mutex::new(SpeedRing::new(Instant::now())),
+        }
+    }
+}
+
+impl TrafficStats {
+    pub fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.speed
+            .lock()
+            .unwrap()
+            .add(Instant::now(), bytes as u64, 0);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.speed
+            .lock()
+            .unwrap()
+            .add(Instant::now(), 0, bytes as u64);
+    }
+
+    pub fn add_corrupt_payload(&self) {
+        self.corrupt_payloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counters, with the speeds over the last second. Reading them changes nothing,
+    /// so any number of readers get the same speeds.
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        let (send_speed, receive_speed) = self.speed.lock().unwrap().speeds(Instant::now());
+
+        TrafficSnapshot {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            send_speed,
+            receive_speed,
+            corrupt_payloads: self.corrupt_payloads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn speeds_cover_the_last_complete_slices() {
+        let start = Instant::now();
+        let mut ring = SpeedRing::new(start);
+        ring.add(at(start, 100), 1000, 0);
+        ring.add(at(start, 600), 1000, 500);
+
+        // Still in the first slice.
+        assert_eq!(ring.speeds(at(start, 200)), (0, 0));
+        assert_eq!(ring.speeds(at(start, 1000)), (2000, 500));
+        // The first slice is out of the window.
+        assert_eq!(ring.speeds(at(start, 1250)), (1000, 500));
+        assert_eq!(ring.speeds(at(start, 2000)), (0, 0));
+    }
+
+    #[test]
+    fn reading_does_not_reset_speeds() {
+        let start = Instant::now();
+        let mut ring = SpeedRing::new(start);
+        ring.add(at(start, 0), 4000, 0);
+
+        let now = at(start, 500);
+        assert_eq!(ring.speeds(now), (4000, 0));
+        assert_eq!(ring.speeds(now), (4000, 0));
+    }
+
+    #[test]
+    fn reused_slices_start_empty() {
+        let start = Instant::now();
+        let mut ring = SpeedRing::new(start);
+        ring.add(at(start, 0), 1000, 0);
+        // Same place in the ring, five slices later.
+        ring.add(at(start, 1250), 100, 0);
+
+        assert_eq!(ring.speeds(at(start, 1500)), (100, 0));
+    }
+
+    #[test]
+    fn snapshot_has_totals() {
+        let stats = TrafficStats::default();
+        stats.add_sent(10);
+        stats.add_received(20);
+        stats.add_corrupt_payload();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.sent, 10);
+        assert_eq!(snapshot.received, 20);
+        assert_eq!(snapshot.corrupt_payloads, 1);
+    }
+}
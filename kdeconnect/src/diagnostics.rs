@@ -0,0 +1,392 @@
+//! "Copy diagnostics" in the tray: a summary of the state of the app for issue reports, with
+//! device names, ids and addresses left out.
+//!
+//! Redaction is best effort: known names are replaced wherever they appear, as whole words if
+//! they are short, and anything that parses as an IP address is masked.
+
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use regex::Regex;
+use tao::menu::MenuId;
+
+use crate::{
+    config::DeviceSettings,
+    context::AppContextRef,
+    device::DeviceInfo,
+    event::SystemEvent,
+    logging,
+    plugin::battery::BatteryHistory,
+    service,
+    tray::TrayMenu,
+    utils::{self, clipboard, network},
+};
+
+/// Warnings and errors from the recent log lines that are included at most.
+const MAX_LOG_LINES: usize = 30;
+
+fn copy_menu_id() -> MenuId {
+    MenuId::new("diagnostics:copy")
+}
+
+pub fn add_menu_item(menu: &mut TrayMenu) {
+    menu.add_action(copy_menu_id(), "Copy diagnostics");
+}
+
+/// Copy the diagnostics to the clipboard if the event is a click on the menu item.
+pub async fn handle_event(event: SystemEvent, ctx: &AppContextRef) {
+    if !event.is_menu_clicked(copy_menu_id()) {
+        return;
+    }
+
+    let text = collect(ctx).await;
+    match clipboard::write_text_async(text).await {
+        Ok(()) => {
+            let content = "Device and computer names and addresses were replaced. Check the text \
+                           before sharing it.";
+            utils::simple_toast("Diagnostics copied", Some(content), None).await;
+        }
+        Err(e) => {
+            log::error!("Failed to copy diagnostics: {:?}", e);
+            utils::simple_toast("Failed to copy diagnostics", Some(&e.to_string()), None).await;
+        }
+    }
+}
+
+async fn collect(ctx: &AppContextRef) -> String {
+    let settings = ctx.settings();
+    let known = settings.devices();
+    let mut redactor = Redactor::new();
+
+    let mut report = String::new();
+    writeln!(report, "KDE Connect {}", env!("CARGO_PKG_VERSION")).ok();
+    writeln!(
+        report,
+        "Running as a service: {}",
+        service::link::get().is_some()
+    )
+    .ok();
+
+    let network_settings = settings.network();
+    writeln!(
+        report,
+        "Ports: discovery {}, transfers {}",
+        network_settings.discovery_ports, network_settings.transfer_ports
+    )
+    .ok();
+
+    writeln!(report, "\nDevices:").ok();
+    let connected = ctx
+        .device_manager()
+        .list_devices()
+        .await
+        .unwrap_or_default();
+    let devices = list_devices(&known, &connected);
+    for (i, device) in devices.iter().enumerate() {
+        let label = format!("device-{}", i + 1);
+        redactor.replace(&device.id, &label);
+        for name in &device.names {
+            redactor.replace(name, &label);
+        }
+
+        let is_connected = device.connected;
+        let state = if is_connected {
+            "connected"
+        } else {
+            "not connected"
+        };
+        writeln!(report, "  {}: {}", label, state).ok();
+
+        let history = BatteryHistory::load(&device.id).await;
+        if !history.is_empty() {
+            let trend = history.trend(utils::unix_ts_ms());
+            writeln!(
+                report,
+                "    battery history: {} samples, {}",
+                history.len(),
+                trend.as_deref().unwrap_or("no recent trend")
+            )
+            .ok();
+        }
+
+        if !is_connected {
+            continue;
+        }
+        match ctx.device_manager().query_capabilities(&device.id).await {
+            Ok(Some(caps)) => {
+                let join = |set: &std::collections::BTreeSet<String>| {
+                    set.iter().cloned().collect::<Vec<_>>().join(", ")
+                };
+                writeln!(report, "    incoming: {}", join(&caps.incoming)).ok();
+                writeln!(report, "    outgoing: {}", join(&caps.outgoing)).ok();
+            }
+            res => {
+                writeln!(report, "    capabilities unavailable: {:?}", res).ok();
+            }
+        }
+    }
+    if devices.is_empty() {
+        writeln!(report, "  (none)").ok();
+    }
+
+    writeln!(report, "\nNetwork interfaces:").ok();
+    match tokio::task::spawn_blocking(network::interface_addresses).await {
+        Ok(Ok(interfaces)) => {
+            for (ip, mask) in interfaces {
+                writeln!(
+                    report,
+                    "  {}/{}",
+                    redact_ip(ip.into()),
+                    u32::from(mask).count_ones()
+                )
+                .ok();
+            }
+        }
+        res => {
+            writeln!(report, "  (unavailable: {:?})", res).ok();
+        }
+    }
+
+    writeln!(report, "\nRecent warnings and errors:").ok();
+    let lines = logging::recent_lines()
+        .into_iter()
+        .filter(|line| line.contains(" WARN ") || line.contains(" ERROR "))
+        .collect::<Vec<_>>();
+    for line in &lines[lines.len().saturating_sub(MAX_LOG_LINES)..] {
+        writeln!(report, "  {}", line).ok();
+    }
+    if lines.is_empty() {
+        writeln!(report, "  (none)").ok();
+    }
+
+    redactor.apply(&report)
+}
+
+/// A device in the report.
+#[derive(Debug)]
+struct ListedDevice {
+    id: String,
+    /// The last known name, and the current one if it is connected.
+    names: Vec<String>,
+    connected: bool,
+}
+
+/// The known devices, followed by those connected without settings yet.
+fn list_devices(known: &[DeviceSettings], connected: &[DeviceInfo]) -> Vec<ListedDevice> {
+    let mut devices = known
+        .iter()
+        .map(|device| ListedDevice {
+            id: device.id.clone(),
+            names: device.name.iter().cloned().collect(),
+            connected: false,
+        })
+        .collect::<Vec<_>>();
+
+    for info in connected {
+        match devices.iter_mut().find(|device| device.id == info.id) {
+            Some(device) => {
+                device.connected = true;
+                if !device.names.contains(&info.name) {
+                    device.names.push(info.name.clone());
+                }
+            }
+            None => devices.push(ListedDevice {
+                id: info.id.clone(),
+                names: vec![info.name.clone()],
+                connected: true,
+            }),
+        }
+    }
+    devices
+}
+
+/// Keep the kind of network (private range, subnet) but not the host.
+fn redact_ip(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.x", a, b, c)
+        }
+        // The rest of the prefix identifies the network of the user.
+        IpAddr::V6(ip) => format!("{:x}::x", ip.segments()[0]),
+    }
+}
+
+/// Replaces known identifiers, IP addresses, the computer name and the user profile folder in
+/// text.
+struct Redactor {
+    replacements: Vec<(Regex, String)>,
+    ipv4: Regex,
+    /// Candidates for IPv6 addresses, which are checked by parsing them.
+    ipv6: Regex,
+}
+
+impl Redactor {
+    fn new() -> Self {
+        let mut this = Self {
+            replacements: vec![],
+            ipv4: Regex::new(r"\b(\d{1,3}\.\d{1,3}\.\d{1,3})\.\d{1,3}\b").unwrap(),
+            ipv6: Regex::new(r"[\w:.]*:[\w:.%]*").unwrap(),
+        };
+        if let Some(home) = directories::UserDirs::new() {
+            this.replace(&home.home_dir().to_string_lossy(), "%USERPROFILE%");
+        }
+        this.replace(
+            &gethostname::gethostname().to_string_lossy(),
+            "%COMPUTERNAME%",
+        );
+        this
+    }
+
+    fn replace(&mut self, from: &str, to: &str) {
+        if from.is_empty() {
+            return;
+        }
+        // Short names are only replaced as whole words, not to mangle the rest of the text.
+        let mut pattern = regex::escape(from);
+        if from.chars().count() < 3 {
+            let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+            if is_word(from.chars().next()) {
+                pattern.insert_str(0, r"\b");
+            }
+            if is_word(from.chars().last()) {
+                pattern.push_str(r"\b");
+            }
+        }
+        // Windows names are case insensitive.
+        let pattern = format!("(?i){}", pattern);
+        self.replacements
+            .push((Regex::new(&pattern).unwrap(), to.to_string()));
+    }
+
+    fn apply(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (from, to) in &self.replacements {
+            text = from.replace_all(&text, regex::NoExpand(to)).into_owned();
+        }
+        let text = self.ipv6.replace_all(&text, |caps: &regex::Captures| {
+            let candidate = &caps[0];
+            // Drop the zone, e.g. `%12`.
+            let address = candidate.split('%').next().unwrap_or_default();
+            match address.parse::<Ipv6Addr>() {
+                Ok(ip) if !ip.is_loopback() && !ip.is_unspecified() => redact_ip(ip.into()),
+                _ => candidate.to_string(),
+            }
+        });
+        self.ipv4.replace_all(&text, "$1.x").into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn ipv4_addresses_keep_the_subnet() {
+        let redactor = Redactor::new();
+        assert_eq!(
+            redactor.apply("Connected to 192.168.1.23:1716"),
+            "Connected to 192.168.1.x:1716"
+        );
+        assert_eq!(redact_ip(Ipv4Addr::new(10, 0, 0, 5).into()), "10.0.0.x");
+    }
+
+    #[test]
+    fn ipv6_addresses_are_masked() {
+        let redactor = Redactor::new();
+        assert_eq!(
+            redactor.apply("Connected to [2001:db8:85a3::8a2e:370:7334]:1716"),
+            "Connected to [2001::x]:1716"
+        );
+        assert_eq!(
+            redactor.apply("from fe80::1c2b:3aff:fe4d:5e6f%12 failed"),
+            "from fe80::x failed"
+        );
+        assert_eq!(redactor.apply("mapped ::ffff:192.168.1.23"), "mapped 0::x");
+    }
+
+    #[test]
+    fn text_that_is_no_address_is_kept() {
+        let redactor = Redactor::new();
+        let text = "12:34:56.789 WARN kdeconnect::device::manager: aa:bb:cc:dd:ee:ff on ::1";
+        assert_eq!(redactor.apply(text), text);
+    }
+
+    #[test]
+    fn names_are_replaced() {
+        let mut redactor = Redactor::new();
+        redactor.replace("Pixel 7", "device-1");
+        redactor.replace("0123456789abcdef", "device-1");
+        assert_eq!(
+            redactor.apply("Pixel 7 (0123456789abcdef) disconnected, pixel 7 is gone"),
+            "device-1 (device-1) disconnected, device-1 is gone"
+        );
+    }
+
+    #[test]
+    fn short_names_are_replaced_as_words() {
+        let mut redactor = Redactor::new();
+        redactor.replace("Al", "device-1");
+        redactor.replace("A!", "device-2");
+        assert_eq!(
+            redactor.apply("Al connected, Alice is not, A! is"),
+            "device-1 connected, Alice is not, device-2 is"
+        );
+    }
+
+    #[test]
+    fn computer_name_is_replaced() {
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        let redactor = Redactor::new();
+        assert_eq!(
+            redactor.apply(&format!("Identity: {}", hostname)),
+            "Identity: %COMPUTERNAME%"
+        );
+    }
+
+    #[test]
+    fn connected_devices_without_settings_are_listed() {
+        let known = vec![DeviceSettings {
+            id: "known".into(),
+            name: Some("Old name".into()),
+            ..Default::default()
+        }];
+        let connected = vec![
+            DeviceInfo {
+                id: "known".into(),
+                name: "New name".into(),
+                address: Ipv4Addr::LOCALHOST.into(),
+            },
+            DeviceInfo {
+                id: "unknown".into(),
+                name: "Stranger".into(),
+                address: Ipv4Addr::LOCALHOST.into(),
+            },
+        ];
+
+        let devices = list_devices(&known, &connected);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, "known");
+        assert_eq!(devices[0].names, ["Old name", "New name"]);
+        assert!(devices[0].connected);
+        assert_eq!(devices[1].id, "unknown");
+        assert_eq!(devices[1].names, ["Stranger"]);
+        assert!(devices[1].connected);
+    }
+
+    #[test]
+    fn known_devices_are_listed_when_not_connected() {
+        let known = vec![DeviceSettings {
+            id: "known".into(),
+            ..Default::default()
+        }];
+        let devices = list_devices(&known, &[]);
+        assert_eq!(devices.len(), 1);
+        assert!(devices[0].names.is_empty());
+        assert!(!devices[0].connected);
+    }
+}
@@ -1,15 +1,24 @@
-use tao::menu::MenuId;
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use tao::{accelerator::AcceleratorId, menu::MenuId};
+use tokio::sync::broadcast;
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+use crate::utils::tao_serde::{AcceleratorIdDef, MenuIdDef};
+
+#[derive(Debug, Clone, PartialEq, Eq, Copy, Serialize, Deserialize)]
 #[non_exhaustive]
 #[allow(dead_code)]
 pub enum SystemEvent {
     ClipboardUpdated,
     PowerStatusUpdated,
-    HotkeyPressed,
+    HotkeyPressed(#[serde(with = "AcceleratorIdDef")] AcceleratorId),
     MediaSessionsChanged,
-    TrayMenuClicked(MenuId),
+    TrayMenuClicked(#[serde(with = "MenuIdDef")] MenuId),
+    /// The tray menu is about to be shown.
+    TrayMenuOpened,
+    /// The computer woke up from sleep, network connections may be dead.
+    SystemResumed,
+    /// The device of the plugin changed its name. Only delivered to the plugins of that device.
+    DeviceRenamed,
 }
 
 impl SystemEvent {
@@ -19,7 +28,88 @@ impl SystemEvent {
             _ => false,
         }
     }
+
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            SystemEvent::ClipboardUpdated => EventTopic::Clipboard,
+            SystemEvent::PowerStatusUpdated | SystemEvent::SystemResumed => EventTopic::Power,
+            SystemEvent::HotkeyPressed(_) => EventTopic::Hotkey,
+            SystemEvent::MediaSessionsChanged => EventTopic::Media,
+            SystemEvent::TrayMenuClicked(_) | SystemEvent::TrayMenuOpened => EventTopic::Tray,
+            SystemEvent::DeviceRenamed => EventTopic::Device,
+        }
+    }
+}
+
+/// Category of a [`SystemEvent`], used to only deliver events to interested subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventTopic {
+    Clipboard,
+    Power,
+    Media,
+    Tray,
+    Hotkey,
+    Device,
+}
+
+impl EventTopic {
+    pub const ALL: &'static [EventTopic] = &[
+        EventTopic::Clipboard,
+        EventTopic::Power,
+        EventTopic::Media,
+        EventTopic::Tray,
+        EventTopic::Hotkey,
+        EventTopic::Device,
+    ];
+}
+
+/// Publish/subscribe channel for system events.
+///
+/// Publishing never blocks, so it is safe to use from the UI thread and from
+/// Windows callbacks.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<SystemEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: SystemEvent) {
+        // An error only means that there is no subscriber yet.
+        self.sender.send(event).ok();
+    }
+
+    /// Receive all events in the given topics published from now on.
+    pub fn subscribe(&self, topics: &[EventTopic]) -> EventSubscription {
+        EventSubscription {
+            receiver: self.sender.subscribe(),
+            topics: topics.to_vec(),
+        }
+    }
+}
+
+pub struct EventSubscription {
+    receiver: broadcast::Receiver<SystemEvent>,
+    topics: Vec<EventTopic>,
 }
 
-pub type EventSender = mpsc::Sender<SystemEvent>;
-pub type EventReceiver = mpsc::Receiver<SystemEvent>;
+impl EventSubscription {
+    /// Wait for the next event, returns `None` if the bus has been dropped.
+    pub async fn recv(&mut self) -> Option<SystemEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.topics.contains(&event.topic()) => return Some(event),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    log::warn!("Event subscriber lagged behind, {} events dropped", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Local IPC over named pipes, with one JSON message per line.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions},
+};
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::ERROR_PIPE_BUSY,
+        Security::{
+            Authorization::{
+                ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+            },
+            PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+        },
+        System::Memory::LocalFree,
+    },
+};
+
+/// Pipe between the service and the UI helper of the user with the SID `user`, running in the
+/// session `session_id`.
+///
+/// The security descriptor of a pipe is that of its first instance, so every user gets a new
+/// pipe rather than a new instance of the previous one.
+pub fn ui_pipe(session_id: u32, user: &str) -> String {
+    format!(r"\\.\pipe\kdeconnect-rs-ui-{}-{}", session_id, user)
+}
+
+/// Pipe for other applications, see [`crate::api`].
+pub const API_PIPE: &str = r"\\.\pipe\kdeconnect-rs";
+
+/// Clipboard contents and tray menus are the largest messages, anything bigger than this
+/// means the peer is broken.
+const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Read the next message, or `None` if the other side closed the pipe.
+pub async fn read_message<T: DeserializeOwned>(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<T>> {
+    let mut buf = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut buf)
+        .await?;
+
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.last() != Some(&b'\n') {
+        if buf.len() > MAX_MESSAGE_SIZE {
+            anyhow::bail!("Message larger than {} bytes", MAX_MESSAGE_SIZE);
+        }
+        anyhow::bail!("Pipe closed in the middle of a message");
+    }
+
+    Ok(Some(
+        serde_json::from_slice(&buf).context("Invalid message")?,
+    ))
+}
+
+pub async fn write_message<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &T,
+) -> Result<()> {
+    // Newlines in strings are escaped, so the only one is the delimiter.
+    let mut buf = serde_json::to_vec(message)?;
+    buf.push(b'\n');
+
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Create an instance of a pipe, which only clients allowed by the `sddl` security
+/// descriptor can open.
+///
+/// `first` makes this fail if the pipe already exists, so that no other process can squat
+/// the name before us.
+pub fn create_server(name: &str, sddl: &str, first: bool) -> Result<NamedPipeServer> {
+    let mut options = ServerOptions::new();
+    options
+        .first_pipe_instance(first)
+        .reject_remote_clients(true);
+
+    unsafe {
+        let mut descriptor = PSECURITY_DESCRIPTOR(std::ptr::null_mut());
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(sddl),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+        .ok()
+        .context("Invalid security descriptor")?;
+
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0,
+            bInheritHandle: false.into(),
+        };
+        let server =
+            options.create_with_security_attributes_raw(name, &mut attributes as *mut _ as *mut _);
+        LocalFree(descriptor.0 as isize);
+
+        server.with_context(|| format!("Create pipe {}", name))
+    }
+}
+
+/// Open a pipe, waiting while all of its instances are busy.
+pub async fn connect(name: &str) -> Result<NamedPipeClient> {
+    loop {
+        match ClientOptions::new().open(name) {
+            Ok(client) => return Ok(client),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {}
+            Err(e) => return Err(e).with_context(|| format!("Open pipe {}", name)),
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -1,6 +1,24 @@
-use tracing_subscriber::{filter, prelude::*};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    sync::Mutex,
+};
 
-pub fn setup_logger() -> Result<(), tracing_subscriber::util::TryInitError> {
+use tracing_subscriber::{filter, fmt::MakeWriter, prelude::*};
+
+/// Lines kept in memory for crash reports.
+const RECENT_LINES: usize = 100;
+/// The log file is moved aside on startup once larger than this.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(RECENT_LINES));
+}
+
+/// Log to stderr and to `kdeconnect.log` in `log_dir`.
+pub fn setup_logger(log_dir: &Path) -> Result<(), tracing_subscriber::util::TryInitError> {
     let mut filter = filter::Targets::new().with_default(tracing::Level::INFO);
 
     if cfg!(debug_assertions) {
@@ -11,8 +29,71 @@ pub fn setup_logger() -> Result<(), tracing_subscriber::util::TryInitError> {
 
     let stderr_log = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
 
+    let file_log = match open_log_file(log_dir) {
+        Ok(file) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file)),
+        ),
+        Err(e) => {
+            eprintln!("Failed to open log file: {:?}", e);
+            None
+        }
+    };
+
+    let recent_log = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(RecentLines);
+
     tracing_subscriber::registry()
         .with(stderr_log)
+        .with(file_log)
+        .with(recent_log)
         .with(filter)
         .try_init()
 }
+
+fn open_log_file(log_dir: &Path) -> io::Result<File> {
+    let path = log_dir.join("kdeconnect.log");
+    if path.metadata().is_ok_and(|m| m.len() > MAX_LOG_SIZE) {
+        std::fs::rename(&path, log_dir.join("kdeconnect.old.log"))?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The last lines logged, oldest first.
+pub fn recent_lines() -> Vec<String> {
+    // May be called while logging panicked.
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Keeps the last [`RECENT_LINES`] lines in [`RECENT`].
+struct RecentLines;
+
+impl<'a> MakeWriter<'a> for RecentLines {
+    type Writer = RecentLines;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecentLines
+    }
+}
+
+impl io::Write for RecentLines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+        for line in String::from_utf8_lossy(buf).lines() {
+            if recent.len() == RECENT_LINES {
+                recent.pop_front();
+            }
+            recent.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
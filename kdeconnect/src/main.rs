@@ -3,71 +3,77 @@
 use std::{
     io::Write,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
     time::Duration,
 };
 
-use anyhow::{bail, Context, Result};
-use context::AppContextRef;
+use anyhow::{Context, Result};
+use context::{AppContextRef, TrayUpdater};
+use device::connection::{self, Handshake, Role};
+use serde::{Deserialize, Serialize};
 use socket2::{Domain, Socket};
 use tao::{
-    event::{Event, WindowEvent},
+    event::{Event, TrayEvent, WindowEvent},
     event_loop::{ControlFlow, EventLoop, EventLoopProxy},
     global_shortcut::ShortcutManager,
-    menu::{ContextMenu, MenuType},
+    menu::MenuType,
     system_tray::SystemTrayBuilder,
     window::{Icon, WindowBuilder},
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
     net::{TcpListener, TcpStream, UdpSocket},
-    sync::mpsc,
-};
-use tokio_rustls::{
-    rustls::{ClientConfig, ServerConfig, ServerName},
-    TlsAcceptor, TlsConnector,
+    sync::oneshot,
 };
+use utils::rate_limit::{Rate, RateLimiter};
+use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
 
 mod packet;
-use packet::{IdentityPacket, NetworkPacket, NetworkPacketWithPayload};
+use packet::NetworkPacket;
 
+mod api;
 mod cache;
+mod codec;
 mod config;
 mod context;
+mod crash;
 mod device;
+mod diagnostics;
 mod event;
+mod ipc;
 mod logging;
 mod platform_listener;
 mod plugin;
+mod service;
+#[cfg(test)]
+mod testing;
 mod tls;
+mod transfer;
+mod tray;
 mod utils;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CustomWindowEvent {
-    ClipboardUpdated,
-    PowerStatusUpdated,
-    SetTrayMenu(ContextMenu),
-    SetTrayIcon(Icon),
+    SetTrayMenu(tray::TrayMenu),
+    SetTrayIcon(tray::IconState),
+    SetTrayTooltip(String),
+    /// Sent by the UI process itself rather than the service, as it follows the taskbar of its
+    /// own session.
+    SetTrayTheme(tray::theme::TaskbarTheme),
 }
 
 pub const AUM_ID: &str = "Midori.KDEConnectRS";
-
-#[derive(Debug)]
-enum Role {
-    Server,
-    Client { remote_identity: IdentityPacket },
-}
-
-impl Role {
-    fn as_str(&self) -> &'static str {
-        match self {
-            Role::Server => "server",
-            Role::Client { .. } => "client",
-        }
-    }
-}
-
-/// Broadcasts packets for discovery.
-async fn udp_server(tcp_port: u16, ctx: AppContextRef) -> Result<()> {
+/// COM class that Windows starts to deliver toast activations, e.g. from the Action Center.
+const TOAST_ACTIVATOR_CLSID: windows::core::GUID =
+    windows::core::GUID::from_u128(0x74975f96_186d_4114_baac_4eca9f2703dd);
+/// How long a process started by COM waits for the activation it was started for.
+const TOAST_ACTIVATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identity packets handled per source address and window, more are dropped. Devices broadcast
+/// every few seconds at most, possibly once per network interface.
+const UDP_RATE_LIMIT: u32 = 10;
+const UDP_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+fn broadcast_socket() -> Result<UdpSocket> {
     let socket = Socket::new(
         Domain::IPV4,
         socket2::Type::DGRAM,
@@ -77,44 +83,97 @@ async fn udp_server(tcp_port: u16, ctx: AppContextRef) -> Result<()> {
     socket.set_reuse_address(true)?;
     socket.set_nonblocking(true)?;
 
-    let udp_socket = UdpSocket::from_std(socket.into())?;
-    let broadcast_addr = (Ipv4Addr::BROADCAST, 1716u16);
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Where to send the identity packet for discovery, as configured in the settings.
+fn discovery_targets(settings: &config::DiscoverySettings) -> Vec<Ipv4Addr> {
+    let mut targets = vec![Ipv4Addr::BROADCAST];
+
+    if settings.directed_broadcasts {
+        match utils::network::broadcast_addresses() {
+            Ok(addresses) => targets.extend(addresses),
+            Err(e) => log::warn!("Failed to list broadcast addresses: {:?}", e),
+        }
+    }
+    targets.extend(&settings.static_addresses);
+
+    targets
+}
+
+/// Broadcasts packets for discovery.
+///
+/// While devices are connected, this continues at a slower pace, so that other devices can
+/// still discover us.
+async fn udp_server(tcp_port: u16, ctx: AppContextRef) -> Result<()> {
+    let mut udp_socket = broadcast_socket()?;
+    let mut power_events = ctx.event_bus().subscribe(&[event::EventTopic::Power]);
 
     log::info!("UDP server started");
 
-    let mut identity_packet = NetworkPacket::new_identity(
-        tcp_port,
-        plugin::ALL_CAPS.0.clone(),
-        plugin::ALL_CAPS.1.clone(),
-        &ctx.config,
-    );
+    let caps = plugin::Capabilities::all();
+    let mut identity_packet =
+        NetworkPacket::new_identity(tcp_port, caps.incoming, caps.outgoing, ctx.config());
 
+    let mut resumed = false;
+    let mut last_sent: Option<tokio::time::Instant> = None;
     loop {
-        if ctx.device_manager.active_device_count() == 0 {
-            // Advertise our presence to all devices on the network if we have no active devices.
+        let settings = ctx.settings().discovery();
+        let interval = if ctx.device_manager().active_device_count() == 0 {
+            Some(settings.interval)
+        } else if settings.connected_interval > 0 {
+            Some(settings.connected_interval)
+        } else {
+            None
+        };
+        let due = match (interval, last_sent) {
+            (Some(interval), Some(sent)) => sent.elapsed() >= Duration::from_secs(interval),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        // After waking up, the existing connections are likely dead.
+        if due || resumed {
             identity_packet.reset_ts();
             let buf = serde_json::to_vec(&identity_packet)?;
-            udp_socket.send_to(&buf, broadcast_addr).await?;
+            for target in discovery_targets(&settings) {
+                if let Err(e) = udp_socket
+                    .send_to(&buf, (target, config::DISCOVERY_UDP_PORT))
+                    .await
+                {
+                    log::debug!("Failed to send identity to {}: {:?}", target, e);
+                }
+            }
+            last_sent = Some(tokio::time::Instant::now());
         }
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-    }
-}
 
-/// Handle incoming discovery packets.
-async fn handle_udp_packet(buf: &[u8], addr: SocketAddr, ctx: &AppContextRef) -> Result<()> {
-    let remote_identity_packet = serde_json::from_slice::<NetworkPacket>(buf)?;
-    if remote_identity_packet.typ != packet::PACKET_TYPE_IDENTITY {
-        bail!("Invalid packet type: {:?}", remote_identity_packet.typ);
+        resumed = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(settings.interval.max(1))) => false,
+            event = power_events.recv() => event == Some(event::SystemEvent::SystemResumed),
+        };
+        if resumed {
+            log::info!("System resumed, restarting discovery");
+            // The network interfaces may have changed while sleeping.
+            udp_socket = broadcast_socket()?;
+        }
     }
+}
 
-    let remote_identity = remote_identity_packet.into_body::<IdentityPacket>()?;
-
-    if remote_identity.device_id == ctx.config.uuid {
-        // Don't connect to ourself.
+/// Connect to a device that announced itself over UDP.
+async fn handle_udp_identity(
+    remote_identity: packet::IdentityPacket,
+    addr: SocketAddr,
+    ctx: &AppContextRef,
+) -> Result<()> {
+    if ctx
+        .device_manager()
+        .query_device(&remote_identity.device_id)
+        .await?
+    {
+        // Don't connect to devices we're already connected to.
         return Ok(());
     }
-    if ctx.device_manager.query_device(&remote_identity.device_id).await? {
-        // Don't connect to devices we're already connected to.
+    if ctx.device_manager().refuses(&remote_identity.device_id) {
         return Ok(());
     }
 
@@ -152,48 +211,79 @@ async fn udp_listener(ctx: AppContextRef) -> Result<()> {
     socket.set_nonblocking(true)?;
     socket.bind(&socket2::SockAddr::from(SocketAddr::new(
         Ipv4Addr::UNSPECIFIED.into(),
-        1716u16,
+        config::DISCOVERY_UDP_PORT,
     )))?;
 
     let udp_socket = UdpSocket::from_std(socket.into())?;
 
     log::info!("UDP listener started");
 
-    let mut buf = vec![0u8; 1024 * 512];
+    // Larger datagrams fail to be received, as they could not be valid identities anyway.
+    let mut buf = vec![0u8; packet::MAX_IDENTITY_SIZE];
+    let mut limiter = RateLimiter::new(UDP_RATE_LIMIT, UDP_RATE_WINDOW);
+    let mut rejected = 0u64;
+
     loop {
-        let (n, addr) = udp_socket.recv_from(&mut buf).await?;
+        let (n, addr) = match udp_socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // E.g. an oversized datagram, or an ICMP error for an earlier packet.
+            Err(e) => {
+                log::debug!("Failed to receive UDP packet: {}", e);
+                continue;
+            }
+        };
 
-        if let Err(e) = handle_udp_packet(&buf[..n], addr, &ctx).await {
-            log::error!("Error handling UDP packet: {}", e);
+        match limiter.check(addr.ip()) {
+            Rate::Allowed => {}
+            Rate::Exceeded => {
+                rejected += 1;
+                log::warn!("Too many UDP packets from {}, ignoring some", addr.ip());
+                continue;
+            }
+            Rate::Limited => {
+                rejected += 1;
+                continue;
+            }
         }
-    }
-}
 
-/// Opens a TCP listener on an empty port.
-async fn open_tcp_server() -> Result<(TcpListener, u16)> {
-    const MIN_PORT: u16 = 1716;
-    const MAX_PORT: u16 = 1764;
+        let identity = match NetworkPacket::parse_identity(&buf[..n]) {
+            Ok(identity) => identity,
+            Err(e) => {
+                rejected += 1;
+                log::warn!(
+                    "Rejected UDP packet from {} ({} so far): {:#}",
+                    addr,
+                    rejected,
+                    e
+                );
+                continue;
+            }
+        };
 
-    let mut last_error = None;
+        if identity.device_id == ctx.config().uuid {
+            // Our own broadcasts are received too, but nobody else should use our id.
+            if !utils::network::is_local_address(addr.ip()).unwrap_or(true) {
+                rejected += 1;
+                log::warn!(
+                    "Rejected identity with our own device id from {} ({} so far)",
+                    addr,
+                    rejected
+                );
+            }
+            continue;
+        }
 
-    for port in MIN_PORT..=MAX_PORT {
-        let addr = (Ipv4Addr::UNSPECIFIED, port);
-        match TcpListener::bind(addr).await {
-            Ok(listener) => return Ok((listener, port)),
-            Err(err) => last_error = Some(err),
+        if let Err(e) = handle_udp_identity(identity, addr, &ctx).await {
+            log::error!("Error handling UDP packet: {}", e);
         }
     }
-
-    Err(last_error.unwrap().into())
 }
 
-/// Opens a TCP listener on an empty port for payload serving.
-async fn open_payload_tcp_server() -> Result<(TcpListener, u16)> {
-    const MIN_PORT: u16 = 1765;
-
+/// Opens a TCP listener on an empty port in `ports`.
+async fn open_tcp_server(ports: config::PortRange) -> Result<(TcpListener, u16)> {
     let mut last_error = None;
 
-    for port in MIN_PORT.. {
+    for port in ports.ports() {
         let addr = (Ipv4Addr::UNSPECIFIED, port);
         match TcpListener::bind(addr).await {
             Ok(listener) => return Ok((listener, port)),
@@ -201,162 +291,27 @@ async fn open_payload_tcp_server() -> Result<(TcpListener, u16)> {
         }
     }
 
-    Err(last_error.unwrap().into())
-}
-
-/// Serve payload data on the given listener.
-async fn serve_payload(server: TcpListener, data: Arc<Vec<u8>>, ctx: AppContextRef) {
-    let task = async move {
-        loop {
-            let (stream, addr) = match server.accept().await {
-                Ok(s) => s,
-                Err(e) => {
-                    log::error!("Error accepting payload connection: {:?}", e);
-                    break;
-                }
-            };
-
-            log::info!("Payload connection from {}", addr);
-            let data = data.clone();
-            let acceptor = ctx.tls_acceptor();
-
-            tokio::spawn(async move {
-                let mut stream = match acceptor.accept(stream).await {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        log::error!("Failed to accept payload TLS connection: {}", e);
-                        return;
-                    }
-                };
-
-                if let Err(err) = stream.write_all(&data).await {
-                    log::error!("Error writing payload to {}: {:?}", addr, err);
-                    return;
-                }
-
-                if let Err(e) = stream.flush().await {
-                    log::error!("Error flushing payload to {}: {:?}", addr, e);
-                }
-            });
-        }
-    };
-
-    tokio::time::timeout(Duration::from_secs(60), task)
-        .await
-        .ok();
-}
-
-async fn send_packet<W: AsyncWrite + Unpin>(
-    mut stream: W,
-    mut packet: NetworkPacketWithPayload,
-    ctx: AppContextRef,
-) -> Result<()> {
-    if let Some(payload) = packet.payload {
-        match open_payload_tcp_server().await {
-            Ok((payload_server, payload_port)) => {
-                packet.packet.set_payload(payload.len() as _, payload_port);
-
-                log::info!(
-                    "Serving a payload of {} bytes on {}",
-                    payload.len(),
-                    payload_port
-                );
-
-                let ctx = ctx.clone();
-                tokio::spawn(async move {
-                    serve_payload(payload_server, payload, ctx).await;
-                });
-            }
-            Err(e) => {
-                log::error!("Failed to start payload server: {:?}", e);
-            }
-        }
+    match last_error {
+        Some(err) => Err(err).with_context(|| format!("No free port in {}", ports)),
+        None => anyhow::bail!("Empty port range {}", ports),
     }
-
-    let mut bytes = packet.packet.to_vec();
-    bytes.push(0x0A);
-
-    stream
-        .write_all(&bytes)
-        .await
-        .context("Write to connection")?;
-    stream.flush().await.context("Flush connection")?;
-
-    Ok(())
 }
 
 async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextRef) -> Result<()> {
-    let s2_socket = Socket::from(stream.into_std()?);
-    // enable keepalive
-    s2_socket.set_keepalive(true)?;
-    s2_socket.set_tcp_keepalive(
-        &socket2::TcpKeepalive::new()
-            // time to start sending keepalive packets (seconds)
-            .with_time(Duration::from_secs(10))
-            // interval between keepalive packets after the initial period (seconds)
-            .with_interval(Duration::from_secs(5)),
-    )?;
-    let mut stream = TcpStream::from_std(s2_socket.into())?;
-
     let role_text = role.as_str();
 
-    let (stream, remote_identity) = match role {
-        Role::Server => {
-            let mut remote_identity = vec![];
-            loop {
-                let b = stream.read_u8().await?;
-                if b == 0x0A {
-                    break;
-                }
-                remote_identity.push(b);
-            }
-
-            let remote_identity_packet: NetworkPacket = serde_json::from_slice(&remote_identity)?;
-            if remote_identity_packet.typ != packet::PACKET_TYPE_IDENTITY {
-                bail!("Invalid packet type: {:?}", remote_identity_packet.typ);
-            }
-            let remote_identity = remote_identity_packet.into_body::<IdentityPacket>()?;
-
-            (
-                tokio_rustls::TlsStream::from(
-                    ctx.tls_connector()
-                        .connect(ServerName::IpAddress(ip), stream)
-                        .await
-                        .context("TLS connect")?,
-                ),
-                remote_identity,
-            )
-        }
-        Role::Client { remote_identity } => {
-            let local_identity_packet = NetworkPacket::new_identity(
-                None,
-                plugin::ALL_CAPS.0.clone(),
-                plugin::ALL_CAPS.1.clone(),
-                &ctx.config,
-            );
-            stream.write_all(&local_identity_packet.to_vec()).await?;
-            stream.write_all(b"\n").await?;
-
-            (
-                tokio_rustls::TlsStream::from(
-                    ctx.tls_acceptor()
-                        .accept(stream)
-                        .await
-                        .context("TLS accept")?,
-                ),
-                remote_identity,
-            )
-        }
-    };
+    let Handshake {
+        conn,
+        remote_identity,
+        advertised_caps,
+        info,
+    } = connection::handshake(role, stream, ip, &ctx).await?;
 
     let device_id = remote_identity.device_id.as_str();
-    let _peer_cert = stream
-        .get_ref()
-        .1
-        .peer_certificates()
-        .and_then(|c| c.first());
-
-    let mut stream = BufStream::new(stream);
+    if ctx.device_manager().refuses(device_id) {
+        log::info!("Refused connection from {} at {}", device_id, ip);
+        return Ok(());
+    }
 
     log::info!(
         "Handshake successful for {} ({}) at {} as {}",
@@ -366,74 +321,60 @@ async fn handle_conn(role: Role, stream: TcpStream, ip: IpAddr, ctx: AppContextR
         role_text
     );
 
+    let local_cert = ctx.config().tls_cert.clone();
+    let pair_info = info.clone();
     let (conn_id, mut packet_rx, device_handle) = ctx
-        .device_manager
-        .add_device(device_id, &remote_identity.device_name, ip)
+        .device_manager()
+        .add_device(device_id, &remote_identity.device_name, ip, info)
         .await?;
+    let device_name = remote_identity.device_name.clone();
+    let mut conn = conn
+        .with_stats(device_handle.stats().clone())
+        .with_packet_log(device_handle.packet_log().clone())
+        .on_pair(move |timestamp| {
+            // Let the user compare the key with the one shown on the device.
+            let title = format!("Paired with {}", device_name);
+            let content = pair_info
+                .pairing_verification_key(&local_cert, timestamp)
+                .map(|key| format!("Verification key: {}", key));
+            tokio::spawn(async move {
+                utils::simple_toast(&title, content.as_deref(), None).await;
+            });
+        });
 
-    loop {
-        let mut line = String::new();
-
-        tokio::select! {
-            packet = packet_rx.recv() => {
-                // Send packet
-                if let Some(packet) = packet {
-                    if let Err(e) = send_packet(&mut stream, packet, ctx.clone()).await {
-                        log::error!("Error sending packet to {}: {:?}", ip, e);
-                        break;
-                    }
-                } else {
-                    log::info!("Device {} packet sender disconnected", device_id);
-                    break;
-                }
-            }
-
-            read_result = stream.read_line(&mut line) => {
-                // Receive packet
-                match read_result {
-                    Ok(0) => {
-                        log::warn!("Connection closed (EOF)");
-                        break;
-                    }
-                    Err(e) => {
-                        log::error!("Failed to read from connection: {:?}", e);
-                        break;
-                    }
-                    Ok(_) => {
-                        // We have actual data to process
-                    }
-                }
-
-                match serde_json::from_str::<NetworkPacket>(&line) {
-                    Ok(packet) => match packet.typ.as_str() {
-                        packet::PACKET_TYPE_PAIR => {
-                            // Directly handle pairing requests
-                            NetworkPacket::new_pair(true)
-                                .write_to_conn(&mut stream)
-                                .await?;
-                            log::info!("Accepted pairing request");
-                        }
-                        _ => {
-                            device_handle.dispatch_packet(packet).await;
-                        },
-                    },
-                    Err(err) => {
-                        log::error!("Failed to parse packet: {:?}", err);
-                    }
-                }
-            }
+    // The plugins loaded for this device may differ from what we advertised during the
+    // handshake (e.g. a plugin failed to initialize), so tell the device what we really support.
+    let mut result = Ok(());
+    if let Some(caps) = ctx.device_manager().query_capabilities(device_id).await? {
+        if caps != advertised_caps {
+            log::info!(
+                "Capabilities changed for {}, re-sending identity",
+                device_id
+            );
+            let identity =
+                NetworkPacket::new_identity(None, caps.incoming, caps.outgoing, ctx.config());
+            result = conn.send(identity.into()).await;
         }
+    }
 
-        if let Err(e) = stream.flush().await {
-            log::error!("Failed to flush stream: {:?}", e);
-            break;
-        }
+    if result.is_ok() {
+        result = conn
+            .run(&mut packet_rx, |packet| {
+                let device_handle = device_handle.clone();
+                async move { device_handle.dispatch_packet(packet).await }
+            })
+            .await;
+    }
+    if let Err(e) = result {
+        log::error!("Connection to {} failed: {:?}", ip, e);
     }
+    // Also stops serving the payloads sent on this connection.
+    drop(conn);
 
     // Wait for some time before removing device and notify the user.
     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
-    ctx.device_manager.remove_device(device_id, conn_id).await;
+    ctx.device_manager().remove_device(device_id, conn_id).await;
 
     Ok(())
 }
@@ -460,22 +401,27 @@ async fn tcp_server(listener: TcpListener, ctx: AppContextRef) -> Result<()> {
     }
 }
 
-async fn event_handler(mut rx: event::EventReceiver, ctx: AppContextRef) {
+async fn event_handler(mut subscription: event::EventSubscription, ctx: AppContextRef) {
     let mut last_message = None;
 
     loop {
         tokio::select! {
-            message = rx.recv() => {
+            message = subscription.recv() => {
                 if let Some(current_message) = message {
                     if last_message == Some(current_message) {
                         // The message has been received twice in a row, ignore it.
                         continue;
                     }
 
+                    if current_message == event::SystemEvent::SystemResumed {
+                        ctx.device_manager().check_connections().await;
+                        ctx.update_tray().await;
+                    }
+
                     // The message has changed, send the last one and store the new one.
 
                     if let Some(last_message) = last_message.take() {
-                        ctx.device_manager.broadcast_event(last_message).await;
+                        ctx.device_manager().broadcast_event(last_message).await;
                     }
 
                     last_message = Some(current_message);
@@ -486,7 +432,7 @@ async fn event_handler(mut rx: event::EventReceiver, ctx: AppContextRef) {
             // Wait for 100ms before sending the message.
             _ = tokio::time::sleep(Duration::from_millis(100)), if last_message.is_some() => {
                 // Send the last message and clear it.
-                ctx.device_manager.broadcast_event(last_message.take().unwrap()).await;
+                ctx.device_manager().broadcast_event(last_message.take().unwrap()).await;
             }
         };
     }
@@ -494,46 +440,53 @@ async fn event_handler(mut rx: event::EventReceiver, ctx: AppContextRef) {
 
 #[tokio::main]
 async fn server_main(
-    event_channel: (event::EventSender, event::EventReceiver),
-    event_loop_proxy: EventLoopProxy<CustomWindowEvent>,
-    hotkey_manager: ShortcutManager,
+    event_bus: event::EventBus,
+    event_subscription: event::EventSubscription,
+    event_loop_proxy: Option<EventLoopProxy<CustomWindowEvent>>,
+    hotkey_manager: Option<ShortcutManager>,
+    shutdown: oneshot::Receiver<()>,
 ) -> Result<()> {
-    let (_, event_rx) = event_channel;
-    let (tcp_listener, tcp_port) = open_tcp_server().await?;
+    let run_as_service = event_loop_proxy.is_none();
+
+    let config = config::Config::init_or_load("./config.json")?;
+    let settings = config::SettingsStore::load_or_default("./config.toml")?;
+
+    let (tcp_listener, tcp_port) = open_tcp_server(settings.network().discovery_ports).await?;
 
     log::info!("TCP port: {}", tcp_port);
 
-    let config = config::Config::init_or_load("./config.json")?;
+    let (tls_acceptor, tls_connector) = tls::build_tls(&config).context("Set up TLS")?;
+    let mut builder = context::ApplicationContext::builder(config, settings)
+        .tls(tls_acceptor, tls_connector)
+        .event_bus(event_bus);
+    if let Some(proxy) = event_loop_proxy {
+        builder = builder.event_loop_proxy(proxy);
+    }
+    if let Some(hotkey_manager) = hotkey_manager {
+        builder = builder.hotkey_manager(hotkey_manager);
+    }
+    let ctx = builder.build();
+    crash::set_context(ctx.clone());
 
-    let ctx = context::ApplicationContext::new(config, event_loop_proxy, hotkey_manager)
-        .await
-        .context("Initialize context")?;
-
-    // Use the same certificate when we are acting as client and server.
-
-    let client_config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(Arc::new(tls::ServerVerifier::AlwaysOk))
-        .with_single_cert(
-            vec![tokio_rustls::rustls::Certificate(
-                ctx.config.tls_cert.clone(),
-            )],
-            tokio_rustls::rustls::PrivateKey(ctx.config.tls_key.clone()),
-        )?;
-
-    let server_config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_client_cert_verifier(Arc::new(tls::ClientVerifier::AlwaysOk))
-        .with_single_cert(
-            vec![tokio_rustls::rustls::Certificate(
-                ctx.config.tls_cert.clone(),
-            )],
-            tokio_rustls::rustls::PrivateKey(ctx.config.tls_key.clone()),
-        )?;
-
-    let tls_connector = TlsConnector::from(Arc::new(client_config));
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    ctx.setup_tls(tls_acceptor, tls_connector);
+    if !run_as_service {
+        let known_devices = ctx.settings().devices().into_iter().map(|d| d.id).collect();
+        tokio::spawn(utils::remove_stale_toasts(known_devices));
+        tokio::spawn(utils::firewall::check_on_startup(ctx.clone()));
+    }
+
+    if run_as_service {
+        let lctx = ctx.clone();
+        tokio::spawn(async move {
+            let e = service::link::serve(lctx).await;
+            log::error!("UI helper listener exited with {:?}", e);
+        });
+    }
+
+    let actx = ctx.clone();
+    tokio::spawn(async move {
+        let e = api::serve(actx, run_as_service).await;
+        log::error!("API listener exited with {:?}", e);
+    });
 
     let uctx = ctx.clone();
     let udp_task = tokio::spawn(async move {
@@ -549,31 +502,113 @@ async fn server_main(
 
     let ectx = ctx.clone();
     let event_task = tokio::spawn(async move {
-        event_handler(event_rx, ectx).await;
+        event_handler(event_subscription, ectx).await;
         log::warn!("Event handler exited");
     });
 
+    let sctx = ctx.clone();
     let tcp_task = tokio::spawn(async move {
         let e = tcp_server(tcp_listener, ctx).await;
         log::warn!("TCP server exited with {:?}", e);
     });
 
-    udp_task.await?;
-    udp_listener_task.await?;
-    tcp_task.await?;
-    event_task.await?;
+    tokio::select! {
+        _ = shutdown => {
+            log::info!("Shutting down");
+            if tokio::time::timeout(Duration::from_secs(5), sctx.device_manager().shutdown())
+                .await
+                .is_err()
+            {
+                log::warn!("Timed out waiting for plugins to stop");
+            }
+        }
+        r = async {
+            udp_task.await?;
+            udp_listener_task.await?;
+            tcp_task.await?;
+            event_task.await
+        } => r?,
+    }
 
     Ok(())
 }
 
+/// Where logs, crash reports and other files that are not settings go.
+fn data_dir() -> PathBuf {
+    let base_dirs = directories::BaseDirs::new().expect("Failed to get base dirs");
+    base_dirs.data_dir().join("kde-connect-rs")
+}
+
 fn main() -> Result<()> {
-    logging::setup_logger().expect("Failed to set up logger");
+    let data_dir = data_dir();
+    std::fs::create_dir_all(&data_dir)?;
+    logging::setup_logger(&data_dir).expect("Failed to set up logger");
+    crash::install_hook(data_dir);
+
+    match std::env::args().nth(1).as_deref() {
+        Some("--service") => service::run(),
+        Some("--install-service") => service::install(),
+        Some("--uninstall-service") => service::uninstall(),
+        Some("--ui-helper") => run_ui(true),
+        Some("--unregister") => unregister_toasts(),
+        Some(winrt_toast::EMBEDDING_ARG) => handle_toast_activation(),
+        _ => run_ui(false),
+    }
+}
 
-    let (event_tx, event_rx) = mpsc::channel(10);
+fn log_toast_activation(activation: winrt_toast::Activation) {
+    log::info!("Toast activated: {:?}", activation.arguments);
+}
 
-    let base_dirs = directories::BaseDirs::new().expect("Failed to get base dirs");
-    let data_dir = base_dirs.data_dir().join("kde-connect-rs");
-    std::fs::create_dir_all(&data_dir)?;
+/// Started by COM for a toast clicked in the Action Center while KDE Connect was not running.
+/// The callbacks of the toast are gone with the process that showed it, so the activation is
+/// only logged, without starting the rest of the app.
+fn handle_toast_activation() -> Result<()> {
+    unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tx = std::sync::Mutex::new(tx);
+    let _activator =
+        winrt_toast::ToastActivator::register(AUM_ID, TOAST_ACTIVATOR_CLSID, move |activation| {
+            tx.lock().unwrap().send(activation).ok();
+        })?;
+
+    match rx.recv_timeout(TOAST_ACTIVATION_TIMEOUT) {
+        Ok(activation) => log_toast_activation(activation),
+        Err(_) => log::warn!("Started by COM, but no toast was activated"),
+    }
+    Ok(())
+}
+
+/// Remove the registry keys that make toasts work, e.g. before deleting the app.
+fn unregister_toasts() -> Result<()> {
+    winrt_toast::ToastActivator::unregister(AUM_ID, TOAST_ACTIVATOR_CLSID)?;
+    winrt_toast::unregister(AUM_ID)?;
+    log::info!("Removed the toast registration of {}", AUM_ID);
+    Ok(())
+}
+
+/// Run the tray and everything else that needs the interactive session, either with the
+/// server in this process, or as the UI helper of the service.
+fn run_ui(helper: bool) -> Result<()> {
+    // Keeps other helpers in this session from starting while it is held.
+    let _instance = if helper {
+        match service::helper::single_instance()? {
+            Some(instance) => Some(instance),
+            None => {
+                log::info!("UI helper is already running in this session");
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+
+    let event_bus = event::EventBus::new();
+    // Subscribe before any event gets published, so that nothing is lost during startup.
+    let event_subscription = event_bus.subscribe(event::EventTopic::ALL);
+
+    let data_dir = data_dir();
 
     {
         let icon_path = data_dir.join("notification.ico");
@@ -585,7 +620,7 @@ fn main() -> Result<()> {
         winrt_toast::register(AUM_ID, "KDE Connect", Some(&icon_path))?;
     }
 
-    platform_listener::mpris::start(event_tx.clone())?;
+    platform_listener::mpris::start(event_bus.clone())?;
 
     let event_loop: EventLoop<CustomWindowEvent> = EventLoop::with_user_event();
 
@@ -597,7 +632,20 @@ fn main() -> Result<()> {
 
     let hotkey_manager = ShortcutManager::new(&event_loop);
 
-    let windows_listener = platform_listener::windows::WindowsListener::new(&event_loop)?;
+    let theme_proxy = event_loop.create_proxy();
+    utils::log_if_error(
+        "Failed to follow the taskbar theme",
+        tray::theme::watch(move |theme| {
+            theme_proxy
+                .send_event(CustomWindowEvent::SetTrayTheme(theme))
+                .ok();
+        }),
+    );
+    // Redrawn with the latest state when the theme changes.
+    let mut icon_state = None;
+    let mut taskbar_theme = tray::theme::TaskbarTheme::default();
+
+    let windows_listener = platform_listener::windows::WindowsListener::new(event_bus.clone())?;
 
     let window = WindowBuilder::new()
         .with_title("KDEConnect.rs")
@@ -605,14 +653,36 @@ fn main() -> Result<()> {
         .build(&event_loop)
         .unwrap();
 
-    let event_tx_main = event_tx.clone();
+    // Needs COM, which is initialized with the window. Toasts clicked after a restart launch
+    // the app again through it, their callbacks in this process are gone by then.
+    let _toast_activator =
+        winrt_toast::ToastActivator::register(AUM_ID, TOAST_ACTIVATOR_CLSID, log_toast_activation)
+            .map_err(|e| log::warn!("Failed to register toast activator: {:?}", e))
+            .ok();
+
+    let event_bus_main = event_bus.clone();
     let proxy = event_loop.create_proxy();
-    std::thread::spawn(|| {
-        let r = server_main((event_tx_main, event_rx), proxy, hotkey_manager);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (server_done_tx, server_done_rx) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        let r = if helper {
+            drop(event_bus_main);
+            service::helper::run(event_subscription, proxy, shutdown_rx)
+        } else {
+            server_main(
+                event_bus_main,
+                event_subscription,
+                Some(proxy),
+                Some(hotkey_manager),
+                shutdown_rx,
+            )
+        };
         if let Err(e) = r {
             log::error!("Server exited with error: {}", e);
         }
+        server_done_tx.send(()).ok();
     });
+    let mut shutdown_tx = Some(shutdown_tx);
 
     event_loop.run(move |event, _, control_flow| {
         let _ = windows_listener;
@@ -628,6 +698,14 @@ fn main() -> Result<()> {
             Event::MainEventsCleared => {
                 window.request_redraw();
             }
+            Event::LoopDestroyed => {
+                // Give plugins a chance to clean up before the process exits.
+                if let Some(tx) = shutdown_tx.take() {
+                    if tx.send(()).is_ok() {
+                        server_done_rx.recv_timeout(Duration::from_secs(10)).ok();
+                    }
+                }
+            }
             // Event::GlobalShortcutEvent(hotkey_id) if hotkey_id == shortcut_1.clone().id() => {
             //     println!("Pressed `shortcut_1` -- unregister for future use");
             //     // unregister key
@@ -650,31 +728,35 @@ fn main() -> Result<()> {
             //     println!("Pressed on `shortcut_4`");
             // }
             Event::GlobalShortcutEvent(hotkey_id) => {
-                println!("hotkey_id {:?}", hotkey_id);
+                event_bus.publish(event::SystemEvent::HotkeyPressed(hotkey_id));
+            }
+            Event::TrayEvent {
+                event: TrayEvent::RightClick,
+                ..
+            } => {
+                event_bus.publish(event::SystemEvent::TrayMenuOpened);
             }
             Event::MenuEvent {
                 menu_id, origin, ..
             } if origin == MenuType::ContextMenu => {
-                event_tx
-                    .blocking_send(event::SystemEvent::TrayMenuClicked(menu_id))
-                    .ok();
+                event_bus.publish(event::SystemEvent::TrayMenuClicked(menu_id));
             }
             Event::UserEvent(event) => match event {
-                CustomWindowEvent::ClipboardUpdated => {
-                    event_tx
-                        .blocking_send(event::SystemEvent::ClipboardUpdated)
-                        .ok();
+                CustomWindowEvent::SetTrayMenu(menu) => {
+                    system_tray.set_menu(&menu.to_context_menu());
                 }
-                CustomWindowEvent::PowerStatusUpdated => {
-                    event_tx
-                        .blocking_send(event::SystemEvent::PowerStatusUpdated)
-                        .ok();
+                CustomWindowEvent::SetTrayIcon(state) => {
+                    system_tray.set_icon(state.to_icon(taskbar_theme));
+                    icon_state = Some(state);
                 }
-                CustomWindowEvent::SetTrayMenu(menu) => {
-                    system_tray.set_menu(&menu);
+                CustomWindowEvent::SetTrayTheme(theme) => {
+                    taskbar_theme = theme;
+                    if let Some(state) = icon_state {
+                        system_tray.set_icon(state.to_icon(theme));
+                    }
                 }
-                CustomWindowEvent::SetTrayIcon(icon) => {
-                    system_tray.set_icon(icon);
+                CustomWindowEvent::SetTrayTooltip(tooltip) => {
+                    system_tray.set_tooltip(&tooltip);
                 }
             },
             _ => {}
@@ -1,18 +1,37 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, io, num::NonZeroUsize, sync::Arc};
 
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::{config::Config, utils};
+use crate::{config::Config, transfer::PayloadProgress, utils};
 
 pub const PACKET_TYPE_IDENTITY: &str = "kdeconnect.identity";
 pub const PACKET_TYPE_PAIR: &str = "kdeconnect.pair";
+/// Probe sent on idle connections. Devices without a handler ignore it.
+pub const PACKET_TYPE_KEEPALIVE: &str = "kdeconnect.keepalive";
+
+/// Newest protocol version we speak. Devices with an older one get the behavior of theirs.
+pub const PROTOCOL_VERSION: u8 = 8;
+
+/// Maximum size of a packet on the wire. Anything larger should be sent as a payload.
+pub const MAX_PACKET_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum size of an identity packet, which is read before the connection is encrypted.
+pub const MAX_IDENTITY_SIZE: usize = 64 * 1024;
+
+const MAX_PACKET_TYPE_LENGTH: usize = 128;
+const MAX_DEVICE_ID_LENGTH: usize = 64;
+const MAX_DEVICE_NAME_LENGTH: usize = 128;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PairPacket {
-    pair: bool,
+    pub pair: bool,
+    /// When the request was made in seconds since the epoch, sent from protocol version 8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -27,6 +46,33 @@ pub struct IdentityPacket {
     pub tcp_port: Option<u16>,
 }
 
+impl IdentityPacket {
+    /// Check that the identity is usable, since the device id ends up in file names and menu ids.
+    pub fn validate(&self) -> Result<()> {
+        let id = &self.device_id;
+        if id.is_empty() || id.len() > MAX_DEVICE_ID_LENGTH {
+            bail!("Invalid device id length: {}", id.len());
+        }
+        if !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!("Invalid device id: {:?}", id);
+        }
+
+        let name = self.device_name.trim();
+        if name.is_empty() || name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+            bail!("Invalid device name: {:?}", self.device_name);
+        }
+
+        if self.tcp_port == Some(0) {
+            bail!("Invalid TCP port");
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NetworkPacket {
@@ -67,7 +113,7 @@ impl NetworkPacket {
             IdentityPacket {
                 device_id: config.uuid.clone(),
                 device_name: gethostname::gethostname().to_string_lossy().to_string(),
-                protocol_version: 7,
+                protocol_version: PROTOCOL_VERSION,
                 device_type: "desktop".into(),
                 incoming_capabilities: in_caps.into_iter().collect(),
                 outgoing_capabilities: out_caps.into_iter().collect(),
@@ -77,11 +123,73 @@ impl NetworkPacket {
     }
 
     pub fn new_pair(pair: bool) -> Self {
-        Self::new(PACKET_TYPE_PAIR, PairPacket { pair })
+        Self::new(
+            PACKET_TYPE_PAIR,
+            PairPacket {
+                pair,
+                timestamp: None,
+            },
+        )
+    }
+
+    pub fn new_keepalive(reply: bool) -> Self {
+        Self::new(PACKET_TYPE_KEEPALIVE, serde_json::json!({ "reply": reply }))
+    }
+
+    /// Parse and validate a packet received from the network.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() > MAX_PACKET_SIZE {
+            bail!("Packet too large: {} bytes", buf.len());
+        }
+
+        let packet: Self = serde_json::from_slice(buf)?;
+        packet.validate()?;
+        Ok(packet)
+    }
+
+    /// Check the fields that are not checked when deserializing.
+    pub fn validate(&self) -> Result<()> {
+        if self.typ.len() > MAX_PACKET_TYPE_LENGTH || !self.typ.starts_with("kdeconnect.") {
+            bail!("Invalid packet type: {:?}", self.typ);
+        }
+        if !self.body.is_object() {
+            bail!("Packet body is not an object");
+        }
+        match (&self.payload_transfer_info, self.payload_size) {
+            (Some(info), Some(_)) if info.port == 0 => bail!("Invalid payload port"),
+            (Some(_), None) => bail!("Payload transfer info without payload size"),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Parse and validate an identity packet.
+    pub fn parse_identity(buf: &[u8]) -> Result<IdentityPacket> {
+        if buf.len() > MAX_IDENTITY_SIZE {
+            bail!("Identity packet too large: {} bytes", buf.len());
+        }
+
+        let packet = Self::parse(buf)?;
+        if packet.typ != PACKET_TYPE_IDENTITY {
+            bail!("Invalid packet type: {:?}", packet.typ);
+        }
+
+        let identity = packet.into_body::<IdentityPacket>()?;
+        identity.validate()?;
+
+        Ok(identity)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("Failed to serialize packet")
+    /// Append the packet to `buf`, followed by the newline that ends it on the wire.
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<(), serde_json::Error> {
+        serde_json::to_writer(buf.writer(), self)?;
+        buf.put_u8(b'\n');
+        Ok(())
     }
 
     /// Reset the timestamp of the packet to the current time.
@@ -93,8 +201,9 @@ impl NetworkPacket {
         &self,
         mut conn: W,
     ) -> Result<(), std::io::Error> {
-        conn.write_all(&self.to_vec()).await?;
-        conn.write_all(b"\n").await?;
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf)?;
+        conn.write_all(&buf).await?;
         conn.flush().await?;
         Ok(())
     }
@@ -106,6 +215,17 @@ impl NetworkPacket {
         serde_json::from_value(self.body)
     }
 
+    /// The payload announced by the packet, if any.
+    pub fn payload(&self) -> Option<Payload> {
+        match (self.payload_size, &self.payload_transfer_info) {
+            (Some(size), Some(info)) => Some(Payload {
+                size,
+                port: info.port,
+            }),
+            _ => None,
+        }
+    }
+
     pub fn set_payload(&mut self, size: u64, port: u16) {
         self.payload_size = Some(size);
         self.payload_transfer_info = Some(PayloadTransferInfo { port });
@@ -117,10 +237,44 @@ pub struct PayloadTransferInfo {
     pub port: u16,
 }
 
+/// A payload to fetch from the device, see [`NetworkPacket::payload`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payload {
+    pub size: u64,
+    pub port: u16,
+}
+
+/// Read the identity packet sent in plain text before the TLS handshake.
+///
+/// Reads byte by byte, so that nothing after the newline is consumed.
+pub async fn read_identity_line<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![];
+    loop {
+        let b = reader.read_u8().await?;
+        if b == b'\n' {
+            return Ok(buf);
+        }
+        if buf.len() >= MAX_IDENTITY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Identity packet too large",
+            ));
+        }
+        buf.push(b);
+    }
+}
+
 #[derive(Clone)]
 pub struct NetworkPacketWithPayload {
     pub packet: NetworkPacket,
     pub payload: Option<Arc<Vec<u8>>>,
+    /// How many complete downloads of the payload are served before its server stops.
+    consumers: NonZeroUsize,
+    /// Tracks the download of the payload, see [`transfer::send`](crate::transfer::send).
+    pub progress: Option<Arc<PayloadProgress>>,
 }
 
 impl Debug for NetworkPacketWithPayload {
@@ -133,6 +287,7 @@ impl Debug for NetworkPacketWithPayload {
         f.debug_struct("NetworkPacketWithPayload")
             .field("packet", &self.packet)
             .field("payload", &payload_desc)
+            .field("consumers", &self.consumers)
             .finish()
     }
 }
@@ -142,6 +297,8 @@ impl From<NetworkPacket> for NetworkPacketWithPayload {
         Self {
             packet,
             payload: None,
+            consumers: NonZeroUsize::MIN,
+            progress: None,
         }
     }
 }
@@ -151,6 +308,204 @@ impl NetworkPacketWithPayload {
         Self {
             packet,
             payload: Some(payload),
+            consumers: NonZeroUsize::MIN,
+            progress: None,
+        }
+    }
+
+    /// Serve the payload for several downloads, e.g. if the device may fetch it again.
+    pub fn with_consumers(mut self, consumers: NonZeroUsize) -> Self {
+        self.consumers = consumers;
+        self
+    }
+
+    /// How many complete downloads of the payload are served, at least one.
+    pub fn consumers(&self) -> NonZeroUsize {
+        self.consumers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: &[&str] = &[
+        r#"{"id":1,"type":"kdeconnect.identity","body":{"deviceId":"a_b-c","deviceName":"Phone","protocolVersion":7,"deviceType":"phone","incomingCapabilities":["kdeconnect.ping"],"outgoingCapabilities":[],"tcpPort":1716}}"#,
+        r#"{"id":2,"type":"kdeconnect.ping","body":{"message":"hi"}}"#,
+        r#"{"id":3,"type":"kdeconnect.clipboard","body":{"content":"text"}}"#,
+        r#"{"id":4,"type":"kdeconnect.share.request","body":{"filename":"a.txt"},"payloadSize":10,"payloadTransferInfo":{"port":1739}}"#,
+        r#"{"id":5,"type":"kdeconnect.notification","body":{"id":"0|com.app|1","appName":"App","ticker":"t","isClearable":true}}"#,
+        r#"{"id":6,"type":"kdeconnect.mpris.request","body":{"player":"p","action":"PlayPause"}}"#,
+    ];
+
+    /// xorshift64, good enough to generate reproducible garbage.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn mutate(rng: &mut Rng, input: &[u8]) -> Vec<u8> {
+        let mut buf = input.to_vec();
+        for _ in 0..=rng.below(8) {
+            match rng.below(4) {
+                0 if !buf.is_empty() => {
+                    let i = rng.below(buf.len());
+                    buf[i] = rng.next() as u8;
+                }
+                1 if !buf.is_empty() => {
+                    let i = rng.below(buf.len());
+                    buf.remove(i);
+                }
+                2 => {
+                    let i = rng.below(buf.len() + 1);
+                    let b = b"{}[]\",:0-e\\\n"[rng.below(12)];
+                    buf.insert(i, b);
+                }
+                _ => {
+                    let len = rng.below(buf.len() + 1);
+                    buf.truncate(len);
+                }
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn parses_valid_samples() {
+        for sample in SAMPLES {
+            NetworkPacket::parse(sample.as_bytes()).unwrap();
+        }
+        let identity = NetworkPacket::parse_identity(SAMPLES[0].as_bytes()).unwrap();
+        assert_eq!(identity.device_id, "a_b-c");
+    }
+
+    #[test]
+    fn encode_appends_newline_terminated_packets() {
+        let mut buf = BytesMut::new();
+        for sample in &SAMPLES[..2] {
+            NetworkPacket::parse(sample.as_bytes())
+                .unwrap()
+                .encode(&mut buf)
+                .unwrap();
+        }
+
+        let lines: Vec<_> = buf[..].split(|b| *b == b'\n').collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].is_empty());
+        for (line, sample) in lines.iter().zip(SAMPLES) {
+            let expected = NetworkPacket::parse(sample.as_bytes()).unwrap();
+            assert_eq!(*line, expected.to_vec().unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let cases = [
+            "",
+            "null",
+            "[]",
+            r#"{"id":1,"type":"kdeconnect.ping","body":[]}"#,
+            r#"{"id":1,"type":"kdeconnect.ping","body":"x"}"#,
+            r#"{"id":1,"type":"ping","body":{}}"#,
+            r#"{"id":1,"body":{}}"#,
+            r#"{"id":-1,"type":"kdeconnect.ping","body":{}}"#,
+            r#"{"id":1,"type":"kdeconnect.share.request","body":{},"payloadTransferInfo":{"port":1739}}"#,
+            r#"{"id":1,"type":"kdeconnect.share.request","body":{},"payloadSize":1,"payloadTransferInfo":{"port":0}}"#,
+            r#"{"id":1,"type":"kdeconnect.share.request","body":{},"payloadSize":-1}"#,
+        ];
+        for case in cases {
+            assert!(NetworkPacket::parse(case.as_bytes()).is_err(), "{}", case);
+        }
+
+        let long_type = format!(
+            r#"{{"id":1,"type":"kdeconnect.{}","body":{{}}}}"#,
+            "a".repeat(MAX_PACKET_TYPE_LENGTH)
+        );
+        assert!(NetworkPacket::parse(long_type.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_identities() {
+        let identity = |id: &str, name: &str, port: &str| {
+            format!(
+                r#"{{"id":1,"type":"kdeconnect.identity","body":{{"deviceId":{},"deviceName":{},"protocolVersion":7,"deviceType":"phone","incomingCapabilities":[],"outgoingCapabilities":[],"tcpPort":{}}}}}"#,
+                id, name, port
+            )
+        };
+        let cases = [
+            identity(r#""""#, r#""Phone""#, "1716"),
+            identity(r#""../../etc""#, r#""Phone""#, "1716"),
+            identity(&format!(r#""{}""#, "a".repeat(65)), r#""Phone""#, "1716"),
+            identity(r#""abc""#, r#""   ""#, "1716"),
+            identity(r#""abc""#, r#""Phone""#, "0"),
+            identity(r#""abc""#, r#""Phone""#, "70000"),
+            identity("1", r#""Phone""#, "1716"),
+            SAMPLES[1].to_string(),
+        ];
+        for case in &cases {
+            assert!(
+                NetworkPacket::parse_identity(case.as_bytes()).is_err(),
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn fuzz_parse_does_not_panic() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20_000 {
+            let sample = SAMPLES[rng.below(SAMPLES.len())].as_bytes();
+            let input = mutate(&mut rng, sample);
+
+            if let Ok(packet) = NetworkPacket::parse(&input) {
+                // Whatever we accept must survive a round trip.
+                let bytes = packet.to_vec().unwrap();
+                NetworkPacket::parse(&bytes).unwrap();
+            }
+            let _ = NetworkPacket::parse_identity(&input);
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..5_000 {
+            let len = rng.below(256);
+            let input = (0..len).map(|_| rng.next() as u8).collect::<Vec<_>>();
+            assert!(NetworkPacket::parse(&input).is_err());
         }
     }
+
+    #[test]
+    fn payloads_are_served_once_by_default() {
+        let packet = NetworkPacket::new("kdeconnect.ping", serde_json::json!({}));
+        let with_payload = NetworkPacketWithPayload::new(packet.clone(), Arc::new(vec![1]));
+        assert_eq!(with_payload.consumers().get(), 1);
+        assert_eq!(NetworkPacketWithPayload::from(packet).consumers().get(), 1);
+
+        let three = NonZeroUsize::new(3).unwrap();
+        assert_eq!(with_payload.with_consumers(three).consumers(), three);
+    }
+
+    #[tokio::test]
+    async fn read_identity_line_enforces_limit() {
+        let data = vec![b'a'; MAX_IDENTITY_SIZE + 10];
+        let err = read_identity_line(&mut &data[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut data = &b"{}\nrest"[..];
+        assert_eq!(read_identity_line(&mut data).await.unwrap(), b"{}");
+        assert_eq!(data, b"rest");
+    }
 }
@@ -3,13 +3,13 @@ use windows::{
     Foundation::TypedEventHandler, Media::Control::GlobalSystemMediaTransportControlsSessionManager,
 };
 
-use crate::event::EventSender;
+use crate::event::{EventBus, SystemEvent};
 
-pub fn start(tx: EventSender) -> Result<()> {
+pub fn start(bus: EventBus) -> Result<()> {
     let manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()?.get()?;
 
     manager.SessionsChanged(&TypedEventHandler::new(move |_, _| {
-        let _ = tx.blocking_send(crate::event::SystemEvent::MediaSessionsChanged);
+        bus.publish(SystemEvent::MediaSessionsChanged);
         Ok(())
     }))?;
 
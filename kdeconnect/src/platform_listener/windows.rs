@@ -1,5 +1,4 @@
 use anyhow::Result;
-use tao::event_loop::{EventLoop, EventLoopProxy};
 
 use windows::{
     core::{HSTRING, PCWSTR},
@@ -20,9 +19,12 @@ use windows::{
     },
 };
 
-use crate::CustomWindowEvent;
+use crate::{
+    event::{EventBus, SystemEvent},
+    utils::monitor,
+};
 
-/// Clipboard and power status listener on Windows.
+/// Clipboard, power status and display layout listener on Windows.
 pub struct WindowsListener {
     hwnd: HWND,
     handle_acdc: HPOWERNOTIFY,
@@ -30,7 +32,7 @@ pub struct WindowsListener {
 }
 
 impl WindowsListener {
-    pub fn new(event_loop: &EventLoop<CustomWindowEvent>) -> Result<Self> {
+    pub fn new(bus: EventBus) -> Result<Self> {
         unsafe {
             let wnd_class_name = HSTRING::from("kde_connect_rs_clipboard");
 
@@ -63,9 +65,7 @@ impl WindowsListener {
                 anyhow::bail!("CreateWindowExW failed");
             }
 
-            let subclass_data = Box::new(SubclassData {
-                proxy: event_loop.create_proxy(),
-            });
+            let subclass_data = Box::new(SubclassData { bus });
 
             SetWindowSubclass(
                 hwnd,
@@ -105,7 +105,7 @@ impl Drop for WindowsListener {
 }
 
 struct SubclassData {
-    proxy: EventLoopProxy<CustomWindowEvent>,
+    bus: EventBus,
 }
 
 unsafe extern "system" fn subclass_proc(
@@ -125,16 +125,17 @@ unsafe extern "system" fn subclass_proc(
 
     match msg {
         WM_CLIPBOARDUPDATE => {
-            subclass_data
-                .proxy
-                .send_event(CustomWindowEvent::ClipboardUpdated)
-                .ok();
+            subclass_data.bus.publish(SystemEvent::ClipboardUpdated);
+        }
+        WM_DISPLAYCHANGE => {
+            monitor::displays_changed();
         }
         WM_POWERBROADCAST => {
-            subclass_data
-                .proxy
-                .send_event(CustomWindowEvent::PowerStatusUpdated)
-                .ok();
+            if wparam.0 as u32 == PBT_APMRESUMEAUTOMATIC {
+                subclass_data.bus.publish(SystemEvent::SystemResumed);
+            } else {
+                subclass_data.bus.publish(SystemEvent::PowerStatusUpdated);
+            }
         }
         _ => {}
     }
@@ -0,0 +1,217 @@
+//! Battery levels reported by a device over time, kept on disk per device to show how fast it
+//! drains or charges.
+
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::hash::ContentHash;
+
+/// Samples older than this are dropped.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+const MAX_SAMPLES: usize = 1000;
+/// A report with the same level as the last one is only recorded after this long, devices may
+/// be asked for their battery every few minutes.
+const MIN_INTERVAL: Duration = Duration::from_secs(60 * 10);
+/// The period [`BatteryHistory::trend`] describes.
+const TREND_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// A trend over a shorter period would not say much.
+const MIN_TREND_SPAN: Duration = Duration::from_secs(60 * 10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+struct Sample {
+    /// Unix milliseconds.
+    time: u64,
+    charge: u8,
+    charging: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BatteryHistory {
+    /// Oldest first.
+    samples: VecDeque<Sample>,
+}
+
+impl BatteryHistory {
+    /// The history saved for a device, empty if there is none.
+    pub async fn load(device_id: &str) -> Self {
+        let path = path(device_id);
+        match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                log::warn!("Failed to read {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub async fn save(&self, device_id: &str) -> Result<()> {
+        let path = path(device_id);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+
+    /// Delete the history of a device that is forgotten.
+    pub async fn remove(device_id: &str) {
+        match tokio::fs::remove_file(path(device_id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to remove battery history of {}: {}", device_id, e),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Record a report, returns whether the history changed.
+    pub fn record(&mut self, now: u64, charge: u8, charging: bool) -> bool {
+        let sample = Sample {
+            time: now,
+            charge,
+            charging,
+        };
+        if let Some(last) = self.samples.back() {
+            let same = last.charge == charge && last.charging == charging;
+            if same && now.saturating_sub(last.time) < MIN_INTERVAL.as_millis() as u64 {
+                return false;
+            }
+        }
+
+        self.samples.push_back(sample);
+        self.prune(now);
+        true
+    }
+
+    fn prune(&mut self, now: u64) {
+        let max_age = MAX_AGE.as_millis() as u64;
+        while let Some(first) = self.samples.front() {
+            if now.saturating_sub(first.time) <= max_age && self.samples.len() <= MAX_SAMPLES {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// How the level changed over the last hour, e.g. "dropped 12% in the last hour", as long as
+    /// the device has been charging, or not, all along.
+    pub fn trend(&self, now: u64) -> Option<String> {
+        let latest = self.samples.back()?;
+        let window_start = now.saturating_sub(TREND_WINDOW.as_millis() as u64);
+
+        // The oldest sample in the window, or the one before it, which gives the level at the
+        // start of the window.
+        let mut baseline = latest;
+        for sample in self.samples.iter().rev() {
+            if sample.charging != latest.charging {
+                break;
+            }
+            baseline = sample;
+            if sample.time <= window_start {
+                break;
+            }
+        }
+
+        let span = now.saturating_sub(baseline.time.max(window_start));
+        if span < MIN_TREND_SPAN.as_millis() as u64 {
+            return None;
+        }
+
+        let change = latest.charge as i16 - baseline.charge as i16;
+        let verb = match change {
+            0 => return None,
+            c if c < 0 => "dropped",
+            _ => "charged",
+        };
+        let period = if baseline.time <= window_start {
+            "the last hour".to_string()
+        } else {
+            format!("the last {} minutes", span / 60_000)
+        };
+        Some(format!("{} {}% in {}", verb, change.abs(), period))
+    }
+}
+
+fn path(device_id: &str) -> PathBuf {
+    // Device ids come from the network, they are not used as file names as is.
+    crate::data_dir()
+        .join("battery-history")
+        .join(format!("{}.json", ContentHash::sha256(device_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000;
+
+    #[test]
+    fn repeated_reports_are_recorded_sparingly() {
+        let mut history = BatteryHistory::default();
+
+        assert!(history.record(0, 80, false));
+        assert!(!history.record(MINUTE, 80, false));
+        assert!(history.record(2 * MINUTE, 79, false));
+        assert!(history.record(3 * MINUTE, 79, true));
+        assert!(history.record(14 * MINUTE, 79, true));
+        assert_eq!(history.len(), 4);
+    }
+
+    #[test]
+    fn old_samples_are_pruned() {
+        let mut history = BatteryHistory::default();
+        let day = 24 * 60 * MINUTE;
+
+        history.record(0, 90, false);
+        history.record(day, 80, false);
+        history.record(8 * day, 70, false);
+        assert_eq!(history.len(), 2);
+
+        for i in 0..MAX_SAMPLES as u64 + 10 {
+            history.record(8 * day + i * MINUTE, (i % 100) as u8, false);
+        }
+        assert_eq!(history.len(), MAX_SAMPLES);
+    }
+
+    #[test]
+    fn trend_over_the_last_hour() {
+        let mut history = BatteryHistory::default();
+        history.record(0, 90, false);
+        history.record(30 * MINUTE, 85, false);
+        history.record(90 * MINUTE, 73, false);
+
+        // The level an hour ago is the one reported at 30 minutes.
+        assert_eq!(
+            history.trend(95 * MINUTE).as_deref(),
+            Some("dropped 12% in the last hour")
+        );
+    }
+
+    #[test]
+    fn trend_since_charging_changed() {
+        let mut history = BatteryHistory::default();
+        history.record(0, 90, false);
+        history.record(30 * MINUTE, 50, true);
+        history.record(60 * MINUTE, 58, true);
+
+        assert_eq!(
+            history.trend(70 * MINUTE).as_deref(),
+            Some("charged 8% in the last 40 minutes")
+        );
+        // Too short to tell.
+        history.record(65 * MINUTE, 57, false);
+        assert_eq!(history.trend(70 * MINUTE), None);
+    }
+}
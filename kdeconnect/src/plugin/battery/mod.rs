@@ -23,20 +23,33 @@ to ask the peer device to send a package like the mentioned above, and should
 also answer this same kind of packages with its own information.
 
 If the battery is low and discharging, it will notify the user.
+
+The levels reported by the device are kept for a week, to show how fast the battery drains or
+charges in the tray tooltip.
  */
-use std::{mem::MaybeUninit, sync::Arc};
+use std::{mem::MaybeUninit, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuItemAttributes};
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, task::JoinHandle};
 use windows::Win32::System::Power::GetSystemPowerStatus;
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent, packet::NetworkPacket,
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::DeviceMenu,
+    utils,
+};
+
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
 };
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+mod history;
+pub use history::BatteryHistory;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -48,11 +61,21 @@ struct BatteryReport {
     threshold_event: u8,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatteryRequest {
+    #[serde(default)]
+    request: bool,
+}
+
 #[derive(Debug)]
 pub struct BatteryPlugin {
     ctx: AppContextRef,
     battery_status: Mutex<Option<BatteryReport>>,
+    /// Loaded in `start`.
+    history: Mutex<BatteryHistory>,
     device: DeviceHandle,
+    refresh_task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl BatteryPlugin {
@@ -60,10 +83,22 @@ impl BatteryPlugin {
         Self {
             ctx,
             battery_status: Mutex::new(None),
+            history: Mutex::new(BatteryHistory::default()),
             device: dev,
+            refresh_task: Mutex::new(None),
         }
     }
 
+    /// Ask the device to send its battery status.
+    async fn request_battery_status(&self) {
+        self.device
+            .send_packet(NetworkPacket::new(
+                PacketKind::BatteryRequest,
+                BatteryRequest { request: true },
+            ))
+            .await;
+    }
+
     pub async fn send_battery_status(&self) -> Result<()> {
         let power_status = unsafe {
             let mut power_status = MaybeUninit::uninit();
@@ -86,34 +121,82 @@ impl BatteryPlugin {
         };
 
         self.device
-            .send_packet(NetworkPacket::new(
-                "kdeconnect.battery",
-                battery_status.clone(),
-            ))
+            .send_packet(NetworkPacket::new(PacketKind::Battery, battery_status))
             .await;
 
         Ok(())
     }
+
+    async fn receive_report(&self, report: BatteryReport) -> Result<()> {
+        {
+            let mut history = self.history.lock().await;
+            if history.record(
+                utils::unix_ts_ms(),
+                report.current_charge,
+                report.is_charging,
+            ) {
+                utils::log_if_error(
+                    "Failed to save battery history",
+                    history.save(self.device.device_id()).await,
+                );
+            }
+        }
+
+        *self.battery_status.lock().await = Some(report);
+        self.ctx.update_tray().await;
+        Ok(())
+    }
+
+    async fn receive_request(&self, _request: BatteryRequest) -> Result<()> {
+        self.send_battery_status().await
+    }
+}
+
+plugin_packets! {
+    BatteryPlugin {
+        incoming {
+            Battery(BatteryReport) => receive_report,
+            BatteryRequest(BatteryRequest) => receive_request,
+        }
+        outgoing [Battery, BatteryRequest]
+        events [Power]
+    }
 }
 
 #[async_trait::async_trait]
 impl KdeConnectPlugin for BatteryPlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            "kdeconnect.battery" => {
-                let report: BatteryReport = packet.into_body()?;
-                *self.battery_status.lock().await = Some(report);
-                self.ctx.update_tray().await;
-            }
-            "kdeconnect.battery.request" => {
-                self.send_battery_status().await?;
+    async fn start(self: Arc<Self>) -> Result<()> {
+        *self.history.lock().await = BatteryHistory::load(self.device.device_id()).await;
+
+        let this = Arc::downgrade(&self);
+
+        // The device only reports changes, so the tray would be empty until the first one.
+        let task = tokio::spawn(async move {
+            while let Some(this) = this.upgrade() {
+                this.request_battery_status().await;
+
+                let settings = this.ctx.settings().device(this.device.device_id());
+                drop(this);
+
+                let minutes = settings.plugins.battery.refresh_interval;
+                if minutes == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
             }
-            _ => {}
-        }
+        });
+        *self.refresh_task.lock().await = Some(task);
+
         Ok(())
     }
 
-    async fn tray_menu(&self, menu: &mut ContextMenu) {
+    async fn stop(&self) {
+        if let Some(task) = self.refresh_task.lock().await.take() {
+            task.abort();
+        }
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
         let status = self.battery_status.lock().await;
         if let Some(x) = status.as_ref() {
             let text = format!(
@@ -121,7 +204,9 @@ impl KdeConnectPlugin for BatteryPlugin {
                 x.current_charge,
                 if x.is_charging { "+" } else { "" }
             );
-            menu.add_item(MenuItemAttributes::new(&text).with_enabled(false));
+            menu.status.add_label(text);
+            menu.battery = Some(x.current_charge);
+            menu.battery_trend = self.history.lock().await.trend(utils::unix_ts_ms());
         }
     }
 
@@ -135,18 +220,3 @@ impl KdeConnectPlugin for BatteryPlugin {
         Ok(())
     }
 }
-
-impl KdeConnectPluginMetadata for BatteryPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![
-            "kdeconnect.battery".into(),
-            "kdeconnect.battery.request".into(),
-        ]
-    }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![
-            "kdeconnect.battery".into(),
-            "kdeconnect.battery.request".into(),
-        ]
-    }
-}
@@ -8,28 +8,55 @@ have the same content.
 
 This plugin is symmetric to its counterpart in the other device: both have the
 same behaviour.
+
+By default, changes are only sent from the tray menu (see `ClipboardSettings::manual_sync`),
+where the last few text entries can be copied or sent again. Sending every change is opt-in.
+
+Content that password managers mark as excluded from clipboard history is never sent.
+Large text is shared as a file with a payload instead, see `ClipboardSettings::inline_limit`.
+
+When a device connects, both sides send a package with type kdeconnect.clipboard.connect with
+the fields "content" (string) and "timestamp" (int), the Unix time in milliseconds at which the
+clipboard last changed, or 0 if it is not known. The newer clipboard is kept on both sides.
  */
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use tao::menu::MenuId;
 use tokio::sync::Mutex;
 
 use crate::{
+    config::ClipboardSettings,
+    context::{AppContextRef, TrayUpdater},
     device::DeviceHandle,
     event::SystemEvent,
     packet::NetworkPacket,
+    transfer::{self, Kind},
+    tray::{DeviceMenu, TrayMenu},
     utils::{self, clipboard::ClipboardContent},
 };
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    share, KdeConnectPlugin,
+};
 
-const PACKET_TYPE_CLIPBOARD: &str = "kdeconnect.clipboard";
-const PACKET_TYPE_CLIPBOARD_CONNECT: &str = "kdeconnect.clipboard.connect";
+/// Longest label of a history entry in the tray menu, in characters.
+const HISTORY_LABEL_LEN: usize = 40;
+/// Name of the file that large text is shared as.
+const LARGE_TEXT_FILENAME: &str = "clipboard.txt";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CurrentClipboardContent {
     content: ClipboardContent,
+    /// When the clipboard changed to this content, 0 if unknown.
     ts: u64,
 }
 
@@ -42,100 +69,379 @@ impl CurrentClipboardContent {
     }
 }
 
+lazy_static::lazy_static! {
+    /// The clipboard last read or written for any device, so that the time it changed is still
+    /// known when a device connects again.
+    static ref LAST_CONTENT: std::sync::Mutex<Option<CurrentClipboardContent>> = Default::default();
+}
+
+fn remember(content: &CurrentClipboardContent) {
+    *LAST_CONTENT.lock().unwrap() = Some(content.clone());
+}
+
+#[derive(Debug)]
+struct HistoryEntry {
+    text: String,
+    copy_menu_id: MenuId,
+    send_menu_id: MenuId,
+}
+
+impl HistoryEntry {
+    /// The first line of the text, shortened to fit in the menu.
+    fn label(&self) -> String {
+        let line = self.text.trim().lines().next().unwrap_or_default();
+        if line.chars().count() > HISTORY_LABEL_LEN {
+            let short: String = line.chars().take(HISTORY_LABEL_LEN - 1).collect();
+            format!("{}…", short)
+        } else {
+            line.to_string()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct ClipboardPacket {
     content: String,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ClipboardConnectPacket {
+    content: String,
+    #[serde(default)]
+    timestamp: u64,
+}
+
+/// Puts `content` on the clipboard of the device.
+pub fn clipboard_packet(content: String) -> NetworkPacket {
+    NetworkPacket::new(PacketKind::Clipboard, ClipboardPacket { content })
+}
+
 #[derive(Debug)]
 pub struct ClipboardPlugin {
     content: Mutex<Option<CurrentClipboardContent>>,
+    /// Most recent first.
+    history: Mutex<VecDeque<HistoryEntry>>,
+    next_entry_id: AtomicUsize,
     device: DeviceHandle,
+    ctx: AppContextRef,
+    send_menu_id: MenuId,
+    manual_sync_menu_id: MenuId,
 }
 
 impl ClipboardPlugin {
-    pub fn new(dev: DeviceHandle) -> Self {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
         Self {
             content: Mutex::new(None),
+            history: Mutex::new(VecDeque::new()),
+            next_entry_id: AtomicUsize::new(0),
+            send_menu_id: MenuId::new(&format!("{}:clipboard:send", dev.device_id())),
+            manual_sync_menu_id: MenuId::new(&format!("{}:clipboard:manual_sync", dev.device_id())),
             device: dev,
+            ctx,
         }
     }
 
-    async fn read_clipboard(&self) -> Result<()> {
-        let content = tokio::task::spawn_blocking(utils::clipboard::read).await??;
+    fn settings(&self) -> ClipboardSettings {
+        self.ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .clipboard
+    }
+
+    fn toggle_manual_sync(&self) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.device.device_id(), |settings| {
+                let settings = &mut settings.plugins.clipboard;
+                settings.manual_sync = !settings.manual_sync;
+            })
+    }
+
+    /// Read the clipboard, returns whether it changed since the last read or write.
+    async fn read_clipboard(&self) -> Result<bool> {
+        let content = utils::clipboard::read_async().await?;
 
         let mut c = self.content.lock().await;
-        *c = Some(CurrentClipboardContent::new_now(content));
+        let changed = c.as_ref().map(|c| &c.content) != Some(&content);
+        if changed {
+            let current = CurrentClipboardContent::new_now(content);
+            remember(&current);
+            *c = Some(current);
+        }
 
+        Ok(changed)
+    }
+
+    /// Read the clipboard when the device connects. The time it changed is only known if it
+    /// has not changed since it was last seen for any device.
+    async fn read_initial_clipboard(&self) -> Result<()> {
+        let content = utils::clipboard::read_async().await?;
+
+        let last = LAST_CONTENT.lock().unwrap().clone();
+        let current = match last {
+            Some(last) if last.content == content => last,
+            _ => CurrentClipboardContent { content, ts: 0 },
+        };
+        *self.content.lock().await = Some(current);
         Ok(())
     }
 
-    async fn write_clipboard(&self, text: impl Into<String>) -> Result<()> {
+    /// Write text to the clipboard, which changed at `ts`.
+    async fn write_clipboard(&self, text: impl Into<String>, ts: u64) -> Result<()> {
         let text = text.into();
 
-        tokio::task::spawn_blocking(move || utils::clipboard::write(ClipboardContent::Text(text)))
-            .await??;
+        // Remembered first, so that the change notification is not sent back.
+        let current = CurrentClipboardContent {
+            content: ClipboardContent::Text(text.clone()),
+            ts,
+        };
+        remember(&current);
+        *self.content.lock().await = Some(current);
+        utils::clipboard::write_text_async(text).await
+    }
+
+    /// Add text to the front of the history, returns whether the history is enabled.
+    async fn add_to_history(&self, text: &str) -> bool {
+        let size = self.settings().history_size;
+        let mut history = self.history.lock().await;
+
+        history.retain(|e| e.text != text);
+        if size > 0 && !text.trim().is_empty() {
+            let id = self.next_entry_id.fetch_add(1, Ordering::Relaxed);
+            let prefix = format!("{}:clipboard:history:{}", self.device.device_id(), id);
+            history.push_front(HistoryEntry {
+                text: text.to_string(),
+                copy_menu_id: MenuId::new(&format!("{}:copy", prefix)),
+                send_menu_id: MenuId::new(&format!("{}:send", prefix)),
+            });
+        }
+        history.truncate(size);
+
+        size > 0
+    }
 
+    /// Copy or send a history entry, if one of its items was clicked.
+    async fn handle_history_click(&self, menu_id: MenuId) -> Result<()> {
+        let clicked = {
+            let history = self.history.lock().await;
+            history.iter().find_map(|e| {
+                if e.copy_menu_id == menu_id {
+                    Some((e.text.clone(), false))
+                } else if e.send_menu_id == menu_id {
+                    Some((e.text.clone(), true))
+                } else {
+                    None
+                }
+            })
+        };
+
+        match clicked {
+            Some((text, true)) => {
+                self.send_text(text).await;
+            }
+            Some((text, false)) => {
+                self.write_clipboard(text, utils::unix_ts_ms())
+                    .await
+                    .context("Write clipboard")?;
+            }
+            None => {}
+        }
         Ok(())
     }
 
+    /// Send text to the device, as a file if it is too large for a packet.
+    async fn send_text(&self, text: String) {
+        let settings = self.settings();
+
+        if text.len() > settings.max_size {
+            log::warn!(
+                "Not sending {} bytes of clipboard text to {}, the limit is {}",
+                text.len(),
+                self.device.device_name(),
+                settings.max_size
+            );
+        } else if text.len() > settings.inline_limit {
+            log::info!(
+                "Sharing {} bytes of clipboard text with {} as a file",
+                text.len(),
+                self.device.device_name()
+            );
+            transfer::send(
+                &self.ctx,
+                &self.device,
+                Kind::File,
+                LARGE_TEXT_FILENAME,
+                share::file_packet(LARGE_TEXT_FILENAME.into(), text.into_bytes()),
+            )
+            .await;
+        } else {
+            self.device.send_packet(clipboard_packet(text)).await;
+        }
+    }
+
     async fn send_clipboard(&self) {
         let content = self.content.lock().await;
         if let Some(content) = content.as_ref() {
             match &content.content {
                 ClipboardContent::Text(s) => {
-                    let packet = NetworkPacket::new(
-                        PACKET_TYPE_CLIPBOARD,
-                        ClipboardPacket { content: s.clone() },
-                    );
-                    self.device.send_packet(packet).await;
+                    self.send_text(s.clone()).await;
                 }
                 ClipboardContent::Files(_) => {}
+                ClipboardContent::Sensitive => {
+                    log::info!(
+                        "Not sending sensitive clipboard content to {}",
+                        self.device.device_name()
+                    );
+                    if self.settings().notify_sensitive {
+                        utils::simple_toast(
+                            "Clipboard not sent",
+                            Some("The copied content is marked as sensitive."),
+                            None,
+                        )
+                        .await;
+                    }
+                }
                 ClipboardContent::Unsupported => {}
             }
         }
     }
+
+    async fn receive_clipboard(&self, body: ClipboardPacket) -> Result<()> {
+        if self.add_to_history(&body.content).await {
+            self.ctx.update_tray().await;
+        }
+        self.write_clipboard(body.content, utils::unix_ts_ms())
+            .await
+            .context("Write clipboard")
+    }
+
+    /// Send our clipboard with the time it changed, unless changes are only sent on request.
+    async fn send_connect(&self) {
+        let settings = self.settings();
+        if settings.manual_sync {
+            return;
+        }
+
+        let packet = match self.content.lock().await.as_ref() {
+            Some(CurrentClipboardContent {
+                content: ClipboardContent::Text(text),
+                ts,
+            }) if text.len() <= settings.inline_limit => ClipboardConnectPacket {
+                content: text.clone(),
+                timestamp: *ts,
+            },
+            _ => return,
+        };
+        self.device
+            .send_packet(NetworkPacket::new(PacketKind::ClipboardConnect, packet))
+            .await;
+    }
+
+    /// The clipboard of the device when it connects, which replaces ours if it is newer.
+    async fn receive_connect(&self, body: ClipboardConnectPacket) -> Result<()> {
+        let local_ts = match self.content.lock().await.as_ref() {
+            Some(c) if c.content == ClipboardContent::Text(body.content.clone()) => return Ok(()),
+            Some(c) => c.ts,
+            None => 0,
+        };
+        if body.timestamp == 0 || body.timestamp <= local_ts {
+            log::debug!(
+                "Keeping our clipboard, the one of {} is not newer",
+                self.device.device_name()
+            );
+            return Ok(());
+        }
+
+        if self.add_to_history(&body.content).await {
+            self.ctx.update_tray().await;
+        }
+        self.write_clipboard(body.content, body.timestamp)
+            .await
+            .context("Write clipboard")
+    }
+}
+
+plugin_packets! {
+    ClipboardPlugin {
+        incoming {
+            Clipboard(ClipboardPacket) => receive_clipboard,
+            ClipboardConnect(ClipboardConnectPacket) => receive_connect,
+        }
+        outgoing [Clipboard, ClipboardConnect]
+        events [Clipboard, Tray]
+    }
 }
 
 #[async_trait::async_trait]
 impl KdeConnectPlugin for ClipboardPlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            PACKET_TYPE_CLIPBOARD => {
-                let body: ClipboardPacket = packet.into_body()?;
-                self.write_clipboard(body.content)
-                    .await
-                    .context("Write clipboard")?;
-            }
-            PACKET_TYPE_CLIPBOARD_CONNECT => {}
-            _ => {}
-        }
+    async fn start(self: Arc<Self>) -> Result<()> {
+        self.read_initial_clipboard()
+            .await
+            .context("Read clipboard")?;
+        self.send_connect().await;
         Ok(())
     }
 
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        let settings = self.settings();
+
+        if settings.manual_sync {
+            menu.actions.add_action(self.send_menu_id, "Send clipboard");
+        }
+
+        let history = self.history.lock().await;
+        let mut submenu = TrayMenu::new();
+        for entry in history.iter().take(settings.history_size) {
+            let mut entry_menu = TrayMenu::new();
+            entry_menu.add_action(entry.copy_menu_id, "Copy");
+            entry_menu.add_action(entry.send_menu_id, "Send to device");
+            submenu.add_submenu(entry.label(), entry_menu);
+        }
+        // Hidden when the history is empty or disabled
+        menu.actions.add_submenu("Clipboard History", submenu);
+
+        menu.settings.add_toggle(
+            self.manual_sync_menu_id,
+            "Only send clipboard on request",
+            settings.manual_sync,
+        );
+    }
+
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         match event {
             SystemEvent::ClipboardUpdated => {
-                self.read_clipboard().await.context("Read clipboard")?;
-                // self.send_clipboard().await;
+                if !self.read_clipboard().await.context("Read clipboard")? {
+                    return Ok(());
+                }
+
+                let text = match self.content.lock().await.as_ref().map(|c| &c.content) {
+                    Some(ClipboardContent::Text(text)) => Some(text.clone()),
+                    _ => None,
+                };
+                if let Some(text) = text {
+                    if self.add_to_history(&text).await {
+                        self.ctx.update_tray().await;
+                    }
+                }
+
+                if !self.settings().manual_sync {
+                    self.send_clipboard().await;
+                }
+            }
+            SystemEvent::TrayMenuClicked(menu_id) => {
+                if menu_id == self.send_menu_id {
+                    self.send_clipboard().await;
+                } else if menu_id == self.manual_sync_menu_id {
+                    self.toggle_manual_sync()
+                        .context("Save clipboard settings")?;
+                    self.ctx.update_tray().await;
+                } else {
+                    self.handle_history_click(menu_id).await?;
+                }
             }
             _ => {}
         }
         Ok(())
     }
 }
-
-impl KdeConnectPluginMetadata for ClipboardPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_CLIPBOARD.into(),
-            PACKET_TYPE_CLIPBOARD_CONNECT.into(),
-        ]
-    }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_CLIPBOARD.into(),
-            PACKET_TYPE_CLIPBOARD_CONNECT.into(),
-        ]
-    }
-}
@@ -9,15 +9,35 @@ signalStrengths (object<string, object>): Maps each SIM (subscription ID) to the
 
 It also sends empty packages with type kdeconnect.connectivity_report.request
 to ask the peer device to send a package like the mentioned above.
+
+The report is shown next to the connection status in the tray. Devices only send it when the
+signal changes, so it is requested again when the tray menu is opened.
  */
-use std::collections::HashMap;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::DeviceMenu,
+};
 
-use crate::packet::NetworkPacket;
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+/// The report is requested at most this often when the tray menu is opened.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -29,38 +49,102 @@ struct SignalStrength {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ConnectivityReport {
-    signal_strengths: HashMap<String, SignalStrength>,
+    /// Sorted by subscription, so that SIMs are always shown in the same order.
+    signal_strengths: BTreeMap<String, SignalStrength>,
+}
+
+impl ConnectivityReport {
+    /// E.g. "LTE 3/4", or "5G 4/4, LTE 1/4" with two SIMs.
+    fn summary(&self) -> String {
+        if self.signal_strengths.is_empty() {
+            return "No cellular network".to_string();
+        }
+
+        self.signal_strengths
+            .values()
+            .map(|s| format!("{} {}/4", s.network_type, s.signal_strength.min(4)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
 #[derive(Debug)]
-pub struct ConnectivityReportPlugin;
+pub struct ConnectivityReportPlugin {
+    ctx: AppContextRef,
+    device: DeviceHandle,
+    report: Mutex<Option<ConnectivityReport>>,
+    last_request: Mutex<Option<Instant>>,
+}
 
-#[async_trait::async_trait]
-impl KdeConnectPlugin for ConnectivityReportPlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            "kdeconnect.connectivity_report" => {
-                let strengths: ConnectivityReport = packet.into_body()?;
-                log::info!("Connectivity report: {:?}", strengths);
-            }
-            "kdeconnect.connectivity_report.request" => {
-                // ignore
+impl ConnectivityReportPlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self {
+            ctx,
+            device: dev,
+            report: Mutex::new(None),
+            last_request: Mutex::new(None),
+        }
+    }
+
+    /// Ask the device for its report, unless that was done recently.
+    async fn request_report(&self) {
+        {
+            let mut last_request = self.last_request.lock().await;
+            if last_request.is_some_and(|t| t.elapsed() < REQUEST_INTERVAL) {
+                return;
             }
-            _ => {}
+            *last_request = Some(Instant::now());
         }
 
+        self.device
+            .send_packet(NetworkPacket::new(
+                PacketKind::ConnectivityReportRequest,
+                serde_json::json!({}),
+            ))
+            .await;
+    }
+
+    async fn receive_report(&self, report: ConnectivityReport) -> Result<()> {
+        log::debug!("Connectivity report: {:?}", report);
+        *self.report.lock().await = Some(report);
+        self.ctx.update_tray().await;
         Ok(())
     }
+
+    async fn receive_request(&self, _body: IgnoredAny) -> Result<()> {
+        // There is no cellular network to report on a computer.
+        Ok(())
+    }
+}
+
+plugin_packets! {
+    ConnectivityReportPlugin {
+        incoming {
+            ConnectivityReport(ConnectivityReport) => receive_report,
+            ConnectivityReportRequest(IgnoredAny) => receive_request,
+        }
+        outgoing [ConnectivityReportRequest]
+        events [Tray]
+    }
 }
 
-impl KdeConnectPluginMetadata for ConnectivityReportPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![
-            "kdeconnect.connectivity_report".into(),
-            "kdeconnect.connectivity_report.request".into(),
-        ]
+#[async_trait::async_trait]
+impl KdeConnectPlugin for ConnectivityReportPlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        self.request_report().await;
+        Ok(())
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        if let Some(report) = self.report.lock().await.as_ref() {
+            menu.network = Some(report.summary());
+        }
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec!["kdeconnect.connectivity_report.request".into()]
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event == SystemEvent::TrayMenuOpened {
+            self.request_report().await;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,140 @@
+//! This plugin keeps the last packets exchanged with the device, for debugging. It is disabled
+//! by default and handles no packets itself: the connection records them in the
+//! [`PacketLog`](crate::device::packet_log::PacketLog) of the device, which this plugin
+//! configures from the settings.
+//!
+//! The records can be saved to a file from the tray. The `devicePacketLog` method of the local
+//! API returns them too, but without their bodies.
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use tao::menu::MenuId;
+
+use crate::{
+    config::EventLogSettings,
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    tray::DeviceMenu,
+    utils,
+};
+
+use super::{packets::plugin_packets, KdeConnectPlugin};
+
+#[derive(Debug)]
+pub struct EventLogPlugin {
+    dev: DeviceHandle,
+    ctx: AppContextRef,
+    enable_menu_id: MenuId,
+    save_menu_id: MenuId,
+}
+
+impl EventLogPlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self {
+            enable_menu_id: MenuId::new(&format!("{}:event_log:enable", dev.device_id())),
+            save_menu_id: MenuId::new(&format!("{}:event_log:save", dev.device_id())),
+            dev,
+            ctx,
+        }
+    }
+
+    fn settings(&self) -> EventLogSettings {
+        self.ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .event_log
+    }
+
+    fn toggle_enabled(&self) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.dev.device_id(), |settings| {
+                let settings = &mut settings.plugins.event_log;
+                settings.enabled = !settings.enabled;
+            })
+    }
+
+    fn apply_settings(&self) {
+        let settings = self.settings();
+        let capacity = if settings.enabled {
+            settings.capacity
+        } else {
+            0
+        };
+        self.dev.packet_log().configure(capacity, settings.verbose);
+    }
+
+    /// Write the records to a new file in the data directory.
+    async fn save(&self) -> Result<PathBuf> {
+        let records = self.dev.packet_log().records();
+        let content = serde_json::to_vec_pretty(&records)?;
+
+        let dir = crate::data_dir().join("packet-logs");
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("Create directory")?;
+        let path = dir.join(format!(
+            "{}-{}.json",
+            self.dev.device_id(),
+            utils::unix_ts_ms()
+        ));
+        tokio::fs::write(&path, content)
+            .await
+            .context("Write file")?;
+        Ok(path)
+    }
+}
+
+plugin_packets! {
+    EventLogPlugin {
+        incoming {}
+        outgoing []
+        events [Tray]
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for EventLogPlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        self.apply_settings();
+        Ok(())
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        let enabled = self.settings().enabled;
+        menu.settings
+            .add_toggle(self.enable_menu_id, "Record packets", enabled);
+        if enabled {
+            menu.settings
+                .add_action(self.save_menu_id, "Save packet log");
+        }
+    }
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.enable_menu_id) {
+            self.toggle_enabled().context("Save event log settings")?;
+            self.apply_settings();
+            self.ctx.update_tray().await;
+        } else if event.is_menu_clicked(self.save_menu_id) {
+            match self.save().await {
+                Ok(path) => utils::log_if_error(
+                    "Failed to open folder",
+                    utils::open::show_in_folder(path).await,
+                ),
+                Err(e) => {
+                    log::error!("Failed to save packet log: {:?}", e);
+                    utils::simple_toast(
+                        "Failed to save packet log",
+                        Some(&format!("{:#}", e)),
+                        Some(&self.dev.device_name()),
+                    )
+                    .await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
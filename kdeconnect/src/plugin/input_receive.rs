@@ -1,16 +1,33 @@
+use std::sync::Mutex;
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::packet::NetworkPacket;
-
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use crate::{
+    context::AppContextRef,
+    device::DeviceHandle,
+    packet::NetworkPacket,
+    service::{self, ServiceMessage},
+    utils::monitor,
+};
 
-use windows::Win32::UI::Input::KeyboardAndMouse;
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
-const PACKET_TYPE_MOUSEPAD_REQUEST: &str = "kdeconnect.mousepad.request";
+use windows::Win32::{
+    Foundation::{POINT, RECT},
+    UI::{Input::KeyboardAndMouse, WindowsAndMessaging::GetCursorPos},
+};
 
 #[derive(Debug)]
-pub struct InputReceivePlugin;
+pub struct InputReceivePlugin {
+    dev: DeviceHandle,
+    ctx: AppContextRef,
+    /// The display setting of the device, and the revision of the settings it was read from.
+    monitor: Mutex<Option<(u64, Option<u32>)>>,
+}
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
 #[serde(untagged)]
@@ -19,6 +36,30 @@ enum MouseDelta {
     Float(f32),
 }
 
+impl MouseDelta {
+    /// The value as a fraction, for positions. Not a number counts as 0.
+    fn value(self) -> f64 {
+        match self {
+            MouseDelta::Int(v) => v as f64,
+            MouseDelta::Float(v) if v.is_nan() => 0.0,
+            MouseDelta::Float(v) => v as f64,
+        }
+    }
+
+    /// The value in pixels, for movements. Fractions are rounded, and values out of range are
+    /// clamped to [`MAX_MOVE`].
+    fn pixels(self) -> i32 {
+        match self {
+            MouseDelta::Int(v) => v.clamp(-MAX_MOVE, MAX_MOVE),
+            MouseDelta::Float(v) if v.is_nan() => 0,
+            MouseDelta::Float(v) => (v.round() as i32).clamp(-MAX_MOVE, MAX_MOVE),
+        }
+    }
+}
+
+/// Largest movement of the pointer in one request, in pixels, more than any display is wide.
+const MAX_MOVE: i32 = 1 << 16;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct MousePadRequestPacket {
@@ -46,117 +87,670 @@ struct MousePadRequestPacket {
 
     dx: Option<MouseDelta>,
     dy: Option<MouseDelta>,
+    /// Not part of KDE Connect: `dx` and `dy` are a position from 0 to 1 in the display the
+    /// pointer is kept on, or in all displays, instead of a movement.
+    #[serde(default)]
+    absolute: bool,
 
     special_key: Option<u32>,
     key: Option<String>,
+    /// Whether the device wants an echo once the key has been typed.
+    #[serde(default)]
+    send_ack: bool,
 }
 
-impl InputReceivePlugin {}
-
-#[async_trait::async_trait]
-impl KdeConnectPlugin for InputReceivePlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            PACKET_TYPE_MOUSEPAD_REQUEST => {
-                let request: MousePadRequestPacket = packet.into_body()?;
-
-                let mut inputs = vec![];
-
-                if let (Some(MouseDelta::Int(dx)), Some(MouseDelta::Int(dy)), false) =
-                    (request.dx, request.dy, request.scroll)
-                {
-                    // Short path for smooth mouse movement, we should never have other fields set in this case.
-                    let mouse_input = KeyboardAndMouse::MOUSEINPUT {
-                        dx,
-                        dy,
-                        dwFlags: KeyboardAndMouse::MOUSEEVENTF_MOVE,
-                        ..Default::default()
-                    };
-                    unsafe {
-                        KeyboardAndMouse::SendInput(
-                            &[KeyboardAndMouse::INPUT {
-                                r#type: KeyboardAndMouse::INPUT_MOUSE,
-                                Anonymous: KeyboardAndMouse::INPUT_0 { mi: mouse_input },
-                            }],
-                            std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
-                        );
-                    }
-                    return Ok(());
-                }
+impl MousePadRequestPacket {
+    fn is_key_event(&self) -> bool {
+        self.special_key.is_some() || !self.key.as_deref().unwrap_or_default().is_empty()
+    }
+}
 
-                log::info!("Mousepad request: {:?}", request);
+/// Echo of a key event, sent back when it asked for it with "sendAck".
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MousePadEchoPacket {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    special_key: Option<u32>,
+    alt: bool,
+    ctrl: bool,
+    shift: bool,
+    #[serde(rename = "super")]
+    xuper: bool,
+    /// Tells the device that this acknowledges its own key event, rather than being a key
+    /// event to type.
+    is_ack: bool,
+}
 
-                let mut mouse_click_down = KeyboardAndMouse::MOUSE_EVENT_FLAGS::default();
-                let mut mouse_click_up = KeyboardAndMouse::MOUSE_EVENT_FLAGS::default();
-                if request.singleclick {
-                    mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_LEFTDOWN;
-                    mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_LEFTUP;
-                }
-                if request.rightclick {
-                    mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_RIGHTDOWN;
-                    mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_RIGHTUP;
-                }
-                if request.middleclick {
-                    mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_MIDDLEDOWN;
-                    mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_MIDDLEUP;
-                }
-                if mouse_click_down != KeyboardAndMouse::MOUSE_EVENT_FLAGS::default() {
-                    let down = KeyboardAndMouse::INPUT {
-                        r#type: KeyboardAndMouse::INPUT_MOUSE,
-                        Anonymous: KeyboardAndMouse::INPUT_0 {
-                            mi: KeyboardAndMouse::MOUSEINPUT {
-                                dwFlags: mouse_click_down,
-                                ..Default::default()
-                            },
-                        },
-                    };
-                    let mut up = down;
-                    up.Anonymous.mi.dwFlags = mouse_click_up;
-                    inputs.push(down);
-                    inputs.push(up);
-                }
+impl MousePadEchoPacket {
+    fn ack(request: &MousePadRequestPacket) -> Self {
+        Self {
+            key: request.key.clone(),
+            special_key: request.special_key,
+            alt: request.alt,
+            ctrl: request.ctrl,
+            shift: request.shift,
+            xuper: request.xuper,
+            is_ack: true,
+        }
+    }
+}
+
+/// Send a mousepad request, forwarded by the service, to the desktop of the current session.
+pub fn inject(packet: NetworkPacket, monitor: Option<u32>) -> Result<()> {
+    inject_request(packet.into_body()?, monitor)
+}
+
+fn inject_request(request: MousePadRequestPacket, monitor: Option<u32>) -> Result<()> {
+    let mut inputs = vec![];
+
+    if let (Some(dx), Some(dy), true) = (request.dx, request.dy, request.absolute) {
+        let (x, y) = absolute_position(pointer_area(monitor), dx.value(), dy.value());
+        move_pointer_to(x, y);
+        return Ok(());
+    }
+
+    if let (Some(dx), Some(dy), false, Some(_)) = (request.dx, request.dy, request.scroll, monitor)
+    {
+        // Moving relative to the cursor would let it leave the display.
+        let area = pointer_area(monitor);
+        let mut cursor = POINT::default();
+        unsafe { GetCursorPos(&mut cursor) }.ok()?;
+        let (x, y) = relative_position(area, cursor, dx.pixels(), dy.pixels());
+        move_pointer_to(x, y);
+        return Ok(());
+    }
+
+    if let (Some(dx), Some(dy), false) = (request.dx, request.dy, request.scroll) {
+        // Short path for smooth mouse movement, we should never have other fields set in this case.
+        let mouse_input = KeyboardAndMouse::MOUSEINPUT {
+            dx: dx.pixels(),
+            dy: dy.pixels(),
+            dwFlags: KeyboardAndMouse::MOUSEEVENTF_MOVE,
+            ..Default::default()
+        };
+        unsafe {
+            KeyboardAndMouse::SendInput(
+                &[KeyboardAndMouse::INPUT {
+                    r#type: KeyboardAndMouse::INPUT_MOUSE,
+                    Anonymous: KeyboardAndMouse::INPUT_0 { mi: mouse_input },
+                }],
+                std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
+            );
+        }
+        return Ok(());
+    }
+
+    log::debug!(
+        "Mousepad request: {} typed characters, special key {:?}",
+        request.key.as_deref().map_or(0, |key| key.chars().count()),
+        request.special_key
+    );
+
+    let mut mouse_click_down = KeyboardAndMouse::MOUSE_EVENT_FLAGS::default();
+    let mut mouse_click_up = KeyboardAndMouse::MOUSE_EVENT_FLAGS::default();
+    if request.singleclick {
+        mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_LEFTDOWN;
+        mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_LEFTUP;
+    }
+    if request.rightclick {
+        mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_RIGHTDOWN;
+        mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_RIGHTUP;
+    }
+    if request.middleclick {
+        mouse_click_down |= KeyboardAndMouse::MOUSEEVENTF_MIDDLEDOWN;
+        mouse_click_up |= KeyboardAndMouse::MOUSEEVENTF_MIDDLEUP;
+    }
+    if mouse_click_down != KeyboardAndMouse::MOUSE_EVENT_FLAGS::default() {
+        let down = KeyboardAndMouse::INPUT {
+            r#type: KeyboardAndMouse::INPUT_MOUSE,
+            Anonymous: KeyboardAndMouse::INPUT_0 {
+                mi: KeyboardAndMouse::MOUSEINPUT {
+                    dwFlags: mouse_click_down,
+                    ..Default::default()
+                },
+            },
+        };
+        let mut up = down;
+        up.Anonymous.mi.dwFlags = mouse_click_up;
+        inputs.push(down);
+        inputs.push(up);
+    }
+
+    if request.doubleclick {
+        let down = KeyboardAndMouse::INPUT {
+            r#type: KeyboardAndMouse::INPUT_MOUSE,
+            Anonymous: KeyboardAndMouse::INPUT_0 {
+                mi: KeyboardAndMouse::MOUSEINPUT {
+                    dwFlags: KeyboardAndMouse::MOUSEEVENTF_LEFTDOWN,
+                    ..Default::default()
+                },
+            },
+        };
+
+        let mut up = down;
+        up.Anonymous.mi.dwFlags = KeyboardAndMouse::MOUSEEVENTF_LEFTUP;
+
+        inputs.push(down);
+        inputs.push(up);
+        inputs.push(down);
+        inputs.push(up);
+    }
+
+    if request.is_key_event() {
+        push_key_inputs(&mut inputs, &request);
+    }
+
+    if !inputs.is_empty() {
+        unsafe {
+            KeyboardAndMouse::SendInput(
+                inputs.as_slice(),
+                std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
+            );
+        }
+    }
+    // if let (Some(dx), Some(dy), true) = (request.dx, request.dy, request.scroll) {}
+    Ok(())
+}
+
+/// Where the pointer may go: the display with this number if it exists, all of them otherwise.
+fn pointer_area(monitor: Option<u32>) -> RECT {
+    if let Some(number) = monitor {
+        match monitor::display_rect(number) {
+            Ok(Some(rect)) => return rect,
+            // Logged once by `display_rect`.
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to find display {}: {:?}", number, e),
+        }
+    }
+    monitor::virtual_desktop()
+}
+
+/// The pixel at a position from 0 to 1 across `area`, clamped to it.
+fn absolute_position(area: RECT, x: f64, y: f64) -> (i32, i32) {
+    let position = |start: i32, end: i32, v: f64| {
+        start + ((end - start - 1).max(0) as f64 * v.clamp(0.0, 1.0)).round() as i32
+    };
+    (
+        position(area.left, area.right, x),
+        position(area.top, area.bottom, y),
+    )
+}
+
+/// The pixel `dx` and `dy` away from `cursor`, clamped to `area`.
+fn relative_position(area: RECT, cursor: POINT, dx: i32, dy: i32) -> (i32, i32) {
+    let clamp = |v: i32, d: i32, start: i32, end: i32| {
+        v.saturating_add(d).clamp(start, (end - 1).max(start))
+    };
+    (
+        clamp(cursor.x, dx, area.left, area.right),
+        clamp(cursor.y, dy, area.top, area.bottom),
+    )
+}
+
+/// A coordinate of the virtual desktop, which spans from `start` to `end`, in the absolute
+/// coordinates of `SendInput`, which go from 0 to 65535 across it.
+fn normalize(v: i32, start: i32, end: i32) -> i32 {
+    ((v - start) as i64 * 65535 / (end - start - 1).max(1) as i64) as i32
+}
+
+/// Move the pointer to a pixel of the virtual desktop.
+fn move_pointer_to(x: i32, y: i32) {
+    let desktop = monitor::virtual_desktop();
+
+    let mouse_input = KeyboardAndMouse::MOUSEINPUT {
+        dx: normalize(x, desktop.left, desktop.right),
+        dy: normalize(y, desktop.top, desktop.bottom),
+        dwFlags: KeyboardAndMouse::MOUSEEVENTF_MOVE
+            | KeyboardAndMouse::MOUSEEVENTF_ABSOLUTE
+            | KeyboardAndMouse::MOUSEEVENTF_VIRTUALDESK,
+        ..Default::default()
+    };
+    unsafe {
+        KeyboardAndMouse::SendInput(
+            &[KeyboardAndMouse::INPUT {
+                r#type: KeyboardAndMouse::INPUT_MOUSE,
+                Anonymous: KeyboardAndMouse::INPUT_0 { mi: mouse_input },
+            }],
+            std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
+        );
+    }
+}
+
+fn keyboard_input(
+    vk: KeyboardAndMouse::VIRTUAL_KEY,
+    scan: u16,
+    up: bool,
+) -> KeyboardAndMouse::INPUT {
+    let mut flags = KeyboardAndMouse::KEYBD_EVENT_FLAGS::default();
+    if vk.0 == 0 {
+        flags |= KeyboardAndMouse::KEYEVENTF_UNICODE;
+    }
+    if up {
+        flags |= KeyboardAndMouse::KEYEVENTF_KEYUP;
+    }
+    KeyboardAndMouse::INPUT {
+        r#type: KeyboardAndMouse::INPUT_KEYBOARD,
+        Anonymous: KeyboardAndMouse::INPUT_0 {
+            ki: KeyboardAndMouse::KEYBDINPUT {
+                wVk: vk,
+                wScan: scan,
+                dwFlags: flags,
+                ..Default::default()
+            },
+        },
+    }
+}
+
+/// The virtual key of a "specialKey" sent by KDE Connect.
+fn special_key_to_vk(key: u32) -> Option<KeyboardAndMouse::VIRTUAL_KEY> {
+    use KeyboardAndMouse::*;
+
+    let vk = match key {
+        1 => VK_BACK,
+        2 => VK_TAB,
+        // Linefeed
+        3 => VK_RETURN,
+        4 => VK_LEFT,
+        5 => VK_UP,
+        6 => VK_RIGHT,
+        7 => VK_DOWN,
+        8 => VK_PRIOR,
+        9 => VK_NEXT,
+        10 => VK_HOME,
+        11 => VK_END,
+        12 => VK_RETURN,
+        13 => VK_DELETE,
+        14 => VK_ESCAPE,
+        15 => VK_SNAPSHOT,
+        16 => VK_SCROLL,
+        21..=32 => VIRTUAL_KEY(VK_F1.0 + (key - 21) as u16),
+        _ => return None,
+    };
+    Some(vk)
+}
 
-                if request.doubleclick {
-                    let down = KeyboardAndMouse::INPUT {
-                        r#type: KeyboardAndMouse::INPUT_MOUSE,
-                        Anonymous: KeyboardAndMouse::INPUT_0 {
-                            mi: KeyboardAndMouse::MOUSEINPUT {
-                                dwFlags: KeyboardAndMouse::MOUSEEVENTF_LEFTDOWN,
-                                ..Default::default()
-                            },
-                        },
-                    };
-
-                    let mut up = down;
-                    up.Anonymous.mi.dwFlags = KeyboardAndMouse::MOUSEEVENTF_LEFTUP;
-
-                    inputs.push(down);
-                    inputs.push(up);
-                    inputs.push(down);
-                    inputs.push(up);
+/// A key of the keyboard layout and the modifiers that type a character with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyScan {
+    vk: KeyboardAndMouse::VIRTUAL_KEY,
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+}
+
+impl KeyScan {
+    /// Decode the result of `VkKeyScanW`: the virtual key in the low byte and the shift state in
+    /// the high byte, or -1 if no key types the character.
+    fn from_vk_key_scan(scan: i16) -> Option<Self> {
+        if scan == -1 {
+            return None;
+        }
+        let state = (scan >> 8) as u8;
+        Some(Self {
+            vk: KeyboardAndMouse::VIRTUAL_KEY((scan & 0xff) as u16),
+            shift: state & 1 != 0,
+            ctrl: state & 2 != 0,
+            alt: state & 4 != 0,
+        })
+    }
+}
+
+/// Type the key of a request, holding its modifiers.
+fn push_key_inputs(inputs: &mut Vec<KeyboardAndMouse::INPUT>, request: &MousePadRequestPacket) {
+    let has_modifiers = request.ctrl || request.alt || request.shift || request.xuper;
+
+    let shortcut = match (&request.special_key, &request.key, has_modifiers) {
+        // Shortcuts like Ctrl+C need the key itself, not the character.
+        (None, Some(key), true) => {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii() => {
+                    KeyScan::from_vk_key_scan(unsafe { KeyboardAndMouse::VkKeyScanW(c as u16) })
                 }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    // A character may need more modifiers than the shortcut, e.g. Shift for Ctrl+? on a US
+    // layout.
+    let modifiers = [
+        (
+            request.ctrl || shortcut.is_some_and(|s| s.ctrl),
+            KeyboardAndMouse::VK_CONTROL,
+        ),
+        (
+            request.alt || shortcut.is_some_and(|s| s.alt),
+            KeyboardAndMouse::VK_MENU,
+        ),
+        (
+            request.shift || shortcut.is_some_and(|s| s.shift),
+            KeyboardAndMouse::VK_SHIFT,
+        ),
+        (request.xuper, KeyboardAndMouse::VK_LWIN),
+    ];
+    let modifiers = modifiers
+        .iter()
+        .filter(|(held, _)| *held)
+        .map(|(_, vk)| *vk);
 
-                if !inputs.is_empty() {
-                    unsafe {
-                        KeyboardAndMouse::SendInput(
-                            inputs.as_slice(),
-                            std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
-                        );
-                    }
+    inputs.extend(modifiers.clone().map(|vk| keyboard_input(vk, 0, false)));
+
+    if let Some(special_key) = request.special_key {
+        match special_key_to_vk(special_key) {
+            Some(vk) => {
+                inputs.push(keyboard_input(vk, 0, false));
+                inputs.push(keyboard_input(vk, 0, true));
+            }
+            None => log::warn!("Unknown special key: {}", special_key),
+        }
+    } else if let Some(key) = &request.key {
+        match shortcut {
+            Some(KeyScan { vk, .. }) => {
+                inputs.push(keyboard_input(vk, 0, false));
+                inputs.push(keyboard_input(vk, 0, true));
+            }
+            None => {
+                for unit in key.encode_utf16() {
+                    inputs.push(keyboard_input(
+                        KeyboardAndMouse::VIRTUAL_KEY(0),
+                        unit,
+                        false,
+                    ));
+                    inputs.push(keyboard_input(KeyboardAndMouse::VIRTUAL_KEY(0), unit, true));
                 }
-                // if let (Some(dx), Some(dy), true) = (request.dx, request.dy, request.scroll) {}
             }
-            _ => {}
+        }
+    }
+
+    inputs.extend(modifiers.map(|vk| keyboard_input(vk, 0, true)));
+}
+
+impl InputReceivePlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self {
+            dev,
+            ctx,
+            monitor: Mutex::new(None),
+        }
+    }
+
+    /// The display the pointer is kept on. Movements come in quickly, so the settings are only
+    /// read again after they changed.
+    fn monitor(&self) -> Option<u32> {
+        let settings = self.ctx.settings();
+        let revision = settings.revision();
+        let mut cached = self.monitor.lock().unwrap();
+        match *cached {
+            Some((r, monitor)) if r == revision => monitor,
+            _ => {
+                let monitor = settings
+                    .device(self.dev.device_id())
+                    .plugins
+                    .input_receive
+                    .monitor;
+                *cached = Some((revision, monitor));
+                monitor
+            }
+        }
+    }
+
+    async fn receive_request(&self, request: MousePadRequestPacket) -> Result<()> {
+        let monitor = self.monitor();
+        let ack =
+            (request.send_ack && request.is_key_event()).then(|| MousePadEchoPacket::ack(&request));
+
+        // A service has no desktop of its own, the UI helper injects it instead.
+        if let Some(link) = service::link::get() {
+            let packet = NetworkPacket::new(PacketKind::MousepadRequest, request);
+            link.send(ServiceMessage::Input { packet, monitor });
+        } else {
+            inject_request(request, monitor)?;
+        }
+
+        if let Some(ack) = ack {
+            self.dev
+                .send_packet(NetworkPacket::new(PacketKind::MousepadEcho, ack))
+                .await;
         }
         Ok(())
     }
 }
 
-impl KdeConnectPluginMetadata for InputReceivePlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_MOUSEPAD_REQUEST.into()]
+plugin_packets! {
+    InputReceivePlugin {
+        incoming {
+            MousepadRequest(MousePadRequestPacket) => receive_request,
+        }
+        outgoing [MousepadEcho]
+    }
+}
+
+impl KdeConnectPlugin for InputReceivePlugin {}
+
+#[cfg(test)]
+mod tests {
+    use KeyboardAndMouse::*;
+
+    use super::*;
+
+    fn request(body: serde_json::Value) -> MousePadRequestPacket {
+        serde_json::from_value(body).unwrap()
+    }
+
+    /// The virtual key and whether it is released, for each keyboard input.
+    fn keys(inputs: &[INPUT]) -> Vec<(VIRTUAL_KEY, bool)> {
+        inputs
+            .iter()
+            .map(|input| {
+                assert_eq!(input.r#type, INPUT_KEYBOARD);
+                let ki = unsafe { input.Anonymous.ki };
+                (ki.wVk, (ki.dwFlags & KEYEVENTF_KEYUP).0 != 0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn special_keys() {
+        assert_eq!(special_key_to_vk(1), Some(VK_BACK));
+        assert_eq!(special_key_to_vk(3), Some(VK_RETURN));
+        assert_eq!(special_key_to_vk(12), Some(VK_RETURN));
+        assert_eq!(special_key_to_vk(14), Some(VK_ESCAPE));
+        assert_eq!(special_key_to_vk(16), Some(VK_SCROLL));
+        assert_eq!(special_key_to_vk(21), Some(VK_F1));
+        assert_eq!(special_key_to_vk(32), Some(VK_F12));
+        for unknown in [0, 17, 20, 33, u32::MAX] {
+            assert_eq!(special_key_to_vk(unknown), None, "{}", unknown);
+        }
+    }
+
+    #[test]
+    fn key_events() {
+        assert!(request(serde_json::json!({ "specialKey": 1 })).is_key_event());
+        assert!(request(serde_json::json!({ "key": "a" })).is_key_event());
+        assert!(!request(serde_json::json!({ "key": "" })).is_key_event());
+        assert!(!request(serde_json::json!({ "dx": 1, "dy": 2 })).is_key_event());
+        assert!(!request(serde_json::json!({ "singleclick": true })).is_key_event());
+    }
+
+    #[test]
+    fn requests_accept_both_kinds_of_deltas() {
+        let moved = request(serde_json::json!({ "dx": 3, "dy": -1.5, "super": true }));
+        assert_eq!(moved.dx, Some(MouseDelta::Int(3)));
+        assert_eq!(moved.dy, Some(MouseDelta::Float(-1.5)));
+        assert!(moved.xuper);
+    }
+
+    #[test]
+    fn echo_serialization() {
+        let echo = MousePadEchoPacket::ack(&request(serde_json::json!({
+            "key": "a",
+            "ctrl": true,
+            "super": true,
+            "sendAck": true,
+        })));
+        assert_eq!(
+            serde_json::to_value(echo).unwrap(),
+            serde_json::json!({
+                "key": "a",
+                "alt": false,
+                "ctrl": true,
+                "shift": false,
+                "super": true,
+                "isAck": true,
+            })
+        );
+
+        let echo = MousePadEchoPacket::ack(&request(serde_json::json!({ "specialKey": 12 })));
+        assert_eq!(
+            serde_json::to_value(echo).unwrap(),
+            serde_json::json!({
+                "specialKey": 12,
+                "alt": false,
+                "ctrl": false,
+                "shift": false,
+                "super": false,
+                "isAck": true,
+            })
+        );
+    }
+
+    #[test]
+    fn key_scans_keep_their_shift_state() {
+        assert_eq!(KeyScan::from_vk_key_scan(-1), None);
+        // 'c' on a US layout.
+        assert_eq!(
+            KeyScan::from_vk_key_scan(0x0043),
+            Some(KeyScan {
+                vk: VIRTUAL_KEY(0x43),
+                shift: false,
+                ctrl: false,
+                alt: false,
+            })
+        );
+        // '?' on a US layout is Shift+VK_OEM_2.
+        assert_eq!(
+            KeyScan::from_vk_key_scan(0x01bf),
+            Some(KeyScan {
+                vk: VK_OEM_2,
+                shift: true,
+                ctrl: false,
+                alt: false,
+            })
+        );
+        // '@' on a German layout is AltGr+Q, which is Ctrl+Alt.
+        assert_eq!(
+            KeyScan::from_vk_key_scan(0x0651),
+            Some(KeyScan {
+                vk: VIRTUAL_KEY(0x51),
+                shift: false,
+                ctrl: true,
+                alt: true,
+            })
+        );
+    }
+
+    #[test]
+    fn special_keys_are_typed_with_their_modifiers() {
+        let mut inputs = vec![];
+        push_key_inputs(
+            &mut inputs,
+            &request(serde_json::json!({ "specialKey": 4, "ctrl": true, "shift": true })),
+        );
+        assert_eq!(
+            keys(&inputs),
+            [
+                (VK_CONTROL, false),
+                (VK_SHIFT, false),
+                (VK_LEFT, false),
+                (VK_LEFT, true),
+                (VK_CONTROL, true),
+                (VK_SHIFT, true),
+            ]
+        );
+    }
+
+    const DISPLAY: RECT = RECT {
+        left: -1920,
+        top: 100,
+        right: 0,
+        bottom: 1180,
+    };
+
+    #[test]
+    fn float_deltas_are_clamped() {
+        assert_eq!(MouseDelta::Float(2.6).pixels(), 3);
+        assert_eq!(MouseDelta::Float(-2.6).pixels(), -3);
+        assert_eq!(MouseDelta::Float(f32::NAN).pixels(), 0);
+        assert_eq!(MouseDelta::Float(f32::INFINITY).pixels(), MAX_MOVE);
+        assert_eq!(MouseDelta::Float(-1e30).pixels(), -MAX_MOVE);
+        assert_eq!(MouseDelta::Int(i32::MIN).pixels(), -MAX_MOVE);
+        assert_eq!(MouseDelta::Int(-7).pixels(), -7);
+
+        assert_eq!(MouseDelta::Float(f32::NAN).value(), 0.0);
+        assert_eq!(MouseDelta::Float(0.25).value(), 0.25);
+        assert_eq!(MouseDelta::Int(1).value(), 1.0);
+    }
+
+    #[test]
+    fn absolute_positions_stay_on_the_display() {
+        assert_eq!(absolute_position(DISPLAY, 0.0, 0.0), (-1920, 100));
+        assert_eq!(absolute_position(DISPLAY, 1.0, 1.0), (-1, 1179));
+        assert_eq!(absolute_position(DISPLAY, 0.5, 0.5), (-960, 640));
+        assert_eq!(absolute_position(DISPLAY, -3.0, 7.0), (-1920, 1179));
+        assert_eq!(
+            absolute_position(DISPLAY, f64::NEG_INFINITY, f64::INFINITY),
+            (-1920, 1179)
+        );
+    }
+
+    #[test]
+    fn relative_positions_stay_on_the_display() {
+        let cursor = POINT { x: -10, y: 1000 };
+        assert_eq!(relative_position(DISPLAY, cursor, 5, -5), (-5, 995));
+        assert_eq!(relative_position(DISPLAY, cursor, 50, 500), (-1, 1179));
+        assert_eq!(
+            relative_position(DISPLAY, cursor, -MAX_MOVE, -MAX_MOVE),
+            (-1920, 100)
+        );
+        // The cursor may be on another display, and deltas may be extreme.
+        let far = POINT {
+            x: i32::MAX,
+            y: i32::MIN,
+        };
+        assert_eq!(
+            relative_position(DISPLAY, far, i32::MAX, i32::MIN),
+            (-1, 100)
+        );
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![]
+
+    #[test]
+    fn positions_are_normalized_across_the_virtual_desktop() {
+        assert_eq!(normalize(-1920, -1920, 1920), 0);
+        assert_eq!(normalize(1919, -1920, 1920), 65535);
+        assert_eq!(normalize(0, -1920, 1920), 32776);
+        // An empty desktop does not divide by zero.
+        assert_eq!(normalize(0, 0, 0), 0);
+    }
+
+    #[test]
+    fn text_is_typed_as_unicode() {
+        let mut inputs = vec![];
+        push_key_inputs(&mut inputs, &request(serde_json::json!({ "key": "é?" })));
+        assert_eq!(inputs.len(), 4);
+        for (input, (scan, up)) in
+            inputs
+                .iter()
+                .zip([('é', false), ('é', true), ('?', false), ('?', true)])
+        {
+            let ki = unsafe { input.Anonymous.ki };
+            assert_eq!(ki.wVk, VIRTUAL_KEY(0));
+            assert_eq!(ki.wScan, scan as u16);
+            assert!((ki.dwFlags & KEYEVENTF_UNICODE).0 != 0);
+            assert_eq!((ki.dwFlags & KEYEVENTF_KEYUP).0 != 0, up);
+        }
     }
 }
@@ -1,145 +1,307 @@
 use anyhow::Result;
-use std::{collections::HashSet, sync::Arc};
-use tao::menu::ContextMenu;
+use futures::FutureExt;
+use std::{
+    any::Any,
+    collections::{BTreeSet, HashSet},
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
-    context::AppContextRef, device::DeviceHandle, event::SystemEvent, packet::NetworkPacket, utils,
+    context::AppContextRef,
+    crash,
+    device::DeviceHandle,
+    event::{EventTopic, SystemEvent},
+    packet::NetworkPacket,
+    tray::DeviceMenu,
+    utils,
 };
 
-mod battery;
-mod clipboard;
+use self::packets::{PacketHandler, PacketKind};
+
+pub mod battery;
+pub mod clipboard;
 mod connectivity_report;
-mod input_receive;
+mod event_log;
+pub mod input_receive;
+mod monitor_control;
 mod mpris;
-mod notification_receive;
-mod ping;
+pub mod notification_receive;
+pub mod packets;
+pub mod ping;
 mod run_command;
-mod share;
+pub mod share;
+mod state;
+mod system_stats;
 mod system_volume;
+mod telephony;
+pub mod wake_on_lan;
 
+/// A plugin of a device. Incoming packets are passed to the handlers declared with
+/// [`packets::plugin_packets!`].
 #[async_trait::async_trait]
-pub trait KdeConnectPlugin: std::fmt::Debug + Send + Sync {
+pub trait KdeConnectPlugin: PacketHandler + std::fmt::Debug + Send + Sync {
     async fn start(self: Arc<Self>) -> Result<()> {
         Ok(())
     }
-    async fn handle(&self, packet: NetworkPacket) -> Result<()>;
     async fn handle_event(self: Arc<Self>, _event: SystemEvent) -> Result<()> {
         Ok(())
     }
     async fn hotkeys(&self) -> Vec<()> {
         vec![]
     }
-    /// Create necessary context menu items for this plugin.
-    async fn tray_menu(&self, _menu: &mut ContextMenu) {}
+    /// Add the items of this plugin to the tray submenu of the device.
+    async fn tray_menu(&self, _menu: &mut DeviceMenu) {}
+    /// Stop any background work spawned in `start`.
+    ///
+    /// Called when the device is removed or the application shuts down, before `dispose`.
+    async fn stop(&self) {}
+    /// Release resources held by this plugin. The plugin will not be used afterwards.
     async fn dispose(&self) {}
 }
 
+/// Implemented by [`packets::plugin_packets!`].
 pub trait KdeConnectPluginMetadata {
-    fn incoming_capabilities() -> Vec<String>;
-    fn outgoing_capabilities() -> Vec<String>;
+    fn incoming_capabilities() -> Vec<PacketKind>;
+    fn outgoing_capabilities() -> Vec<PacketKind>;
+    /// Topics of the system events passed to `handle_event`.
+    fn event_topics() -> Vec<EventTopic> {
+        vec![]
+    }
+}
+
+/// Incoming and outgoing packet types of a list of plugins.
+macro_rules! capabilities_of {
+    ($($plugin:ty),* $(,)?) => {{
+        let mut incoming = BTreeSet::new();
+        let mut outgoing = BTreeSet::new();
+        $(
+            incoming.extend(<$plugin>::incoming_capabilities());
+            outgoing.extend(<$plugin>::outgoing_capabilities());
+        )*
+        (incoming, outgoing)
+    }};
 }
 
 lazy_static::lazy_static! {
-    pub static ref ALL_CAPS: (Vec<String>, Vec<String>) = {
-        let mut incoming_caps = vec![];
-        let mut outgoing_caps = vec![];
-
-        incoming_caps.extend(ping::PingPlugin::incoming_capabilities());
-        outgoing_caps.extend(ping::PingPlugin::outgoing_capabilities());
-        // incoming_caps
-        //     .extend(connectivity_report::ConnectivityReportPlugin::incoming_capabilities());
-        // outgoing_caps
-        //     .extend(connectivity_report::ConnectivityReportPlugin::outgoing_capabilities());
-        incoming_caps.extend(clipboard::ClipboardPlugin::incoming_capabilities());
-        outgoing_caps.extend(clipboard::ClipboardPlugin::outgoing_capabilities());
-        incoming_caps.extend(mpris::MprisPlugin::incoming_capabilities());
-        outgoing_caps.extend(mpris::MprisPlugin::outgoing_capabilities());
-        incoming_caps.extend(mpris::remote::MprisRemotePlugin::incoming_capabilities());
-        outgoing_caps.extend(mpris::remote::MprisRemotePlugin::outgoing_capabilities());
-        incoming_caps
-            .extend(notification_receive::NotificationReceivePlugin::incoming_capabilities());
-        outgoing_caps
-            .extend(notification_receive::NotificationReceivePlugin::outgoing_capabilities());
-        incoming_caps.extend(input_receive::InputReceivePlugin::incoming_capabilities());
-        outgoing_caps.extend(input_receive::InputReceivePlugin::outgoing_capabilities());
-        incoming_caps.extend(battery::BatteryPlugin::incoming_capabilities());
-        outgoing_caps.extend(battery::BatteryPlugin::outgoing_capabilities());
-        incoming_caps.extend(share::SharePlugin::incoming_capabilities());
-        outgoing_caps.extend(share::SharePlugin::outgoing_capabilities());
-        incoming_caps.extend(run_command::RunCommandPlugin::incoming_capabilities());
-        outgoing_caps.extend(run_command::RunCommandPlugin::outgoing_capabilities());
-        incoming_caps.extend(system_volume::SystemVolumePlugin::incoming_capabilities());
-        outgoing_caps.extend(system_volume::SystemVolumePlugin::outgoing_capabilities());
-
-        (incoming_caps, outgoing_caps)
+    pub static ref ALL_CAPS: (BTreeSet<PacketKind>, BTreeSet<PacketKind>) = {
+        let (mut incoming, mut outgoing) = capabilities_of!(
+            ping::PingPlugin,
+            connectivity_report::ConnectivityReportPlugin,
+            clipboard::ClipboardPlugin,
+            mpris::MprisPlugin,
+            mpris::remote::MprisRemotePlugin,
+            notification_receive::NotificationReceivePlugin,
+            input_receive::InputReceivePlugin,
+            battery::BatteryPlugin,
+            share::SharePlugin,
+            run_command::RunCommandPlugin,
+            system_volume::SystemVolumePlugin,
+            system_stats::SystemStatsPlugin,
+            wake_on_lan::WakeOnLanPlugin,
+            monitor_control::MonitorControlPlugin,
+            telephony::TelephonyPlugin,
+            event_log::EventLogPlugin,
+        );
+        // Handled by the repository itself.
+        incoming.insert(PacketKind::Plugins);
+        outgoing.insert(PacketKind::Plugins);
+        (incoming, outgoing)
     };
 }
 
+/// Packet types a set of plugins can receive and send, as advertised in identity packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub incoming: BTreeSet<String>,
+    pub outgoing: BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// Capabilities of all known plugins, used before we know which plugins a device gets.
+    pub fn all() -> Self {
+        Self::new(&ALL_CAPS.0, &ALL_CAPS.1)
+    }
+
+    fn new<'a>(
+        incoming: impl IntoIterator<Item = &'a PacketKind>,
+        outgoing: impl IntoIterator<Item = &'a PacketKind>,
+    ) -> Self {
+        Self {
+            incoming: incoming.into_iter().map(|k| k.to_string()).collect(),
+            outgoing: outgoing.into_iter().map(|k| k.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PluginEntry {
+    name: &'static str,
+    incoming_caps: HashSet<PacketKind>,
+    event_topics: HashSet<EventTopic>,
+    plugin: Arc<dyn KdeConnectPlugin>,
+    /// Whether the user has already been notified about a panic in this plugin.
+    panicked: AtomicBool,
+}
+
+impl PluginEntry {
+    /// Run a call into the plugin, catching any panic so that it does not take down
+    /// the task (and the device) it is running on.
+    async fn isolate<F: Future>(&self, what: &str, fut: F) -> Option<F::Output> {
+        match AssertUnwindSafe(crash::expect_panics(fut))
+            .catch_unwind()
+            .await
+        {
+            Ok(r) => Some(r),
+            Err(payload) => {
+                let msg = panic_message(&*payload);
+                log::error!("Plugin {} panicked in {}: {}", self.name, what, msg);
+
+                if !self.panicked.swap(true, Ordering::Relaxed) {
+                    utils::simple_toast(&format!("Plugin {} crashed", self.name), Some(&msg), None)
+                        .await;
+                }
+
+                None
+            }
+        }
+    }
+}
+
+async fn start_plugins(plugins: Vec<Arc<PluginEntry>>) {
+    for entry in plugins {
+        if let Some(Err(e)) = entry.isolate("start", entry.plugin.clone().start()).await {
+            log::error!("Failed to start plugin {}: {:?}", entry.name, e);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
 #[derive(Debug)]
 pub struct PluginRepository {
-    plugins: Vec<(HashSet<String>, Arc<dyn KdeConnectPlugin>)>,
-    pub incoming_caps: HashSet<String>,
-    pub outgoing_caps: HashSet<String>,
+    plugins: Vec<Arc<PluginEntry>>,
+    pub incoming_caps: HashSet<PacketKind>,
+    pub outgoing_caps: HashSet<PacketKind>,
+    /// Names of the plugins [`register`](Self::register) skips.
+    disabled: HashSet<String>,
     dev: DeviceHandle,
 }
 
 impl PluginRepository {
-    pub async fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
-        let mut this = Self {
+    /// A repository without any plugin, to register them one by one.
+    pub fn empty(dev: DeviceHandle) -> Self {
+        Self {
             plugins: vec![],
-            incoming_caps: HashSet::new(),
-            outgoing_caps: HashSet::new(),
-            dev: dev.clone(),
-        };
+            // The state of the plugins is exchanged by the repository itself.
+            incoming_caps: HashSet::from([PacketKind::Plugins]),
+            outgoing_caps: HashSet::from([PacketKind::Plugins]),
+            disabled: HashSet::new(),
+            dev,
+        }
+    }
+
+    pub async fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        let mut this = Self::empty(dev.clone());
+        this.disabled = ctx
+            .settings()
+            .device(dev.device_id())
+            .plugins
+            .disabled
+            .into_iter()
+            .collect();
 
         // This also determines the order in which plugins are shown in tray menu.
-        this.register(battery::BatteryPlugin::new(dev.clone(), ctx.clone()));
-        this.register(ping::PingPlugin::new(dev.clone()));
-        // this.register(connectivity_report::ConnectivityReportPlugin);
-        this.register(clipboard::ClipboardPlugin::new(dev.clone()));
+        this.register_or_log(battery::BatteryPlugin::new(dev.clone(), ctx.clone()));
+        this.register_or_log(ping::PingPlugin::new(dev.clone()));
+        this.register_or_log(connectivity_report::ConnectivityReportPlugin::new(
+            dev.clone(),
+            ctx.clone(),
+        ));
+        this.register_or_log(clipboard::ClipboardPlugin::new(dev.clone(), ctx.clone()));
         utils::log_if_error(
             "Failed to initialize MPRIS plugin",
             mpris::MprisPlugin::new(dev.clone(), ctx.clone())
                 .await
-                .map(|p| this.register(p)),
+                .and_then(|p| this.register(p)),
         );
-        this.register(mpris::remote::MprisRemotePlugin::new(
+        this.register_or_log(mpris::remote::MprisRemotePlugin::new(
+            dev.clone(),
+            ctx.clone(),
+        ));
+        this.register_or_log(notification_receive::NotificationReceivePlugin::new(
             dev.clone(),
             ctx.clone(),
         ));
-        this.register(notification_receive::NotificationReceivePlugin::new(
+        this.register_or_log(input_receive::InputReceivePlugin::new(
             dev.clone(),
             ctx.clone(),
         ));
-        this.register(input_receive::InputReceivePlugin);
-        this.register(share::SharePlugin::new(dev.clone()));
-        this.register(run_command::RunCommandPlugin::new(dev.clone()));
-        this.register(system_volume::SystemVolumePlugin::new(dev.clone()));
+        this.register_or_log(share::SharePlugin::new(dev.clone(), ctx.clone()));
+        this.register_or_log(run_command::RunCommandPlugin::new(dev.clone()));
+        this.register_or_log(system_volume::SystemVolumePlugin::new(
+            dev.clone(),
+            ctx.clone(),
+        ));
+        this.register_or_log(system_stats::SystemStatsPlugin::new(
+            dev.clone(),
+            ctx.clone(),
+        ));
+        this.register_or_log(wake_on_lan::WakeOnLanPlugin::new(dev.clone(), ctx.clone()));
+        this.register_or_log(monitor_control::MonitorControlPlugin::new(dev.clone()));
+        this.register_or_log(telephony::TelephonyPlugin::new(dev.clone(), ctx.clone()));
+        this.register_or_log(event_log::EventLogPlugin::new(dev.clone(), ctx.clone()));
 
-        // Start the plugins
-        let plugins = this
-            .plugins
-            .iter()
-            .map(|(_, p)| Arc::clone(p))
-            .collect::<Vec<_>>();
-        tokio::spawn(async move {
-            for plugin in plugins {
-                if let Err(e) = plugin.clone().start().await {
-                    log::error!("Failed to start plugin {:?}: {:?}", plugin, e);
-                }
-            }
-        });
+        tokio::spawn(start_plugins(this.plugins.clone()));
 
         this
     }
 
-    pub fn register<P>(&mut self, plugin: P)
+    /// Start the plugins, which [`new`](Self::new) does in the background.
+    #[cfg(test)]
+    pub async fn start(&self) {
+        start_plugins(self.plugins.clone()).await;
+    }
+
+    /// Add a plugin, unless it is disabled in the settings of the device.
+    ///
+    /// Fails if one of its incoming packet types is already handled by another plugin, as each
+    /// packet goes to a single plugin.
+    pub fn register<P>(&mut self, plugin: P) -> Result<()>
     where
         P: KdeConnectPlugin + KdeConnectPluginMetadata + 'static,
     {
         let in_caps = P::incoming_capabilities();
         let out_caps = P::outgoing_capabilities();
 
+        let name = std::any::type_name::<P>();
+        let name = name.rsplit("::").next().unwrap_or(name);
+
+        if self.disabled.contains(&state::config_name(name)) {
+            log::debug!("Not registering plugin {}: disabled", name);
+            return Ok(());
+        }
+
+        if let Some(kind) = in_caps.iter().find(|k| self.incoming_caps.contains(k)) {
+            anyhow::bail!(
+                "Not registering plugin {}: {} is already handled",
+                name,
+                kind
+            );
+        }
+
         log::debug!(
             "Registering plugin: {:?} with in={:?}, out={:?}",
             plugin,
@@ -148,49 +310,399 @@ impl PluginRepository {
         );
 
         self.incoming_caps.extend(in_caps.iter().cloned());
-        self.outgoing_caps.extend(out_caps.into_iter());
+        self.outgoing_caps.extend(out_caps);
+
+        self.plugins.push(Arc::new(PluginEntry {
+            name,
+            incoming_caps: in_caps.into_iter().collect(),
+            event_topics: P::event_topics().into_iter().collect(),
+            plugin: Arc::new(plugin),
+            panicked: AtomicBool::new(false),
+        }));
+        Ok(())
+    }
+
+    fn register_or_log<P>(&mut self, plugin: P)
+    where
+        P: KdeConnectPlugin + KdeConnectPluginMetadata + 'static,
+    {
+        utils::log_if_error("Failed to register plugin", self.register(plugin));
+    }
 
-        self.plugins
-            .push((in_caps.into_iter().collect(), Arc::new(plugin)));
+    /// Capabilities of the plugins actually loaded for this device.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(&self.incoming_caps, &self.outgoing_caps)
     }
 
     pub async fn handle_packet(&self, packet: NetworkPacket) -> Result<()> {
-        let typ = packet.typ.as_str();
+        tracing::debug!("Incoming packet {} of type {}", packet.id, packet.typ);
 
-        tracing::debug!("Incoming packet: {:?}", packet);
+        if packet.typ == PacketKind::Plugins.as_str() {
+            return self.receive_state(packet).await;
+        }
 
-        let mut handled = false;
-        for (in_caps, plguin) in &self.plugins {
-            if in_caps.contains(typ) {
-                plguin.handle(packet.clone()).await?;
-                handled = true;
+        let entry = PacketKind::from_type(&packet.typ).and_then(|kind| {
+            self.plugins
+                .iter()
+                .find(|e| e.incoming_caps.contains(&kind))
+        });
+        let entry = match entry {
+            Some(entry) => entry,
+            None => anyhow::bail!("No plugin found for packet type {}", packet.typ),
+        };
+
+        entry
+            .isolate("handle", entry.plugin.dispatch(packet))
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("Plugin {} panicked", entry.name)))
+    }
+
+    pub async fn handle_event(&self, event: SystemEvent) {
+        let topic = event.topic();
+
+        for entry in &self.plugins {
+            if !entry.event_topics.contains(&topic) {
+                continue;
+            }
+            let fut = entry.plugin.clone().handle_event(event);
+            if let Some(Err(e)) = entry.isolate("handle_event", fut).await {
+                log::error!("Error handling event: {}", e);
             }
         }
+    }
+
+    pub async fn create_tray_menu(&self, menu: &mut DeviceMenu) {
+        for entry in &self.plugins {
+            entry
+                .isolate("tray_menu", entry.plugin.tray_menu(menu))
+                .await;
+        }
+    }
 
-        if handled {
+    /// Stop and dispose all plugins.
+    pub async fn dispose(&self) {
+        for entry in &self.plugins {
+            entry.isolate("stop", entry.plugin.stop()).await;
+        }
+        for entry in &self.plugins {
+            entry.isolate("dispose", entry.plugin.dispose()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde::Deserialize;
+
+    use crate::{packet::Payload, testing::mock::MockDeviceHandle};
+
+    use super::{packets::plugin_packets, *};
+
+    #[derive(Debug, Deserialize)]
+    struct TestBody {
+        n: u32,
+    }
+
+    type Received = Arc<Mutex<Vec<(u32, Option<Payload>)>>>;
+
+    /// Records the pings it receives.
+    #[derive(Debug, Default)]
+    struct RecorderPlugin {
+        received: Received,
+    }
+
+    impl RecorderPlugin {
+        async fn receive(&self, body: TestBody, payload: Option<Payload>) -> Result<()> {
+            self.received.lock().unwrap().push((body.n, payload));
             Ok(())
-        } else {
-            Err(anyhow::anyhow!("No plugin found for packet type {}", typ))
         }
     }
 
-    pub async fn handle_event(&self, event: SystemEvent) {
-        for (_, plugin) in &self.plugins {
-            if let Err(e) = plugin.clone().handle_event(event).await {
-                log::error!("Error handling event: {}", e);
+    plugin_packets! {
+        RecorderPlugin {
+            incoming {
+                Ping(TestBody) => receive with payload,
             }
+            outgoing [Ping]
         }
     }
 
-    pub async fn create_tray_menu(&self, menu: &mut ContextMenu) {
-        for (_, plugin) in &self.plugins {
-            plugin.tray_menu(menu).await;
+    #[async_trait::async_trait]
+    impl KdeConnectPlugin for RecorderPlugin {}
+
+    /// Also claims pings, which the repository must refuse.
+    #[derive(Debug)]
+    struct RivalPlugin;
+
+    impl RivalPlugin {
+        async fn receive(&self, _body: serde::de::IgnoredAny) -> Result<()> {
+            panic!("Pings should go to the first plugin")
         }
     }
 
-    pub async fn dispose(&self) {
-        for (_, plugin) in &self.plugins {
-            plugin.dispose().await;
+    plugin_packets! {
+        RivalPlugin {
+            incoming {
+                Battery(serde::de::IgnoredAny) => receive,
+                Ping(serde::de::IgnoredAny) => receive,
+            }
+            outgoing [Battery]
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl KdeConnectPlugin for RivalPlugin {}
+
+    fn ping(body: serde_json::Value) -> NetworkPacket {
+        NetworkPacket::new("kdeconnect.ping", body)
+    }
+
+    #[tokio::test]
+    async fn refuses_a_second_plugin_for_a_packet_type() {
+        let dev = MockDeviceHandle::new("test-device");
+        let mut repo = PluginRepository::empty(dev.handle());
+        let recorder = RecorderPlugin::default();
+        let received = recorder.received.clone();
+
+        repo.register(recorder).unwrap();
+        let err = repo.register(RivalPlugin).unwrap_err();
+        assert!(err.to_string().contains("kdeconnect.ping"), "{}", err);
+
+        // Nothing of the refused plugin is kept.
+        assert_eq!(repo.plugins.len(), 1);
+        assert!(!repo.incoming_caps.contains(&PacketKind::Battery));
+        assert!(!repo.outgoing_caps.contains(&PacketKind::Battery));
+
+        repo.handle_packet(ping(serde_json::json!({ "n": 1 })))
+            .await
+            .unwrap();
+        assert_eq!(*received.lock().unwrap(), [(1, None)]);
+    }
+
+    #[tokio::test]
+    async fn skips_disabled_plugins() {
+        let dev = MockDeviceHandle::new("test-device");
+        let mut repo = PluginRepository::empty(dev.handle());
+        repo.disabled.insert("recorder".into());
+
+        repo.register(RecorderPlugin::default()).unwrap();
+        assert!(repo.plugins.is_empty());
+        assert!(!repo.incoming_caps.contains(&PacketKind::Ping));
+
+        // The packet types are free for another plugin.
+        repo.register(RivalPlugin).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dispatches_packets_to_their_handler() {
+        let dev = MockDeviceHandle::new("test-device");
+        let mut repo = PluginRepository::empty(dev.handle());
+        let recorder = RecorderPlugin::default();
+        let received = recorder.received.clone();
+        repo.register(recorder).unwrap();
+
+        repo.handle_packet(ping(serde_json::json!({ "n": 1 })))
+            .await
+            .unwrap();
+        let mut packet = ping(serde_json::json!({ "n": 2 }));
+        packet.set_payload(10, 1739);
+        repo.handle_packet(packet).await.unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                (1, None),
+                (
+                    2,
+                    Some(Payload {
+                        size: 10,
+                        port: 1739
+                    })
+                )
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_and_unhandled_packets() {
+        let dev = MockDeviceHandle::new("test-device");
+        let mut repo = PluginRepository::empty(dev.handle());
+        let recorder = RecorderPlugin::default();
+        let received = recorder.received.clone();
+        repo.register(recorder).unwrap();
+
+        let err = repo
+            .handle_packet(ping(serde_json::json!({ "n": "one" })))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid Ping packet"), "{}", err);
+
+        for typ in ["kdeconnect.battery", "kdeconnect.unknown"] {
+            let packet = NetworkPacket::new(typ, serde_json::json!({}));
+            assert!(repo.handle_packet(packet).await.is_err());
+        }
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dispatch_rejects_other_packet_types() {
+        let plugin = RecorderPlugin::default();
+        let packet = NetworkPacket::new("kdeconnect.battery", serde_json::json!({ "n": 1 }));
+        let err = plugin.dispatch(packet).await.unwrap_err();
+        assert!(
+            err.to_string().contains("Unexpected packet type"),
+            "{}",
+            err
+        );
+    }
+
+    fn caps<P: KdeConnectPluginMetadata>() -> (Vec<&'static str>, Vec<&'static str>) {
+        (
+            P::incoming_capabilities()
+                .iter()
+                .map(|k| k.as_str())
+                .collect(),
+            P::outgoing_capabilities()
+                .iter()
+                .map(|k| k.as_str())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn plugin_capabilities_are_unchanged() {
+        // The capabilities each plugin advertised as strings, before packet types were declared
+        // with `plugin_packets!`. The echo of input_receive was added afterwards.
+        let expected = [
+            (
+                caps::<battery::BatteryPlugin>(),
+                vec!["kdeconnect.battery", "kdeconnect.battery.request"],
+                vec!["kdeconnect.battery", "kdeconnect.battery.request"],
+            ),
+            (
+                caps::<clipboard::ClipboardPlugin>(),
+                vec!["kdeconnect.clipboard", "kdeconnect.clipboard.connect"],
+                vec!["kdeconnect.clipboard", "kdeconnect.clipboard.connect"],
+            ),
+            (
+                caps::<connectivity_report::ConnectivityReportPlugin>(),
+                vec![
+                    "kdeconnect.connectivity_report",
+                    "kdeconnect.connectivity_report.request",
+                ],
+                vec!["kdeconnect.connectivity_report.request"],
+            ),
+            (caps::<event_log::EventLogPlugin>(), vec![], vec![]),
+            (
+                caps::<input_receive::InputReceivePlugin>(),
+                vec!["kdeconnect.mousepad.request"],
+                vec!["kdeconnect.mousepad.echo"],
+            ),
+            (
+                caps::<monitor_control::MonitorControlPlugin>(),
+                vec!["kdeconnect.monitorcontrol.request"],
+                vec![],
+            ),
+            (
+                caps::<mpris::MprisPlugin>(),
+                vec!["kdeconnect.mpris.request"],
+                vec!["kdeconnect.mpris"],
+            ),
+            (
+                caps::<mpris::remote::MprisRemotePlugin>(),
+                vec!["kdeconnect.mpris"],
+                vec!["kdeconnect.mpris.request"],
+            ),
+            (
+                caps::<notification_receive::NotificationReceivePlugin>(),
+                vec!["kdeconnect.notification"],
+                vec![
+                    "kdeconnect.notification.request",
+                    "kdeconnect.notification.reply",
+                ],
+            ),
+            (
+                caps::<ping::PingPlugin>(),
+                vec!["kdeconnect.ping"],
+                vec!["kdeconnect.ping"],
+            ),
+            (
+                caps::<run_command::RunCommandPlugin>(),
+                vec!["kdeconnect.runcommand", "kdeconnect.runcommand.request"],
+                vec!["kdeconnect.runcommand", "kdeconnect.runcommand.request"],
+            ),
+            (
+                caps::<share::SharePlugin>(),
+                vec![
+                    "kdeconnect.share.request",
+                    "kdeconnect.share.request.update",
+                ],
+                vec![
+                    "kdeconnect.share.request",
+                    "kdeconnect.share.request.update",
+                ],
+            ),
+            (
+                caps::<system_stats::SystemStatsPlugin>(),
+                vec!["kdeconnect.systemstats.request"],
+                vec!["kdeconnect.systemstats"],
+            ),
+            (
+                caps::<system_volume::SystemVolumePlugin>(),
+                vec!["kdeconnect.systemvolume.request"],
+                vec!["kdeconnect.systemvolume"],
+            ),
+            (
+                caps::<telephony::TelephonyPlugin>(),
+                vec!["kdeconnect.telephony"],
+                vec![],
+            ),
+            (
+                caps::<wake_on_lan::WakeOnLanPlugin>(),
+                vec!["kdeconnect.wake.request"],
+                vec![],
+            ),
+        ];
+
+        let mut incoming = BTreeSet::new();
+        let mut outgoing = BTreeSet::new();
+        for ((actual_in, actual_out), expected_in, expected_out) in expected {
+            assert_eq!(actual_in, expected_in);
+            assert_eq!(actual_out, expected_out);
+            incoming.extend(expected_in.into_iter().map(String::from));
+            outgoing.extend(expected_out.into_iter().map(String::from));
+        }
+
+        // Plus the packets of the repository.
+        incoming.insert("kdeconnect.plugins".into());
+        outgoing.insert("kdeconnect.plugins".into());
+        assert_eq!(Capabilities::all(), Capabilities { incoming, outgoing });
+    }
+
+    #[test]
+    fn no_packet_type_is_claimed_twice() {
+        let claims = [
+            caps::<battery::BatteryPlugin>().0,
+            caps::<clipboard::ClipboardPlugin>().0,
+            caps::<connectivity_report::ConnectivityReportPlugin>().0,
+            caps::<input_receive::InputReceivePlugin>().0,
+            caps::<monitor_control::MonitorControlPlugin>().0,
+            caps::<mpris::MprisPlugin>().0,
+            caps::<mpris::remote::MprisRemotePlugin>().0,
+            caps::<notification_receive::NotificationReceivePlugin>().0,
+            caps::<ping::PingPlugin>().0,
+            caps::<run_command::RunCommandPlugin>().0,
+            caps::<share::SharePlugin>().0,
+            caps::<system_stats::SystemStatsPlugin>().0,
+            caps::<system_volume::SystemVolumePlugin>().0,
+            caps::<telephony::TelephonyPlugin>().0,
+            caps::<wake_on_lan::WakeOnLanPlugin>().0,
+        ];
+        let mut seen = HashSet::new();
+        for kind in claims.into_iter().flatten() {
+            assert!(seen.insert(kind), "{} is claimed twice", kind);
         }
     }
 }
@@ -0,0 +1,69 @@
+//! This plugin receives packets with type "kdeconnect.monitorcontrol.request" to change the
+//! brightness of the monitors over DDC/CI, or to turn them off:
+//!
+//! brightness (int) [optional]: Set the brightness to this percentage
+//! brightnessDelta (int) [optional]: Change the brightness by this many percent
+//! powerOff (boolean) [optional]: Turn off the displays, after changing the brightness
+//!
+//! The same actions are available as built-in commands of the run command plugin.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{device::DeviceHandle, utils::monitor};
+
+use super::{packets::plugin_packets, KdeConnectPlugin};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MonitorControlRequest {
+    brightness: Option<u8>,
+    brightness_delta: Option<i32>,
+    #[serde(default)]
+    power_off: bool,
+}
+
+#[derive(Debug)]
+pub struct MonitorControlPlugin {
+    dev: DeviceHandle,
+}
+
+impl MonitorControlPlugin {
+    pub fn new(dev: DeviceHandle) -> Self {
+        Self { dev }
+    }
+
+    async fn receive_request(&self, request: MonitorControlRequest) -> Result<()> {
+        log::info!(
+            "Monitor control from {}: {:?}",
+            self.dev.device_name(),
+            request
+        );
+        tokio::task::spawn_blocking(move || apply(request)).await?
+    }
+}
+
+plugin_packets! {
+    MonitorControlPlugin {
+        incoming {
+            MonitorControlRequest(MonitorControlRequest) => receive_request,
+        }
+        outgoing []
+    }
+}
+
+fn apply(request: MonitorControlRequest) -> Result<()> {
+    if let Some(brightness) = request.brightness {
+        monitor::set_brightness(brightness)?;
+    }
+    if let Some(delta) = request.brightness_delta {
+        // Comes from the network, anything beyond a full swing means the same.
+        monitor::adjust_brightness(delta.clamp(-100, 100))?;
+    }
+    if request.power_off {
+        monitor::power_off()?;
+    }
+    Ok(())
+}
+
+impl KdeConnectPlugin for MonitorControlPlugin {}
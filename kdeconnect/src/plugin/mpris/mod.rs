@@ -12,6 +12,12 @@ detected or a known one dissappears, it should also send this list. Note that
 players are identified only by its name (its MPRIS Identity), so there can not
 be two players with the same display name.
 
+Windows identifies media sessions by the AppUserModelID of their app, which is
+mapped to a friendly name before being used as the player name. An app can have
+several sessions, e.g. two Chrome windows, which are named "Chrome", "Chrome (2)"
+and so on. A session keeps its name for as long as it exists. Players can be
+hidden from the list in the settings.
+
 This plugins also reports the current song, extracted from MPRIS Metadata. It
 should send it when it changes or when receiving a package containing a boolean
 "requestNowPlaying" set to true.
@@ -21,26 +27,32 @@ packages will contain a string "player" with the name of the player they want to
 command and a string "action" with the name of an MPRIS call (like "Play",
 "Next"...).
 
-This plugin can also control the system volume. The peer device can send a
-package with "requestVolume" set to true to ask for the current volume, or send
+This plugin can also control the volume of the players. The peer device can send
+a package with "requestVolume" set to true to ask for the current volume, or send
 a package with "setVolume" set to an integer in the range [0,100] to change it.
+That is the volume of the audio sessions of the app, or the volume of the default
+output if the app has none. It is also included in the metadata.
 */
 
 use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::{
-    cache::PAYLOAD_CACHE,
+    cache::{CacheKey, CacheKind, PAYLOAD_CACHE},
     context::AppContextRef,
     device::DeviceHandle,
     event::SystemEvent,
     packet::{NetworkPacket, NetworkPacketWithPayload},
-    utils,
+    plugin::system_volume,
+    transfer::{self, Kind},
+    utils::{self, debounce::Debouncer, hash::ContentHash, retry::Backoff},
 };
 use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::Mutex;
 use windows::{
+    core::{IUnknown, Interface, Vtable},
     Foundation::{EventRegistrationToken, TypedEventHandler},
     Media::Control::{
         GlobalSystemMediaTransportControlsSession,
@@ -50,13 +62,22 @@ use windows::{
     Storage::Streams::DataReader,
 };
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
+mod names;
 pub mod remote;
 
-pub(self) const PACKET_TYPE_MPRIS: &str = "kdeconnect.mpris";
-pub(self) const PACKET_TYPE_MPRIS_REQUEST: &str = "kdeconnect.mpris.request";
 const COVER_URL_PREFIX: &str = "file:///";
+/// Sessions usually fire several events for a single change.
+const METADATA_DEBOUNCE: Duration = Duration::from_millis(300);
+/// The thumbnail is often set a moment after the rest of the metadata.
+const THUMBNAIL_BACKOFF: Backoff = Backoff::new(Duration::from_millis(500), 5);
+/// Covers are scaled down to fit in a square of this size before being sent.
+const ALBUM_ART_MAX_SIZE: u32 = 512;
+const ALBUM_ART_JPEG_QUALITY: u8 = 85;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
@@ -98,17 +119,22 @@ pub(self) struct MprisMetadata {
     properties: WindowsMediaMetadata,
     #[serde(flatten)]
     status: WindowsPlaybackInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    volume: Option<u8>,
 }
 
 /*
     can_seek: bool,
     length: u64,
     pos: u64,
-    volume: u8,
 */
 
 #[derive(Debug)]
 struct CurrentSession {
+    /// See [`session_key`].
+    key: String,
+    /// Display name of the app, the player name without the number.
+    name: String,
     session: GlobalSystemMediaTransportControlsSession,
     media_props_token: EventRegistrationToken,
     playback_info_token: EventRegistrationToken,
@@ -141,6 +167,8 @@ pub(self) enum MprisPacket {
     },
     #[serde(rename_all = "camelCase")]
     Metadata(MprisMetadata),
+    /// Answer to "requestVolume".
+    Volume { player: String, volume: u8 },
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -166,6 +194,8 @@ pub struct MprisPlugin {
     device: DeviceHandle,
     sessions: Mutex<HashMap<String, CurrentSession>>,
     metadatas: Mutex<HashMap<String, MprisMetadata>>,
+    /// Session IDs with changed metadata, set up in `start`.
+    metadata_debouncer: OnceCell<Debouncer<String>>,
     rt_handle: tokio::runtime::Handle,
 }
 
@@ -185,21 +215,26 @@ impl MprisPlugin {
             device: dev,
             sessions: Mutex::new(HashMap::new()),
             metadatas: Mutex::new(HashMap::new()),
+            metadata_debouncer: OnceCell::new(),
             rt_handle: tokio::runtime::Handle::current(),
         })
     }
 
-    async fn update_metadata(&self, sid: &str) -> Result<()> {
+    /// Read the metadata of a session, and send it to the device if it has changed.
+    ///
+    /// Returns `false` if the thumbnail could not be loaded.
+    async fn update_metadata(&self, sid: &str) -> Result<bool> {
         let sessions = self.sessions.lock().await;
 
         let session = if let Some(session) = sessions.get(sid) {
             session
         } else {
-            log::warn!("Session {} not found", sid);
-            return Ok(());
+            log::debug!("Session {} is gone", sid);
+            return Ok(true);
         };
 
         let metadata = session.session.TryGetMediaPropertiesAsync()?.await?;
+        let aumid = session.session.SourceAppUserModelId()?.to_string_lossy();
 
         let title = metadata.Title()?.to_string_lossy();
         let artist = metadata.Artist()?.to_string_lossy();
@@ -214,7 +249,7 @@ impl MprisPlugin {
                 title,
                 album: metadata.AlbumTitle()?.to_string_lossy(),
                 artist,
-                player: session.session.SourceAppUserModelId()?.to_string_lossy(),
+                player: sid.to_string(),
                 album_art_url: None,
             },
             status: WindowsPlaybackInfo {
@@ -225,40 +260,39 @@ impl MprisPlugin {
                 is_playing: status
                     == GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing,
             },
+            volume: None,
         };
 
         drop(sessions);
 
+        match system_volume::app_volume(&aumid).await {
+            Ok(volume) => mm.volume = volume,
+            Err(e) => log::debug!("Failed to get volume of {}: {:?}", sid, e),
+        }
+
         let mut metadatas = self.metadatas.lock().await;
-        let mut update_thumbnail = true;
         if let Some(current_metadata) = metadatas.get(sid) {
-            if current_metadata == &mm && mm.properties.album_art_url.is_some() {
-                // No need to update, we already have the thumbnail
-                return Ok(());
-            }
-
-            // Metadata as a whole has changed
+            // Same track, keep the thumbnail that has already been loaded
             if current_metadata.properties == mm.properties {
-                // No need to update thumbnail
-                update_thumbnail = false;
                 mm.properties.album_art_url = current_metadata.properties.album_art_url.clone();
             }
         }
 
-        if update_thumbnail || mm.properties.album_art_url.is_none() {
+        // Covers of tracks that have been seen before are already in the cache.
+        let cached_key = album_art_key(&mm.properties);
+        if mm.properties.album_art_url.is_none() {
+            if let Some(key) = &cached_key {
+                if PAYLOAD_CACHE.get_path(key).await?.is_some() {
+                    mm.properties.album_art_url = Some(album_art_url(key));
+                }
+            }
+        }
+
+        if mm.properties.album_art_url.is_none() {
             log::info!("Loading thumbnail for {}", sid);
 
             let task = tokio::task::spawn_blocking(move || {
                 let stream = metadata.Thumbnail()?.OpenReadAsync()?.get()?;
-                let content_type = stream.ContentType()?.to_string_lossy();
-
-                let extension = match content_type.as_str() {
-                    "image/jpeg" => "jpg",
-                    "image/png" => "png",
-                    _ => {
-                        anyhow::bail!("Unsupported content type: {}", content_type);
-                    }
-                };
 
                 let size = stream.Size()? as u32;
                 let data_loader = DataReader::CreateDataReader(&stream.GetInputStreamAt(0)?)?;
@@ -275,46 +309,75 @@ impl MprisPlugin {
                 let mut buffer = vec![0; loaded_size as usize];
                 data_loader.ReadBytes(buffer.as_mut_slice())?;
 
-                let filename = format!("{:x}.{}", md5::compute(buffer.as_slice()), extension);
+                let buffer = scale_album_art(&buffer).context("Scale thumbnail")?;
+                let key = cached_key.unwrap_or_else(|| {
+                    CacheKey::new(CacheKind::AlbumArt, ContentHash::sha256(&buffer))
+                });
 
-                Ok::<_, anyhow::Error>((filename, buffer))
+                Ok::<_, anyhow::Error>((key, buffer))
             });
 
             match task.await? {
-                Ok((filename, buffer)) => {
+                Ok((key, buffer)) => {
                     log::info!("Thumbnail loaded for {} ({} bytes)", sid, buffer.len());
-                    PAYLOAD_CACHE.put(&filename, buffer).await?;
-                    mm.properties.album_art_url = Some(format!("{}{}", COVER_URL_PREFIX, filename));
+                    PAYLOAD_CACHE.put(&key, buffer).await?;
+                    mm.properties.album_art_url = Some(album_art_url(&key));
                 }
                 Err(e) => {
-                    log::warn!("Failed to load thumbnail: {:?}", e);
+                    log::debug!("Failed to load thumbnail: {:?}", e);
                 }
             }
         }
 
-        // Do update
+        let has_thumbnail = mm.properties.album_art_url.is_some();
+        if metadatas.get(sid) == Some(&mm) {
+            return Ok(has_thumbnail);
+        }
+
         metadatas.insert(sid.to_string(), mm);
         drop(metadatas);
         self.send_now_playing(sid).await;
 
-        Ok(())
+        Ok(has_thumbnail)
     }
 
-    async fn update_metadata_with_retry(&self, sid: &str) {
-        utils::log_if_error("Failed to update metadata", self.update_metadata(sid).await);
-
-        // Some delay to ensure that thumbnail is populated
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    /// Update the metadata of a session, retrying for a while if the thumbnail is missing.
+    async fn refresh_metadata(&self, sid: &str) {
+        let mut delays = THUMBNAIL_BACKOFF.delays();
+
+        loop {
+            match self.update_metadata(sid).await {
+                Ok(true) => return,
+                Ok(false) => match delays.next() {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => {
+                        log::debug!("No thumbnail for {}", sid);
+                        return;
+                    }
+                },
+                Err(e) => {
+                    log::error!("Failed to update metadata: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
 
-        utils::log_if_error("Failed to update metadata", self.update_metadata(sid).await);
+    /// Refresh the metadata of a session once its events have settled down.
+    async fn schedule_update(&self, sid: String) {
+        match self.metadata_debouncer.get() {
+            Some(debouncer) => debouncer.call(sid).await,
+            None => self.refresh_metadata(&sid).await,
+        }
     }
 
     async fn init_session(
         self: Arc<Self>,
+        id: String,
+        key: String,
+        name: String,
         session: GlobalSystemMediaTransportControlsSession,
     ) -> Result<CurrentSession> {
-        let id = session.SourceAppUserModelId()?.to_string_lossy();
-
         let this = Arc::downgrade(&self);
         let sid = id.clone();
         let media_props_token = session
@@ -325,7 +388,7 @@ impl MprisPlugin {
                     let sid = sid.clone();
 
                     this.rt_handle.clone().spawn(async move {
-                        this.update_metadata_with_retry(&sid).await;
+                        this.schedule_update(sid).await;
                     });
                 }
 
@@ -343,7 +406,7 @@ impl MprisPlugin {
                     let sid = id.clone();
 
                     this.rt_handle.clone().spawn(async move {
-                        this.update_metadata_with_retry(&sid).await;
+                        this.schedule_update(sid).await;
                     });
                 }
 
@@ -352,6 +415,8 @@ impl MprisPlugin {
             .context("Subscribe to PlaybackInfoChanged")?;
 
         Ok(CurrentSession {
+            key,
+            name,
             session,
             media_props_token,
             playback_info_token,
@@ -368,16 +433,83 @@ impl MprisPlugin {
             .into_iter()
             .collect::<Vec<_>>();
 
+        let hidden_players = self
+            .ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .mpris
+            .hidden_players;
+        let is_hidden = |s: &str| hidden_players.iter().any(|h| h.eq_ignore_ascii_case(s));
+
         let mut ids = vec![];
 
-        {
+        let removed = {
             let mut sessions_map = self.sessions.lock().await;
-            sessions_map.clear();
-
+            let mut previous = std::mem::take(&mut *sessions_map)
+                .into_iter()
+                .map(|(id, session)| (session.key.clone(), (id, session)))
+                .collect::<HashMap<_, _>>();
+
+            // Sessions that were already known keep their name, so that new sessions of the
+            // same app do not take it over. A session that fails is skipped rather than
+            // returning, which would drop all the others.
+            let mut new_sessions = vec![];
             for session in sessions {
-                let id = session.SourceAppUserModelId()?.to_string_lossy();
+                let identified = session_key(&session)
+                    .and_then(|key| Ok((key, session.SourceAppUserModelId()?.to_string_lossy())));
+                let (key, aumid) = match identified {
+                    Ok(identified) => identified,
+                    Err(e) => {
+                        log::warn!("Skipping media session: {:?}", e);
+                        continue;
+                    }
+                };
+                match previous.get(&key) {
+                    Some((_, current)) => {
+                        if is_hidden(&aumid) || is_hidden(&current.name) {
+                            continue;
+                        }
+                        if let Some((id, current)) = previous.remove(&key) {
+                            sessions_map.insert(id, current);
+                        }
+                    }
+                    None => new_sessions.push((key, aumid, session)),
+                }
+            }
+
+            for (key, aumid, session) in new_sessions {
+                let name = {
+                    let app = aumid.clone();
+                    match tokio::task::spawn_blocking(move || names::display_name(&app)).await {
+                        Ok(name) => name,
+                        Err(e) => {
+                            log::warn!("Failed to get the name of {}: {:?}", aumid, e);
+                            continue;
+                        }
+                    }
+                };
+                if is_own_session(&aumid) {
+                    continue;
+                }
+                if is_hidden(&aumid) || is_hidden(&name) {
+                    log::debug!("Hiding player {} ({})", name, aumid);
+                    continue;
+                }
 
-                match self.clone().init_session(session).await {
+                // The device identifies players by name, so they have to be unique.
+                let mut id = name.clone();
+                let mut n = 2;
+                while sessions_map.contains_key(&id) {
+                    id = format!("{} ({})", name, n);
+                    n += 1;
+                }
+
+                match self
+                    .clone()
+                    .init_session(id.clone(), key, name, session)
+                    .await
+                {
                     Ok(session) => {
                         ids.push(id.clone());
                         sessions_map.insert(id, session);
@@ -387,15 +519,22 @@ impl MprisPlugin {
                     }
                 }
             }
+
+            previous.into_values().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+
+        if !removed.is_empty() {
+            let mut metadatas = self.metadatas.lock().await;
+            for id in &removed {
+                log::debug!("Player {} is gone", id);
+                metadatas.remove(id);
+            }
         }
 
         self.send_player_list().await;
 
         for id in ids {
-            let this = self.clone();
-            tokio::spawn(async move {
-                this.update_metadata_with_retry(&id).await;
-            });
+            self.schedule_update(id).await;
         }
 
         Ok(())
@@ -408,7 +547,7 @@ impl MprisPlugin {
         };
 
         let packet = NetworkPacket::new(
-            PACKET_TYPE_MPRIS,
+            PacketKind::Mpris,
             MprisPacket::PlayerList {
                 player_list: players,
                 support_album_art_payload: Some(true),
@@ -422,7 +561,7 @@ impl MprisPlugin {
         let metadatas = self.metadatas.lock().await;
         if let Some(current_metadata) = metadatas.get(sid) {
             let packet = NetworkPacket::new(
-                PACKET_TYPE_MPRIS,
+                PacketKind::Mpris,
                 MprisPacket::Metadata(current_metadata.clone()),
             );
 
@@ -430,11 +569,11 @@ impl MprisPlugin {
         }
     }
 
-    async fn send_album_art(&self, filename: &str) {
-        let data = match PAYLOAD_CACHE.get(filename).await {
+    async fn send_album_art(&self, key: &CacheKey) {
+        let data = match PAYLOAD_CACHE.get(key).await {
             Ok(Some(data)) => data,
             Ok(None) => {
-                log::warn!("Album art not found: {}", filename);
+                log::warn!("Album art not found: {}", key.file_name());
                 return;
             }
             Err(e) => {
@@ -444,16 +583,55 @@ impl MprisPlugin {
         };
 
         let packet = NetworkPacket::new(
-            PACKET_TYPE_MPRIS,
+            PacketKind::Mpris,
             MprisPacket::TransferringAlbumArt {
                 transferring_album_art: true,
-                album_art_url: format!("{}{}", COVER_URL_PREFIX, filename),
+                album_art_url: album_art_url(key),
             },
         );
 
-        self.device
-            .send_packet(NetworkPacketWithPayload::new(packet, data))
-            .await;
+        transfer::send(
+            &self.ctx,
+            &self.device,
+            Kind::Background,
+            "Album art",
+            NetworkPacketWithPayload::new(packet, data),
+        )
+        .await;
+    }
+
+    /// The AppUserModelID of the app of a session.
+    async fn session_app(&self, sid: &str) -> Result<Option<String>> {
+        let sessions = self.sessions.lock().await;
+        match sessions.get(sid) {
+            Some(session) => Ok(Some(
+                session.session.SourceAppUserModelId()?.to_string_lossy(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn send_volume(&self, sid: &str) -> Result<()> {
+        let app = match self.session_app(sid).await? {
+            Some(app) => app,
+            None => {
+                log::warn!("Session {} not found", sid);
+                return Ok(());
+            }
+        };
+
+        if let Some(volume) = system_volume::app_volume(&app).await? {
+            let packet = NetworkPacket::new(
+                PacketKind::Mpris,
+                MprisPacket::Volume {
+                    player: sid.to_string(),
+                    volume,
+                },
+            );
+            self.device.send_packet(packet).await;
+        }
+
+        Ok(())
     }
 
     async fn execute_commands(&self, sid: &str, commands: HashMap<String, Value>) -> Result<()> {
@@ -490,6 +668,11 @@ impl MprisPlugin {
                         log::warn!("Unsupported action: {}", action);
                     }
                 },
+                ("setVolume", Value::Number(volume)) => {
+                    let volume = volume.as_u64().unwrap_or_default().min(100) as u8;
+                    let app = session.session.SourceAppUserModelId()?.to_string_lossy();
+                    system_volume::set_app_volume(&app, volume).await?;
+                }
                 (cmd, val) => {
                     log::warn!("Unsupported command: {:?}", (cmd, val));
                 }
@@ -498,35 +681,8 @@ impl MprisPlugin {
 
         Ok(())
     }
-}
-
-#[async_trait::async_trait]
-impl KdeConnectPlugin for MprisPlugin {
-    async fn start(self: Arc<Self>) -> Result<()> {
-        utils::log_if_error(
-            "Failed to initialize sessions",
-            self.handle_sessions_changed().await,
-        );
-        Ok(())
-    }
-
-    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
-        match event {
-            SystemEvent::MediaSessionsChanged => {
-                utils::log_if_error(
-                    "Failed to update sessions",
-                    self.handle_sessions_changed().await,
-                );
-            }
-            _ => {}
-        };
-
-        Ok(())
-    }
-
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        let body: MprisRequest = packet.into_body()?;
 
+    async fn receive_request(&self, body: MprisRequest) -> Result<()> {
         if body.request_player_list == Some(true) {
             log::debug!("Request player list");
 
@@ -539,14 +695,22 @@ impl KdeConnectPlugin for MprisPlugin {
             self.send_now_playing(id).await;
         }
 
+        if let (Some(id), Some(true)) = (&body.player, body.request_volume) {
+            log::debug!("Request volume for {}", id);
+
+            utils::log_if_error("Failed to send volume", self.send_volume(id).await);
+        }
+
         if let Some(url) = &body.album_art_url {
             log::debug!("Request album art: {}", url);
 
-            if url.len() > COVER_URL_PREFIX.len() {
-                let filename = &url[COVER_URL_PREFIX.len()..];
-                self.send_album_art(filename).await;
-            } else {
-                log::warn!("Invalid album art url (too short): {}", url);
+            let key = url
+                .strip_prefix(COVER_URL_PREFIX)
+                .and_then(CacheKey::parse)
+                .filter(|key| key.kind == CacheKind::AlbumArt);
+            match key {
+                Some(key) => self.send_album_art(&key).await,
+                None => log::warn!("Invalid album art url: {}", url),
             }
         }
 
@@ -560,19 +724,174 @@ impl KdeConnectPlugin for MprisPlugin {
 
         Ok(())
     }
+}
 
-    async fn dispose(&self) {
-        // Drop all sessions
-        self.sessions.lock().await.clear();
-        self.metadatas.lock().await.clear();
+/// Local players paused by [`pause_playing`].
+#[derive(Debug, Default)]
+pub struct PausedPlayers {
+    sessions: Vec<GlobalSystemMediaTransportControlsSession>,
+}
+
+impl PausedPlayers {
+    /// Resume the players that were paused.
+    pub async fn resume(self) {
+        for session in self.sessions {
+            let res: windows::core::Result<bool> = async { session.TryPlayAsync()?.await }.await;
+            utils::log_if_error("Failed to resume player", res);
+        }
+    }
+}
+
+/// Pause all local players that are playing, e.g. during a phone call.
+///
+/// Players of the devices are left alone, as they are not played on this computer.
+pub async fn pause_playing() -> Result<PausedPlayers> {
+    let manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()?.await?;
+
+    let sessions = manager.GetSessions()?.into_iter().collect::<Vec<_>>();
+
+    let mut paused = PausedPlayers::default();
+    for session in sessions {
+        let aumid = session.SourceAppUserModelId()?.to_string_lossy();
+        if is_own_session(&aumid) {
+            continue;
+        }
+
+        let status = session.GetPlaybackInfo()?.PlaybackStatus()?;
+        if status != GlobalSystemMediaTransportControlsSessionPlaybackStatus::Playing {
+            continue;
+        }
+
+        if session.TryPauseAsync()?.await? {
+            log::info!("Paused {}", aumid);
+            paused.sessions.push(session);
+        }
+    }
+
+    Ok(paused)
+}
+
+/// Identifies a session across updates of the session list: several sessions may have the same
+/// AppUserModelID, but they are different objects.
+fn session_key(session: &GlobalSystemMediaTransportControlsSession) -> Result<String> {
+    let aumid = session.SourceAppUserModelId()?.to_string_lossy();
+    let identity = session.cast::<IUnknown>()?;
+    Ok(format!("{}#{:x}", aumid, identity.as_raw() as usize))
+}
+
+/// Whether a session belongs to this application, i.e. shows a player of a device (see
+/// [`remote`]). Sending it back to the devices would create a loop.
+fn is_own_session(aumid: &str) -> bool {
+    if aumid.eq_ignore_ascii_case(crate::AUM_ID) {
+        return true;
+    }
+
+    // Without a package, the session is identified by the executable name.
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .is_some_and(|name| name.eq_ignore_ascii_case(aumid))
+}
+
+/// Key of the cached cover of a track, shared by all tracks of the same album.
+///
+/// Returns `None` if there is not enough information to identify the track.
+fn album_art_key(props: &WindowsMediaMetadata) -> Option<CacheKey> {
+    let key = if !props.album.is_empty() {
+        format!("{}\0{}", props.artist, props.album)
+    } else if !props.title.is_empty() {
+        format!("{}\0{}\0{}", props.player, props.artist, props.title)
+    } else {
+        return None;
+    };
+
+    Some(CacheKey::new(CacheKind::AlbumArt, ContentHash::sha256(key)))
+}
+
+/// The URL of a cached cover, which the device asks for to get it.
+fn album_art_url(key: &CacheKey) -> String {
+    format!("{}{}", COVER_URL_PREFIX, key.file_name())
+}
+
+/// Re-encode a cover as a JPEG no larger than `ALBUM_ART_MAX_SIZE`.
+fn scale_album_art(data: &[u8]) -> Result<Vec<u8>> {
+    let format = image::guess_format(data)?;
+    let image = image::load_from_memory_with_format(data, format)?;
+
+    let fits = image.width() <= ALBUM_ART_MAX_SIZE && image.height() <= ALBUM_ART_MAX_SIZE;
+    if fits && format == image::ImageFormat::Jpeg {
+        return Ok(data.to_vec());
     }
+
+    let image = if fits {
+        image
+    } else {
+        image.resize(
+            ALBUM_ART_MAX_SIZE,
+            ALBUM_ART_MAX_SIZE,
+            image::imageops::FilterType::Triangle,
+        )
+    };
+    let image = image.into_rgb8();
+
+    let mut out = vec![];
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, ALBUM_ART_JPEG_QUALITY)
+        .encode_image(&image)?;
+
+    Ok(out)
 }
 
-impl KdeConnectPluginMetadata for MprisPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_MPRIS_REQUEST.into()]
+plugin_packets! {
+    MprisPlugin {
+        incoming {
+            MprisRequest(MprisRequest) => receive_request,
+        }
+        outgoing [Mpris]
+        events [Media]
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for MprisPlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        let this = Arc::downgrade(&self);
+        let rt_handle = self.rt_handle.clone();
+        let debouncer = Debouncer::new(
+            move |sid: String| {
+                if let Some(this) = this.upgrade() {
+                    rt_handle.spawn(async move {
+                        this.refresh_metadata(&sid).await;
+                    });
+                }
+            },
+            METADATA_DEBOUNCE,
+        );
+        self.metadata_debouncer.set(debouncer).ok();
+
+        utils::log_if_error(
+            "Failed to initialize sessions",
+            self.handle_sessions_changed().await,
+        );
+        Ok(())
+    }
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        match event {
+            SystemEvent::MediaSessionsChanged => {
+                utils::log_if_error(
+                    "Failed to update sessions",
+                    self.handle_sessions_changed().await,
+                );
+            }
+            _ => {}
+        };
+
+        Ok(())
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_MPRIS.into()]
+
+    async fn dispose(&self) {
+        // Drop all sessions
+        self.sessions.lock().await.clear();
+        self.metadatas.lock().await.clear();
     }
 }
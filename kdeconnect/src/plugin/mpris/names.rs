@@ -0,0 +1,126 @@
+//! Friendly names for media sessions, which are identified by the AppUserModelID of their app.
+
+use std::collections::HashMap;
+
+use windows::{
+    core::HSTRING,
+    Win32::{
+        System::Com::{CoInitializeEx, CoTaskMemFree, CoUninitialize, COINIT_MULTITHREADED},
+        UI::Shell::{
+            FOLDERID_AppsFolder, IShellItem, SHCreateItemInKnownFolder, SIGDN_NORMALDISPLAY,
+        },
+    },
+};
+
+/// Apps whose AppUserModelID is not registered in the Apps folder, or whose name there is
+/// not what users call them. Matched case-insensitively against the whole ID.
+const KNOWN_APPS: &[(&str, &str)] = &[
+    ("Spotify.exe", "Spotify"),
+    ("SpotifyAB.SpotifyMusic_zpdnekdrzrea0!Spotify", "Spotify"),
+    ("chrome", "Google Chrome"),
+    ("chrome.exe", "Google Chrome"),
+    ("msedge", "Microsoft Edge"),
+    ("msedge.exe", "Microsoft Edge"),
+    ("firefox.exe", "Firefox"),
+    ("308046B0AF4A39CB", "Firefox"),
+    ("vlc.exe", "VLC"),
+    ("foobar2000.exe", "foobar2000"),
+    ("MusicBee.exe", "MusicBee"),
+    ("AIMP.exe", "AIMP"),
+    (
+        "Microsoft.ZuneMusic_8wekyb3d8bbwe!Microsoft.ZuneMusic",
+        "Media Player",
+    ),
+    (
+        "Microsoft.ZuneVideo_8wekyb3d8bbwe!Microsoft.ZuneVideo",
+        "Movies & TV",
+    ),
+];
+
+lazy_static::lazy_static! {
+    static ref NAME_CACHE: std::sync::Mutex<HashMap<String, String>> = Default::default();
+}
+
+/// Get a name for the app with the given AppUserModelID, suitable to be shown to users.
+///
+/// This blocks while the shell is queried, and the result is cached.
+pub fn display_name(aumid: &str) -> String {
+    if let Some(name) = NAME_CACHE.lock().unwrap().get(aumid) {
+        return name.clone();
+    }
+
+    let name = KNOWN_APPS
+        .iter()
+        .find(|(id, _)| id.eq_ignore_ascii_case(aumid))
+        .map(|(_, name)| name.to_string())
+        .or_else(|| match shell_display_name(aumid) {
+            Ok(name) if !name.is_empty() => Some(name),
+            Ok(_) => None,
+            Err(e) => {
+                log::debug!("No display name for {}: {:?}", aumid, e);
+                None
+            }
+        })
+        .unwrap_or_else(|| fallback_name(aumid));
+
+    NAME_CACHE
+        .lock()
+        .unwrap()
+        .insert(aumid.to_string(), name.clone());
+    name
+}
+
+/// Balances a successful `CoInitializeEx` on the current thread when dropped.
+struct ComGuard;
+
+impl Drop for ComGuard {
+    fn drop(&mut self) {
+        unsafe { CoUninitialize() };
+    }
+}
+
+/// Look up the app in the Apps folder, which contains everything in the Start menu.
+fn shell_display_name(aumid: &str) -> windows::core::Result<String> {
+    unsafe {
+        // Threads already in another apartment keep it, which is fine for this call. Declared
+        // first, so that the objects below are released before COM is uninitialized.
+        let _com = CoInitializeEx(None, COINIT_MULTITHREADED)
+            .ok()
+            .map(|_| ComGuard);
+
+        let item: IShellItem =
+            SHCreateItemInKnownFolder(&FOLDERID_AppsFolder, 0, &HSTRING::from(aumid))?;
+        let name = item.GetDisplayName(SIGDN_NORMALDISPLAY)?;
+        let res = name.to_string();
+        CoTaskMemFree(Some(name.0 as *const _));
+
+        Ok(res.unwrap_or_default())
+    }
+}
+
+/// Make something readable out of an executable name or a packaged app ID.
+fn fallback_name(aumid: &str) -> String {
+    // "Publisher.Package_hash!App"
+    let name = match aumid.split_once('!') {
+        Some((package, app)) if app.eq_ignore_ascii_case("App") => package
+            .split('_')
+            .next()
+            .and_then(|p| p.rsplit('.').next())
+            .unwrap_or(package),
+        Some((_, app)) => app.rsplit('.').next().unwrap_or(app),
+        None => aumid,
+    };
+
+    // "C:\Program Files\Foo\Foo.exe" or "Foo.exe"
+    let name = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let name = match name.len().checked_sub(4) {
+        Some(i) if name.is_char_boundary(i) && name[i..].eq_ignore_ascii_case(".exe") => &name[..i],
+        _ => name,
+    };
+
+    if name.is_empty() {
+        aumid.to_string()
+    } else {
+        name.to_string()
+    }
+}
@@ -1,20 +1,34 @@
 use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    context::AppContextRef,
+    context::{AppContextRef, TrayUpdater},
     device::DeviceHandle,
     event::SystemEvent,
     packet::NetworkPacket,
-    plugin::{KdeConnectPlugin, KdeConnectPluginMetadata},
+    plugin::{
+        packets::{plugin_packets, PacketKind},
+        KdeConnectPlugin,
+    },
+    tray::{DeviceMenu, TrayMenu},
 };
 use anyhow::Result;
-use tao::menu::{ContextMenu, MenuId, MenuItem, MenuItemAttributes};
-use tokio::sync::RwLock;
-
-use super::{
-    MprisMetadata, MprisPacket, MprisRequest, PACKET_TYPE_MPRIS, PACKET_TYPE_MPRIS_REQUEST,
+use tao::menu::MenuId;
+use tokio::{
+    sync::{mpsc, Mutex, RwLock},
+    task::JoinHandle,
+};
+use windows::{
+    core::HSTRING,
+    Foundation::{EventRegistrationToken, TypedEventHandler},
+    Media::{
+        MediaPlaybackStatus, MediaPlaybackType, Playback::MediaPlayer,
+        SystemMediaTransportControls, SystemMediaTransportControlsButton,
+        SystemMediaTransportControlsButtonPressedEventArgs,
+    },
 };
 
+use super::{MprisMetadata, MprisPacket, MprisRequest};
+
 #[derive(Debug)]
 struct Player {
     metadata: Option<MprisMetadata>,
@@ -36,26 +50,158 @@ impl Player {
     }
 }
 
+impl Player {
+    fn is_playing(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|m| m.status.is_playing)
+    }
+}
+
+/// A Windows media session showing a player of the device, so that the media keys and the
+/// volume flyout control it.
+#[derive(Debug)]
+struct RemoteSession {
+    /// Never plays anything, it only provides the controls.
+    _player: MediaPlayer,
+    controls: SystemMediaTransportControls,
+    button_token: EventRegistrationToken,
+}
+
+impl RemoteSession {
+    /// Create the session, sending the MPRIS action of each button pressed to `buttons`.
+    fn new(buttons: mpsc::UnboundedSender<&'static str>) -> Result<Self> {
+        let player = MediaPlayer::new()?;
+        // Otherwise the player handles the buttons itself.
+        player.CommandManager()?.SetIsEnabled(false)?;
+
+        let controls = player.SystemMediaTransportControls()?;
+        let button_token = controls.ButtonPressed(&TypedEventHandler::new(
+            move |_, args: &Option<SystemMediaTransportControlsButtonPressedEventArgs>| {
+                let args = match args {
+                    Some(args) => args,
+                    None => return Ok(()),
+                };
+                let action = match args.Button()? {
+                    SystemMediaTransportControlsButton::Play => "Play",
+                    SystemMediaTransportControlsButton::Pause => "Pause",
+                    SystemMediaTransportControlsButton::Stop => "Stop",
+                    SystemMediaTransportControlsButton::Next => "Next",
+                    SystemMediaTransportControlsButton::Previous => "Previous",
+                    _ => return Ok(()),
+                };
+                buttons.send(action).ok();
+                Ok(())
+            },
+        ))?;
+
+        Ok(Self {
+            _player: player,
+            controls,
+            button_token,
+        })
+    }
+
+    fn update(&self, metadata: &MprisMetadata) -> Result<()> {
+        let controls = &self.controls;
+        controls.SetIsEnabled(true)?;
+        controls.SetIsPlayEnabled(metadata.status.can_play)?;
+        controls.SetIsPauseEnabled(metadata.status.can_pause)?;
+        controls.SetIsStopEnabled(true)?;
+        controls.SetIsNextEnabled(metadata.status.can_go_next)?;
+        controls.SetIsPreviousEnabled(metadata.status.can_go_previous)?;
+        controls.SetPlaybackStatus(if metadata.status.is_playing {
+            MediaPlaybackStatus::Playing
+        } else {
+            MediaPlaybackStatus::Paused
+        })?;
+
+        let updater = controls.DisplayUpdater()?;
+        updater.SetType(MediaPlaybackType::Music)?;
+        let music = updater.MusicProperties()?;
+        music.SetTitle(&HSTRING::from(&metadata.properties.title))?;
+        music.SetArtist(&HSTRING::from(&metadata.properties.artist))?;
+        music.SetAlbumTitle(&HSTRING::from(&metadata.properties.album))?;
+        updater.Update()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for RemoteSession {
+    fn drop(&mut self) {
+        self.controls.RemoveButtonPressed(self.button_token).ok();
+        self.controls.SetIsEnabled(false).ok();
+    }
+}
+
 #[derive(Debug)]
 pub struct MprisRemotePlugin {
     ctx: AppContextRef,
     dev: DeviceHandle,
     players: RwLock<HashMap<String, Player>>,
+    /// The player shown in the Windows media session, and the session itself.
+    session: Mutex<Option<(String, RemoteSession)>>,
+    button_tx: mpsc::UnboundedSender<&'static str>,
+    button_rx: Mutex<Option<mpsc::UnboundedReceiver<&'static str>>>,
+    button_task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl MprisRemotePlugin {
     pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        let (button_tx, button_rx) = mpsc::unbounded_channel();
+
         Self {
             ctx,
             dev,
             players: RwLock::new(HashMap::new()),
+            session: Mutex::new(None),
+            button_tx,
+            button_rx: Mutex::new(Some(button_rx)),
+            button_task: Mutex::new(None),
         }
     }
 
+    /// Show the most relevant player in the Windows media session: the one that is playing,
+    /// preferring the one already shown.
+    async fn update_session(&self) -> Result<()> {
+        let players = self.players.read().await;
+        let mut session = self.session.lock().await;
+
+        let current = session
+            .as_ref()
+            .and_then(|(id, _)| players.get_key_value(id));
+        let chosen = match current {
+            Some((id, player)) if player.is_playing() => Some((id, player)),
+            _ => players
+                .iter()
+                .find(|(_, p)| p.is_playing())
+                .or(current)
+                .or_else(|| players.iter().find(|(_, p)| p.metadata.is_some())),
+        };
+
+        let (id, metadata) = match chosen.and_then(|(id, p)| Some((id, p.metadata.as_ref()?))) {
+            Some(chosen) => chosen,
+            None => {
+                *session = None;
+                return Ok(());
+            }
+        };
+
+        match session.as_mut() {
+            Some((current_id, _)) if current_id != id => *current_id = id.clone(),
+            Some(_) => {}
+            None => *session = Some((id.clone(), RemoteSession::new(self.button_tx.clone())?)),
+        }
+        if let Some((_, s)) = session.as_ref() {
+            s.update(metadata)?;
+        }
+
+        Ok(())
+    }
+
     async fn request_player_list(&self) {
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_MPRIS_REQUEST,
+                PacketKind::MprisRequest,
                 MprisRequest {
                     request_now_playing: Some(true),
                     ..Default::default()
@@ -67,7 +213,7 @@ impl MprisRemotePlugin {
     async fn request_now_playing(&self, player_id: &str) {
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_MPRIS_REQUEST,
+                PacketKind::MprisRequest,
                 MprisRequest {
                     player: Some(player_id.to_string()),
                     request_now_playing: Some(true),
@@ -83,7 +229,7 @@ impl MprisRemotePlugin {
 
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_MPRIS_REQUEST,
+                PacketKind::MprisRequest,
                 MprisRequest {
                     player: Some(player_id.to_string()),
                     commands,
@@ -92,17 +238,8 @@ impl MprisRemotePlugin {
             ))
             .await;
     }
-}
 
-#[async_trait::async_trait]
-impl KdeConnectPlugin for MprisRemotePlugin {
-    async fn start(self: Arc<Self>) -> Result<()> {
-        self.request_player_list().await;
-        Ok(())
-    }
-
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        let packet = packet.into_body::<MprisPacket>()?;
+    async fn receive_mpris(&self, packet: MprisPacket) -> Result<()> {
         match packet {
             MprisPacket::PlayerList { player_list, .. } => {
                 {
@@ -119,30 +256,72 @@ impl KdeConnectPlugin for MprisRemotePlugin {
                         players.insert(player.clone(), Player::new(self.dev.device_id(), &player));
                     }
                 }
+                self.update_session().await?;
                 self.ctx.update_tray().await;
             }
             MprisPacket::Metadata(metadata) => {
                 let mut players = self.players.write().await;
                 if let Some(player) = players.get_mut(&metadata.properties.player) {
                     player.metadata = Some(metadata);
+                    drop(players);
+
+                    self.update_session().await?;
                     self.ctx.update_tray().await;
                 }
             }
-            MprisPacket::TransferringAlbumArt { .. } => {
+            MprisPacket::TransferringAlbumArt { .. } | MprisPacket::Volume { .. } => {
                 // Ignore
             }
         }
         Ok(())
     }
+}
 
-    async fn tray_menu(&self, menu: &mut ContextMenu) {
-        let players = self.players.read().await;
-        if players.is_empty() {
-            // Hide the menu
-            return;
+plugin_packets! {
+    MprisRemotePlugin {
+        incoming {
+            Mpris(MprisPacket) => receive_mpris,
+        }
+        outgoing [MprisRequest]
+        events [Tray]
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for MprisRemotePlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        if let Some(mut button_rx) = self.button_rx.lock().await.take() {
+            let this = Arc::downgrade(&self);
+
+            let task = tokio::spawn(async move {
+                while let Some(action) = button_rx.recv().await {
+                    let this = match this.upgrade() {
+                        Some(this) => this,
+                        None => break,
+                    };
+                    let player = this.session.lock().await.as_ref().map(|(id, _)| id.clone());
+                    if let Some(player) = player {
+                        this.send_action(&player, action).await;
+                    }
+                }
+            });
+            *self.button_task.lock().await = Some(task);
         }
 
-        let mut submenu = ContextMenu::new();
+        self.request_player_list().await;
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        if let Some(task) = self.button_task.lock().await.take() {
+            task.abort();
+        }
+        *self.session.lock().await = None;
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        let players = self.players.read().await;
+        let mut submenu = TrayMenu::new();
 
         for (id, player) in players.iter() {
             if let Some(metadata) = player.metadata.as_ref() {
@@ -155,30 +334,26 @@ impl KdeConnectPlugin for MprisRemotePlugin {
                         "Paused"
                     }
                 );
-                submenu.add_item(MenuItemAttributes::new(&title).with_id(player.play_menu_id));
+                submenu.add_action(player.play_menu_id, title);
 
                 if !metadata.properties.now_playing.is_empty() {
-                    submenu.add_item(
-                        MenuItemAttributes::new(&metadata.properties.now_playing)
-                            .with_enabled(false),
-                    );
+                    submenu.add_label(&metadata.properties.now_playing);
                 }
                 if metadata.status.can_go_previous {
-                    submenu.add_item(
-                        MenuItemAttributes::new("Previous").with_id(player.previous_menu_id),
-                    );
+                    submenu.add_action(player.previous_menu_id, "Previous");
                 }
                 if metadata.status.can_go_next {
-                    submenu.add_item(MenuItemAttributes::new("Next").with_id(player.next_menu_id));
+                    submenu.add_action(player.next_menu_id, "Next");
                 }
             } else {
-                submenu.add_item(MenuItemAttributes::new(&format!("{}\t\t\t  Unknown", id,)));
+                submenu.add_label(format!("{}\t\t\t  Unknown", id));
             }
 
-            submenu.add_native_item(MenuItem::Separator);
+            submenu.add_separator();
         }
 
-        menu.add_submenu("Media Control", true, submenu)
+        // Hidden when there are no players
+        menu.actions.add_submenu("Media Control", submenu)
     }
 
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
@@ -198,13 +373,3 @@ impl KdeConnectPlugin for MprisRemotePlugin {
         Ok(())
     }
 }
-
-impl KdeConnectPluginMetadata for MprisRemotePlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_MPRIS.into()]
-    }
-
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_MPRIS_REQUEST.into()]
-    }
-}
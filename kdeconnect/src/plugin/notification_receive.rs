@@ -29,6 +29,9 @@ in PNG format. If there another field will be present:
 The content of these fields is used to display the notifications to the user.
 Note that if we receive a second notification with the same "id", the existing notification is updated.
 
+Devices send all their notifications again when they reconnect. Notifications that were already
+shown, with the same content, are remembered for a day across restarts and not shown again.
+
 If the user dismisses a notification from this device, we have to request the
 other device to remove it. This is done by sending a package with the fields
 "id" set to the id of the notification we want to dismiss and a boolean "cancel"
@@ -46,19 +49,66 @@ use std::{
 
 use anyhow::{Context, Result};
 use lru_cache::LruCache;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuId, MenuItemAttributes};
+use tao::menu::MenuId;
 use tokio::sync::Mutex;
-use winrt_toast::{DismissalReason, Header, Text, Toast};
+use winrt_toast::{
+    Audio, DismissalReason, Header, Mirroring, Scenario, Text, Toast, ToastArgs, ToastPriority,
+    ToastTag, WinToastError,
+};
 
 use crate::{
-    cache::PAYLOAD_CACHE, context::AppContextRef, device::DeviceHandle, event::SystemEvent,
-    packet::NetworkPacket, utils,
+    cache::{CacheKey, CacheKind, PAYLOAD_CACHE},
+    config::NotificationAlert,
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::{NetworkPacket, Payload},
+    transfer::{Direction, Kind, Transfer, TransferState},
+    tray::{DeviceMenu, TrayMenu},
+    utils::{
+        self,
+        hash::ContentHash,
+        rate_limit::{Rate, RateLimiter},
+        seen::SeenCache,
+        toast::{ToastCallbacks, TOASTS},
+    },
 };
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
-const PACKET_TYPE_NOTIFICATION_REQUEST: &str = "kdeconnect.notification.request";
+/// Activation argument of the header of the toasts.
+const ACTION_HEADER_CLICK: &str = "headerClick";
+/// Activation argument of the toast itself.
+const ACTION_OPEN: &str = "open";
+
+/// Icons larger than this are not downloaded.
+const ICON_MAX_PAYLOAD_SIZE: u64 = 4 * 1024 * 1024;
+/// Icons are scaled down to fit in a square of this size, larger than any toast shows them.
+const ICON_MAX_SIZE: u32 = 256;
+
+/// Devices send all their notifications again when they reconnect, those shown within this
+/// time are not shown again.
+const SEEN_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+const SEEN_MAX_ENTRIES: usize = 2000;
+
+/// Notifications shown at most per [`TOAST_WINDOW`] for each device, so that a device sending
+/// a flood of them cannot bury the screen.
+const MAX_TOASTS: u32 = 20;
+const TOAST_WINDOW: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    /// Notifications already shown, of all devices.
+    static ref SEEN_NOTIFICATIONS: SeenCache = SeenCache::load(
+        crate::data_dir().join("seen-notifications.json"),
+        SEEN_TTL,
+        SEEN_MAX_ENTRIES,
+    );
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -81,29 +131,67 @@ struct IncomingNotification {
     ticker: Option<String>,
     title: Option<String>,
     text: Option<String>,
+    /// Only set for notifications that can be replied to, i.e. conversations.
+    request_reply_id: Option<String>,
+    #[serde(default)]
+    silent: bool,
 }
 
 #[derive(Debug)]
 pub struct NotificationReceivePlugin {
     ctx: AppContextRef,
     device: DeviceHandle,
-    group_hash: String,
+    group_hash: ToastTag,
     id_to_icon_path: Mutex<LruCache<String, PathBuf>>,
     mute_menu_id: MenuId,
     muted: AtomicBool,
+    action_center_only_menu_id: MenuId,
+    alert_menu_ids: Vec<(NotificationAlert, MenuId)>,
+    /// Recently seen app names, and the menu items used to mute them.
+    recent_apps: Mutex<LruCache<String, MenuId>>,
+    blocked_titles: Vec<Regex>,
+    toast_limiter: std::sync::Mutex<RateLimiter<()>>,
 }
 
 impl NotificationReceivePlugin {
     pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        let settings = ctx
+            .settings()
+            .device(dev.device_id())
+            .plugins
+            .notification_receive;
+        let blocked_titles = settings
+            .blocked_titles
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    log::warn!("Invalid title filter {:?}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+
         Self {
             ctx,
-            group_hash: format!(
-                "{:x}",
-                md5::compute(&format!("receive_notifications:{}", dev.device_id()))
-            ),
+            group_hash: utils::device_toast_group(dev.device_id()),
             mute_menu_id: MenuId::new(&format!("{}:notifications:mute", dev.device_id())),
             muted: AtomicBool::new(false),
+            action_center_only_menu_id: MenuId::new(&format!(
+                "{}:notifications:action_center_only",
+                dev.device_id()
+            )),
+            alert_menu_ids: NotificationAlert::ALL
+                .into_iter()
+                .map(|alert| {
+                    let id = format!("{}:notifications:alert:{:?}", dev.device_id(), alert);
+                    (alert, MenuId::new(&id))
+                })
+                .collect(),
             id_to_icon_path: Mutex::new(LruCache::new(100)),
+            recent_apps: Mutex::new(LruCache::new(10)),
+            blocked_titles,
+            toast_limiter: std::sync::Mutex::new(RateLimiter::new(MAX_TOASTS, TOAST_WINDOW)),
             device: dev,
         }
     }
@@ -111,10 +199,12 @@ impl NotificationReceivePlugin {
     async fn show_notification(
         &self,
         notification: IncomingNotification,
-        payload_info: Option<PayloadInfo>,
+        payload: Option<Payload>,
     ) -> Result<()> {
-        let id_hash = format!("{:x}", md5::compute(&notification.id));
-        let app_name_hash = format!("{:x}", md5::compute(&notification.app_name));
+        let id_hash = ToastTag::hashed(&notification.id);
+        let app_name_hash = ContentHash::md5(&notification.app_name).to_string();
+
+        let suppress_popup = notification.silent || self.is_action_center_only();
 
         let (title, text) =
             if let (Some(title), Some(text)) = (notification.title, notification.text) {
@@ -126,23 +216,53 @@ impl NotificationReceivePlugin {
         let icon_path = {
             let mut id_to_icon_path = self.id_to_icon_path.lock().await;
 
-            if let Some(h) = notification.payload_hash {
+            if let Some(hash) = notification
+                .payload_hash
+                .as_deref()
+                .and_then(ContentHash::parse_md5)
+            {
                 drop(id_to_icon_path);
 
-                let name = format!("{}.png", h);
-
-                let icon_path = if let Some(path) = PAYLOAD_CACHE.get_path(&name).await? {
-                    Some(path)
-                } else if let Some(payload_info) = payload_info {
-                    let data = self
-                        .device
-                        .fetch_payload(payload_info.port, payload_info.size as usize)
-                        .await?;
-
-                    PAYLOAD_CACHE.put(&name, data).await?;
-                    let path = PAYLOAD_CACHE.get_path(&name).await?.unwrap();
+                // Older versions cached the icons as sent, whatever their format.
+                let key = CacheKey::new(CacheKind::NotificationIcon, hash);
 
+                let icon_path = if let Some(path) = PAYLOAD_CACHE.get_path(&key).await? {
                     Some(path)
+                } else if let Some(payload) = payload {
+                    if payload.size > ICON_MAX_PAYLOAD_SIZE {
+                        log::warn!("Ignoring icon of {} bytes", payload.size);
+                        None
+                    } else {
+                        let transfer = Transfer::start(
+                            &self.ctx,
+                            &self.device,
+                            Direction::Incoming,
+                            Kind::Background,
+                            "Notification icon",
+                            payload.size,
+                        )
+                        .await;
+                        let res = transfer
+                            .fetch_verified(&self.device, payload.port, payload.size as usize, hash)
+                            .await;
+                        let state = match &res {
+                            Ok(_) => TransferState::Completed,
+                            Err(e) => TransferState::failed(e),
+                        };
+                        transfer.finish(state).await;
+                        let data = res?;
+
+                        match tokio::task::spawn_blocking(move || icon_to_png(&data)).await? {
+                            Ok(png) => {
+                                PAYLOAD_CACHE.put(&key, png).await?;
+                                PAYLOAD_CACHE.get_path(&key).await?
+                            }
+                            Err(e) => {
+                                log::warn!("Failed to convert notification icon: {:?}", e);
+                                None
+                            }
+                        }
+                    }
                 } else {
                     None
                 };
@@ -160,33 +280,57 @@ impl NotificationReceivePlugin {
             }
         };
 
+        let is_conversation = notification.request_reply_id.is_some();
+
         let mut toast = Toast::new();
         toast
             .header(Header::new(
                 &app_name_hash,
                 &notification.app_name,
-                "action=headerClick",
+                ToastArgs::new().with("action", ACTION_HEADER_CLICK),
             ))
+            .launch(
+                ToastArgs::new()
+                    .with("action", ACTION_OPEN)
+                    .with("id", &notification.id),
+            )
             .text1(title)
             .text2(text)
             .text3(Text::new(self.device.device_name()).as_attribution())
-            .expires_in(Duration::from_secs(60 * 60 * 12))
-            .tag(&id_hash)
+            .tag(id_hash)
             .group(&self.group_hash)
-            .remote_id(&notification.id);
+            .remote_id(&notification.id)
+            .suppress_popup(suppress_popup)
+            // The notification came from another device, do not mirror it back.
+            .mirroring(Mirroring::Disabled);
+
+        match self.alert() {
+            NotificationAlert::Silent => {
+                toast.audio(Audio::silent());
+            }
+            NotificationAlert::Normal => {}
+            NotificationAlert::Urgent => {
+                toast
+                    .scenario(Scenario::Urgent)
+                    .priority(ToastPriority::High);
+            }
+        }
 
         if let Some(path) = icon_path {
-            toast.image(
-                1,
-                winrt_toast::Image::new_local(path)?
-                    .with_placement(winrt_toast::content::image::ImagePlacement::AppLogoOverride),
-            );
+            let image = winrt_toast::Image::new_local(path)?;
+            // The icon of a conversation is the photo of the contact.
+            let image = if is_conversation {
+                image.as_avatar()
+            } else {
+                image.with_placement(winrt_toast::content::image::ImagePlacement::AppLogoOverride)
+            };
+            toast.image(1, image);
         }
 
         let id = notification.id.clone();
         let dev = self.device.clone();
         let rt_handle = tokio::runtime::Handle::current();
-        let on_dismissed = Box::new(move |reason| match reason {
+        let on_dismissed = move |reason: winrt_toast::Result<DismissalReason>| match reason {
             Ok(DismissalReason::UserCanceled) => {
                 // Dismiss the remote notification
                 let dev = dev.clone();
@@ -194,7 +338,7 @@ impl NotificationReceivePlugin {
 
                 let task = async move {
                     dev.send_packet(NetworkPacket::new(
-                        PACKET_TYPE_NOTIFICATION_REQUEST,
+                        PacketKind::NotificationRequest,
                         serde_json::json!({
                             "cancel": id,
                         }),
@@ -208,68 +352,211 @@ impl NotificationReceivePlugin {
             Err(e) => {
                 tracing::error!("Failed to get dismissal reason: {:?}", e);
             }
-        });
+        };
 
         let id = notification.id.clone();
-        let on_failed = Box::new(move |e| {
+        let on_failed = move |e: WinToastError| {
             tracing::error!("Failed to show notification {}: {:?}", id, e);
-        });
+        };
 
-        let on_activated = Box::new(move |_arg| {});
+        let app_name = notification.app_name.clone();
+        let on_activated = move |arg: winrt_toast::Result<String>| {
+            let args = match arg {
+                Ok(arg) => ToastArgs::parse(&arg),
+                Err(e) => {
+                    tracing::error!("Failed to get activation arguments: {:?}", e);
+                    return;
+                }
+            };
+            match args.get("action") {
+                Some(ACTION_HEADER_CLICK) => {
+                    tracing::debug!("Header of notifications from {} clicked", app_name)
+                }
+                Some(ACTION_OPEN) => {
+                    tracing::debug!("Notification {:?} clicked", args.get("id"))
+                }
+                action => tracing::warn!("Unknown notification activation {:?}", action),
+            }
+        };
 
-        tokio::task::spawn_blocking(move || {
-            utils::TOAST_MANAGER.show_with_callbacks(
-                &toast,
-                Some(on_activated),
-                Some(on_dismissed),
-                Some(on_failed),
-            )
-        })
-        .await??;
+        let callbacks = ToastCallbacks::new()
+            .on_activated(on_activated)
+            .on_dismissed(on_dismissed)
+            .on_failed(on_failed);
+        TOASTS.show_with_callbacks(toast, callbacks).await?;
 
         Ok(())
     }
 
     async fn remove_notification(&self, id: &str) -> Result<()> {
-        let group_hash = self.group_hash.clone();
-        let id_hash = format!("{:x}", md5::compute(id));
+        let id_hash = ToastTag::hashed(id);
 
-        tokio::task::spawn_blocking(move || {
-            utils::TOAST_MANAGER.remove_grouped_tag(&group_hash, &id_hash)
-        })
-        .await??;
-
-        Ok(())
+        TOASTS
+            .remove_grouped_tag(self.group_hash.as_str(), id_hash.as_str())
+            .await
     }
 
     fn is_muted(&self) -> bool {
         self.muted.load(Ordering::Relaxed)
     }
-}
 
-struct PayloadInfo {
-    size: u64,
-    port: u16,
-}
+    /// Whether the notification should be hidden according to the filtering rules.
+    fn is_filtered(&self, notification: &IncomingNotification) -> bool {
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .notification_receive;
+        let app_name = &notification.app_name;
+
+        if !settings.allowed_apps.is_empty() && !settings.allowed_apps.contains(app_name) {
+            return true;
+        }
+        if settings.blocked_apps.contains(app_name) {
+            return true;
+        }
+        if let Some(title) = &notification.title {
+            if self.blocked_titles.iter().any(|r| r.is_match(title)) {
+                return true;
+            }
+        }
 
-#[async_trait::async_trait]
-impl KdeConnectPlugin for NotificationReceivePlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        // Extract payload
-        let payload_info = if let (Some(size), Some(pi)) = (
-            packet.payload_size.as_ref(),
-            packet.payload_transfer_info.as_ref(),
-        ) {
-            Some(PayloadInfo {
-                size: *size,
-                port: pi.port,
+        false
+    }
+
+    fn is_action_center_only(&self) -> bool {
+        self.ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .notification_receive
+            .action_center_only
+    }
+
+    fn toggle_action_center_only(&self) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.device.device_id(), |settings| {
+                let settings = &mut settings.plugins.notification_receive;
+                settings.action_center_only = !settings.action_center_only;
             })
-        } else {
-            None
-        };
+    }
+
+    fn alert(&self) -> NotificationAlert {
+        self.ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .notification_receive
+            .alert
+    }
+
+    fn set_alert(&self, alert: NotificationAlert) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.device.device_id(), |settings| {
+                settings.plugins.notification_receive.alert = alert;
+            })
+    }
+
+    fn is_suppressed_by_focus_assist(&self) -> bool {
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .notification_receive;
+        // Windows decides whether urgent notifications break through.
+        let ignore_focus_assist =
+            settings.ignore_focus_assist || settings.alert == NotificationAlert::Urgent;
+
+        !ignore_focus_assist && utils::is_do_not_disturb()
+    }
+
+    fn is_app_muted(&self, app_name: &str) -> bool {
+        self.ctx
+            .settings()
+            .device(self.device.device_id())
+            .plugins
+            .notification_receive
+            .blocked_apps
+            .iter()
+            .any(|a| a == app_name)
+    }
+
+    fn toggle_app_muted(&self, app_name: &str) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.device.device_id(), |settings| {
+                let blocked = &mut settings.plugins.notification_receive.blocked_apps;
+                if let Some(pos) = blocked.iter().position(|a| a == app_name) {
+                    blocked.remove(pos);
+                } else {
+                    blocked.push(app_name.to_string());
+                }
+            })
+    }
+
+    /// Remember the app that posted a notification, returns `true` if it was not seen before.
+    async fn record_app(&self, app_name: &str) -> bool {
+        let mut recent_apps = self.recent_apps.lock().await;
+        if recent_apps.get_mut(app_name).is_some() {
+            return false;
+        }
+
+        let menu_id = MenuId::new(&format!(
+            "{}:notifications:app:{}",
+            self.device.device_id(),
+            app_name
+        ));
+        recent_apps.insert(app_name.to_string(), menu_id);
+        true
+    }
+
+    /// Remember a notification as shown, returns `false` if it already was. Notifications that
+    /// are updated, e.g. with a new message, have a new time and are shown again.
+    async fn mark_seen(&self, notification: &IncomingNotification) -> bool {
+        let content = ContentHash::sha256(format!(
+            "{}\0{}\0{}",
+            notification.time,
+            notification.title.as_deref().unwrap_or_default(),
+            notification.text.as_deref().unwrap_or_default()
+        ));
+        let key = format!(
+            "{}:{}:{}",
+            self.device.device_id(),
+            notification.id,
+            content
+        );
+
+        if !SEEN_NOTIFICATIONS.insert(&key) {
+            return false;
+        }
+        SEEN_NOTIFICATIONS.save_soon();
+        true
+    }
 
-        let body: NotificationBody = packet.into_body()?;
+    /// Count a toast against the limit of the device, returning whether it may be shown.
+    fn allow_toast(&self) -> bool {
+        match self.toast_limiter.lock().unwrap().check(()) {
+            Rate::Allowed => true,
+            Rate::Exceeded => {
+                log::warn!(
+                    "Too many notifications from {}, dropping some of them",
+                    self.device.device_id()
+                );
+                false
+            }
+            Rate::Limited => false,
+        }
+    }
 
+    async fn receive_notification(
+        &self,
+        body: NotificationBody,
+        payload: Option<Payload>,
+    ) -> Result<()> {
         match body {
             NotificationBody::Cancelled { id, .. } => {
                 tracing::debug!("Cancelled {}", id);
@@ -278,12 +565,24 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
                     .context("Remove notification")?;
             }
             NotificationBody::Posted(notif) => {
+                if self.record_app(&notif.app_name).await {
+                    self.ctx.update_tray().await;
+                }
+
                 if self.is_muted() {
                     tracing::debug!("Posted {} (muted)", notif.id);
+                } else if self.is_filtered(&notif) {
+                    tracing::debug!("Posted {} (filtered)", notif.id);
+                } else if self.is_suppressed_by_focus_assist() {
+                    tracing::debug!("Posted {} (do not disturb)", notif.id);
+                } else if !self.mark_seen(&notif).await {
+                    tracing::debug!("Posted {} (already shown)", notif.id);
+                } else if !self.allow_toast() {
+                    tracing::debug!("Posted {} (too many)", notif.id);
                 } else {
                     tracing::debug!("Posted {}", notif.id);
 
-                    self.show_notification(notif, payload_info)
+                    self.show_notification(notif, payload)
                         .await
                         .context("Show notification")?;
                 }
@@ -292,14 +591,54 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
 
         Ok(())
     }
+}
+
+/// Convert an icon to a PNG no larger than `ICON_MAX_SIZE`, as Windows does not show every
+/// format Android sends (e.g. WebP).
+fn icon_to_png(data: &[u8]) -> Result<Vec<u8>> {
+    let format = image::guess_format(data)?;
+    let image = image::load_from_memory_with_format(data, format)?;
+
+    let fits = image.width() <= ICON_MAX_SIZE && image.height() <= ICON_MAX_SIZE;
+    if fits && format == image::ImageFormat::Png {
+        return Ok(data.to_vec());
+    }
+
+    let image = if fits {
+        image
+    } else {
+        image.resize(
+            ICON_MAX_SIZE,
+            ICON_MAX_SIZE,
+            image::imageops::FilterType::Triangle,
+        )
+    };
+
+    let mut out = std::io::Cursor::new(vec![]);
+    image.write_to(&mut out, image::ImageOutputFormat::Png)?;
+
+    Ok(out.into_inner())
+}
+
+plugin_packets! {
+    NotificationReceivePlugin {
+        incoming {
+            Notification(NotificationBody) => receive_notification with payload,
+        }
+        outgoing [NotificationRequest, NotificationReply]
+        events [Tray]
+    }
+}
 
+#[async_trait::async_trait]
+impl KdeConnectPlugin for NotificationReceivePlugin {
     async fn start(self: Arc<Self>) -> Result<()> {
         // Request all remote notifications
         let dev = self.device.clone();
 
         tokio::spawn(async move {
             dev.send_packet(NetworkPacket::new(
-                PACKET_TYPE_NOTIFICATION_REQUEST,
+                PacketKind::NotificationRequest,
                 serde_json::json!({
                     "request": true,
                 }),
@@ -310,33 +649,101 @@ impl KdeConnectPlugin for NotificationReceivePlugin {
         Ok(())
     }
 
-    async fn tray_menu(&self, menu: &mut ContextMenu) {
-        let mut submenu = ContextMenu::new();
-        submenu.add_item(
-            MenuItemAttributes::new("Mute")
-                .with_selected(self.is_muted())
-                .with_id(self.mute_menu_id),
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        let mut submenu = TrayMenu::new();
+        submenu.add_toggle(self.mute_menu_id, "Mute", self.is_muted());
+        submenu.add_toggle(
+            self.action_center_only_menu_id,
+            "Action Center only",
+            self.is_action_center_only(),
         );
-        menu.add_submenu("Notifications", true, submenu);
+
+        let alert = self.alert();
+        let mut alert_submenu = TrayMenu::new();
+        for (item, menu_id) in &self.alert_menu_ids {
+            let label = match item {
+                NotificationAlert::Silent => "Silent",
+                NotificationAlert::Normal => "Normal",
+                NotificationAlert::Urgent => "Urgent",
+            };
+            alert_submenu.add_toggle(*menu_id, label, *item == alert);
+        }
+        submenu.add_submenu("Alert", alert_submenu);
+
+        let recent_apps = self.recent_apps.lock().await;
+        let mut apps_submenu = TrayMenu::new();
+        for (app_name, menu_id) in recent_apps.iter() {
+            apps_submenu.add_toggle(
+                *menu_id,
+                format!("Mute {}", app_name),
+                self.is_app_muted(app_name),
+            );
+        }
+        submenu.add_submenu("Applications", apps_submenu);
+
+        menu.settings.add_submenu("Notifications", submenu);
     }
 
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         if event.is_menu_clicked(self.mute_menu_id) {
             self.muted.fetch_xor(true, Ordering::Relaxed);
             self.ctx.update_tray().await;
+        } else if event.is_menu_clicked(self.action_center_only_menu_id) {
+            self.toggle_action_center_only()
+                .context("Save notification settings")?;
+            self.ctx.update_tray().await;
+        } else if let Some((alert, _)) = self
+            .alert_menu_ids
+            .iter()
+            .find(|(_, id)| event.is_menu_clicked(*id))
+        {
+            self.set_alert(*alert)
+                .context("Save notification settings")?;
+            self.ctx.update_tray().await;
+        } else if let SystemEvent::TrayMenuClicked(menu_id) = event {
+            let app_name = {
+                let recent_apps = self.recent_apps.lock().await;
+                recent_apps
+                    .iter()
+                    .find(|(_, id)| **id == menu_id)
+                    .map(|(app_name, _)| app_name.clone())
+            };
+
+            if let Some(app_name) = app_name {
+                self.toggle_app_muted(&app_name)
+                    .context("Save notification filter")?;
+                self.ctx.update_tray().await;
+            }
         }
         Ok(())
     }
 }
 
-impl KdeConnectPluginMetadata for NotificationReceivePlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec!["kdeconnect.notification".into()]
-    }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_NOTIFICATION_REQUEST.into(),
-            "kdeconnect.notification.reply".into(),
-        ]
+#[cfg(test)]
+mod tests {
+    use crate::testing::{mock::MockDeviceHandle, TestContext};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn toasts_are_limited_per_device() -> Result<()> {
+        let test = TestContext::new()?;
+        let flooding = NotificationReceivePlugin::new(
+            MockDeviceHandle::new("flooding").handle(),
+            test.ctx.clone(),
+        );
+        let other = NotificationReceivePlugin::new(
+            MockDeviceHandle::new("other").handle(),
+            test.ctx.clone(),
+        );
+
+        for _ in 0..MAX_TOASTS {
+            assert!(flooding.allow_toast());
+        }
+        assert!(!flooding.allow_toast());
+        assert!(!flooding.allow_toast());
+        assert!(other.allow_toast());
+
+        Ok(())
     }
 }
@@ -0,0 +1,191 @@
+//! The packet types handled by plugins, and the macro that routes them to typed handlers.
+
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use crate::packet::NetworkPacket;
+
+macro_rules! packet_kinds {
+    ($($kind:ident => $typ:literal,)*) => {
+        /// Type of a packet exchanged with a plugin, e.g. [`PacketKind::Ping`] for
+        /// "kdeconnect.ping".
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum PacketKind {
+            $($kind,)*
+        }
+
+        impl PacketKind {
+            #[cfg(test)]
+            const ALL: &'static [PacketKind] = &[$(PacketKind::$kind,)*];
+
+            /// The type string used on the wire.
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(PacketKind::$kind => $typ,)*
+                }
+            }
+
+            pub fn from_type(typ: &str) -> Option<Self> {
+                match typ {
+                    $($typ => Some(PacketKind::$kind),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+packet_kinds! {
+    Battery => "kdeconnect.battery",
+    BatteryRequest => "kdeconnect.battery.request",
+    Clipboard => "kdeconnect.clipboard",
+    ClipboardConnect => "kdeconnect.clipboard.connect",
+    ConnectivityReport => "kdeconnect.connectivity_report",
+    ConnectivityReportRequest => "kdeconnect.connectivity_report.request",
+    MonitorControlRequest => "kdeconnect.monitorcontrol.request",
+    MousepadEcho => "kdeconnect.mousepad.echo",
+    MousepadRequest => "kdeconnect.mousepad.request",
+    Mpris => "kdeconnect.mpris",
+    MprisRequest => "kdeconnect.mpris.request",
+    Notification => "kdeconnect.notification",
+    NotificationReply => "kdeconnect.notification.reply",
+    NotificationRequest => "kdeconnect.notification.request",
+    Ping => "kdeconnect.ping",
+    Plugins => "kdeconnect.plugins",
+    RunCommand => "kdeconnect.runcommand",
+    RunCommandRequest => "kdeconnect.runcommand.request",
+    ShareRequest => "kdeconnect.share.request",
+    ShareRequestUpdate => "kdeconnect.share.request.update",
+    SystemStats => "kdeconnect.systemstats",
+    SystemStatsRequest => "kdeconnect.systemstats.request",
+    SystemVolume => "kdeconnect.systemvolume",
+    SystemVolumeRequest => "kdeconnect.systemvolume.request",
+    Telephony => "kdeconnect.telephony",
+    WakeRequest => "kdeconnect.wake.request",
+}
+
+impl Display for PacketKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<PacketKind> for String {
+    fn from(kind: PacketKind) -> Self {
+        kind.as_str().to_string()
+    }
+}
+
+/// Passes incoming packets to the handler of their type, see [`plugin_packets!`].
+#[async_trait::async_trait]
+pub trait PacketHandler {
+    async fn dispatch(&self, packet: NetworkPacket) -> Result<()>;
+}
+
+/// Declare the packets of a plugin, which implements [`PacketHandler`] and
+/// [`KdeConnectPluginMetadata`](super::KdeConnectPluginMetadata) for it.
+///
+/// Each incoming packet is deserialized into its body type and given to its handler, along
+/// with its [`Payload`](crate::packet::Payload) if followed by `with payload`. Use
+/// [`serde::de::IgnoredAny`] for packets whose body is not needed.
+///
+/// ```ignore
+/// plugin_packets! {
+///     BatteryPlugin {
+///         incoming {
+///             Battery(BatteryReport) => receive_report,
+///             BatteryRequest(IgnoredAny) => send_status,
+///         }
+///         outgoing [Battery, BatteryRequest]
+///         events [Power]
+///     }
+/// }
+/// ```
+macro_rules! plugin_packets {
+    (
+        $plugin:ty {
+            incoming {
+                $($kind:ident($body:ty) => $handler:ident $(with $payload:ident)?,)*
+            }
+            outgoing [$($out:ident),* $(,)?]
+            $(events [$($topic:ident),* $(,)?])?
+        }
+    ) => {
+        #[async_trait::async_trait]
+        impl $crate::plugin::packets::PacketHandler for $plugin {
+            async fn dispatch(&self, packet: $crate::packet::NetworkPacket) -> anyhow::Result<()> {
+                // Unused by plugins without incoming packets.
+                #[allow(unused_imports)]
+                use anyhow::Context as _;
+
+                match $crate::plugin::packets::PacketKind::from_type(&packet.typ) {
+                    $(Some($crate::plugin::packets::PacketKind::$kind) => {
+                        $(let $payload = packet.payload();)?
+                        let body: $body = packet
+                            .into_body()
+                            .context(concat!("Invalid ", stringify!($kind), " packet"))?;
+                        self.$handler(body $(, $payload)?).await
+                    })*
+                    _ => anyhow::bail!("Unexpected packet type {}", packet.typ),
+                }
+            }
+        }
+
+        impl $crate::plugin::KdeConnectPluginMetadata for $plugin {
+            fn incoming_capabilities() -> Vec<$crate::plugin::packets::PacketKind> {
+                vec![$($crate::plugin::packets::PacketKind::$kind),*]
+            }
+            fn outgoing_capabilities() -> Vec<$crate::plugin::packets::PacketKind> {
+                vec![$($crate::plugin::packets::PacketKind::$out),*]
+            }
+            $(fn event_topics() -> Vec<$crate::event::EventTopic> {
+                vec![$($crate::event::EventTopic::$topic),*]
+            })?
+        }
+    };
+}
+
+pub(crate) use plugin_packets;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn packet_types_round_trip() {
+        for &kind in PacketKind::ALL {
+            assert_eq!(PacketKind::from_type(kind.as_str()), Some(kind));
+            assert_eq!(kind.to_string(), kind.as_str());
+            assert_eq!(String::from(kind), kind.as_str());
+            assert!(kind.as_str().starts_with("kdeconnect."), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn packet_types_are_unique() {
+        let types = PacketKind::ALL
+            .iter()
+            .map(|k| k.as_str())
+            .collect::<HashSet<_>>();
+        assert_eq!(types.len(), PacketKind::ALL.len());
+    }
+
+    #[test]
+    fn unknown_packet_types() {
+        for typ in [
+            "",
+            "kdeconnect",
+            "kdeconnect.",
+            "kdeconnect.ping.",
+            "KDECONNECT.PING",
+            " kdeconnect.ping",
+            "kdeconnect.identity",
+            "kdeconnect.pair",
+        ] {
+            assert_eq!(PacketKind::from_type(typ), None, "{:?}", typ);
+        }
+    }
+}
@@ -7,13 +7,16 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use tao::menu::{ContextMenu, MenuId, MenuItemAttributes};
+use tao::menu::MenuId;
 
-use crate::{device::DeviceHandle, event::SystemEvent, packet::NetworkPacket, utils};
+use crate::{
+    device::DeviceHandle, event::SystemEvent, packet::NetworkPacket, tray::DeviceMenu, utils,
+};
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
-
-const PACKET_TYPE_PING: &str = "kdeconnect.ping";
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct PingPacket {
@@ -21,6 +24,11 @@ struct PingPacket {
     message: Option<String>,
 }
 
+/// A ping, shown on the device with `message` as its text.
+pub fn ping_packet(message: Option<String>) -> NetworkPacket {
+    NetworkPacket::new(PacketKind::Ping, PingPacket { message })
+}
+
 #[derive(Debug)]
 pub struct PingPlugin {
     dev: DeviceHandle,
@@ -35,48 +43,49 @@ impl PingPlugin {
         }
     }
 
-    pub async fn send_ping(&self) {
-        self.dev
-            .send_packet(NetworkPacket::new(
-                PACKET_TYPE_PING,
-                PingPacket { message: None },
-            ))
-            .await;
+    pub async fn send_ping(&self) -> Result<()> {
+        self.dev.send_packet_with_ack(ping_packet(None)).await
     }
-}
-
-#[async_trait::async_trait]
-impl KdeConnectPlugin for PingPlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        let body: PingPacket = packet.into_body()?;
 
+    async fn receive_ping(&self, body: PingPacket) -> Result<()> {
         utils::simple_toast(
             "Ping",
             body.message.as_deref(),
-            Some(self.dev.device_name()),
+            Some(&self.dev.device_name()),
         )
         .await;
 
         Ok(())
     }
+}
+
+plugin_packets! {
+    PingPlugin {
+        incoming {
+            Ping(PingPacket) => receive_ping,
+        }
+        outgoing [Ping]
+        events [Tray]
+    }
+}
 
-    async fn tray_menu(&self, menu: &mut ContextMenu) {
-        menu.add_item(MenuItemAttributes::new("Ping").with_id(self.menu_id));
+#[async_trait::async_trait]
+impl KdeConnectPlugin for PingPlugin {
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        menu.actions.add_action(self.menu_id, "Ping");
     }
 
     async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
         if event.is_menu_clicked(self.menu_id) {
-            self.send_ping().await;
+            if let Err(e) = self.send_ping().await {
+                utils::simple_toast(
+                    "Failed to send ping",
+                    Some(&e.to_string()),
+                    Some(&self.dev.device_name()),
+                )
+                .await;
+            }
         }
         Ok(())
     }
 }
-
-impl KdeConnectPluginMetadata for PingPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_PING.into()]
-    }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_PING.into()]
-    }
-}
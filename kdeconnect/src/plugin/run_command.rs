@@ -1,14 +1,48 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    os::windows::process::CommandExt,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use tao::menu::MenuId;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::Mutex,
+    task::JoinHandle,
+};
+use windows::Win32::System::Threading::CREATE_NO_WINDOW;
+use winrt_toast::{Toast, ToastTag};
 
-use crate::{device::DeviceHandle, packet::NetworkPacket};
+use crate::{
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::DeviceMenu,
+    utils::{self, monitor},
+};
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    ping, KdeConnectPlugin,
+};
 
-const PACKET_TYPE_RUNCOMMAND: &str = "kdeconnect.runcommand";
-const PACKET_TYPE_RUNCOMMAND_REQUEST: &str = "kdeconnect.runcommand.request";
+/// Commands defined by the user, in the same format as the command list sent to the device.
+const COMMANDS_PATH: &str = "./commands.json";
+/// How often the commands file is checked for changes.
+const COMMANDS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Bytes of stdout and stderr kept from a command, the rest is discarded.
+const OUTPUT_LIMIT: u64 = 16 * 1024;
+/// Characters of output sent back to the device, from the end of the output.
+const SUMMARY_MAX_CHARS: usize = 300;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
@@ -28,94 +62,462 @@ struct RunCommandPacket {
     command_list: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct Command {
     name: String,
     command: String,
 }
 
+/// A command implemented by this application instead of a shell command line.
+struct BuiltinCommand {
+    key: &'static str,
+    name: &'static str,
+    run: fn() -> Result<()>,
+}
+
+/// Brightness change of the brightness commands, in percent.
+const BRIGHTNESS_STEP: i32 = 10;
+
+const BUILTIN_COMMANDS: &[BuiltinCommand] = &[
+    BuiltinCommand {
+        key: "builtin-brightness-up",
+        name: "Brightness up",
+        run: || monitor::adjust_brightness(BRIGHTNESS_STEP),
+    },
+    BuiltinCommand {
+        key: "builtin-brightness-down",
+        name: "Brightness down",
+        run: || monitor::adjust_brightness(-BRIGHTNESS_STEP),
+    },
+    BuiltinCommand {
+        key: "builtin-display-off",
+        name: "Turn off displays",
+        run: monitor::power_off,
+    },
+];
+
+/// The entries of the commands file in order, including duplicate keys which a map would
+/// silently drop.
+struct CommandEntries(Vec<(String, Command)>);
+
+impl<'de> Deserialize<'de> for CommandEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = CommandEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of keys to commands")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(CommandEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+/// Parse the commands file, checking that keys are unique and commands are not empty.
+fn parse_commands(s: &str) -> Result<HashMap<String, Command>> {
+    let CommandEntries(entries) = serde_json::from_str(s)?;
+
+    let mut commands = HashMap::new();
+    for (key, command) in entries {
+        if key.is_empty() {
+            bail!("A command has an empty key");
+        }
+        if BUILTIN_COMMANDS.iter().any(|b| b.key == key) {
+            bail!("Key {:?} is used by a built-in command", key);
+        }
+        if command.command.trim().is_empty() {
+            bail!("Command {:?} is empty", key);
+        }
+        if commands.insert(key.clone(), command).is_some() {
+            bail!("Key {:?} is used more than once", key);
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Load the commands defined by the user, none if the file does not exist.
+fn load_commands() -> Result<HashMap<String, Command>> {
+    match std::fs::read_to_string(COMMANDS_PATH) {
+        Ok(s) => parse_commands(&s).with_context(|| format!("Invalid {}", COMMANDS_PATH)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn commands_modified() -> Option<SystemTime> {
+    tokio::fs::metadata(COMMANDS_PATH)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// Open the commands file in an editor, creating it if needed.
+async fn edit_commands() -> Result<()> {
+    if tokio::fs::metadata(COMMANDS_PATH).await.is_err() {
+        tokio::fs::write(COMMANDS_PATH, "{\n}\n").await?;
+    }
+
+    // JSON files have no default application on a fresh install.
+    if let Err(e) = utils::open::open_path(COMMANDS_PATH).await {
+        log::debug!("Failed to open {}, using Notepad: {:?}", COMMANDS_PATH, e);
+        std::process::Command::new("notepad.exe")
+            .arg(COMMANDS_PATH)
+            .spawn()?;
+    }
+
+    Ok(())
+}
+
+/// Show why the commands file is invalid. Every connected device notices the change, so the
+/// toast has a fixed tag to be shown only once.
+async fn show_invalid_commands_toast(error: &anyhow::Error) {
+    let mut toast = Toast::new();
+    toast
+        .text1("Invalid commands")
+        .text2(format!("{:#}", error))
+        .tag(ToastTag::hashed("run-command:invalid"));
+
+    utils::show_toast(toast).await;
+}
+
+/// Read at most `limit` bytes, and discard the rest so that the process does not block on a
+/// full pipe. Returns whether anything was discarded.
+async fn read_bounded(mut r: impl AsyncRead + Unpin, limit: u64) -> Result<(Vec<u8>, bool)> {
+    let mut buf = vec![];
+    (&mut r).take(limit).read_to_end(&mut buf).await?;
+    let discarded = tokio::io::copy(&mut r, &mut tokio::io::sink()).await?;
+    Ok((buf, discarded > 0))
+}
+
+struct CommandOutput {
+    code: Option<i32>,
+    /// stdout followed by stderr.
+    output: String,
+    truncated: bool,
+}
+
+impl CommandOutput {
+    fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// The exit code and the end of the output, short enough for a notification.
+    fn summary(&self, name: &str) -> String {
+        let status = match self.code {
+            Some(code) => format!("{} exited with code {}", name, code),
+            None => format!("{} was terminated", name),
+        };
+        let output = self.output.trim();
+        if output.is_empty() {
+            return status;
+        }
+
+        let count = output.chars().count();
+        if count > SUMMARY_MAX_CHARS || self.truncated {
+            let tail: String = output
+                .chars()
+                .skip(count.saturating_sub(SUMMARY_MAX_CHARS))
+                .collect();
+            format!("{}\n…{}", status, tail)
+        } else {
+            format!("{}\n{}", status, output)
+        }
+    }
+}
+
+/// Run a command line with `cmd`, without showing a console window.
+async fn run_shell_command(command: &str) -> Result<CommandOutput> {
+    let mut std_command = std::process::Command::new("cmd");
+    // Passed as is, as cmd does its own parsing of the command line.
+    std_command
+        .arg("/C")
+        .raw_arg(command)
+        .creation_flags(CREATE_NO_WINDOW.0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = tokio::process::Command::from(std_command).spawn()?;
+    let stdout = child.stdout.take().context("No stdout")?;
+    let stderr = child.stderr.take().context("No stderr")?;
+
+    let ((mut output, stdout_truncated), (stderr, stderr_truncated), status) = tokio::try_join!(
+        read_bounded(stdout, OUTPUT_LIMIT),
+        read_bounded(stderr, OUTPUT_LIMIT),
+        async { Ok(child.wait().await?) },
+    )?;
+    output.extend(stderr);
+
+    Ok(CommandOutput {
+        code: status.code(),
+        output: String::from_utf8_lossy(&output).into_owned(),
+        truncated: stdout_truncated || stderr_truncated,
+    })
+}
+
 #[derive(Debug)]
 pub struct RunCommandPlugin {
     dev: DeviceHandle,
+    edit_menu_id: MenuId,
+    watch_task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl RunCommandPlugin {
     pub fn new(dev: DeviceHandle) -> Self {
         RunCommandPlugin {
+            edit_menu_id: MenuId::new(&format!("{}:run_command:edit", dev.device_id())),
             dev,
-            // ctx,
+            watch_task: Mutex::new(None),
         }
     }
 
     async fn send_command_list(&self) -> Result<()> {
         let mut command_list = HashMap::new();
-        command_list.insert(
-            "test".to_string(),
-            Command {
-                name: "Test".to_string(),
-                command: "echo \"Hello World\"".to_string(),
-            },
-        );
-        command_list.insert(
-            "test2".to_string(),
-            Command {
-                name: "Test2".to_string(),
-                command: "echo \"Hello World2\"".to_string(),
-            },
-        );
+        for builtin in BUILTIN_COMMANDS {
+            command_list.insert(
+                builtin.key.to_string(),
+                Command {
+                    name: builtin.name.to_string(),
+                    command: "(built-in)".to_string(),
+                },
+            );
+        }
+        command_list.extend(load_commands()?);
         let command_list = serde_json::to_string(&command_list)?;
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_RUNCOMMAND,
+                PacketKind::RunCommand,
                 RunCommandPacket { command_list },
             ))
             .await;
 
         Ok(())
     }
+
+    async fn receive_command_list(&self, _: IgnoredAny) -> Result<()> {
+        // TODO
+        Ok(())
+    }
+
+    async fn receive_request(&self, request: RunCommandRequestPacket) -> Result<()> {
+        match request {
+            RunCommandRequestPacket::RequestCommandList { .. } => {
+                self.send_command_list().await?;
+            }
+            RunCommandRequestPacket::Setup { .. } => {
+                self.send_command_list().await?;
+            }
+            RunCommandRequestPacket::RunCommand { key } => {
+                log::info!("Received command with key: {}", key);
+
+                if let Some(builtin) = BUILTIN_COMMANDS.iter().find(|c| c.key == key) {
+                    tokio::task::spawn_blocking(builtin.run).await??;
+                } else if let Some(command) = load_commands()?.remove(&key) {
+                    // Commands may run for a long time, do not hold up other packets.
+                    tokio::spawn(run_and_report(self.dev.clone(), command));
+                } else {
+                    log::warn!("Unknown command: {}", key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run a command, and send the result to the device as a ping. A toast is also shown if the
+/// command fails.
+async fn run_and_report(dev: DeviceHandle, command: Command) {
+    log::info!("Running command {:?}: {}", command.name, command.command);
+
+    let summary = match run_shell_command(&command.command).await {
+        Ok(output) => {
+            log::debug!("Output of {:?}: {}", command.name, output.output);
+            let summary = output.summary(&command.name);
+            if !output.success() {
+                utils::simple_toast("Command failed", Some(&summary), Some(&dev.device_name()))
+                    .await;
+            }
+            summary
+        }
+        Err(e) => {
+            log::error!("Failed to run command {:?}: {:?}", command.name, e);
+            let summary = format!("Failed to run {}: {}", command.name, e);
+            utils::simple_toast("Command failed", Some(&summary), Some(&dev.device_name())).await;
+            summary
+        }
+    };
+
+    dev.send_packet(ping::ping_packet(Some(summary))).await;
+}
+
+plugin_packets! {
+    RunCommandPlugin {
+        incoming {
+            RunCommand(IgnoredAny) => receive_command_list,
+            RunCommandRequest(RunCommandRequestPacket) => receive_request,
+        }
+        outgoing [RunCommand, RunCommandRequest]
+        events [Tray]
+    }
 }
 
 #[async_trait::async_trait]
 impl KdeConnectPlugin for RunCommandPlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            PACKET_TYPE_RUNCOMMAND => {
-                // TODO
-            }
-            PACKET_TYPE_RUNCOMMAND_REQUEST => {
-                let body: RunCommandRequestPacket = packet.into_body()?;
+    async fn start(self: Arc<Self>) -> Result<()> {
+        let this = Arc::downgrade(&self);
 
-                match body {
-                    RunCommandRequestPacket::RequestCommandList { .. } => {
-                        self.send_command_list().await?;
-                    }
-                    RunCommandRequestPacket::Setup { .. } => {
-                        self.send_command_list().await?;
-                    }
-                    RunCommandRequestPacket::RunCommand { key } => {
-                        log::info!("Received command with key: {}", key);
+        // Send the commands again whenever the file changes.
+        let task = tokio::spawn(async move {
+            let mut last_modified = commands_modified().await;
+            loop {
+                tokio::time::sleep(COMMANDS_POLL_INTERVAL).await;
+
+                let modified = commands_modified().await;
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                match this.send_command_list().await {
+                    Ok(()) => log::info!("Sent changed commands to {}", this.dev.device_name()),
+                    Err(e) => {
+                        log::warn!("{:?}", e);
+                        show_invalid_commands_toast(&e).await;
                     }
                 }
             }
-            _ => {}
+        });
+        *self.watch_task.lock().await = Some(task);
+
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        if let Some(task) = self.watch_task.lock().await.take() {
+            task.abort();
         }
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        menu.settings
+            .add_action(self.edit_menu_id, "Edit commands…");
+    }
 
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.edit_menu_id) {
+            edit_commands().await.context("Edit commands")?;
+        }
         Ok(())
     }
 }
 
-impl KdeConnectPluginMetadata for RunCommandPlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_RUNCOMMAND.into(),
-            PACKET_TYPE_RUNCOMMAND_REQUEST.into(),
-        ]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(code: Option<i32>, output: &str, truncated: bool) -> CommandOutput {
+        CommandOutput {
+            code,
+            output: output.into(),
+            truncated,
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let commands = parse_commands(
+            r#"{
+                "lock": { "name": "Lock", "command": "rundll32 user32.dll,LockWorkStation" },
+                "sleep": { "name": "Sleep", "command": "shutdown /h" }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands["lock"].name, "Lock");
+        assert_eq!(commands["sleep"].command, "shutdown /h");
+        assert!(parse_commands("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for s in [
+            r#"{ "": { "name": "No key", "command": "echo" } }"#,
+            r#"{ "blank": { "name": "Blank", "command": "  " } }"#,
+            r#"{ "builtin-display-off": { "name": "Taken", "command": "echo" } }"#,
+            r#"{ "twice": { "name": "A", "command": "echo a" },
+                 "twice": { "name": "B", "command": "echo b" } }"#,
+            r#"{ "no-command": { "name": "Missing" } }"#,
+            r#"[]"#,
+        ] {
+            assert!(parse_commands(s).is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_bounded_discards_the_rest() {
+        let (buf, discarded) = read_bounded(&b"hello world"[..], 5).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(discarded);
+
+        let (buf, discarded) = read_bounded(&b"hello"[..], 5).await.unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(!discarded);
+
+        let (buf, discarded) = read_bounded(&b""[..], 5).await.unwrap();
+        assert!(buf.is_empty());
+        assert!(!discarded);
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_RUNCOMMAND.into(),
-            PACKET_TYPE_RUNCOMMAND_REQUEST.into(),
-        ]
+
+    #[test]
+    fn summary_has_the_status() {
+        assert_eq!(
+            output(Some(0), "  \n", false).summary("Lock"),
+            "Lock exited with code 0"
+        );
+        assert_eq!(
+            output(None, "", false).summary("Lock"),
+            "Lock was terminated"
+        );
+        assert_eq!(
+            output(Some(1), "Access denied\r\n", false).summary("Lock"),
+            "Lock exited with code 1\nAccess denied"
+        );
+    }
+
+    #[test]
+    fn summary_keeps_the_end_of_long_output() {
+        let long = format!("{}end", "é".repeat(SUMMARY_MAX_CHARS));
+        let summary = output(Some(0), &long, false).summary("Build");
+
+        let tail = summary.strip_prefix("Build exited with code 0\n…").unwrap();
+        assert_eq!(tail.chars().count(), SUMMARY_MAX_CHARS);
+        assert!(tail.ends_with("end"));
+    }
+
+    #[test]
+    fn summary_marks_truncated_output() {
+        assert_eq!(
+            output(Some(0), "first part", true).summary("Build"),
+            "Build exited with code 0\n…first part"
+        );
     }
 }
@@ -4,86 +4,1334 @@ attached, it will download it as a file with the filename set in the field
 "filename" (string). If that field is not set it should generate a filename.
 
 If the content transferred is text, it can be sent in a field "text" (string)
-instead of an attached payload. In that case, this plugin opens a text editor
-with the content instead of saving it as a file.
+instead of an attached payload. In that case, this plugin copies it to the
+clipboard, opens it in a text editor, or both, depending on the text-mode setting.
 
 If the content transferred is a url, it can be sent in a field "url" (string).
-In that case, this plugin opens that url in the default browser.
+In that case, this plugin opens that url in the default browser, or asks first
+unless the device is trusted with everything and the confirm-urls setting is off.
+Only the schemes in the url-schemes setting are opened.
+
+Files are saved right away unless the trust setting of the device is "ask", in
+which case a toast asks whether to receive them, once per batch. A file retried
+from the "Transfers" menu is a batch of its own, and is not asked for again.
+
+When several files are shared at once, each of them is sent in its own packet,
+with "numberOfFiles" (int) and "totalPayloadSize" (int) describing the whole batch.
+If more files are added to the batch while it is being sent, a packet with type
+kdeconnect.share.request.update carries the new totals.
  */
-use anyhow::Result;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use tao::menu::MenuId;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{oneshot, Mutex},
+};
+use winrt_toast::{Action, Progress, ProgressValue, Text, Toast, ToastTag};
 
 use crate::{
+    cache::{CacheKey, CacheKind, PAYLOAD_CACHE},
+    config::{TextShareMode, TrustLevel},
+    context::{AppContextRef, TrayUpdater},
     device::DeviceHandle,
-    packet::NetworkPacket,
-    utils::{self, clipboard::ClipboardContent},
+    event::SystemEvent,
+    packet::{NetworkPacket, NetworkPacketWithPayload, Payload},
+    transfer::{Direction, Kind, Transfer, TransferState},
+    tray::{DeviceMenu, TrayMenu},
+    utils::{
+        self,
+        hash::ContentHash,
+        retry::Backoff,
+        toast::{ToastCallbacks, TOASTS},
+    },
 };
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
+
+/// How often the progress toast is updated while receiving.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// Give up on a payload connection that stops sending data.
+const PAYLOAD_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the payload is requested again after the connection dropped.
+const PAYLOAD_BACKOFF: Backoff = Backoff::new(Duration::from_millis(500), 3);
+/// A batch with no new file for this long is considered abandoned by the device.
+const TRANSFER_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Files not accepted within this time are declined.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
 
-const PACKET_TYPE_SHARE_REQUEST: &str = "kdeconnect.share.request";
-const PACKET_TYPE_SHARE_REQUEST_UPDATE: &str = "kdeconnect.share.request.update";
+const ACTION_ACCEPT: &str = "accept";
+const ACTION_DECLINE: &str = "decline";
+const ACTION_CANCEL: &str = "cancel";
+const ACTION_OPEN: &str = "open";
+const ACTION_OPEN_FOLDER: &str = "open-folder";
+const ACTION_OPEN_URL: &str = "open-url";
+
+/// Names that refer to devices on Windows, regardless of the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// Keep some room for the directory and a " (n)" suffix within MAX_PATH.
+const MAX_FILENAME_LENGTH: usize = 128;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 enum ShareRequestPacket {
     Text { text: String },
     Url { url: String },
+    File(ShareFile),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ShareFile {
+    filename: String,
+    number_of_files: Option<u32>,
+    total_payload_size: Option<u64>,
+    /// MD5 hash of the payload, not sent by all clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ShareRequestUpdate {
+    number_of_files: Option<u32>,
+    total_payload_size: Option<u64>,
+}
+
+/// A batch of files being received from the device.
+#[derive(Debug)]
+struct Batch {
+    number_of_files: u32,
+    total_size: u64,
+    /// Files that have been received, failed or skipped
+    finished_files: u32,
+    /// Paths of the files that have been saved
+    saved: Vec<PathBuf>,
+    received_size: u64,
+    current_file: String,
+    cancelled: Arc<AtomicBool>,
+    last_activity: Instant,
+}
+
+impl Batch {
+    fn is_done(&self) -> bool {
+        self.finished_files >= self.number_of_files
+    }
+
+    /// The progress toast, showing the speed if known (in bytes per second).
+    fn progress(&self, speed: Option<u64>) -> Progress {
+        let value = if self.total_size > 0 {
+            ProgressValue::Determinate(self.received_size as f64 / self.total_size as f64)
+        } else {
+            ProgressValue::Indeterminate
+        };
+
+        let mut value_string = format!(
+            "{}/{} files",
+            (self.finished_files + 1).min(self.number_of_files),
+            self.number_of_files
+        );
+        if let Some(speed) = speed {
+            value_string += &format!(", {}/s", utils::format_size(speed));
+        }
+
+        Progress::new(format!("Receiving {}", self.current_file), value)
+            .with_value_string(value_string)
+    }
+}
+
+/// Shares `text`, which the device puts on its clipboard.
+pub fn text_packet(text: String) -> NetworkPacket {
+    NetworkPacket::new(PacketKind::ShareRequest, ShareRequestPacket::Text { text })
+}
+
+/// Shares `url`, which the device opens.
+pub fn url_packet(url: String) -> NetworkPacket {
+    NetworkPacket::new(PacketKind::ShareRequest, ShareRequestPacket::Url { url })
+}
+
+/// Shares a single file with `data` as its content.
+pub fn file_packet(filename: String, data: Vec<u8>) -> NetworkPacketWithPayload {
+    let packet = NetworkPacket::new(
+        PacketKind::ShareRequest,
+        ShareRequestPacket::File(ShareFile {
+            filename,
+            number_of_files: Some(1),
+            total_payload_size: Some(data.len() as u64),
+            payload_hash: Some(ContentHash::md5(&data).to_string()),
+        }),
+    );
+    NetworkPacketWithPayload::new(packet, Arc::new(data))
 }
 
 #[derive(Debug)]
 pub struct SharePlugin {
     dev: DeviceHandle,
+    ctx: AppContextRef,
+    download_dir_menu_id: MenuId,
+    trust_menu_ids: Vec<(TrustLevel, MenuId)>,
+    transfer: std::sync::Mutex<Option<Batch>>,
+    /// Ports of the payloads that the user retried, see [`Self::set_retry`].
+    retried_ports: Arc<std::sync::Mutex<HashSet<u16>>>,
+    /// Files are written one at a time, in the order they were sent.
+    download_lock: Mutex<()>,
+    toast_tag: ToastTag,
+    toast_group: ToastTag,
 }
 
 impl SharePlugin {
-    pub fn new(dev: DeviceHandle) -> Self {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        let toast_tag = ToastTag::hashed(format!("share:{}", dev.device_id()));
+        let toast_group = utils::device_toast_group(dev.device_id());
+        let download_dir_menu_id = MenuId::new(&format!("{}:share:download_dir", dev.device_id()));
+
+        let trust_menu_ids = TrustLevel::ALL
+            .into_iter()
+            .map(|trust| {
+                let id = format!("{}:share:trust:{:?}", dev.device_id(), trust);
+                (trust, MenuId::new(&id))
+            })
+            .collect();
+
         SharePlugin {
             dev,
-            // ctx,
+            ctx,
+            download_dir_menu_id,
+            trust_menu_ids,
+            transfer: std::sync::Mutex::new(None),
+            retried_ports: Default::default(),
+            download_lock: Mutex::new(()),
+            toast_tag,
+            toast_group,
         }
     }
-}
 
-#[async_trait::async_trait]
-impl KdeConnectPlugin for SharePlugin {
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            PACKET_TYPE_SHARE_REQUEST => {
-                let body: ShareRequestPacket = packet.into_body()?;
-                match body {
-                    ShareRequestPacket::Text { text } => {
-                        log::info!("Received text: {}", text);
-                        tokio::task::spawn_blocking(move || {
-                            utils::clipboard::write(ClipboardContent::Text(text))
-                        })
-                        .await??;
-                    }
-                    ShareRequestPacket::Url { url } => {
-                        log::info!("Received URL: {}", url);
-                        utils::open::open_url(url).await?;
+    /// Where received files are saved, created on demand.
+    fn download_dir(&self) -> Result<PathBuf> {
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .share;
+        if let Some(dir) = settings.download_dir {
+            return Ok(dir);
+        }
+
+        let dirs = directories::UserDirs::new().context("Failed to locate user directories")?;
+        let downloads = match dirs.download_dir() {
+            Some(dir) => dir.to_path_buf(),
+            None => dirs.home_dir().join("Downloads"),
+        };
+
+        Ok(downloads
+            .join("KDE Connect")
+            .join(sanitize_filename(&self.dev.device_name())))
+    }
+
+    async fn change_download_dir(&self) -> Result<()> {
+        let current = self.download_dir()?;
+        let picked = utils::dialog::pick_folder(
+            &format!("Download folder for {}", self.dev.device_name()),
+            Some(&current),
+        )
+        .await?;
+
+        if let Some(dir) = picked {
+            log::info!(
+                "Saving files from {} to {}",
+                self.dev.device_name(),
+                dir.display()
+            );
+            self.ctx
+                .settings()
+                .update_device(self.dev.device_id(), |d| {
+                    d.plugins.share.download_dir = Some(dir)
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn trust(&self) -> TrustLevel {
+        self.ctx.settings().device(self.dev.device_id()).trust
+    }
+
+    fn set_trust(&self, trust: TrustLevel) -> Result<()> {
+        log::info!(
+            "Trust level of {} set to {:?}",
+            self.dev.device_name(),
+            trust
+        );
+        self.ctx
+            .settings()
+            .update_device(self.dev.device_id(), |d| d.trust = trust)
+    }
+
+    async fn receive_file(&self, file: ShareFile, payload: Option<Payload>) -> Result<()> {
+        let Payload { size, port } = match payload {
+            Some(payload) => payload,
+            None => bail!("File {} has no payload", file.filename),
+        };
+
+        let _guard = self.download_lock.lock().await;
+
+        // A retry is received as a batch of its own, the batch it interrupts continues after.
+        let retry = self.retried_ports.lock().unwrap().remove(&port);
+        let interrupted = if retry { self.interrupt_batch() } else { None };
+
+        let res = self.receive_in_batch(file, size, port, retry).await;
+
+        if let Some(batch) = interrupted {
+            let cancelled = batch.cancelled.clone();
+            self.resume_batch(batch);
+            if !cancelled.load(Ordering::Relaxed) {
+                self.show_progress_toast(cancelled).await;
+            }
+        }
+        res
+    }
+
+    /// Receive a file of the current batch. A retry was accepted already.
+    async fn receive_in_batch(
+        &self,
+        file: ShareFile,
+        size: u64,
+        port: u16,
+        retry: bool,
+    ) -> Result<()> {
+        let (cancelled, is_new) = self.begin_file(&file, size);
+        if cancelled.load(Ordering::Relaxed) {
+            log::info!("Skipping {}, the transfer was cancelled", file.filename);
+            self.finish_file(None).await;
+            return Ok(());
+        }
+        if is_new {
+            if !retry && !self.trust().accepts_files() && !self.ask_accept(&file, size).await {
+                log::info!("Declined {} from {}", file.filename, self.dev.device_name());
+                cancelled.store(true, Ordering::Relaxed);
+                self.finish_file(None).await;
+                return Ok(());
+            }
+            self.show_progress_toast(cancelled.clone()).await;
+        }
+
+        let dir = self.download_dir()?;
+        tokio::fs::create_dir_all(&dir).await?;
+        let (path, placeholder) =
+            create_unique_file(&dir, &sanitize_filename(&file.filename)).await?;
+        drop(placeholder);
+
+        // The empty file keeps the name reserved until the download is complete.
+        let (part_path, part_file) = create_part_file(&path).await?;
+
+        let transfer = Transfer::start(
+            &self.ctx,
+            &self.dev,
+            Direction::Incoming,
+            Kind::File,
+            &file.filename,
+            size,
+        )
+        .await;
+        self.set_retry(&transfer, &file, size, port);
+
+        let mut res = self
+            .download_verified(
+                port,
+                size,
+                part_file,
+                &part_path,
+                file.payload_hash
+                    .as_deref()
+                    .and_then(ContentHash::parse_md5),
+                &cancelled,
+                &transfer,
+            )
+            .await;
+        if let Ok(true) = res {
+            res = tokio::fs::rename(&part_path, &path)
+                .await
+                .map(|_| true)
+                .context("Rename downloaded file");
+        }
+        if !matches!(res, Ok(true)) {
+            tokio::fs::remove_file(&part_path).await.ok();
+            tokio::fs::remove_file(&path).await.ok();
+        }
+        let state = match &res {
+            Ok(true) => TransferState::Completed,
+            Ok(false) => TransferState::Cancelled,
+            Err(e) => TransferState::failed(e),
+        };
+        transfer.finish(state).await;
+
+        match res {
+            Ok(true) => {
+                log::info!("Received {}", path.display());
+                self.finish_file(Some(path)).await;
+            }
+            Ok(false) => {
+                log::info!("Cancelled receiving {}", file.filename);
+                self.finish_file(None).await;
+            }
+            Err(e) => {
+                self.finish_file(None).await;
+                return Err(e.context(format!("Failed to receive {}", file.filename)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Retrying hands the share request to the plugin again, which works as long as the device
+    /// still serves the payload.
+    fn set_retry(&self, transfer: &Transfer, file: &ShareFile, size: u64, port: u16) {
+        let packet = retry_packet(file, size, port);
+        let dev = self.dev.clone();
+        let retried_ports = self.retried_ports.clone();
+        transfer.set_retry(move || {
+            retried_ports.lock().unwrap().insert(port);
+            let dev = dev.clone();
+            let packet = packet.clone();
+            tokio::spawn(async move { dev.dispatch_packet(packet).await });
+        });
+    }
+
+    /// Put the current batch aside while a retry is received.
+    fn interrupt_batch(&self) -> Option<Batch> {
+        self.transfer.lock().unwrap().take()
+    }
+
+    /// Continue a batch put aside by [`Self::interrupt_batch`], not counting the time it was.
+    fn resume_batch(&self, mut batch: Batch) {
+        batch.last_activity = Instant::now();
+        *self.transfer.lock().unwrap() = Some(batch);
+    }
+
+    /// Account for a new file in the current batch, or start a new batch.
+    ///
+    /// Returns the cancellation flag of the batch and whether it has just been started.
+    fn begin_file(&self, file: &ShareFile, size: u64) -> (Arc<AtomicBool>, bool) {
+        let mut transfer = self.transfer.lock().unwrap();
+
+        let stale = match &*transfer {
+            Some(t) => t.is_done() || t.last_activity.elapsed() > TRANSFER_IDLE_TIMEOUT,
+            None => true,
+        };
+        if stale {
+            *transfer = Some(Batch {
+                number_of_files: file.number_of_files.unwrap_or(1).max(1),
+                total_size: file.total_payload_size.unwrap_or(size),
+                finished_files: 0,
+                saved: vec![],
+                received_size: 0,
+                current_file: String::new(),
+                cancelled: Arc::new(AtomicBool::new(false)),
+                last_activity: Instant::now(),
+            });
+        }
+
+        let t = transfer.as_mut().unwrap();
+        t.current_file = file.filename.clone();
+        t.last_activity = Instant::now();
+
+        (t.cancelled.clone(), stale)
+    }
+
+    /// Download the payload into `file` at `path`, checking its hash if the device sent one.
+    ///
+    /// A corrupt file is counted in the stats and downloaded once more, in case the device
+    /// still serves it.
+    #[allow(clippy::too_many_arguments)]
+    async fn download_verified(
+        &self,
+        port: u16,
+        size: u64,
+        mut file: tokio::fs::File,
+        path: &Path,
+        hash: Option<ContentHash>,
+        cancelled: &AtomicBool,
+        transfer: &Transfer,
+    ) -> Result<bool> {
+        let mut retried = false;
+        loop {
+            if !self
+                .download(port, size, &mut file, cancelled, transfer)
+                .await?
+            {
+                return Ok(false);
+            }
+
+            let expected = match hash {
+                Some(hash) => hash,
+                None => return Ok(true),
+            };
+            let actual = md5_file(path).await?;
+            if actual == expected {
+                return Ok(true);
+            }
+
+            self.dev.stats().add_corrupt_payload();
+            if retried {
+                bail!(
+                    "Hash mismatch: {} (received) != {} (expected)",
+                    actual,
+                    expected
+                );
+            }
+            log::warn!("Hash mismatch for {}, downloading again", path.display());
+            retried = true;
+            file.set_len(0).await?;
+            file.rewind().await?;
+
+            // The file is received again, do not count it twice.
+            if let Some(t) = self.transfer.lock().unwrap().as_mut() {
+                t.received_size = t.received_size.saturating_sub(size);
+            }
+            transfer.progress().reset();
+        }
+    }
+
+    /// Copy the payload into `file`, returning `false` if the transfer was cancelled.
+    ///
+    /// If the connection drops, the payload is requested again as allowed by `PAYLOAD_BACKOFF`.
+    /// There is no way to ask for a range, so the device sends it from the start and the part
+    /// already written is skipped.
+    async fn download(
+        &self,
+        port: u16,
+        size: u64,
+        file: &mut tokio::fs::File,
+        cancelled: &AtomicBool,
+        transfer: &Transfer,
+    ) -> Result<bool> {
+        let mut written = 0;
+        let mut delays = PAYLOAD_BACKOFF.delays();
+
+        loop {
+            let res = self
+                .download_from(port, size, file, &mut written, cancelled, transfer)
+                .await;
+            match res {
+                Ok(true) => break,
+                Err(e) if !cancelled.load(Ordering::Relaxed) && !transfer.is_cancelled() => {
+                    match delays.next() {
+                        Some(delay) => {
+                            log::warn!(
+                                "Payload interrupted after {} of {} bytes, retrying: {:#}",
+                                written,
+                                size,
+                                e
+                            );
+                            tokio::time::sleep(delay).await;
+                        }
+                        None => return Err(e),
                     }
                 }
+                res => return res,
+            }
+        }
+
+        file.flush().await?;
+        let len = file.metadata().await?.len();
+        if len != size {
+            bail!("Received {} bytes instead of {}", len, size);
+        }
+        Ok(true)
+    }
+
+    /// Connect to the payload server and copy the payload into `file`, starting at `written`
+    /// which is updated as data is written.
+    async fn download_from(
+        &self,
+        port: u16,
+        size: u64,
+        file: &mut tokio::fs::File,
+        written: &mut u64,
+        cancelled: &AtomicBool,
+        transfer: &Transfer,
+    ) -> Result<bool> {
+        let mut stream = self.dev.connect_payload(port).await?;
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut last_update = Instant::now();
+
+        let mut skip = *written;
+        while skip > 0 {
+            let len = buf.len().min(skip as usize);
+            let n = tokio::time::timeout(PAYLOAD_READ_TIMEOUT, stream.read(&mut buf[..len]))
+                .await
+                .context("Timed out reading payload")??;
+            if n == 0 {
+                bail!("Payload connection closed while skipping received data");
+            }
+            skip -= n as u64;
+            self.dev.stats().add_received(n);
+            transfer.throttle(n).await;
+        }
+
+        let mut remaining = size - *written;
+
+        while remaining > 0 {
+            if cancelled.load(Ordering::Relaxed) || transfer.is_cancelled() {
+                return Ok(false);
+            }
+
+            let len = buf.len().min(remaining as usize);
+            let n = tokio::time::timeout(PAYLOAD_READ_TIMEOUT, stream.read(&mut buf[..len]))
+                .await
+                .context("Timed out reading payload")??;
+            if n == 0 {
+                bail!(
+                    "Payload connection closed with {} bytes remaining",
+                    remaining
+                );
+            }
+
+            file.write_all(&buf[..n]).await?;
+            remaining -= n as u64;
+            *written += n as u64;
+            self.dev.stats().add_received(n);
+            transfer.progress().add(n);
+            transfer.throttle(n).await;
+
+            let update_due = last_update.elapsed() >= PROGRESS_INTERVAL;
+            let speed = update_due.then(|| self.dev.stats().snapshot().receive_speed);
+            let progress = {
+                let mut transfer = self.transfer.lock().unwrap();
+                transfer.as_mut().map(|t| {
+                    t.received_size += n as u64;
+                    t.last_activity = Instant::now();
+                    t.progress(speed)
+                })
+            };
+            if let Some(progress) = progress {
+                if update_due {
+                    last_update = Instant::now();
+                    self.update_progress_toast(progress).await;
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Mark the current file as finished, and show the result once the batch is complete.
+    async fn finish_file(&self, saved: Option<PathBuf>) {
+        let done = {
+            let mut transfer = self.transfer.lock().unwrap();
+            let t = match transfer.as_mut() {
+                Some(t) => t,
+                None => return,
+            };
+
+            t.finished_files += 1;
+            t.saved.extend(saved);
+
+            if t.is_done() {
+                transfer.take()
+            } else {
+                None
+            }
+        };
+
+        if let Some(t) = done {
+            self.show_result_toast(&t).await;
+        }
+    }
+
+    fn update_totals(&self, update: ShareRequestUpdate) {
+        let mut transfer = self.transfer.lock().unwrap();
+        if let Some(t) = transfer.as_mut() {
+            if let Some(n) = update.number_of_files {
+                t.number_of_files = n.max(t.finished_files + 1);
+            }
+            if let Some(size) = update.total_payload_size {
+                t.total_size = size;
+            }
+            t.last_activity = Instant::now();
+        }
+    }
+
+    /// Ask whether the batch that `file` starts should be received, which is declined if the
+    /// toast is dismissed or not answered in time.
+    async fn ask_accept(&self, file: &ShareFile, size: u64) -> bool {
+        let (number_of_files, total_size) = match &*self.transfer.lock().unwrap() {
+            Some(t) => (t.number_of_files, t.total_size),
+            None => (1, size),
+        };
+        let mut toast = Toast::new();
+        toast
+            .text1(accept_title(&file.filename, number_of_files))
+            .text2(utils::format_size(total_size))
+            .text3(Text::new(self.dev.device_name()).as_attribution())
+            .tag(&self.toast_tag)
+            .group(&self.toast_group)
+            .action(Action::new("Accept", ACTION_ACCEPT, ""))
+            .action(Action::new("Decline", ACTION_DECLINE, ""));
+
+        let (answer, rx) = answer_channel();
+        let callbacks = ToastCallbacks::new()
+            .on_dismissed({
+                let answer = answer.clone();
+                move |_| answer(false)
+            })
+            .on_activated(move |arg| answer(matches!(arg.as_deref(), Ok(ACTION_ACCEPT))));
+
+        if let Err(e) = TOASTS.show_with_callbacks(toast, callbacks).await {
+            log::error!("Failed to show toast: {:?}", e);
+            return false;
+        }
+
+        match wait_for_answer(rx, ACCEPT_TIMEOUT).await {
+            Some(accepted) => accepted,
+            None => {
+                TOASTS
+                    .remove_grouped_tag(self.toast_group.as_str(), self.toast_tag.as_str())
+                    .await
+                    .ok();
+                false
+            }
+        }
+    }
+
+    async fn show_progress_toast(&self, cancelled: Arc<AtomicBool>) {
+        let progress = match &*self.transfer.lock().unwrap() {
+            Some(t) => t.progress(None),
+            None => return,
+        };
+
+        let mut toast = Toast::new();
+        toast
+            .text1("Receiving files")
+            .text3(Text::new(self.dev.device_name()).as_attribution())
+            .tag(&self.toast_tag)
+            .group(&self.toast_group)
+            .progress(progress)
+            .action(Action::new("Cancel", ACTION_CANCEL, ""));
+
+        let on_activated = move |arg: winrt_toast::Result<String>| {
+            if matches!(arg.as_deref(), Ok(ACTION_CANCEL)) {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+        };
+
+        let callbacks = ToastCallbacks::new().on_activated(on_activated);
+        if let Err(e) = TOASTS.show_with_callbacks(toast, callbacks).await {
+            log::error!("Failed to show progress toast: {:?}", e);
+        }
+    }
+
+    async fn update_progress_toast(&self, progress: Progress) {
+        let res = TOASTS
+            .update_progress(
+                self.toast_tag.as_str(),
+                Some(self.toast_group.as_str()),
+                progress,
+            )
+            .await;
+        if let Err(e) = res {
+            log::error!("Failed to update progress toast: {:?}", e);
+        }
+    }
+
+    async fn show_result_toast(&self, t: &Batch) {
+        let title = if t.cancelled.load(Ordering::Relaxed) {
+            "Transfer cancelled".to_string()
+        } else if t.saved.len() == t.number_of_files as usize {
+            match t.number_of_files {
+                1 => format!("Received {}", t.current_file),
+                n => format!("Received {} files", n),
+            }
+        } else {
+            format!("Received {} of {} files", t.saved.len(), t.number_of_files)
+        };
+
+        let mut toast = Toast::new();
+        toast
+            .text1(title)
+            .text3(Text::new(self.dev.device_name()).as_attribution())
+            .tag(&self.toast_tag)
+            .group(&self.toast_group);
+
+        let last_saved = t.saved.last().cloned();
+        if let Some(last_saved) = &last_saved {
+            if t.saved.len() == 1 {
+                toast.text2(last_saved.display().to_string());
+                toast.action(Action::new("Open", ACTION_OPEN, ""));
+            }
+            toast.action(Action::new("Open folder", ACTION_OPEN_FOLDER, ""));
+        }
+
+        let rt_handle = tokio::runtime::Handle::current();
+        let on_activated = move |arg: winrt_toast::Result<String>| {
+            let path = match &last_saved {
+                Some(path) => path.clone(),
+                None => return,
+            };
+
+            match arg.as_deref() {
+                Ok(ACTION_OPEN) => {
+                    rt_handle.spawn(async move {
+                        utils::log_if_error(
+                            "Failed to open file",
+                            utils::open::open_path(path).await,
+                        );
+                    });
+                }
+                Ok(ACTION_OPEN_FOLDER) => {
+                    rt_handle.spawn(async move {
+                        utils::log_if_error(
+                            "Failed to open folder",
+                            utils::open::show_in_folder(path).await,
+                        );
+                    });
+                }
+                _ => {}
+            }
+        };
+
+        let callbacks = ToastCallbacks::new().on_activated(on_activated);
+        if let Err(e) = TOASTS.show_with_callbacks(toast, callbacks).await {
+            log::error!("Failed to show toast: {:?}", e);
+        }
+    }
+
+    async fn receive_text(&self, text: String) -> Result<()> {
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .share;
+
+        if matches!(
+            settings.text_mode,
+            TextShareMode::Editor | TextShareMode::Both
+        ) {
+            // Text files open with the default editor. Named by content, so sharing the same
+            // text again reuses the file.
+            let key = CacheKey::new(CacheKind::Text, ContentHash::sha256(&text));
+            PAYLOAD_CACHE.put(&key, text.clone().into_bytes()).await?;
+            if let Some(path) = PAYLOAD_CACHE.get_path(&key).await? {
+                utils::open::open_path(path).await?;
+            }
+        }
+        if matches!(
+            settings.text_mode,
+            TextShareMode::Clipboard | TextShareMode::Both
+        ) {
+            utils::clipboard::write_text_async(text).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn open_url(&self, url: String) -> Result<()> {
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .share;
+        utils::open::check_url(&url, &settings.url_schemes)?;
+
+        if self.trust().opens_urls(settings.confirm_urls) {
+            utils::open::open_url(url, &settings.url_schemes).await
+        } else {
+            self.show_url_toast(url, settings.url_schemes).await
+        }
+    }
+
+    /// Ask whether the URL should be opened.
+    async fn show_url_toast(&self, url: String, url_schemes: Vec<String>) -> Result<()> {
+        let mut toast = Toast::new();
+        toast
+            .text1("Open link?")
+            .text2(url.clone())
+            .text3(Text::new(self.dev.device_name()).as_attribution())
+            .group(&self.toast_group)
+            .action(Action::new("Open", ACTION_OPEN_URL, ""));
+
+        let rt_handle = tokio::runtime::Handle::current();
+        let on_activated = move |arg: winrt_toast::Result<String>| {
+            if matches!(arg.as_deref(), Ok(ACTION_OPEN_URL)) {
+                let url = url.clone();
+                let url_schemes = url_schemes.clone();
+                rt_handle.spawn(async move {
+                    utils::log_if_error(
+                        "Failed to open URL",
+                        utils::open::open_url(url, &url_schemes).await,
+                    );
+                });
+            }
+        };
+
+        let callbacks = ToastCallbacks::new().on_activated(on_activated);
+        TOASTS.show_with_callbacks(toast, callbacks).await?;
+
+        Ok(())
+    }
+
+    async fn receive_request(
+        &self,
+        request: ShareRequestPacket,
+        payload: Option<Payload>,
+    ) -> Result<()> {
+        match request {
+            ShareRequestPacket::Text { text } => {
+                log::info!(
+                    "Received {} characters of text from {}",
+                    text.chars().count(),
+                    self.dev.device_name()
+                );
+                self.receive_text(text).await
+            }
+            ShareRequestPacket::Url { url } => {
+                log::info!("Received a URL from {}", self.dev.device_name());
+                self.open_url(url).await
+            }
+            ShareRequestPacket::File(file) => self.receive_file(file, payload).await,
+        }
+    }
+
+    async fn receive_update(&self, update: ShareRequestUpdate) -> Result<()> {
+        self.update_totals(update);
+        Ok(())
+    }
+}
+
+/// The question asked before receiving a batch.
+fn accept_title(filename: &str, number_of_files: u32) -> String {
+    match number_of_files {
+        1 => format!("Receive {}?", filename),
+        n => format!("Receive {} files?", n),
+    }
+}
+
+/// A function answering a question asked in a toast, of which only the first call counts, and
+/// the receiver of the answer.
+fn answer_channel() -> (
+    impl Fn(bool) + Clone + Send + Sync + 'static,
+    oneshot::Receiver<bool>,
+) {
+    let (tx, rx) = oneshot::channel();
+    let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+    let answer = move |accepted: bool| {
+        if let Some(tx) = tx.lock().unwrap().take() {
+            tx.send(accepted).ok();
+        }
+    };
+    (answer, rx)
+}
+
+/// The answer, or `None` if there was none within `timeout`.
+async fn wait_for_answer(rx: oneshot::Receiver<bool>, timeout: Duration) -> Option<bool> {
+    tokio::time::timeout(timeout, rx).await.ok()?.ok()
+}
+
+/// The share request of a retried file, which describes a batch of that file only.
+fn retry_packet(file: &ShareFile, size: u64, port: u16) -> NetworkPacket {
+    let file = ShareFile {
+        number_of_files: Some(1),
+        total_payload_size: Some(size),
+        ..file.clone()
+    };
+    let mut packet = NetworkPacket::new(PacketKind::ShareRequest, ShareRequestPacket::File(file));
+    packet.set_payload(size, port);
+    packet
+}
+
+/// Make a filename sent by the device safe to be used in the download directory.
+///
+/// Only the last path component is kept, characters that are not allowed by Windows are
+/// replaced, and reserved device names are prefixed.
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows ignores trailing dots and spaces, which would make "a." and "a" the same file.
+    let trimmed = name.trim_start().trim_end_matches(['.', ' ']);
+    name = trimmed.to_string();
+
+    if name.chars().count() > MAX_FILENAME_LENGTH {
+        let path = Path::new(&name);
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().into_owned())
+            .filter(|e| e.chars().count() < 16);
+        let stem_len = MAX_FILENAME_LENGTH - ext.as_ref().map_or(0, |e| e.chars().count() + 1);
+        let stem: String = name.chars().take(stem_len).collect();
+        name = match ext {
+            Some(ext) => format!("{}.{}", stem.trim_end_matches(['.', ' ']), ext),
+            None => stem,
+        };
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        name.insert(0, '_');
+    }
+
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name
+    }
+}
+
+async fn md5_file(path: &Path) -> Result<ContentHash> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut context = md5::Context::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buf[..n]);
+    }
+    Ok(context.compute().into())
+}
+
+/// Create the file that `path` is written to while it is being downloaded, next to it.
+///
+/// The name has a random part and the file must not exist yet, so that no file of the user is
+/// overwritten, even one named like a partial download.
+async fn create_part_file(path: &Path) -> Result<(PathBuf, tokio::fs::File)> {
+    for _ in 0..10 {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(
+            ".{}.part",
+            &uuid::Uuid::new_v4().simple().to_string()[..8]
+        ));
+        let part_path = PathBuf::from(name);
+
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await
+        {
+            Ok(file) => return Ok((part_path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    bail!("Failed to create a partial file for {}", path.display())
+}
+
+/// Create a new file named `name` in `dir`, adding a " (n)" suffix if it already exists.
+async fn create_unique_file(dir: &Path, name: &str) -> Result<(PathBuf, tokio::fs::File)> {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy().into_owned());
+
+    for i in 0..1000 {
+        let candidate = if i == 0 {
+            dir.join(name)
+        } else {
+            match &ext {
+                Some(ext) => dir.join(format!("{} ({}).{}", stem, i, ext)),
+                None => dir.join(format!("{} ({})", stem, i)),
             }
-            PACKET_TYPE_SHARE_REQUEST_UPDATE => {}
-            _ => {}
+        };
+
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await
+        {
+            Ok(file) => return Ok((candidate, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    bail!("Too many files named {} in {}", name, dir.display())
+}
+
+plugin_packets! {
+    SharePlugin {
+        incoming {
+            ShareRequest(ShareRequestPacket) => receive_request with payload,
+            ShareRequestUpdate(ShareRequestUpdate) => receive_update,
+        }
+        outgoing [ShareRequest, ShareRequestUpdate]
+        events [Tray]
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for SharePlugin {
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        menu.settings
+            .add_action(self.download_dir_menu_id, "Change download folder…");
+
+        let trust = self.trust();
+        let mut submenu = TrayMenu::new();
+        for (item, menu_id) in &self.trust_menu_ids {
+            let label = match item {
+                TrustLevel::Ask => "Ask first",
+                TrustLevel::AcceptFiles => "Accept files",
+                TrustLevel::AcceptEverything => "Accept files and links",
+            };
+            submenu.add_toggle(*menu_id, label, *item == trust);
         }
+        menu.settings.add_submenu("Shares", submenu);
+    }
 
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.download_dir_menu_id) {
+            self.change_download_dir()
+                .await
+                .context("Change download folder")?;
+        } else if let Some((trust, _)) = self
+            .trust_menu_ids
+            .iter()
+            .find(|(_, id)| event.is_menu_clicked(*id))
+        {
+            self.set_trust(*trust).context("Save trust level")?;
+            self.ctx.update_tray().await;
+        }
         Ok(())
     }
 }
 
-impl KdeConnectPluginMetadata for SharePlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_SHARE_REQUEST.into(),
-            PACKET_TYPE_SHARE_REQUEST_UPDATE.into(),
-        ]
+#[cfg(test)]
+mod tests {
+    use crate::testing::{mock::MockDeviceHandle, TestContext};
+
+    use super::*;
+
+    fn shared_file(filename: &str, number_of_files: u32) -> ShareFile {
+        ShareFile {
+            filename: filename.to_string(),
+            number_of_files: Some(number_of_files),
+            total_payload_size: Some(number_of_files as u64 * 100),
+            payload_hash: None,
+        }
+    }
+
+    #[test]
+    fn accept_title_names_single_files() {
+        assert_eq!(accept_title("a.jpg", 1), "Receive a.jpg?");
+        assert_eq!(accept_title("a.jpg", 3), "Receive 3 files?");
+    }
+
+    #[tokio::test]
+    async fn first_answer_counts() {
+        let (answer, rx) = answer_channel();
+        let dismissed = answer.clone();
+        answer(true);
+        dismissed(false);
+        assert_eq!(wait_for_answer(rx, ACCEPT_TIMEOUT).await, Some(true));
+    }
+
+    #[tokio::test]
+    async fn unanswered_questions_time_out() {
+        let (answer, rx) = answer_channel();
+        assert_eq!(wait_for_answer(rx, Duration::from_millis(10)).await, None);
+        // Too late.
+        answer(true);
+
+        let (answer, rx) = answer_channel();
+        drop(answer);
+        assert_eq!(wait_for_answer(rx, ACCEPT_TIMEOUT).await, None);
+    }
+
+    #[test]
+    fn retry_packet_is_a_batch_of_one_file() {
+        let packet = retry_packet(&shared_file("b.jpg", 3), 100, 1740);
+        assert_eq!(packet.typ, "kdeconnect.share.request");
+        assert_eq!(packet.payload_size, Some(100));
+        assert_eq!(
+            packet.payload_transfer_info.map(|info| info.port),
+            Some(1740)
+        );
+
+        let file = match serde_json::from_value(packet.body).unwrap() {
+            ShareRequestPacket::File(file) => file,
+            request => panic!("Unexpected request: {:?}", request),
+        };
+        assert_eq!(file.filename, "b.jpg");
+        assert_eq!(file.number_of_files, Some(1));
+        assert_eq!(file.total_payload_size, Some(100));
+    }
+
+    #[test]
+    fn retry_is_received_apart_from_the_batch() -> Result<()> {
+        let context = TestContext::new()?;
+        let device = MockDeviceHandle::new("test-device");
+        let plugin = SharePlugin::new(device.handle(), context.ctx.clone());
+
+        let (_, is_new) = plugin.begin_file(&shared_file("a.jpg", 3), 100);
+        assert!(is_new);
+        plugin
+            .transfer
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .finished_files = 1;
+
+        let batch = plugin.interrupt_batch().unwrap();
+        let retry = retry_packet(&shared_file("b.jpg", 3), 100, 1740);
+        let retry = match serde_json::from_value(retry.body)? {
+            ShareRequestPacket::File(file) => file,
+            request => panic!("Unexpected request: {:?}", request),
+        };
+        let (_, is_new) = plugin.begin_file(&retry, 100);
+        assert!(is_new);
+        {
+            let transfer = plugin.transfer.lock().unwrap();
+            let t = transfer.as_ref().unwrap();
+            assert_eq!((t.finished_files, t.number_of_files), (0, 1));
+        }
+
+        plugin.resume_batch(batch);
+        let transfer = plugin.transfer.lock().unwrap();
+        let t = transfer.as_ref().unwrap();
+        assert_eq!((t.finished_files, t.number_of_files), (1, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn retry_after_cancelled_batch_is_not_skipped() -> Result<()> {
+        let context = TestContext::new()?;
+        let device = MockDeviceHandle::new("test-device");
+        let plugin = SharePlugin::new(device.handle(), context.ctx.clone());
+
+        let (cancelled, _) = plugin.begin_file(&shared_file("a.jpg", 3), 100);
+        cancelled.store(true, Ordering::Relaxed);
+
+        // Another file of the cancelled batch is skipped.
+        let (skipped, is_new) = plugin.begin_file(&shared_file("b.jpg", 3), 100);
+        assert!(!is_new);
+        assert!(skipped.load(Ordering::Relaxed));
+
+        let batch = plugin.interrupt_batch().unwrap();
+        let (retry_cancelled, is_new) = plugin.begin_file(&shared_file("a.jpg", 1), 100);
+        assert!(is_new);
+        assert!(!retry_cancelled.load(Ordering::Relaxed));
+
+        plugin.resume_batch(batch);
+        let (skipped, _) = plugin.begin_file(&shared_file("c.jpg", 3), 100);
+        assert!(skipped.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn filenames_keep_only_the_last_component() {
+        assert_eq!(sanitize_filename("../../secret.txt"), "secret.txt");
+        assert_eq!(sanitize_filename(r"..\..\Windows\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_filename(r"C:\Users\a.txt"), "a.txt");
+        assert_eq!(sanitize_filename("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("photos/"), "file");
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename(""), "file");
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![
-            PACKET_TYPE_SHARE_REQUEST.into(),
-            PACKET_TYPE_SHARE_REQUEST_UPDATE.into(),
-        ]
+
+    #[test]
+    fn filenames_replace_invalid_characters() {
+        assert_eq!(sanitize_filename("a<b>:c\"d|e?f*.txt"), "a_b__c_d_e_f_.txt");
+        assert_eq!(sanitize_filename("tab\there.txt"), "tab_here.txt");
+    }
+
+    #[test]
+    fn filenames_drop_trailing_dots_and_spaces() {
+        assert_eq!(sanitize_filename("report.pdf. . "), "report.pdf");
+        assert_eq!(sanitize_filename("  name"), "name");
+        assert_eq!(sanitize_filename(". ."), "file");
+    }
+
+    #[test]
+    fn filenames_avoid_reserved_names() {
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("con.txt"), "_con.txt");
+        assert_eq!(sanitize_filename("nul .tar.gz"), "_nul .tar.gz");
+        assert_eq!(sanitize_filename("LPT9"), "_LPT9");
+        assert_eq!(sanitize_filename("console.txt"), "console.txt");
+        assert_eq!(sanitize_filename("COM10"), "COM10");
+    }
+
+    #[test]
+    fn long_filenames_keep_their_extension() {
+        let name = sanitize_filename(&format!("{}.txt", "a".repeat(300)));
+        assert_eq!(name.chars().count(), MAX_FILENAME_LENGTH);
+        assert!(name.ends_with("a.txt"));
+    }
+
+    #[tokio::test]
+    async fn part_files_never_replace_existing_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("video.mp4");
+        // A file of the user named like the partial download of the old scheme.
+        tokio::fs::write(dir.join("video.mp4.part"), b"keep").await?;
+
+        let (first, mut file) = create_part_file(&path).await?;
+        file.write_all(b"data").await?;
+        drop(file);
+        let (second, _) = create_part_file(&path).await?;
+
+        let kept = tokio::fs::read(dir.join("video.mp4.part")).await?;
+        let first_content = tokio::fs::read(&first).await?;
+        tokio::fs::remove_dir_all(&dir).await.ok();
+
+        assert_ne!(first, second);
+        assert_ne!(first, dir.join("video.mp4.part"));
+        assert_eq!(first.parent(), Some(dir.as_path()));
+        assert!(first.to_string_lossy().ends_with(".part"));
+        assert_eq!(kept, b"keep");
+        assert_eq!(first_content, b"data");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn md5_of_files() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        // Larger than the read buffer.
+        let data = (0..200 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        tokio::fs::write(&path, &data).await?;
+
+        let hash = md5_file(&path).await;
+        tokio::fs::remove_file(&path).await.ok();
+
+        assert_eq!(hash?, ContentHash::md5(&data));
+        Ok(())
     }
 }
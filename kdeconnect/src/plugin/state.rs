@@ -0,0 +1,106 @@
+/*!
+Exchanges which plugins are enabled with packets of type "kdeconnect.plugins", which is not part
+of the standard protocol, with the following fields:
+
+incomingCapabilities (array<string>) [optional]: Packet types the sender currently accepts
+outgoingCapabilities (array<string>) [optional]: Packet types the sender currently sends
+request (bool) [optional]: Whether the receiver should reply with its own state
+
+Identity packets advertise the capabilities of all plugins, before the device is known, so this
+tells the device which of them are disabled in its settings. Packet types the device stops
+accepting are not sent to it anymore.
+
+Only devices that accept these packets in their identity are sent one, so that the stock apps do
+not get packets they do not know.
+ */
+use std::collections::{BTreeSet, HashSet};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::NetworkPacket;
+
+use super::{packets::PacketKind, PluginRepository};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PluginState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incoming_capabilities: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    outgoing_capabilities: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    request: bool,
+}
+
+/// The name of a plugin in the settings, e.g. "mpris-remote" for `MprisRemotePlugin`.
+pub(super) fn config_name(type_name: &str) -> String {
+    let name = type_name.strip_suffix("Plugin").unwrap_or(type_name);
+    let mut config_name = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                config_name.push('-');
+            }
+            config_name.extend(c.to_lowercase());
+        } else {
+            config_name.push(c);
+        }
+    }
+    config_name
+}
+
+impl PluginRepository {
+    /// Tell the device which packet types the enabled plugins handle, and with `request`, ask
+    /// for the same in return.
+    pub async fn send_state(&self, request: bool) {
+        let caps = self.capabilities();
+        let state = PluginState {
+            incoming_capabilities: Some(caps.incoming),
+            outgoing_capabilities: Some(caps.outgoing),
+            request,
+        };
+        self.dev
+            .send_packet(NetworkPacket::new(PacketKind::Plugins, state))
+            .await;
+    }
+
+    pub(super) async fn receive_state(&self, packet: NetworkPacket) -> Result<()> {
+        let state: PluginState = packet.into_body()?;
+
+        if let Some(incoming) = state.incoming_capabilities {
+            let rejected = self
+                .outgoing_caps
+                .iter()
+                .filter(|&&kind| kind != PacketKind::Plugins)
+                .map(|kind| kind.to_string())
+                .filter(|typ| !incoming.contains(typ))
+                .collect::<HashSet<_>>();
+            if !rejected.is_empty() {
+                log::info!(
+                    "Device {} does not accept {:?}, not sending them",
+                    self.dev.device_id(),
+                    rejected
+                );
+            }
+            self.dev.set_rejected_types(rejected);
+        }
+
+        if state.request {
+            self.send_state(false).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_names() {
+        assert_eq!(config_name("PingPlugin"), "ping");
+        assert_eq!(config_name("MprisRemotePlugin"), "mpris-remote");
+        assert_eq!(config_name("NotificationReceive"), "notification-receive");
+    }
+}
@@ -0,0 +1,290 @@
+//! This plugin periodically sends the CPU, memory and network usage of this computer in packets
+//! with type "kdeconnect.systemstats", for dashboards on the device.
+//!
+//! Fields are only present for the metrics enabled in the settings, and rates only once there
+//! are two samples to compare:
+//!
+//! cpu (float): Percent of CPU time in use since the previous report
+//! memoryUsed, memoryTotal (int): Physical memory, in bytes
+//! networkRx, networkTx (int): Bytes per second received and sent since the previous report
+//!
+//! A packet with type "kdeconnect.systemstats.request" asks for a report right away.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{de::IgnoredAny, Serialize};
+use tao::menu::MenuId;
+use tokio::task::JoinHandle;
+use windows::Win32::{
+    Foundation::FILETIME,
+    NetworkManagement::IpHelper::{FreeMibTable, GetIfTable2, IF_TYPE_SOFTWARE_LOOPBACK},
+    System::{
+        SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX},
+        Threading::GetSystemTimes,
+    },
+};
+
+use crate::{
+    config::{SystemStatsMetric, SystemStatsSettings},
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacket,
+    tray::DeviceMenu,
+};
+
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
+
+/// Shorter intervals would mostly measure the plugin itself.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SystemStatsPacket {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_used: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network_rx: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network_tx: Option<u64>,
+}
+
+/// Counters from the previous sample, to compute usage since then.
+#[derive(Debug, Default)]
+struct Sampler {
+    /// Idle and total CPU time, in 100ns units.
+    cpu: Option<(u64, u64)>,
+    /// Bytes received and sent, and when they were read.
+    network: Option<(u64, u64, Instant)>,
+}
+
+impl Sampler {
+    fn sample(&mut self, metrics: &[SystemStatsMetric]) -> SystemStatsPacket {
+        let mut stats = SystemStatsPacket::default();
+
+        if metrics.contains(&SystemStatsMetric::Cpu) {
+            match cpu_times() {
+                Ok((idle, total)) => {
+                    if let Some((last_idle, last_total)) = self.cpu {
+                        let elapsed = total.saturating_sub(last_total);
+                        let busy = elapsed.saturating_sub(idle.saturating_sub(last_idle));
+                        if elapsed > 0 {
+                            stats.cpu = Some(busy as f32 * 100.0 / elapsed as f32);
+                        }
+                    }
+                    self.cpu = Some((idle, total));
+                }
+                Err(e) => log::warn!("Failed to read CPU usage: {:?}", e),
+            }
+        }
+
+        if metrics.contains(&SystemStatsMetric::Memory) {
+            match memory_usage() {
+                Ok((used, total)) => {
+                    stats.memory_used = Some(used);
+                    stats.memory_total = Some(total);
+                }
+                Err(e) => log::warn!("Failed to read memory usage: {:?}", e),
+            }
+        }
+
+        if metrics.contains(&SystemStatsMetric::Network) {
+            match network_bytes() {
+                Ok((rx, tx)) => {
+                    let now = Instant::now();
+                    if let Some((last_rx, last_tx, last_time)) = self.network {
+                        let secs = (now - last_time).as_secs_f64();
+                        if secs > 0.0 {
+                            stats.network_rx =
+                                Some((rx.saturating_sub(last_rx) as f64 / secs) as u64);
+                            stats.network_tx =
+                                Some((tx.saturating_sub(last_tx) as f64 / secs) as u64);
+                        }
+                    }
+                    self.network = Some((rx, tx, now));
+                }
+                Err(e) => log::warn!("Failed to read network usage: {:?}", e),
+            }
+        }
+
+        stats
+    }
+}
+
+fn filetime_to_u64(time: FILETIME) -> u64 {
+    (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64
+}
+
+/// Idle and total CPU time of all processors since boot.
+fn cpu_times() -> Result<(u64, u64)> {
+    let mut idle = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+    unsafe { GetSystemTimes(Some(&mut idle), Some(&mut kernel), Some(&mut user)) }.ok()?;
+
+    // Kernel time includes idle time.
+    Ok((
+        filetime_to_u64(idle),
+        filetime_to_u64(kernel) + filetime_to_u64(user),
+    ))
+}
+
+/// Used and total physical memory, in bytes.
+fn memory_usage() -> Result<(u64, u64)> {
+    let mut status = MEMORYSTATUSEX {
+        dwLength: std::mem::size_of::<MEMORYSTATUSEX>() as u32,
+        ..Default::default()
+    };
+    unsafe { GlobalMemoryStatusEx(&mut status) }.ok()?;
+
+    Ok((
+        status.ullTotalPhys - status.ullAvailPhys,
+        status.ullTotalPhys,
+    ))
+}
+
+/// Bytes received and sent by all physical interfaces since they came up.
+fn network_bytes() -> Result<(u64, u64)> {
+    unsafe {
+        let mut table = std::ptr::null_mut();
+        GetIfTable2(&mut table).context("Get interface table")?;
+
+        let rows =
+            std::slice::from_raw_parts((*table).Table.as_ptr(), (*table).NumEntries as usize);
+        let (mut rx, mut tx) = (0, 0);
+        for row in rows {
+            // Filter drivers show up as extra rows for the same interface, only count the
+            // hardware one.
+            let is_hardware = row.InterfaceAndOperStatusFlags._bitfield & 1 != 0;
+            if is_hardware && row.Type != IF_TYPE_SOFTWARE_LOOPBACK {
+                rx += row.InOctets;
+                tx += row.OutOctets;
+            }
+        }
+
+        FreeMibTable(table as *const _);
+        Ok((rx, tx))
+    }
+}
+
+#[derive(Debug)]
+pub struct SystemStatsPlugin {
+    dev: DeviceHandle,
+    ctx: AppContextRef,
+    sampler: Mutex<Sampler>,
+    report_task: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    enable_menu_id: MenuId,
+}
+
+impl SystemStatsPlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self {
+            enable_menu_id: MenuId::new(&format!("{}:system_stats:enable", dev.device_id())),
+            dev,
+            ctx,
+            sampler: Mutex::new(Sampler::default()),
+            report_task: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    fn settings(&self) -> SystemStatsSettings {
+        self.ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .system_stats
+    }
+
+    fn toggle_enabled(&self) -> Result<()> {
+        self.ctx
+            .settings()
+            .update_device(self.dev.device_id(), |settings| {
+                let settings = &mut settings.plugins.system_stats;
+                settings.enabled = !settings.enabled;
+            })
+    }
+
+    async fn send_stats(&self, settings: &SystemStatsSettings) {
+        let stats = self.sampler.lock().unwrap().sample(&settings.metrics);
+
+        self.dev
+            .send_packet(NetworkPacket::new(PacketKind::SystemStats, stats))
+            .await;
+    }
+
+    async fn receive_request(&self, _: IgnoredAny) -> Result<()> {
+        let settings = self.settings();
+        if settings.enabled {
+            self.send_stats(&settings).await;
+        }
+        Ok(())
+    }
+}
+
+plugin_packets! {
+    SystemStatsPlugin {
+        incoming {
+            SystemStatsRequest(IgnoredAny) => receive_request,
+        }
+        outgoing [SystemStats]
+        events [Tray]
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for SystemStatsPlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        let this = Arc::downgrade(&self);
+
+        let task = tokio::spawn(async move {
+            // Settings are read on every round, so that changes apply without a reconnect.
+            while let Some(this) = this.upgrade() {
+                let settings = this.settings();
+                if settings.enabled {
+                    this.send_stats(&settings).await;
+                }
+                drop(this);
+
+                let interval = Duration::from_secs(settings.interval).max(MIN_INTERVAL);
+                tokio::time::sleep(interval).await;
+            }
+        });
+        *self.report_task.lock().await = Some(task);
+
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        if let Some(task) = self.report_task.lock().await.take() {
+            task.abort();
+        }
+    }
+
+    async fn tray_menu(&self, menu: &mut DeviceMenu) {
+        menu.settings.add_toggle(
+            self.enable_menu_id,
+            "Send system stats",
+            self.settings().enabled,
+        );
+    }
+
+    async fn handle_event(self: Arc<Self>, event: SystemEvent) -> Result<()> {
+        if event.is_menu_clicked(self.enable_menu_id) {
+            self.toggle_enabled()
+                .context("Save system stats settings")?;
+            self.ctx.update_tray().await;
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,36 @@
 //! This plugin allows to control the system volume.
+//!
+//! Sinks are identified by the ID of their audio endpoint in the `name` field, which stays the
+//! same when several identical devices are attached. The friendly name goes in `description`,
+//! followed by the kind of device and whether it is unplugged when the name does not tell.
+//!
+//! Volume changes made by the device are shown in a toast, unless disabled in its settings. A
+//! slider on the device sends many changes per second, so the toast is updated at most every
+//! [`TOAST_INTERVAL`]. It is not shown in service mode.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use windows_audio_manager::AudioManagerHandle;
+use tokio::{sync::Mutex, task::JoinHandle};
+use windows_audio_manager::{AudioManagerHandle, AudioSinkInfo};
+use winrt_toast::{Audio, Progress, ProgressValue, Text, Toast, ToastTag};
 
-use crate::{device::DeviceHandle, packet::NetworkPacket};
+use crate::{context::AppContextRef, device::DeviceHandle, packet::NetworkPacket, service, utils};
 
-use super::{KdeConnectPlugin, KdeConnectPluginMetadata};
+use super::{
+    packets::{plugin_packets, PacketKind},
+    KdeConnectPlugin,
+};
 
-const PACKET_TYPE_SYSTEM_VOLUME: &str = "kdeconnect.systemvolume";
-const PACKET_TYPE_SYSTEM_VOLUME_REQUEST: &str = "kdeconnect.systemvolume.request";
+/// The volume toast is only useful right after the change.
+const TOAST_EXPIRATION: Duration = Duration::from_secs(10);
+/// Minimum time between two updates of the volume toast.
+const TOAST_INTERVAL: Duration = Duration::from_millis(300);
 
 lazy_static::lazy_static! {
     static ref AUDIO_MANAGER: AudioManagerHandle = {
@@ -19,6 +38,94 @@ lazy_static::lazy_static! {
     };
 }
 
+/// Volume of the default output before [`lower_default_volume`] changed it.
+#[derive(Debug)]
+pub struct PreviousVolume {
+    id: String,
+    volume: u8,
+    lowered_to: u8,
+}
+
+impl PreviousVolume {
+    /// Set the volume back, unless the user changed it in the meantime.
+    pub async fn restore(self) -> Result<()> {
+        let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
+        match sinks.get(&self.id) {
+            Some(sink) if sink.volume == self.lowered_to => {
+                AUDIO_MANAGER.set_volume(&self.id, self.volume).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Lower the volume of the default output to `volume` (0-100), if it is louder.
+pub async fn lower_default_volume(volume: u8) -> Result<Option<PreviousVolume>> {
+    let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
+    let (id, sink) = match sinks.into_iter().find(|(_, sink)| sink.is_active) {
+        Some(default) => default,
+        None => return Ok(None),
+    };
+    if sink.volume <= volume {
+        return Ok(None);
+    }
+
+    AUDIO_MANAGER.set_volume(&id, volume).await?;
+    Ok(Some(PreviousVolume {
+        id,
+        volume: sink.volume,
+        lowered_to: volume,
+    }))
+}
+
+/// The label of a sink on the device, e.g. "Realtek Audio (Headphones, unplugged)".
+fn sink_description(sink: &AudioSinkInfo) -> String {
+    let mut details = vec![];
+    if let Some(label) = sink.form_factor.label() {
+        // Friendly names are often "Speakers (Realtek Audio)" already.
+        if !sink.name.to_lowercase().contains(&label.to_lowercase()) {
+            details.push(label);
+        }
+    }
+    if sink.jack_connected == Some(false) {
+        details.push("unplugged");
+    }
+
+    if details.is_empty() {
+        sink.name.clone()
+    } else {
+        format!("{} ({})", sink.name, details.join(", "))
+    }
+}
+
+/// The volume (0-100) of an application, given by its AppUserModelID, or of the default output
+/// if the application has no audio session there.
+pub async fn app_volume(app: &str) -> Result<Option<u8>> {
+    if let Some(volume) = AUDIO_MANAGER.get_app_volume(app).await? {
+        return Ok(Some(volume));
+    }
+
+    let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
+    Ok(sinks
+        .values()
+        .find(|sink| sink.is_active)
+        .map(|sink| sink.volume))
+}
+
+/// Set the volume of an application, or of the default output if the application has no audio
+/// session there.
+pub async fn set_app_volume(app: &str, volume: u8) -> Result<()> {
+    if AUDIO_MANAGER.set_app_volume(app, volume).await? {
+        return Ok(());
+    }
+
+    let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
+    if let Some((id, _)) = sinks.iter().find(|(_, sink)| sink.is_active) {
+        AUDIO_MANAGER.set_volume(id, volume).await?;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct SystemVolumeSink {
@@ -34,12 +141,18 @@ struct SystemVolumeSink {
 #[serde(untagged)]
 enum SystemVolumePacket {
     #[serde(rename_all = "camelCase")]
-    SinkList { sink_list: Vec<SystemVolumeSink> },
+    SinkList {
+        sink_list: Vec<SystemVolumeSink>,
+    },
     VolumeUpdate {
         name: String,
         volume: u8,
         muted: bool,
     },
+    EnabledUpdate {
+        name: String,
+        enabled: bool,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,34 +169,81 @@ enum RequestPacket {
     },
 }
 
+/// Throttles the updates of the volume toast.
+#[derive(Debug, Default)]
+struct ToastThrottle {
+    /// Sink of the last change, not shown yet.
+    pending: Option<String>,
+    scheduled: bool,
+    last_shown: Option<Instant>,
+}
+
+impl ToastThrottle {
+    /// Record a change of the volume of `id`. Returns after how long the toast must be shown,
+    /// unless it already is scheduled.
+    fn change(&mut self, id: String, now: Instant) -> Option<Duration> {
+        self.pending = Some(id);
+        if self.scheduled {
+            return None;
+        }
+        self.scheduled = true;
+        Some(
+            self.last_shown
+                .map(|shown| TOAST_INTERVAL.saturating_sub(now.saturating_duration_since(shown)))
+                .unwrap_or_default(),
+        )
+    }
+
+    /// The sink to show once the scheduled delay elapsed.
+    fn take(&mut self, now: Instant) -> Option<String> {
+        self.scheduled = false;
+        self.last_shown = Some(now);
+        self.pending.take()
+    }
+}
+
 #[derive(Debug)]
 pub struct SystemVolumePlugin {
     dev: DeviceHandle,
+    ctx: AppContextRef,
+    notify_task: Mutex<Option<JoinHandle<()>>>,
+    /// Endpoint IDs of the sinks last sent to the device, with their friendly names.
+    sinks: Mutex<HashMap<String, String>>,
+    toast_throttle: Arc<std::sync::Mutex<ToastThrottle>>,
 }
 
 impl SystemVolumePlugin {
-    pub fn new(dev: DeviceHandle) -> Self {
-        SystemVolumePlugin { dev }
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        SystemVolumePlugin {
+            dev,
+            ctx,
+            notify_task: Mutex::new(None),
+            sinks: Mutex::new(HashMap::new()),
+            toast_throttle: Default::default(),
+        }
     }
 
     pub async fn send_sink_list(&self) -> Result<()> {
         let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
         let mut sink_list = Vec::with_capacity(sinks.len());
+        let mut known_sinks = HashMap::with_capacity(sinks.len());
 
-        for (_id, sink) in sinks {
+        for (id, sink) in sinks {
+            known_sinks.insert(id.clone(), sink.name.clone());
             sink_list.push(SystemVolumeSink {
-                name: sink.name,
-                description: sink.description,
+                description: sink_description(&sink),
+                name: id,
                 muted: sink.is_muted,
                 volume: sink.volume,
                 max_volume: 100,
                 enabled: sink.is_active,
             });
         }
+        *self.sinks.lock().await = known_sinks;
 
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_SYSTEM_VOLUME,
+                PacketKind::SystemVolume,
                 SystemVolumePacket::SinkList { sink_list },
             ))
             .await;
@@ -91,10 +251,35 @@ impl SystemVolumePlugin {
         Ok(())
     }
 
+    /// Find the endpoint addressed by a command.
+    ///
+    /// Older clients may still use the friendly name, which is accepted if it is unambiguous.
+    async fn resolve_sink(&self, name: &str) -> Option<String> {
+        let sinks = self.sinks.lock().await;
+        if sinks.contains_key(name) {
+            return Some(name.to_string());
+        }
+
+        let mut matches = sinks.iter().filter(|(_, n)| *n == name);
+        match (matches.next(), matches.next()) {
+            (Some((id, _)), None) => Some(id.clone()),
+            _ => None,
+        }
+    }
+
+    async fn send_enabled_update(&self, name: String, enabled: bool) {
+        self.dev
+            .send_packet(NetworkPacket::new(
+                PacketKind::SystemVolume,
+                SystemVolumePacket::EnabledUpdate { name, enabled },
+            ))
+            .await;
+    }
+
     async fn send_volume_update(&self, name: String, volume: u8, muted: bool) {
         self.dev
             .send_packet(NetworkPacket::new(
-                PACKET_TYPE_SYSTEM_VOLUME,
+                PacketKind::SystemVolume,
                 SystemVolumePacket::VolumeUpdate {
                     name,
                     volume,
@@ -103,6 +288,133 @@ impl SystemVolumePlugin {
             ))
             .await;
     }
+
+    async fn receive_request(&self, request: RequestPacket) -> Result<()> {
+        match request {
+            RequestPacket::RequestSinks { .. } => {
+                self.send_sink_list().await?;
+            }
+            RequestPacket::Command {
+                name,
+                volume,
+                muted,
+                enabled: _enabled,
+            } => {
+                let id = match self.resolve_sink(&name).await {
+                    Some(id) => id,
+                    None => {
+                        log::warn!("Unknown sink: {}", name);
+                        // The device has an outdated list.
+                        self.send_sink_list().await?;
+                        return Ok(());
+                    }
+                };
+
+                if let Some(volume) = volume {
+                    AUDIO_MANAGER.set_volume(&id, volume).await?;
+                }
+                if let Some(muted) = muted {
+                    AUDIO_MANAGER.set_muted(&id, muted).await?;
+                }
+                // if let Some(enabled) = enabled {
+                //     AUDIO_MANAGER.set_default_sink(id).await?;
+                // }
+
+                if volume.is_some() || muted.is_some() {
+                    self.schedule_volume_toast(id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Let the user know why the volume changed. Each device has a single toast, replaced by the
+    /// next change.
+    fn schedule_volume_toast(&self, id: String) {
+        let device_id = self.dev.device_id();
+        if !self
+            .ctx
+            .settings()
+            .device(device_id)
+            .plugins
+            .system_volume
+            .show_toast
+        {
+            return;
+        }
+        if service::link::get().is_some() {
+            // Toasts with a progress bar cannot be forwarded to the session of the user.
+            log::debug!("Not showing the volume toast in service mode");
+            return;
+        }
+
+        let delay = match self
+            .toast_throttle
+            .lock()
+            .unwrap()
+            .change(id, Instant::now())
+        {
+            Some(delay) => delay,
+            None => return,
+        };
+        let throttle = self.toast_throttle.clone();
+        let dev = self.dev.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let id = throttle.lock().unwrap().take(Instant::now());
+            if let Some(id) = id {
+                show_volume_toast(&dev, &id).await;
+            }
+        });
+    }
+}
+
+async fn show_volume_toast(dev: &DeviceHandle, id: &str) {
+    let sink = match AUDIO_MANAGER.get_audio_sink_info().await {
+        Ok(mut sinks) => match sinks.remove(id) {
+            Some(sink) => sink,
+            None => return,
+        },
+        Err(e) => {
+            log::warn!("Failed to get volume for toast: {:?}", e);
+            return;
+        }
+    };
+
+    let status = if sink.is_muted {
+        "Muted".to_string()
+    } else {
+        format!("{}%", sink.volume)
+    };
+    let mut toast = Toast::new();
+    toast
+        .text1("Volume changed")
+        .text3(Text::new(dev.device_name()).as_attribution())
+        .progress(
+            Progress::new(
+                &sink.name,
+                ProgressValue::Determinate(sink.volume as f64 / 100.0),
+            )
+            .with_value_string(status),
+        )
+        .tag(ToastTag::hashed(format!(
+            "system-volume:{}",
+            dev.device_id()
+        )))
+        .audio(Audio::silent())
+        .expires_in(TOAST_EXPIRATION);
+
+    utils::show_toast(toast).await;
+}
+
+plugin_packets! {
+    SystemVolumePlugin {
+        incoming {
+            SystemVolumeRequest(RequestPacket) => receive_request,
+        }
+        outgoing [SystemVolume]
+    }
 }
 
 #[async_trait::async_trait]
@@ -111,7 +423,7 @@ impl KdeConnectPlugin for SystemVolumePlugin {
         let this = Arc::downgrade(&self);
         let mut notify_rx = AUDIO_MANAGER.subscribe_notification().await?;
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             while let Some(notification) = notify_rx.recv().await {
                 if let Some(this) = this.upgrade() {
                     match notification {
@@ -119,12 +431,21 @@ impl KdeConnectPlugin for SystemVolumePlugin {
                             this.send_sink_list().await.ok();
                         }
                         windows_audio_manager::AudioNotification::VolumeUpdated {
-                            id: _id,
-                            name,
+                            id,
+                            name: _name,
                             volume,
                             muted,
                         } => {
-                            this.send_volume_update(name, volume, muted).await;
+                            this.send_volume_update(id.to_string(), volume, muted).await;
+                        }
+                        windows_audio_manager::AudioNotification::DefaultChanged {
+                            id,
+                            previous,
+                        } => {
+                            if let Some(previous) = previous {
+                                this.send_enabled_update(previous.to_string(), false).await;
+                            }
+                            this.send_enabled_update(id.to_string(), true).await;
                         }
                     }
                 } else {
@@ -133,53 +454,129 @@ impl KdeConnectPlugin for SystemVolumePlugin {
                 }
             }
         });
+        *self.notify_task.lock().await = Some(task);
 
         Ok(())
     }
 
-    async fn handle(&self, packet: NetworkPacket) -> Result<()> {
-        match packet.typ.as_str() {
-            PACKET_TYPE_SYSTEM_VOLUME_REQUEST => {
-                match packet.into_body::<RequestPacket>()? {
-                    RequestPacket::RequestSinks { .. } => {
-                        self.send_sink_list().await?;
-                    }
-                    RequestPacket::Command {
-                        name,
-                        volume,
-                        muted,
-                        enabled: _enabled,
-                    } => {
-                        let sinks = AUDIO_MANAGER.get_audio_sink_info().await?;
-
-                        for (id, sink) in sinks {
-                            if sink.name == name {
-                                if let Some(volume) = volume {
-                                    AUDIO_MANAGER.set_volume(&id, volume).await?;
-                                }
-                                if let Some(muted) = muted {
-                                    AUDIO_MANAGER.set_muted(&id, muted).await?;
-                                }
-                                // if let Some(enabled) = enabled {
-                                //     AUDIO_MANAGER.set_default_sink(id).await?;
-                                // }
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {}
+    async fn stop(&self) {
+        if let Some(task) = self.notify_task.lock().await.take() {
+            task.abort();
         }
-
-        Ok(())
     }
 }
 
-impl KdeConnectPluginMetadata for SystemVolumePlugin {
-    fn incoming_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_SYSTEM_VOLUME_REQUEST.into()]
+#[cfg(test)]
+mod tests {
+    use windows_audio_manager::FormFactor;
+
+    use super::*;
+
+    fn sink(name: &str, form_factor: FormFactor, jack_connected: Option<bool>) -> AudioSinkInfo {
+        AudioSinkInfo {
+            name: name.to_string(),
+            description: String::new(),
+            is_active: false,
+            is_muted: false,
+            volume: 50,
+            form_factor,
+            jack_connected,
+        }
     }
-    fn outgoing_capabilities() -> Vec<String> {
-        vec![PACKET_TYPE_SYSTEM_VOLUME.into()]
+
+    #[test]
+    fn description_adds_the_kind_of_device() {
+        assert_eq!(
+            sink_description(&sink("Realtek Audio", FormFactor::Headphones, None)),
+            "Realtek Audio (Headphones)"
+        );
+        assert_eq!(
+            sink_description(&sink("DELL U2720Q", FormFactor::Hdmi, None)),
+            "DELL U2720Q (HDMI)"
+        );
+    }
+
+    #[test]
+    fn description_does_not_repeat_the_name() {
+        assert_eq!(
+            sink_description(&sink(
+                "Speakers (Realtek Audio)",
+                FormFactor::Speakers,
+                None
+            )),
+            "Speakers (Realtek Audio)"
+        );
+        assert_eq!(
+            sink_description(&sink("USB headset", FormFactor::Headset, None)),
+            "USB headset"
+        );
+    }
+
+    #[test]
+    fn description_of_unknown_devices_is_the_name() {
+        assert_eq!(
+            sink_description(&sink("Virtual Cable", FormFactor::Unknown, None)),
+            "Virtual Cable"
+        );
+        assert_eq!(
+            sink_description(&sink("Virtual Cable", FormFactor::Unknown, Some(true))),
+            "Virtual Cable"
+        );
+    }
+
+    #[test]
+    fn description_tells_unplugged_jacks() {
+        assert_eq!(
+            sink_description(&sink("Realtek Audio", FormFactor::Headphones, Some(false))),
+            "Realtek Audio (Headphones, unplugged)"
+        );
+        assert_eq!(
+            sink_description(&sink(
+                "Speakers (Realtek Audio)",
+                FormFactor::Speakers,
+                Some(false)
+            )),
+            "Speakers (Realtek Audio) (unplugged)"
+        );
+    }
+
+    #[test]
+    fn first_change_is_shown_at_once() {
+        let mut throttle = ToastThrottle::default();
+        let now = Instant::now();
+        assert_eq!(throttle.change("a".into(), now), Some(Duration::ZERO));
+        assert_eq!(throttle.take(now), Some("a".to_string()));
+    }
+
+    #[test]
+    fn changes_are_coalesced_until_shown() {
+        let mut throttle = ToastThrottle::default();
+        let now = Instant::now();
+        throttle.change("a".into(), now);
+        assert_eq!(throttle.change("b".into(), now), None);
+        assert_eq!(throttle.change("c".into(), now), None);
+        assert_eq!(throttle.take(now), Some("c".to_string()));
+        assert_eq!(throttle.take(now), None);
+    }
+
+    #[test]
+    fn changes_wait_for_the_interval() {
+        let mut throttle = ToastThrottle::default();
+        let now = Instant::now();
+        throttle.change("a".into(), now);
+        throttle.take(now);
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(
+            throttle.change("a".into(), later),
+            Some(TOAST_INTERVAL - Duration::from_millis(100))
+        );
+        throttle.take(later + TOAST_INTERVAL);
+
+        let much_later = later + TOAST_INTERVAL * 3;
+        assert_eq!(
+            throttle.change("a".into(), much_later),
+            Some(Duration::ZERO)
+        );
     }
 }
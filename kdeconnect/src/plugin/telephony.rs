@@ -0,0 +1,145 @@
+/*!
+This plugin receives the "kdeconnect.telephony" packets sent by the phone when it
+rings or a call is active, with "event" set to "ringing" or "talking". The same
+packet with "isCancel" set to true is sent when the call ends.
+
+During a call, the players of this computer are paused and the volume of the
+default output is lowered, as configured in the settings of the device. Both are
+restored when the call ends.
+ */
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::{context::AppContextRef, device::DeviceHandle, utils};
+
+use super::{
+    mpris::{self, PausedPlayers},
+    packets::plugin_packets,
+    system_volume::{self, PreviousVolume},
+    KdeConnectPlugin,
+};
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum TelephonyEvent {
+    Ringing,
+    Talking,
+    MissedCall,
+    Sms,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TelephonyPacket {
+    event: TelephonyEvent,
+    #[serde(default)]
+    is_cancel: bool,
+}
+
+/// What was changed when the call started, to undo it when it ends.
+#[derive(Debug, Default)]
+struct CallState {
+    paused: PausedPlayers,
+    volume: Option<PreviousVolume>,
+}
+
+#[derive(Debug)]
+pub struct TelephonyPlugin {
+    dev: DeviceHandle,
+    ctx: AppContextRef,
+    /// Set while the phone rings or a call is active.
+    call: Mutex<Option<CallState>>,
+}
+
+impl TelephonyPlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self {
+            dev,
+            ctx,
+            call: Mutex::new(None),
+        }
+    }
+
+    async fn call_started(&self) {
+        let mut call = self.call.lock().await;
+        // "talking" follows "ringing" when the call is picked up.
+        if call.is_some() {
+            return;
+        }
+
+        let settings = self
+            .ctx
+            .settings()
+            .device(self.dev.device_id())
+            .plugins
+            .telephony;
+
+        let mut state = CallState::default();
+        if settings.pause_media {
+            match mpris::pause_playing().await {
+                Ok(paused) => state.paused = paused,
+                Err(e) => log::error!("Failed to pause players: {:?}", e),
+            }
+        }
+        if let Some(volume) = settings.call_volume {
+            match system_volume::lower_default_volume(volume.min(100)).await {
+                Ok(previous) => state.volume = previous,
+                Err(e) => log::error!("Failed to lower volume: {:?}", e),
+            }
+        }
+
+        *call = Some(state);
+    }
+
+    async fn call_ended(&self) {
+        let state = match self.call.lock().await.take() {
+            Some(state) => state,
+            None => return,
+        };
+
+        if let Some(volume) = state.volume {
+            utils::log_if_error("Failed to restore volume", volume.restore().await);
+        }
+        state.paused.resume().await;
+    }
+
+    async fn receive_event(&self, body: TelephonyPacket) -> Result<()> {
+        log::debug!(
+            "Telephony event: {:?}, cancelled: {}",
+            body.event,
+            body.is_cancel
+        );
+
+        match body.event {
+            TelephonyEvent::Ringing | TelephonyEvent::Talking if !body.is_cancel => {
+                self.call_started().await;
+            }
+            TelephonyEvent::Ringing | TelephonyEvent::Talking | TelephonyEvent::MissedCall => {
+                self.call_ended().await;
+            }
+            TelephonyEvent::Sms | TelephonyEvent::Unknown => {}
+        }
+
+        Ok(())
+    }
+}
+
+plugin_packets! {
+    TelephonyPlugin {
+        incoming {
+            Telephony(TelephonyPacket) => receive_event,
+        }
+        outgoing []
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for TelephonyPlugin {
+    async fn stop(&self) {
+        // The device may disconnect in the middle of a call.
+        self.call_ended().await;
+    }
+}
@@ -0,0 +1,218 @@
+//! This plugin remembers the MAC address of the device while it is connected, so that it can be
+//! woken up later with a Wake-on-LAN magic packet from the tray menu.
+//!
+//! It also receives packets with type "kdeconnect.wake.request", which turn on the display of
+//! this computer.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use serde::de::IgnoredAny;
+use tao::menu::MenuId;
+use tokio::net::UdpSocket;
+use windows::Win32::{
+    NetworkManagement::IpHelper::SendARP,
+    System::Power::{SetThreadExecutionState, ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED},
+    UI::Input::KeyboardAndMouse,
+};
+
+use crate::{
+    context::AppContextRef, device::DeviceHandle, event::SystemEvent, tray::TrayMenu, utils,
+};
+
+use super::{packets::plugin_packets, KdeConnectPlugin};
+
+/// The discard port, commonly used for magic packets.
+const WAKE_ON_LAN_PORT: u16 = 9;
+
+#[derive(Debug)]
+pub struct WakeOnLanPlugin {
+    dev: DeviceHandle,
+    ctx: AppContextRef,
+}
+
+impl WakeOnLanPlugin {
+    pub fn new(dev: DeviceHandle, ctx: AppContextRef) -> Self {
+        Self { dev, ctx }
+    }
+
+    /// Look up the MAC address of the device and save it.
+    async fn learn_mac_address(&self) -> Result<()> {
+        let device_id = self.dev.device_id();
+        let address = self
+            .ctx
+            .device_manager()
+            .list_devices()
+            .await?
+            .into_iter()
+            .find(|d| d.id == device_id)
+            .map(|d| d.address);
+
+        let ip = match address {
+            Some(IpAddr::V4(ip)) => ip,
+            // ARP is IPv4 only.
+            _ => return Ok(()),
+        };
+        let mac = tokio::task::spawn_blocking(move || resolve_mac_address(ip)).await??;
+
+        let settings = self.ctx.settings().device(device_id);
+        if settings.plugins.wake_on_lan.mac_address.as_ref() != Some(&mac) {
+            log::info!("MAC address of {} is {}", device_id, mac);
+            self.ctx.settings().update_device(device_id, |settings| {
+                settings.plugins.wake_on_lan.mac_address = Some(mac);
+            })?;
+        }
+
+        Ok(())
+    }
+
+    async fn receive_wake_request(&self, _: IgnoredAny) -> Result<()> {
+        log::info!("Wake request from {}", self.dev.device_name());
+        wake_display();
+        Ok(())
+    }
+}
+
+/// Ask the neighbor with this address for its MAC address.
+fn resolve_mac_address(ip: Ipv4Addr) -> Result<String> {
+    let mut mac = [0u8; 8];
+    let mut len = mac.len() as u32;
+
+    // The address is expected in network byte order.
+    let result = unsafe {
+        SendARP(
+            u32::from_ne_bytes(ip.octets()),
+            0,
+            mac.as_mut_ptr() as *mut _,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        anyhow::bail!("SendARP failed with error {}", result);
+    }
+    if len != 6 {
+        anyhow::bail!("Unexpected hardware address length {}", len);
+    }
+
+    Ok(mac[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+fn parse_mac_address(mac: &str) -> Result<[u8; 6]> {
+    let bytes = mac
+        .split([':', '-'])
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid MAC address {}", mac))?;
+
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid MAC address {}", mac))
+}
+
+/// Broadcast a magic packet: 6 times 0xff, followed by the MAC address 16 times.
+pub async fn send_magic_packet(mac: &str) -> Result<()> {
+    let mac = parse_mac_address(mac)?;
+
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&packet, (Ipv4Addr::BROADCAST, WAKE_ON_LAN_PORT))
+        .await?;
+
+    Ok(())
+}
+
+fn wake_menu_id(device_id: &str) -> MenuId {
+    MenuId::new(&format!("{}:wake", device_id))
+}
+
+/// Add "Wake" items for the known devices which are not connected but can be woken up.
+pub fn add_wake_items(
+    menu: &mut TrayMenu,
+    ctx: &AppContextRef,
+    is_connected: impl Fn(&str) -> bool,
+) {
+    let mut devices: Vec<_> = ctx
+        .settings()
+        .devices()
+        .into_iter()
+        .filter(|d| !is_connected(&d.id) && d.plugins.wake_on_lan.mac_address.is_some())
+        .collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for device in devices {
+        let name = device.name.as_deref().unwrap_or(&device.id);
+        menu.add_action(wake_menu_id(&device.id), format!("Wake {}", name));
+    }
+}
+
+/// Send a magic packet if the event is a click on one of the "Wake" items.
+pub async fn handle_event(event: SystemEvent, ctx: &AppContextRef) {
+    let device = ctx
+        .settings()
+        .devices()
+        .into_iter()
+        .find(|d| event.is_menu_clicked(wake_menu_id(&d.id)));
+
+    if let Some(mac) = device.and_then(|d| d.plugins.wake_on_lan.mac_address) {
+        if let Err(e) = send_magic_packet(&mac).await {
+            utils::simple_toast("Failed to wake device", Some(&e.to_string()), None).await;
+        }
+    }
+}
+
+/// Turn on the display, and wake the computer from modern standby.
+fn wake_display() {
+    unsafe {
+        SetThreadExecutionState(ES_DISPLAY_REQUIRED | ES_SYSTEM_REQUIRED);
+
+        // Resetting the idle timer is not always enough, but some input is.
+        KeyboardAndMouse::SendInput(
+            &[KeyboardAndMouse::INPUT {
+                r#type: KeyboardAndMouse::INPUT_MOUSE,
+                Anonymous: KeyboardAndMouse::INPUT_0 {
+                    mi: KeyboardAndMouse::MOUSEINPUT {
+                        dwFlags: KeyboardAndMouse::MOUSEEVENTF_MOVE,
+                        ..Default::default()
+                    },
+                },
+            }],
+            std::mem::size_of::<KeyboardAndMouse::INPUT>() as i32,
+        );
+    }
+}
+
+plugin_packets! {
+    WakeOnLanPlugin {
+        incoming {
+            WakeRequest(IgnoredAny) => receive_wake_request,
+        }
+        outgoing []
+    }
+}
+
+#[async_trait::async_trait]
+impl KdeConnectPlugin for WakeOnLanPlugin {
+    async fn start(self: Arc<Self>) -> Result<()> {
+        if let Err(e) = self.learn_mac_address().await {
+            log::warn!(
+                "Failed to learn MAC address of {}: {:?}",
+                self.dev.device_id(),
+                e
+            );
+        }
+        Ok(())
+    }
+}
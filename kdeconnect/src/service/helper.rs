@@ -0,0 +1,259 @@
+//! The UI helper, running in the interactive session on behalf of the service.
+
+use std::{os::windows::io::AsRawHandle, time::Duration};
+
+use anyhow::Result;
+use tao::event_loop::EventLoopProxy;
+use tokio::{
+    io::{AsyncWrite, BufReader},
+    net::windows::named_pipe::NamedPipeClient,
+    sync::{mpsc, oneshot},
+};
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE},
+        System::{Pipes::GetNamedPipeServerProcessId, Threading::CreateMutexW},
+    },
+};
+
+use crate::{
+    event::EventSubscription,
+    ipc,
+    plugin::input_receive,
+    tray::{self, IconState, TrayMenu},
+    utils::{
+        self,
+        clipboard::ClipboardContent,
+        toast::{ToastCallbacks, TOASTS},
+    },
+    CustomWindowEvent,
+};
+
+use super::{
+    protocol::{HelperMessage, ServiceMessage, ToastEvent},
+    session,
+};
+
+/// How long to wait before connecting again when the service is not running.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// `Local\` makes the mutex per session.
+const INSTANCE_MUTEX: &str = r"Local\kdeconnect-rs-ui-helper";
+
+/// Held while the helper runs, so that there is only one in each session.
+pub struct Instance(HANDLE);
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// Returns `None` if another helper is already running in this session.
+pub fn single_instance() -> Result<Option<Instance>> {
+    unsafe {
+        let handle = CreateMutexW(None, false, &HSTRING::from(INSTANCE_MUTEX))?;
+        if GetLastError() == ERROR_ALREADY_EXISTS {
+            CloseHandle(handle);
+            return Ok(None);
+        }
+        Ok(Some(Instance(handle)))
+    }
+}
+
+/// Forward events to the service and carry out its requests until `shutdown` fires.
+#[tokio::main]
+pub async fn run(
+    mut events: EventSubscription,
+    proxy: EventLoopProxy<CustomWindowEvent>,
+    mut shutdown: oneshot::Receiver<()>,
+) -> Result<()> {
+    loop {
+        show_disconnected(&proxy);
+
+        let result = tokio::select! {
+            r = serve(&mut events, &proxy) => r,
+            _ = &mut shutdown => return Ok(()),
+        };
+        if let Err(e) = result {
+            log::warn!("Connection to service failed: {:?}", e);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = &mut shutdown => return Ok(()),
+        }
+    }
+}
+
+/// Reset the tray while there is no service to fill it.
+fn show_disconnected(proxy: &EventLoopProxy<CustomWindowEvent>) {
+    let mut menu = TrayMenu::new();
+    menu.add_label("Not connected to the service");
+    menu.add_separator();
+    menu.add_quit();
+
+    let icon = IconState {
+        device_count: 0,
+        battery: None,
+    };
+
+    proxy.send_event(CustomWindowEvent::SetTrayMenu(menu)).ok();
+    proxy.send_event(CustomWindowEvent::SetTrayIcon(icon)).ok();
+    proxy
+        .send_event(CustomWindowEvent::SetTrayTooltip(tray::tooltip([])))
+        .ok();
+}
+
+async fn serve(
+    events: &mut EventSubscription,
+    proxy: &EventLoopProxy<CustomWindowEvent>,
+) -> Result<()> {
+    let name = ipc::ui_pipe(session::current_session()?, &session::current_user_sid()?);
+    let pipe = ipc::connect(&name).await?;
+    check_server(&pipe)?;
+    log::info!("Connected to service");
+
+    let (reader, mut writer) = tokio::io::split(pipe);
+    let mut reader = BufReader::new(reader);
+    // Toast events come from threads of Windows.
+    let (toast_events, mut toast_events_rx) = mpsc::unbounded_channel();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    ipc::write_message(&mut writer, &HelperMessage::Event { event }).await?;
+                }
+                None => return Ok(()),
+            },
+            Some(message) = toast_events_rx.recv() => {
+                ipc::write_message(&mut writer, &message).await?;
+            }
+            message = ipc::read_message(&mut reader) => match message? {
+                Some(message) => handle_message(message, proxy, &mut writer, &toast_events).await?,
+                None => anyhow::bail!("Service closed the connection"),
+            },
+        }
+    }
+}
+
+/// Make sure that the pipe is served by the service, not by a process that took its name
+/// while the service was not running.
+fn check_server(pipe: &NamedPipeClient) -> Result<()> {
+    let mut pid = 0;
+    unsafe { GetNamedPipeServerProcessId(HANDLE(pipe.as_raw_handle() as isize), &mut pid) }.ok()?;
+
+    let service_pid = super::process_id()?;
+    if pid != service_pid {
+        anyhow::bail!(
+            "Pipe is served by process {} instead of the service (process {})",
+            pid,
+            service_pid
+        );
+    }
+    Ok(())
+}
+
+async fn handle_message(
+    message: ServiceMessage,
+    proxy: &EventLoopProxy<CustomWindowEvent>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    toast_events: &mpsc::UnboundedSender<HelperMessage>,
+) -> Result<()> {
+    match message {
+        ServiceMessage::Window { event } => {
+            proxy.send_event(event).ok();
+        }
+        ServiceMessage::Toast {
+            title,
+            content,
+            attribution,
+        } => {
+            utils::simple_toast(&title, content.as_deref(), attribution.as_deref()).await;
+        }
+        ServiceMessage::ShowToast { id, toast } => {
+            let callbacks = forward_toast_events(id, toast_events.clone());
+            let result = TOASTS
+                .show_with_callbacks(toast.to_toast(), callbacks)
+                .await;
+            reply(writer, id, result.map(|_| None)).await?;
+        }
+        ServiceMessage::UpdateProgress {
+            tag,
+            group,
+            progress,
+        } => {
+            let result = TOASTS
+                .update_progress(&tag, group.as_deref(), progress.to_progress())
+                .await;
+            utils::log_if_error("Failed to update progress toast", result);
+        }
+        ServiceMessage::RemoveToasts { group, tag } => {
+            let result = match tag {
+                Some(tag) => TOASTS.remove_grouped_tag(&group, &tag).await,
+                None => TOASTS.remove_group(&group).await,
+            };
+            utils::log_if_error("Failed to remove toasts", result);
+        }
+        ServiceMessage::ReadClipboard { id } => {
+            let result = tokio::task::spawn_blocking(utils::clipboard::read).await?;
+            reply(writer, id, result.map(Some)).await?;
+        }
+        ServiceMessage::WriteClipboard { id, text } => {
+            let result = tokio::task::spawn_blocking(move || {
+                utils::clipboard::write(ClipboardContent::Text(text))
+            })
+            .await?;
+            reply(writer, id, result.map(|_| None)).await?;
+        }
+        ServiceMessage::Input { packet, monitor } => {
+            utils::log_if_error(
+                "Failed to inject input",
+                input_receive::inject(packet, monitor),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Callbacks that send the events of the toast shown for request `id` to the service.
+fn forward_toast_events(id: u64, events: mpsc::UnboundedSender<HelperMessage>) -> ToastCallbacks {
+    let send = move |event| {
+        events.send(HelperMessage::ToastEvent { id, event }).ok();
+    };
+    ToastCallbacks::new()
+        .on_activated({
+            let send = send.clone();
+            move |result| send(ToastEvent::activated(result))
+        })
+        .on_dismissed({
+            let send = send.clone();
+            move |result| send(ToastEvent::dismissed(result))
+        })
+        .on_failed(move |error| send(ToastEvent::failed(error)))
+}
+
+async fn reply(
+    writer: &mut (impl AsyncWrite + Unpin),
+    id: u64,
+    result: Result<Option<ClipboardContent>>,
+) -> Result<()> {
+    let message = match result {
+        Ok(content) => HelperMessage::Reply {
+            id,
+            content,
+            error: None,
+        },
+        Err(e) => HelperMessage::Reply {
+            id,
+            content: None,
+            error: Some(format!("{:#}", e)),
+        },
+    };
+    ipc::write_message(writer, &message).await
+}
@@ -0,0 +1,349 @@
+//! Service side of the connections to the UI helpers, one in each session.
+
+use std::{
+    collections::{HashMap, HashSet},
+    os::windows::io::AsRawHandle,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use tokio::{
+    io::BufReader,
+    net::windows::named_pipe::NamedPipeServer,
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
+};
+use windows::Win32::{Foundation::HANDLE, System::Pipes::GetNamedPipeClientProcessId};
+use winrt_toast::{Toast, ToastTemplate};
+
+use crate::{
+    context::{AppContextRef, TrayUpdater},
+    ipc,
+    utils::{clipboard::ClipboardContent, toast::ToastCallbacks},
+};
+
+use super::{
+    protocol::{HelperMessage, ServiceMessage, ToastEvent},
+    session,
+};
+
+/// How long to wait for the helper to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Reply = Result<Option<ClipboardContent>>;
+
+static LINK: OnceCell<HelperLink> = OnceCell::new();
+
+/// The connected UI helpers, if running as a service.
+pub fn get() -> Option<&'static HelperLink> {
+    LINK.get()
+}
+
+/// Accept a UI helper from `session_id`. Called when a user logs on to the session, before
+/// its helper is started.
+pub fn open_session(session_id: u32) {
+    LINK.get_or_init(HelperLink::new)
+        .opened
+        .send(session_id)
+        .ok();
+}
+
+#[derive(Debug)]
+pub struct HelperLink {
+    /// Messages for the connected helper of each session.
+    helpers: Mutex<HashMap<u32, mpsc::UnboundedSender<ServiceMessage>>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    /// Callbacks of the toasts shown by the helpers, until the toasts are gone.
+    toasts: Mutex<HashMap<u64, ToastCallbacks>>,
+    next_id: AtomicU64,
+    /// Sessions passed to [`open_session`], until [`serve`] takes the receiver.
+    opened: mpsc::UnboundedSender<u32>,
+    opened_rx: Mutex<Option<mpsc::UnboundedReceiver<u32>>>,
+}
+
+impl HelperLink {
+    fn new() -> Self {
+        let (opened, opened_rx) = mpsc::unbounded_channel();
+        Self {
+            helpers: Mutex::default(),
+            pending: Mutex::default(),
+            toasts: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            opened,
+            opened_rx: Mutex::new(Some(opened_rx)),
+        }
+    }
+
+    /// The helper of the session attached to the console.
+    fn active_helper(&self) -> Option<mpsc::UnboundedSender<ServiceMessage>> {
+        let session_id = session::active_console_session()?;
+        self.helpers.lock().unwrap().get(&session_id).cloned()
+    }
+
+    /// Send a message to the helper of the session attached to the console, dropping it if
+    /// none is connected. Tray updates go to the helpers of all sessions.
+    pub fn send(&self, message: ServiceMessage) {
+        if let ServiceMessage::Window { .. } = message {
+            for sender in self.helpers.lock().unwrap().values() {
+                sender.send(message.clone()).ok();
+            }
+            return;
+        }
+
+        match self.active_helper() {
+            Some(sender) => {
+                sender.send(message).ok();
+            }
+            None => log::debug!(
+                "No UI helper connected, dropping a {} message",
+                message.kind()
+            ),
+        }
+    }
+
+    /// Send a request built from a fresh id to the helper of the session attached to the
+    /// console, and wait for the reply.
+    pub async fn request(&self, make: impl FnOnce(u64) -> ServiceMessage) -> Reply {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let sent = self
+            .active_helper()
+            .is_some_and(|s| s.send(make(id)).is_ok());
+        if !sent {
+            self.pending.lock().unwrap().remove(&id);
+            anyhow::bail!("No UI helper connected");
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(anyhow::anyhow!("UI helper disconnected")),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(anyhow::anyhow!("UI helper did not answer"))
+            }
+        }
+    }
+
+    /// Show a toast in the session attached to the console. Its events are passed to
+    /// `callbacks` until it is gone, or its helper disconnects.
+    pub async fn show_toast(&self, toast: &Toast, callbacks: ToastCallbacks) -> Result<()> {
+        let toast = Box::new(ToastTemplate::from(toast));
+        let mut shown = None;
+        let result = self
+            .request(|id| {
+                shown = Some(id);
+                self.toasts.lock().unwrap().insert(id, callbacks);
+                ServiceMessage::ShowToast { id, toast }
+            })
+            .await;
+
+        if result.is_err() {
+            if let Some(id) = shown {
+                self.toasts.lock().unwrap().remove(&id);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Pass an event of a toast to its callbacks. Returns whether the toast is gone.
+    fn toast_event(&self, id: u64, event: ToastEvent) -> bool {
+        // Not called under the lock, in case they show another toast.
+        let Some(mut callbacks) = self.toasts.lock().unwrap().remove(&id) else {
+            return true;
+        };
+        let gone = callbacks.dispatch(event);
+        if !gone {
+            self.toasts.lock().unwrap().insert(id, callbacks);
+        }
+        gone
+    }
+
+    async fn handle(
+        &self,
+        pipe: NamedPipeServer,
+        session_id: u32,
+        outgoing: mpsc::UnboundedReceiver<ServiceMessage>,
+        ctx: &AppContextRef,
+    ) -> Result<()> {
+        let mut shown = HashSet::new();
+        let result = self
+            .relay(pipe, session_id, outgoing, ctx, &mut shown)
+            .await;
+
+        // Their events cannot come anymore.
+        let mut toasts = self.toasts.lock().unwrap();
+        for id in shown {
+            toasts.remove(&id);
+        }
+        result
+    }
+
+    /// Pass messages between the service and a helper, `shown` collects the toasts that are
+    /// still shown by the helper.
+    async fn relay(
+        &self,
+        pipe: NamedPipeServer,
+        session_id: u32,
+        mut outgoing: mpsc::UnboundedReceiver<ServiceMessage>,
+        ctx: &AppContextRef,
+        shown: &mut HashSet<u64>,
+    ) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(pipe);
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            tokio::select! {
+                message = outgoing.recv() => match message {
+                    Some(message) => {
+                        if let ServiceMessage::ShowToast { id, .. } = &message {
+                            shown.insert(*id);
+                        }
+                        ipc::write_message(&mut writer, &message).await?;
+                    }
+                    // Replaced by a newer helper.
+                    None => return Ok(()),
+                },
+                message = ipc::read_message(&mut reader) => match message? {
+                    Some(HelperMessage::Event { event }) => {
+                        // E.g. clipboard changes of a user who switched away.
+                        if session::active_console_session() == Some(session_id) {
+                            ctx.event_bus().publish(event);
+                        }
+                    }
+                    Some(HelperMessage::Reply { id, content, error }) => {
+                        let reply = match error {
+                            Some(e) => Err(anyhow::anyhow!(e)),
+                            None => Ok(content),
+                        };
+                        if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+                            tx.send(reply).ok();
+                        }
+                    }
+                    Some(HelperMessage::ToastEvent { id, event }) => {
+                        if self.toast_event(id, event) {
+                            shown.remove(&id);
+                        }
+                    }
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Accept UI helpers from the sessions passed to [`open_session`].
+pub async fn serve(ctx: AppContextRef) -> Result<()> {
+    let link = LINK.get_or_init(HelperLink::new);
+    let mut opened = link
+        .opened_rx
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Already serving"))?;
+    let mut listeners: HashMap<u32, (Arc<Notify>, JoinHandle<()>)> = HashMap::new();
+
+    while let Some(session_id) = opened.recv().await {
+        match listeners.get(&session_id) {
+            // Someone else may have logged on to the session.
+            Some((user_changed, task)) if !task.is_finished() => user_changed.notify_one(),
+            _ => {
+                let user_changed = Arc::new(Notify::new());
+                let task = tokio::spawn(listen(session_id, user_changed.clone(), ctx.clone()));
+                listeners.insert(session_id, (user_changed, task));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn listen(session_id: u32, user_changed: Arc<Notify>, ctx: AppContextRef) {
+    if let Err(e) = accept_helpers(session_id, &user_changed, ctx).await {
+        log::warn!(
+            "Stopped accepting UI helpers of session {}: {:?}",
+            session_id,
+            e
+        );
+    }
+}
+
+/// Accept UI helpers of one session, one at a time. A new helper replaces the previous one
+/// of the session.
+async fn accept_helpers(session_id: u32, user_changed: &Notify, ctx: AppContextRef) -> Result<()> {
+    let link = LINK.get_or_init(HelperLink::new);
+    let mut user = session::user_sid(session_id)?;
+    let mut server = create_server(session_id, &user, true)?;
+
+    loop {
+        tokio::select! {
+            r = server.connect() => r?,
+            _ = user_changed.notified() => {
+                let new_user = session::user_sid(session_id)?;
+                if new_user != user {
+                    // Only the new user can open the new pipe.
+                    server = create_server(session_id, &new_user, true)?;
+                    user = new_user;
+                }
+                continue;
+            }
+        }
+        let pipe = std::mem::replace(&mut server, create_server(session_id, &user, false)?);
+
+        match client_session(&pipe) {
+            Ok(id) if id == session_id => {}
+            Ok(id) => {
+                log::warn!(
+                    "Refused UI helper of session {} on the pipe of session {}",
+                    id,
+                    session_id
+                );
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Refused UI helper: {:?}", e);
+                continue;
+            }
+        }
+        log::info!("UI helper of session {} connected", session_id);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        link.helpers.lock().unwrap().insert(session_id, tx);
+        // The new helper starts with an empty tray.
+        ctx.update_tray().await;
+
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = link.handle(pipe, session_id, rx, &ctx).await {
+                log::warn!("Connection to UI helper failed: {:?}", e);
+            }
+            log::info!("UI helper of session {} disconnected", session_id);
+
+            let mut helpers = link.helpers.lock().unwrap();
+            if helpers.get(&session_id).is_some_and(|s| s.is_closed()) {
+                helpers.remove(&session_id);
+            }
+        });
+    }
+}
+
+fn create_server(session_id: u32, user: &str, first: bool) -> Result<NamedPipeServer> {
+    ipc::create_server(
+        &ipc::ui_pipe(session_id, user),
+        &session::user_pipe_sddl(user),
+        first,
+    )
+}
+
+/// The session of the process on the other end of the pipe.
+fn client_session(pipe: &NamedPipeServer) -> Result<u32> {
+    let mut pid = 0;
+    unsafe { GetNamedPipeClientProcessId(HANDLE(pipe.as_raw_handle() as isize), &mut pid) }.ok()?;
+    session::process_session(pid)
+}
@@ -0,0 +1,330 @@
+//! Service mode: the network part runs as a Windows service, and a helper process started in
+//! each interactive session shows the tray and toasts, and accesses the clipboard and input
+//! on its behalf.
+//!
+//! Each helper connects over its own pipe, which only the user of its session can open. The
+//! tray is shown in every session, toasts, clipboard and input go to the session attached to
+//! the console.
+//!
+//! Toasts are shown by the helper too, and their clicks and dismissals are sent back to the
+//! service. Everything else still runs in session 0, where nobody sees it, so it is not
+//! supported in service mode: media sessions, dialogs and hotkeys.
+
+pub mod helper;
+pub mod link;
+mod protocol;
+pub mod session;
+
+pub use protocol::{ServiceMessage, ToastEvent};
+
+use std::sync::mpsc;
+
+use anyhow::{Context, Result};
+use tokio::sync::oneshot;
+use windows::{
+    core::{HSTRING, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR},
+        Security::SC_HANDLE,
+        System::{
+            RemoteDesktop::WTSSESSION_NOTIFICATION,
+            Services::{
+                ChangeServiceConfig2W, CloseServiceHandle, ControlService, CreateServiceW,
+                DeleteService, OpenSCManagerW, OpenServiceW, QueryServiceStatusEx,
+                RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+                SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SC_STATUS_PROCESS_INFO,
+                SERVICE_ACCEPT_SESSIONCHANGE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP,
+                SERVICE_ALL_ACCESS, SERVICE_AUTO_START, SERVICE_CONFIG_DESCRIPTION,
+                SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SESSIONCHANGE,
+                SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_DESCRIPTIONW,
+                SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_START_PENDING,
+                SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STATUS_HANDLE,
+                SERVICE_STATUS_PROCESS, SERVICE_STOP, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+                SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+            },
+        },
+        UI::WindowsAndMessaging::WTS_SESSION_LOGON,
+    },
+};
+
+use crate::{event, utils};
+
+pub const SERVICE_NAME: &str = "KdeConnectRs";
+const SERVICE_DISPLAY_NAME: &str = "KDE Connect";
+const SERVICE_DESCRIPTION: &str =
+    "Connects to KDE Connect devices on the network. The tray icon runs in each user session.";
+
+/// Standard access right to delete an object.
+const DELETE: u32 = 0x0001_0000;
+
+enum Control {
+    Stop,
+    /// A user logged on to the session with this id.
+    SessionLogon(u32),
+}
+
+/// Run as the service, called by the service control manager with `--service`.
+///
+/// Blocks until the service is stopped.
+pub fn run() -> Result<()> {
+    let mut name = utils::encode_wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: PWSTR(name.as_mut_ptr()),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW::default(),
+    ];
+
+    unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) }
+        .ok()
+        .context("Connect to the service control manager")?;
+
+    Ok(())
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    if let Err(e) = run_service() {
+        log::error!("Service failed: {:?}", e);
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (control_tx, control_rx) = mpsc::sync_channel::<Control>(8);
+    let stop_tx = control_tx.clone();
+
+    // The handler may be called until the process exits, so its context is never freed.
+    let context = Box::into_raw(Box::new(control_tx));
+    let status_handle = unsafe {
+        RegisterServiceCtrlHandlerExW(
+            &HSTRING::from(SERVICE_NAME),
+            Some(control_handler),
+            Some(context as *const _),
+        )
+    }?;
+
+    set_status(status_handle, SERVICE_START_PENDING, 0);
+
+    // Services start in System32, keep the configuration next to the executable instead.
+    let exe = std::env::current_exe()?;
+    if let Some(dir) = exe.parent() {
+        std::env::set_current_dir(dir)?;
+    }
+
+    let event_bus = event::EventBus::new();
+    let event_subscription = event_bus.subscribe(event::EventTopic::ALL);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let server = std::thread::spawn(move || {
+        let r = crate::server_main(event_bus, event_subscription, None, None, shutdown_rx);
+        stop_tx.send(Control::Stop).ok();
+        r
+    });
+
+    set_status(
+        status_handle,
+        SERVICE_RUNNING,
+        SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN | SERVICE_ACCEPT_SESSIONCHANGE,
+    );
+    log::info!("Service started");
+
+    if let Some(session_id) = session::active_console_session() {
+        link::open_session(session_id);
+        utils::log_if_error(
+            "Failed to start UI helper",
+            session::spawn_helper(session_id),
+        );
+    }
+
+    for control in control_rx {
+        match control {
+            Control::SessionLogon(session_id) => {
+                link::open_session(session_id);
+                utils::log_if_error(
+                    "Failed to start UI helper",
+                    session::spawn_helper(session_id),
+                );
+            }
+            Control::Stop => break,
+        }
+    }
+
+    set_status(status_handle, SERVICE_STOP_PENDING, 0);
+    shutdown_tx.send(()).ok();
+    match server.join() {
+        Ok(Err(e)) => log::error!("Server exited with error: {:?}", e),
+        Err(_) => log::error!("Server panicked"),
+        Ok(Ok(())) => {}
+    }
+    set_status(status_handle, SERVICE_STOPPED, 0);
+
+    Ok(())
+}
+
+unsafe extern "system" fn control_handler(
+    control: u32,
+    event_type: u32,
+    event_data: *mut std::ffi::c_void,
+    context: *mut std::ffi::c_void,
+) -> u32 {
+    let control_tx = &*(context as *const mpsc::SyncSender<Control>);
+
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            control_tx.send(Control::Stop).ok();
+            NO_ERROR.0
+        }
+        SERVICE_CONTROL_SESSIONCHANGE => {
+            if event_type == WTS_SESSION_LOGON && !event_data.is_null() {
+                let notification = &*(event_data as *const WTSSESSION_NOTIFICATION);
+                control_tx
+                    .send(Control::SessionLogon(notification.dwSessionId))
+                    .ok();
+            }
+            NO_ERROR.0
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR.0,
+        _ => ERROR_CALL_NOT_IMPLEMENTED.0,
+    }
+}
+
+fn set_status(handle: SERVICE_STATUS_HANDLE, state: SERVICE_STATUS_CURRENT_STATE, accepted: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: accepted,
+        dwWin32ExitCode: NO_ERROR.0,
+        ..Default::default()
+    };
+
+    if !unsafe { SetServiceStatus(handle, &status) }.as_bool() {
+        log::error!(
+            "Failed to set service status: {:?}",
+            windows::core::Error::from_win32()
+        );
+    }
+}
+
+/// Closes a service control manager handle when dropped.
+struct ScHandle(SC_HANDLE);
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseServiceHandle(self.0);
+        }
+    }
+}
+
+/// Register the service to start automatically as LocalSystem. Needs to run elevated.
+pub fn install() -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let binary_path = format!("\"{}\" --service", exe.display());
+
+    unsafe {
+        let manager = ScHandle(OpenSCManagerW(
+            PCWSTR::null(),
+            PCWSTR::null(),
+            SC_MANAGER_CONNECT | SC_MANAGER_CREATE_SERVICE,
+        )?);
+
+        let service = ScHandle(
+            CreateServiceW(
+                manager.0,
+                &HSTRING::from(SERVICE_NAME),
+                &HSTRING::from(SERVICE_DISPLAY_NAME),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                &HSTRING::from(binary_path),
+                PCWSTR::null(),
+                None,
+                PCWSTR::null(),
+                PCWSTR::null(),
+                PCWSTR::null(),
+            )
+            .context("Create service")?,
+        );
+
+        let mut description = utils::encode_wide(SERVICE_DESCRIPTION);
+        let info = SERVICE_DESCRIPTIONW {
+            lpDescription: PWSTR(description.as_mut_ptr()),
+        };
+        ChangeServiceConfig2W(
+            service.0,
+            SERVICE_CONFIG_DESCRIPTION,
+            Some(&info as *const _ as *const _),
+        )
+        .ok()
+        .context("Set service description")?;
+    }
+
+    log::info!("Installed service {}", SERVICE_NAME);
+    Ok(())
+}
+
+/// The process id of the running service, to check that a pipe is really served by it.
+pub fn process_id() -> Result<u32> {
+    unsafe {
+        let manager = ScHandle(OpenSCManagerW(
+            PCWSTR::null(),
+            PCWSTR::null(),
+            SC_MANAGER_CONNECT,
+        )?);
+        let service = ScHandle(
+            OpenServiceW(
+                manager.0,
+                &HSTRING::from(SERVICE_NAME),
+                SERVICE_QUERY_STATUS,
+            )
+            .context("Open service")?,
+        );
+
+        let mut status = SERVICE_STATUS_PROCESS::default();
+        let mut size = 0;
+        QueryServiceStatusEx(
+            service.0,
+            SC_STATUS_PROCESS_INFO,
+            Some(std::slice::from_raw_parts_mut(
+                &mut status as *mut _ as *mut u8,
+                std::mem::size_of::<SERVICE_STATUS_PROCESS>(),
+            )),
+            &mut size,
+        )
+        .ok()
+        .context("Query service status")?;
+
+        if status.dwCurrentState != SERVICE_RUNNING {
+            anyhow::bail!("Service is not running");
+        }
+        Ok(status.dwProcessId)
+    }
+}
+
+/// Stop and remove the service. Needs to run elevated.
+pub fn uninstall() -> Result<()> {
+    unsafe {
+        let manager = ScHandle(OpenSCManagerW(
+            PCWSTR::null(),
+            PCWSTR::null(),
+            SC_MANAGER_CONNECT,
+        )?);
+        let service = ScHandle(
+            OpenServiceW(
+                manager.0,
+                &HSTRING::from(SERVICE_NAME),
+                SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE,
+            )
+            .context("Open service")?,
+        );
+
+        // Fails if it is not running, which is fine.
+        let mut status = SERVICE_STATUS::default();
+        ControlService(service.0, SERVICE_CONTROL_STOP, &mut status);
+
+        DeleteService(service.0).ok().context("Delete service")?;
+    }
+
+    log::info!("Uninstalled service {}", SERVICE_NAME);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+//! Messages exchanged between the service and the UI helper.
+
+use serde::{Deserialize, Serialize};
+use winrt_toast::{DismissalReason, ProgressTemplate, ToastTemplate, WinToastError};
+
+use crate::{
+    event::SystemEvent, packet::NetworkPacket, utils::clipboard::ClipboardContent,
+    CustomWindowEvent,
+};
+
+/// Sent by the service, for things that only work in the interactive session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServiceMessage {
+    /// Forwarded to the event loop, which owns the tray.
+    Window {
+        event: CustomWindowEvent,
+    },
+    Toast {
+        title: String,
+        content: Option<String>,
+        attribution: Option<String>,
+    },
+    /// A toast with callbacks, answered once it is shown. Its events come back as
+    /// [`HelperMessage::ToastEvent`] with the same `id`.
+    ShowToast {
+        id: u64,
+        toast: Box<ToastTemplate>,
+    },
+    /// New progress of the toast with `tag` in `group`.
+    UpdateProgress {
+        tag: String,
+        group: Option<String>,
+        progress: ProgressTemplate,
+    },
+    /// Remove the toast with `tag` from `group`, or the whole group without a `tag`.
+    RemoveToasts {
+        group: String,
+        tag: Option<String>,
+    },
+    ReadClipboard {
+        id: u64,
+    },
+    WriteClipboard {
+        id: u64,
+        text: String,
+    },
+    /// A mousepad request to be injected as input.
+    Input {
+        packet: NetworkPacket,
+        /// The display the pointer is kept on, see
+        /// [`InputReceiveSettings`](crate::config::InputReceiveSettings).
+        #[serde(default)]
+        monitor: Option<u32>,
+    },
+}
+
+impl ServiceMessage {
+    /// The name of the variant, for logs that must not contain the content.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Window { .. } => "window",
+            Self::Toast { .. } => "toast",
+            Self::ShowToast { .. } => "showToast",
+            Self::UpdateProgress { .. } => "updateProgress",
+            Self::RemoveToasts { .. } => "removeToasts",
+            Self::ReadClipboard { .. } => "readClipboard",
+            Self::WriteClipboard { .. } => "writeClipboard",
+            Self::Input { .. } => "input",
+        }
+    }
+}
+
+/// Sent by the UI helper.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum HelperMessage {
+    /// An event from the listeners of the session, published on the bus of the service.
+    Event { event: SystemEvent },
+    /// Answer to the request with the same `id`.
+    Reply {
+        id: u64,
+        #[serde(default)]
+        content: Option<ClipboardContent>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// Something happened to the toast shown for the request with the same `id`.
+    ToastEvent { id: u64, event: ToastEvent },
+}
+
+/// The callbacks of a toast, called in the session of the UI helper.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ToastEvent {
+    Activated {
+        #[serde(default)]
+        arguments: Option<String>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    Dismissed {
+        #[serde(default)]
+        reason: Option<DismissalReason>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+impl ToastEvent {
+    pub fn activated(result: winrt_toast::Result<String>) -> Self {
+        match result {
+            Ok(arguments) => Self::Activated {
+                arguments: Some(arguments),
+                error: None,
+            },
+            Err(e) => Self::Activated {
+                arguments: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    pub fn dismissed(result: winrt_toast::Result<DismissalReason>) -> Self {
+        match result {
+            Ok(reason) => Self::Dismissed {
+                reason: Some(reason),
+                error: None,
+            },
+            Err(e) => Self::Dismissed {
+                reason: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+
+    pub fn failed(error: WinToastError) -> Self {
+        Self::Failed {
+            error: error.to_string(),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! User sessions: starting the UI helper in them, and restricting pipes to their user.
+
+use anyhow::{Context, Result};
+use windows::{
+    core::{HSTRING, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        Security::{
+            Authorization::ConvertSidToStringSidW, GetTokenInformation, TokenUser, TOKEN_QUERY,
+            TOKEN_USER,
+        },
+        System::{
+            Environment::{CreateEnvironmentBlock, DestroyEnvironmentBlock},
+            Memory::LocalFree,
+            RemoteDesktop::{
+                ProcessIdToSessionId, WTSGetActiveConsoleSessionId, WTSQueryUserToken,
+            },
+            Threading::{
+                CreateProcessAsUserW, GetCurrentProcess, GetCurrentProcessId, OpenProcessToken,
+                CREATE_NO_WINDOW, CREATE_UNICODE_ENVIRONMENT, PROCESS_INFORMATION, STARTUPINFOW,
+            },
+        },
+    },
+};
+
+use crate::utils;
+
+/// The session attached to the physical console, if someone is logged on to it.
+pub fn active_console_session() -> Option<u32> {
+    match unsafe { WTSGetActiveConsoleSessionId() } {
+        u32::MAX => None,
+        id => Some(id),
+    }
+}
+
+/// The session the process with id `pid` runs in.
+pub fn process_session(pid: u32) -> Result<u32> {
+    let mut session_id = 0;
+    unsafe { ProcessIdToSessionId(pid, &mut session_id) }
+        .ok()
+        .with_context(|| format!("No session for process {}", pid))?;
+    Ok(session_id)
+}
+
+/// The session this process runs in.
+pub fn current_session() -> Result<u32> {
+    process_session(unsafe { GetCurrentProcessId() })
+}
+
+/// Security descriptor for a pipe that only the user with the SID `user` can open, besides
+/// the system and administrators. The user can read and write, but not create instances of
+/// the pipe.
+pub fn user_pipe_sddl(user: &str) -> String {
+    format!("D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GRGW;;;{})", user)
+}
+
+/// The SID of the user logged on to `session_id`, e.g. `S-1-5-21-...`.
+///
+/// Only works from a service running as LocalSystem.
+pub fn user_sid(session_id: u32) -> Result<String> {
+    unsafe {
+        let mut token = HANDLE::default();
+        WTSQueryUserToken(session_id, &mut token)
+            .ok()
+            .with_context(|| format!("No user token for session {}", session_id))?;
+        let sid = token_user_sid(token);
+        CloseHandle(token);
+        sid
+    }
+}
+
+/// The SID of the user running this process.
+pub fn current_user_sid() -> Result<String> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)
+            .ok()
+            .context("Open process token")?;
+        let sid = token_user_sid(token);
+        CloseHandle(token);
+        sid
+    }
+}
+
+unsafe fn token_user_sid(token: HANDLE) -> Result<String> {
+    // Fails, but tells the size that is needed.
+    let mut size = 0;
+    GetTokenInformation(token, TokenUser, None, 0, &mut size);
+
+    // `u64` for the alignment of the pointers in `TOKEN_USER`.
+    let mut buf = vec![0u64; size as usize / 8 + 1];
+    GetTokenInformation(
+        token,
+        TokenUser,
+        Some(buf.as_mut_ptr() as *mut _),
+        size,
+        &mut size,
+    )
+    .ok()
+    .context("Query user of token")?;
+    let user = &*(buf.as_ptr() as *const TOKEN_USER);
+
+    let mut string = PWSTR::null();
+    ConvertSidToStringSidW(user.User.Sid, &mut string)
+        .ok()
+        .context("Format SID")?;
+    let sid = string.to_string();
+    LocalFree(string.0 as isize);
+    Ok(sid?)
+}
+
+/// Start `kdeconnect.exe --ui-helper` as the user logged on to `session_id`.
+///
+/// Only works from a service running as LocalSystem.
+pub fn spawn_helper(session_id: u32) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut command_line = utils::encode_wide(format!("\"{}\" --ui-helper", exe.display()));
+    let mut desktop = utils::encode_wide("winsta0\\default");
+
+    unsafe {
+        let mut token = HANDLE::default();
+        WTSQueryUserToken(session_id, &mut token)
+            .ok()
+            .with_context(|| format!("No user token for session {}", session_id))?;
+
+        let mut environment = std::ptr::null_mut();
+        let has_environment = CreateEnvironmentBlock(&mut environment, token, false).as_bool();
+
+        let startup_info = STARTUPINFOW {
+            cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+            lpDesktop: PWSTR(desktop.as_mut_ptr()),
+            ..Default::default()
+        };
+        let mut process_info = PROCESS_INFORMATION::default();
+
+        let created = CreateProcessAsUserW(
+            token,
+            &HSTRING::from(exe.as_os_str()),
+            PWSTR(command_line.as_mut_ptr()),
+            None,
+            None,
+            false,
+            (CREATE_UNICODE_ENVIRONMENT | CREATE_NO_WINDOW).0,
+            has_environment.then_some(environment as *const _),
+            PCWSTR::null(),
+            &startup_info,
+            &mut process_info,
+        )
+        .ok();
+
+        if has_environment {
+            DestroyEnvironmentBlock(environment);
+        }
+        CloseHandle(token);
+        created.context("Start UI helper")?;
+
+        log::info!(
+            "Started UI helper in session {} (pid {})",
+            session_id,
+            process_info.dwProcessId
+        );
+        CloseHandle(process_info.hThread);
+        CloseHandle(process_info.hProcess);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use tao::menu::MenuId;
+
+use crate::{
+    event::SystemEvent,
+    packet::{self, NetworkPacket, NetworkPacketWithPayload},
+    utils::{self, clipboard::ClipboardContent, hash::ContentHash},
+};
+
+use super::{peer::TestPeer, TestApp};
+
+#[tokio::test]
+#[ignore = "Shows a toast"]
+async fn pairing() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Pairing")?;
+    let mut conn = app.connect(&peer).await?;
+
+    conn.send(&NetworkPacket::new_pair(true)).await?;
+
+    let reply = conn.recv_type(packet::PACKET_TYPE_PAIR).await?;
+    assert_eq!(reply.body["pair"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "Shows a toast"]
+async fn ping_round_trip() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Ping")?;
+    // Take the discovery path, so that the application opens the connection.
+    let mut conn = app.discover(&peer).await?;
+
+    conn.send(&NetworkPacket::new(
+        "kdeconnect.ping",
+        serde_json::json!({ "message": "Hello" }),
+    ))
+    .await?;
+
+    // Click "Ping" in the tray menu of the device.
+    let menu_id = MenuId::new(&format!("{}:ping", peer.device_id()));
+    app.ctx
+        .device_manager()
+        .broadcast_event(SystemEvent::TrayMenuClicked(menu_id))
+        .await;
+
+    let ping = conn.recv_type("kdeconnect.ping").await?;
+    assert!(ping.body.get("message").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "Replaces the content of the system clipboard"]
+async fn clipboard_sync() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Clipboard")?;
+    let mut conn = app.connect(&peer).await?;
+
+    let text = format!("kdeconnect-test-{}", uuid::Uuid::new_v4());
+    conn.send(&NetworkPacket::new(
+        "kdeconnect.clipboard",
+        serde_json::json!({ "content": text }),
+    ))
+    .await?;
+
+    for _ in 0..50 {
+        let content = tokio::task::spawn_blocking(utils::clipboard::read).await??;
+        if matches!(content, ClipboardContent::Text(ref t) if t == &text) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("Clipboard was not updated");
+}
+
+#[tokio::test]
+async fn payload_transfer() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Payload")?;
+    let mut conn = app.connect(&peer).await?;
+
+    let data = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    app.ctx
+        .device_manager()
+        .send_packet(
+            peer.device_id(),
+            NetworkPacketWithPayload::new(
+                NetworkPacket::new(
+                    "kdeconnect.share.request",
+                    serde_json::json!({ "filename": "test.bin" }),
+                ),
+                Arc::new(data.clone()),
+            ),
+        )
+        .await;
+
+    let packet = conn.recv_type("kdeconnect.share.request").await?;
+    assert_eq!(packet.payload_size, Some(data.len() as u64));
+
+    let payload = peer.fetch_payload(conn.addr, &packet).await?;
+    assert_eq!(payload, data);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn disconnect_removes_device() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Disconnect")?;
+    let conn = app.connect(&peer).await?;
+
+    conn.close().await?;
+
+    for _ in 0..50 {
+        if !app
+            .ctx
+            .device_manager()
+            .query_device(peer.device_id())
+            .await?
+        {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("Device was not removed");
+}
+
+#[tokio::test]
+#[ignore = "Shows a toast"]
+async fn received_file_is_downloaded_again_if_corrupt() -> Result<()> {
+    let app = TestApp::start().await?;
+    let peer = TestPeer::new("Share")?;
+    let mut conn = app.connect(&peer).await?;
+
+    let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    app.ctx.settings().update_device(peer.device_id(), |d| {
+        d.plugins.share.download_dir = Some(dir.clone());
+    })?;
+
+    let data = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut corrupt = data.clone();
+    corrupt[1000] ^= 0xff;
+    // The connection drops after 1000 bytes, the rest is received corrupt and then right.
+    let port = peer
+        .serve_payloads(vec![data[..1000].to_vec(), corrupt, data.clone()])
+        .await?;
+
+    let mut packet = NetworkPacket::new(
+        "kdeconnect.share.request",
+        serde_json::json!({
+            "filename": "test.bin",
+            "payloadHash": ContentHash::md5(&data).to_string(),
+        }),
+    );
+    packet.set_payload(data.len() as u64, port);
+    conn.send(&packet).await?;
+
+    let path = dir.join("test.bin");
+    let mut received = false;
+    for _ in 0..100 {
+        let files = std::fs::read_dir(&dir)?.count();
+        if files == 1 && std::fs::read(&path).ok().as_ref() == Some(&data) {
+            received = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let stats = app
+        .ctx
+        .device_manager()
+        .query_stats(peer.device_id())
+        .await?;
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert!(received, "File was not received");
+    assert_eq!(stats.map(|s| s.corrupt_payloads), Some(1));
+
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Running plugins against a mock device, without a connection or the device manager.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tao::menu::MenuId;
+
+use crate::{
+    context::AppContextRef,
+    device::{queue::OutgoingReceiver, DeviceHandle, DeviceManagerHandle},
+    event::SystemEvent,
+    packet::NetworkPacket,
+    plugin::{KdeConnectPlugin, KdeConnectPluginMetadata, PluginRepository},
+    tray::DeviceMenu,
+};
+
+use super::TestContext;
+
+/// How long to wait for a packet before failing the test.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before concluding that nothing is sent.
+const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// A device handle whose sent packets are captured instead of going to a connection.
+pub struct MockDeviceHandle {
+    handle: DeviceHandle,
+    sent: OutgoingReceiver,
+}
+
+impl MockDeviceHandle {
+    pub fn new(device_id: &str) -> Self {
+        let (handle, sent) = DeviceManagerHandle::mock_device(device_id);
+        Self { handle, sent }
+    }
+
+    /// The handle to give to the plugins.
+    pub fn handle(&self) -> DeviceHandle {
+        self.handle.clone()
+    }
+
+    /// The next packet sent to the device, failing after a timeout.
+    pub async fn next_packet(&mut self) -> Result<NetworkPacket> {
+        let queued = tokio::time::timeout(SEND_TIMEOUT, self.sent.recv())
+            .await
+            .context("Timed out waiting for packet")?
+            .context("Queue closed")?;
+        Ok(queued.packet.packet)
+    }
+
+    /// Fail if a packet is sent to the device shortly.
+    pub async fn assert_nothing_sent(&mut self) {
+        if let Ok(Some(queued)) = tokio::time::timeout(QUIET_PERIOD, self.sent.recv()).await {
+            panic!("Unexpected packet sent: {:?}", queued.packet);
+        }
+    }
+}
+
+/// Feeds packets and events to the registered plugins, and captures what they send.
+pub struct PluginTestHarness {
+    pub device: MockDeviceHandle,
+    plugins: PluginRepository,
+    context: TestContext,
+}
+
+impl PluginTestHarness {
+    pub fn new() -> Result<Self> {
+        let device = MockDeviceHandle::new("test-device");
+        let plugins = PluginRepository::empty(device.handle());
+
+        Ok(Self {
+            device,
+            plugins,
+            context: TestContext::new()?,
+        })
+    }
+
+    pub fn ctx(&self) -> AppContextRef {
+        self.context.ctx.clone()
+    }
+
+    pub fn dev(&self) -> DeviceHandle {
+        self.device.handle()
+    }
+
+    pub fn register<P>(&mut self, plugin: P)
+    where
+        P: KdeConnectPlugin + KdeConnectPluginMetadata + 'static,
+    {
+        self.plugins
+            .register(plugin)
+            .expect("Failed to register plugin");
+    }
+
+    pub async fn start(&self) {
+        self.plugins.start().await;
+    }
+
+    /// Hand a packet from the device to the plugins.
+    pub async fn receive(&self, packet: NetworkPacket) -> Result<()> {
+        self.plugins.handle_packet(packet).await
+    }
+
+    pub async fn event(&self, event: SystemEvent) {
+        self.plugins.handle_event(event).await;
+    }
+
+    pub async fn click(&self, menu_id: MenuId) {
+        self.event(SystemEvent::TrayMenuClicked(menu_id)).await;
+    }
+
+    /// The items the plugins add to the tray menu of the device.
+    pub async fn tray_menu(&self) -> DeviceMenu {
+        let mut menu = DeviceMenu::default();
+        self.plugins.create_tray_menu(&mut menu).await;
+        menu
+    }
+
+    /// The next packet sent to the device, failing after a timeout.
+    pub async fn next_packet(&mut self) -> Result<NetworkPacket> {
+        self.device.next_packet().await
+    }
+}
@@ -0,0 +1,129 @@
+//! Test harness running the connection path against a simulated remote device.
+//!
+//! Tests that show toasts or write to the clipboard of the system are ignored, so that they do
+//! not get in the way on a desktop or fail on CI. Run them with `cargo test -- --ignored`.
+
+mod e2e;
+pub mod mock;
+pub mod peer;
+mod plugins;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tao::{
+    event_loop::EventLoop, global_shortcut::ShortcutManager, platform::windows::EventLoopExtWindows,
+};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{
+    config::{Config, SettingsStore},
+    context::{AppContextRef, ApplicationContext},
+    event::EventBus,
+    packet::NetworkPacket,
+    tls, CustomWindowEvent,
+};
+
+use self::peer::{PeerConnection, TestPeer};
+
+/// An application context with its own config directory, without any server.
+pub struct TestContext {
+    pub ctx: AppContextRef,
+    dir: PathBuf,
+    // Keeps the event loop proxy in the context valid.
+    _event_loop: EventLoop<CustomWindowEvent>,
+}
+
+impl TestContext {
+    pub fn new() -> Result<Self> {
+        let event_loop = EventLoop::<CustomWindowEvent>::new_any_thread();
+        let hotkey_manager = ShortcutManager::new(&event_loop);
+
+        let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+
+        let config = Config::init()?;
+        let settings = SettingsStore::load_or_default(dir.join("config.toml"))?;
+
+        let (tls_acceptor, tls_connector) = tls::build_tls(&config)?;
+        let ctx = ApplicationContext::builder(config, settings)
+            .tls(tls_acceptor, tls_connector)
+            .event_loop_proxy(event_loop.create_proxy())
+            .event_bus(EventBus::new())
+            .hotkey_manager(hotkey_manager)
+            .build();
+
+        Ok(Self {
+            ctx,
+            dir,
+            _event_loop: event_loop,
+        })
+    }
+}
+
+impl Drop for TestContext {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// An application instance listening on localhost, with its own config directory.
+pub struct TestApp {
+    pub ctx: AppContextRef,
+    /// Address of the TCP server.
+    pub addr: SocketAddr,
+    _context: TestContext,
+}
+
+impl TestApp {
+    pub async fn start() -> Result<Self> {
+        let context = TestContext::new()?;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(crate::tcp_server(listener, context.ctx.clone()));
+
+        Ok(Self {
+            ctx: context.ctx.clone(),
+            addr,
+            _context: context,
+        })
+    }
+
+    /// Connect `peer` to the application over TCP, and wait for the device to be added.
+    pub async fn connect(&self, peer: &TestPeer) -> Result<PeerConnection> {
+        let conn = peer.connect(self.addr).await?;
+        self.wait_for_device(peer.device_id()).await?;
+        Ok(conn)
+    }
+
+    /// Let the application discover `peer` through its UDP identity, and wait for it to
+    /// connect back.
+    pub async fn discover(&self, peer: &TestPeer) -> Result<PeerConnection> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let identity = peer.identity(Some(listener.local_addr()?.port()));
+
+        // The packet only needs a source address, the socket is never used.
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let identity = NetworkPacket::parse_identity(&identity.to_vec()?)?;
+        crate::handle_udp_identity(identity, socket.local_addr()?, &self.ctx).await?;
+
+        let conn = peer.accept(&listener).await?;
+        self.wait_for_device(peer.device_id()).await?;
+        Ok(conn)
+    }
+
+    pub async fn wait_for_device(&self, device_id: &str) -> Result<()> {
+        for _ in 0..100 {
+            if self.ctx.device_manager().query_device(device_id).await? {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        bail!("Device {} was not added", device_id)
+    }
+}
@@ -0,0 +1,207 @@
+//! A minimal KDE Connect peer, standing in for a phone in tests.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{rustls::ServerName, TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::{
+    config::Config,
+    packet::{self, IdentityPacket, NetworkPacket},
+    tls,
+};
+
+/// How long to wait for a packet before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TestPeer {
+    pub config: Config,
+    pub name: String,
+    tls_acceptor: TlsAcceptor,
+    tls_connector: TlsConnector,
+}
+
+impl TestPeer {
+    /// Create a peer with a fresh device id and certificate.
+    pub fn new(name: &str) -> Result<Self> {
+        let config = Config::init()?;
+        let (tls_acceptor, tls_connector) = tls::build_tls(&config)?;
+
+        Ok(Self {
+            config,
+            name: name.to_string(),
+            tls_acceptor,
+            tls_connector,
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.config.uuid
+    }
+
+    pub fn identity(&self, tcp_port: Option<u16>) -> NetworkPacket {
+        NetworkPacket::new(
+            packet::PACKET_TYPE_IDENTITY,
+            IdentityPacket {
+                device_id: self.config.uuid.clone(),
+                device_name: self.name.clone(),
+                protocol_version: 7,
+                device_type: "phone".into(),
+                incoming_capabilities: vec![],
+                outgoing_capabilities: vec![],
+                tcp_port,
+            },
+        )
+    }
+
+    /// Connect to the application over TCP, as a device does after receiving a UDP broadcast.
+    ///
+    /// The side opening the TCP connection acts as the TLS server.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<PeerConnection> {
+        let mut stream = TcpStream::connect(addr).await?;
+        self.identity(None).write_to_conn(&mut stream).await?;
+
+        let stream = self
+            .tls_acceptor
+            .accept(stream)
+            .await
+            .context("TLS accept")?;
+
+        Ok(PeerConnection {
+            stream: BufStream::new(TlsStream::from(stream)),
+            addr,
+        })
+    }
+
+    /// Accept a connection opened by the application in response to our UDP identity.
+    pub async fn accept(&self, listener: &TcpListener) -> Result<PeerConnection> {
+        let (mut stream, addr) = tokio::time::timeout(RECV_TIMEOUT, listener.accept())
+            .await
+            .context("Timed out waiting for connection")??;
+
+        let mut line = vec![];
+        loop {
+            let b = stream.read_u8().await?;
+            if b == b'\n' {
+                break;
+            }
+            line.push(b);
+        }
+        let identity: NetworkPacket = serde_json::from_slice(&line)?;
+        if identity.typ != packet::PACKET_TYPE_IDENTITY {
+            bail!("Expected identity, got {:?}", identity.typ);
+        }
+
+        let stream = self
+            .tls_connector
+            .connect(ServerName::IpAddress(addr.ip()), stream)
+            .await
+            .context("TLS connect")?;
+
+        Ok(PeerConnection {
+            stream: BufStream::new(TlsStream::from(stream)),
+            addr,
+        })
+    }
+
+    /// Fetch the payload announced by `packet` from the application.
+    pub async fn fetch_payload(&self, addr: SocketAddr, packet: &NetworkPacket) -> Result<Vec<u8>> {
+        let port = packet
+            .payload_transfer_info
+            .as_ref()
+            .context("No payload transfer info")?
+            .port;
+        let size = packet.payload_size.context("No payload size")? as usize;
+
+        let stream = TcpStream::connect((addr.ip(), port)).await?;
+        let mut stream = self
+            .tls_connector
+            .connect(ServerName::IpAddress(addr.ip()), stream)
+            .await
+            .context("TLS connect")?;
+
+        let mut buf = Vec::with_capacity(size);
+        tokio::time::timeout(RECV_TIMEOUT, stream.read_to_end(&mut buf))
+            .await
+            .context("Timed out fetching payload")??;
+
+        Ok(buf)
+    }
+
+    /// Serve a payload on localhost, sending `payloads[n]` on the `n`-th connection and closing
+    /// it right after, so that an incomplete or corrupt payload can be followed by the right one.
+    ///
+    /// Returns the port to announce in the packet.
+    pub async fn serve_payloads(&self, payloads: Vec<Vec<u8>>) -> Result<u16> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = self.tls_acceptor.clone();
+
+        tokio::spawn(async move {
+            for payload in payloads {
+                let res: Result<()> = async {
+                    let (stream, _) = listener.accept().await?;
+                    let mut stream = acceptor.accept(stream).await.context("TLS accept")?;
+                    stream.write_all(&payload).await?;
+                    stream.shutdown().await?;
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = res {
+                    log::warn!("Failed to serve payload: {:#}", e);
+                }
+            }
+        });
+
+        Ok(port)
+    }
+}
+
+/// An established, encrypted connection between a `TestPeer` and the application.
+pub struct PeerConnection {
+    stream: BufStream<TlsStream<TcpStream>>,
+    /// Address of the application.
+    pub addr: SocketAddr,
+}
+
+impl PeerConnection {
+    pub async fn send(&mut self, packet: &NetworkPacket) -> Result<()> {
+        packet.write_to_conn(&mut self.stream).await?;
+        Ok(())
+    }
+
+    /// Receive the next packet, failing after a timeout.
+    pub async fn recv(&mut self) -> Result<NetworkPacket> {
+        let mut line = String::new();
+        let n = tokio::time::timeout(RECV_TIMEOUT, self.stream.read_line(&mut line))
+            .await
+            .context("Timed out waiting for packet")??;
+        if n == 0 {
+            bail!("Connection closed");
+        }
+
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Receive packets until one of the given type arrives.
+    pub async fn recv_type(&mut self, typ: &str) -> Result<NetworkPacket> {
+        loop {
+            let packet = self.recv().await?;
+            if packet.typ == typ {
+                return Ok(packet);
+            }
+        }
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use tao::menu::MenuId;
+
+use crate::{
+    packet::NetworkPacket,
+    plugin::{
+        battery::BatteryPlugin, clipboard::ClipboardPlugin,
+        notification_receive::NotificationReceivePlugin, ping::PingPlugin,
+    },
+    tray::TrayItem,
+};
+
+use super::mock::PluginTestHarness;
+
+#[tokio::test]
+async fn battery_status() -> Result<()> {
+    let mut harness = PluginTestHarness::new()?;
+    harness.register(BatteryPlugin::new(harness.dev(), harness.ctx()));
+    harness.start().await;
+
+    let request = harness.next_packet().await?;
+    assert_eq!(request.typ, "kdeconnect.battery.request");
+    assert_eq!(request.body["request"], true);
+
+    harness
+        .receive(NetworkPacket::new(
+            "kdeconnect.battery",
+            serde_json::json!({ "currentCharge": 42, "isCharging": true, "thresholdEvent": 0 }),
+        ))
+        .await?;
+
+    let menu = harness.tray_menu().await;
+    assert_eq!(menu.battery, Some(42));
+    assert_eq!(
+        menu.status.items(),
+        &[TrayItem::Label("Battery:\t\t\t  42%+".into())]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn clipboard_manual_sync() -> Result<()> {
+    let mut harness = PluginTestHarness::new()?;
+    harness.register(ClipboardPlugin::new(harness.dev(), harness.ctx()));
+
+    let send = MenuId::new("test-device:clipboard:send");
+    let manual_sync = MenuId::new("test-device:clipboard:manual_sync");
+
+    // Only sent on request unless enabled.
+    let settings = harness.ctx().settings().device("test-device");
+    assert!(settings.plugins.clipboard.manual_sync);
+    assert_eq!(
+        harness.tray_menu().await.actions.items(),
+        &[TrayItem::Action {
+            id: send,
+            label: "Send clipboard".into(),
+            checked: None,
+            enabled: true,
+        }]
+    );
+
+    // Nothing has been copied yet.
+    harness.click(send).await;
+    harness.device.assert_nothing_sent().await;
+
+    harness.click(manual_sync).await;
+
+    let settings = harness.ctx().settings().device("test-device");
+    assert!(!settings.plugins.clipboard.manual_sync);
+    assert!(harness.tray_menu().await.actions.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn muted_notifications_list_their_app() -> Result<()> {
+    let mut harness = PluginTestHarness::new()?;
+    harness.register(NotificationReceivePlugin::new(harness.dev(), harness.ctx()));
+    harness.start().await;
+
+    let request = harness.next_packet().await?;
+    assert_eq!(request.typ, "kdeconnect.notification.request");
+    assert_eq!(request.body["request"], true);
+
+    // Muted, so that no toast is shown.
+    harness
+        .click(MenuId::new("test-device:notifications:mute"))
+        .await;
+    harness
+        .receive(NetworkPacket::new(
+            "kdeconnect.notification",
+            serde_json::json!({
+                "id": "1",
+                "onlyOnce": false,
+                "isClearable": true,
+                "appName": "Messages",
+                "time": "0",
+                "title": "Alice",
+                "text": "Hello",
+            }),
+        ))
+        .await?;
+
+    let menu = harness.tray_menu().await;
+    let notifications = match menu.settings.items() {
+        [TrayItem::Submenu { label, menu }] if label == "Notifications" => menu,
+        items => panic!("Unexpected settings: {:?}", items),
+    };
+    let apps = notifications.items().iter().find_map(|item| match item {
+        TrayItem::Submenu { label, menu } if label == "Applications" => Some(menu),
+        _ => None,
+    });
+    assert_eq!(
+        apps.map(|m| m.items()),
+        Some(
+            &[TrayItem::Action {
+                id: MenuId::new("test-device:notifications:app:Messages"),
+                label: "Mute Messages".into(),
+                checked: Some(false),
+                enabled: true,
+            }][..]
+        )
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn packets_disabled_on_the_device_are_not_sent() -> Result<()> {
+    let mut harness = PluginTestHarness::new()?;
+    harness.register(PingPlugin::new(harness.dev()));
+    let ping = || NetworkPacket::new("kdeconnect.ping", serde_json::json!({}));
+
+    harness
+        .receive(NetworkPacket::new(
+            "kdeconnect.plugins",
+            serde_json::json!({ "incomingCapabilities": ["kdeconnect.plugins"], "request": true }),
+        ))
+        .await?;
+    let state = harness.next_packet().await?;
+    assert_eq!(state.typ, "kdeconnect.plugins");
+    assert_eq!(
+        state.body["outgoingCapabilities"],
+        serde_json::json!(["kdeconnect.ping", "kdeconnect.plugins"])
+    );
+
+    harness.dev().send_packet(ping()).await;
+    harness.device.assert_nothing_sent().await;
+    assert!(harness.dev().send_packet_with_ack(ping()).await.is_err());
+
+    harness
+        .receive(NetworkPacket::new(
+            "kdeconnect.plugins",
+            serde_json::json!({ "incomingCapabilities": ["kdeconnect.ping"] }),
+        ))
+        .await?;
+    harness.dev().send_packet(ping()).await;
+    assert_eq!(harness.next_packet().await?.typ, "kdeconnect.ping");
+
+    Ok(())
+}
@@ -1,8 +1,61 @@
 use anyhow::Result;
+use std::sync::Arc;
 
 use rcgen::{CertificateParams, DistinguishedName};
+use sha2::{Digest, Sha256};
 use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::ClientSessionMemoryCache;
+use tokio_rustls::rustls::server::ServerSessionMemoryCache;
 use tokio_rustls::rustls::Error as TlsError;
+use tokio_rustls::rustls::{ClientConfig, ServerConfig, Ticketer};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::Config;
+
+/// Number of TLS sessions remembered for resumption, on each side.
+const TLS_SESSION_CACHE_SIZE: usize = 64;
+
+/// The key shown by KDE Connect while pairing, so that the user can check that both sides see
+/// the same certificates: the SHA-256 of both public keys, the larger one first, shortened to
+/// 8 hex digits.
+///
+/// From protocol version 8, the timestamp of the pairing request is hashed too.
+pub fn verification_key(
+    local_cert: &[u8],
+    remote_cert: &[u8],
+    timestamp: Option<u64>,
+) -> Result<String> {
+    let local = public_key(local_cert)?;
+    let remote = public_key(remote_cert)?;
+    let (first, second) = if local > remote {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+
+    let mut hasher = Sha256::new().chain_update(first).chain_update(second);
+    if let Some(timestamp) = timestamp {
+        hasher.update(timestamp.to_string());
+    }
+    let hash = hasher.finalize();
+    Ok(hash[..4].iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+/// The SHA-256 of a DER encoded certificate, as colon separated hex digits.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The DER encoded SubjectPublicKeyInfo of a certificate.
+fn public_key(cert: &[u8]) -> Result<&[u8]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+    Ok(cert.tbs_certificate.subject_pki.raw)
+}
 
 /// Parse a `rustls::Certificate` as an `x509_signature::X509Certificate`, if possible.
 fn get_cert(
@@ -174,6 +227,40 @@ impl tokio_rustls::rustls::server::ClientCertVerifier for ClientVerifier {
     }
 }
 
+/// Build the TLS acceptor and connector for our certificate.
+///
+/// The same certificate is used when we are acting as client and server.
+///
+/// Sessions are resumed on reconnects, so that devices on a flaky network do not go through
+/// the full handshake every time. Both configs are built once and kept in the application
+/// context, so the session caches are shared by all connections.
+pub fn build_tls(config: &Config) -> Result<(TlsAcceptor, TlsConnector)> {
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(ServerVerifier::AlwaysOk))
+        .with_single_cert(
+            vec![rustls::Certificate(config.tls_cert.clone())],
+            rustls::PrivateKey(config.tls_key.clone()),
+        )?;
+
+    client_config.session_storage = ClientSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE);
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(ClientVerifier::AlwaysOk))
+        .with_single_cert(
+            vec![rustls::Certificate(config.tls_cert.clone())],
+            rustls::PrivateKey(config.tls_key.clone()),
+        )?;
+    server_config.session_storage = ServerSessionMemoryCache::new(TLS_SESSION_CACHE_SIZE);
+    server_config.ticketer = Ticketer::new()?;
+
+    Ok((
+        TlsAcceptor::from(Arc::new(server_config)),
+        TlsConnector::from(Arc::new(client_config)),
+    ))
+}
+
 pub fn generate_certs(device_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
     let mut cert_params = CertificateParams::new(vec![]);
 
@@ -194,3 +281,72 @@ pub fn generate_certs(device_id: &str) -> Result<(Vec<u8>, Vec<u8>)> {
 
     Ok((cert_der, key_der))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT_A: &str = "-----BEGIN CERTIFICATE-----
+MIIBuzCCAWCgAwIBAgIBCjAKBggqhkjOPQQDAjA8MQwwCgYDVQQKDANLREUxFDAS
+BgNVBAsMC0tkZSBjb25uZWN0MRYwFAYDVQQDDA10ZXN0X2RldmljZV9hMB4XDTI2
+MTAxNjE2NDIyNVoXDTM2MTAxMzE2NDIyNVowPDEMMAoGA1UECgwDS0RFMRQwEgYD
+VQQLDAtLZGUgY29ubmVjdDEWMBQGA1UEAwwNdGVzdF9kZXZpY2VfYTBZMBMGByqG
+SM49AgEGCCqGSM49AwEHA0IABOHNx9y5X8cBEmmxBJGJPVVez+VyEMe/apWRX/7h
+FNO91RGsFhQUsleuqaL14ikkc7oXwdXuE+lkAD+xW1/hEgSjUzBRMB0GA1UdDgQW
+BBQbK1ezaTQ0IvuWpvsZKb4Cpjpb5jAfBgNVHSMEGDAWgBQbK1ezaTQ0IvuWpvsZ
+Kb4Cpjpb5jAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQDGXuEb
+0f3YDoc70iabo3wOfsJzh54ToHv4hxI5mXVzRwIhAKkOYjQDAr2PHSqVQVLwVwjv
+4tvyYjQQ8KNun/dWtQhG
+-----END CERTIFICATE-----";
+
+    const CERT_B: &str = "-----BEGIN CERTIFICATE-----
+MIIBuzCCAWCgAwIBAgIBCjAKBggqhkjOPQQDAjA8MQwwCgYDVQQKDANLREUxFDAS
+BgNVBAsMC0tkZSBjb25uZWN0MRYwFAYDVQQDDA10ZXN0X2RldmljZV9iMB4XDTI2
+MTAxNjE2NDIyNVoXDTM2MTAxMzE2NDIyNVowPDEMMAoGA1UECgwDS0RFMRQwEgYD
+VQQLDAtLZGUgY29ubmVjdDEWMBQGA1UEAwwNdGVzdF9kZXZpY2VfYjBZMBMGByqG
+SM49AgEGCCqGSM49AwEHA0IABCB8WNz+3JP59gCFoiUnpxpiSjZJvDdEbTDtrLmz
+/58yr7QPaw4XbovdcRSWjyYjXz023QAfHQeoujsrUVgDiOijUzBRMB0GA1UdDgQW
+BBSDfeqeKl7lwOQW993QywLzNhvCETAfBgNVHSMEGDAWgBSDfeqeKl7lwOQW993Q
+ywLzNhvCETAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQDe9c7g
+ydHxRYpMMfN7+lwqJ7F9lspki/lAc5sx6404UAIhALe+0zzF1NfKXL4E4xIK3eB8
+ZThCieX5WqTbBJz1DNvA
+-----END CERTIFICATE-----";
+
+    fn der(pem: &str) -> Vec<u8> {
+        x509_parser::pem::parse_x509_pem(pem.as_bytes())
+            .unwrap()
+            .1
+            .contents
+    }
+
+    // The expected keys were computed outside of this crate, the way KDE Connect does in
+    // `Device::verificationKey`: SHA-256 over the DER public keys as extracted by
+    // `openssl pkey -pubin -outform DER`, the larger one first, then the timestamp in decimal,
+    // shown as the first 8 hex digits in upper case.
+
+    #[test]
+    fn verification_key_matches_kde_connect() {
+        let (a, b) = (der(CERT_A), der(CERT_B));
+        assert_eq!(verification_key(&a, &b, None).unwrap(), "95D1B4F2");
+        assert_eq!(
+            verification_key(&a, &b, Some(1_700_000_000)).unwrap(),
+            "C1BF71E6"
+        );
+    }
+
+    #[test]
+    fn verification_key_is_the_same_on_both_sides() {
+        let (a, b) = (der(CERT_A), der(CERT_B));
+        for timestamp in [None, Some(1_700_000_000)] {
+            assert_eq!(
+                verification_key(&a, &b, timestamp).unwrap(),
+                verification_key(&b, &a, timestamp).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn verification_key_rejects_invalid_certificates() {
+        assert!(verification_key(b"not a certificate", &der(CERT_B), None).is_err());
+    }
+}
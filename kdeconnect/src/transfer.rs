@@ -0,0 +1,677 @@
+//! Payload transfers to and from the devices, listed in the "Transfers" submenu of the tray
+//! where they can be cancelled, or retried once they failed.
+
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tao::menu::MenuId;
+use tokio::{io::AsyncReadExt, sync::Notify};
+
+use crate::{
+    context::{AppContextRef, TrayUpdater},
+    device::DeviceHandle,
+    event::SystemEvent,
+    packet::NetworkPacketWithPayload,
+    tray::TrayMenu,
+    utils::{self, bandwidth::Throttle, hash::ContentHash},
+};
+
+/// Finished transfers that are still listed, the oldest ones are dropped first.
+const MAX_FINISHED: usize = 10;
+/// Give up on a payload connection that stops sending data.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Shared by the user, always listed.
+    File,
+    /// Done on behalf of a plugin, e.g. a notification icon, only listed while running or if
+    /// it failed.
+    Background,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed(String),
+}
+
+impl TransferState {
+    pub fn failed(e: &anyhow::Error) -> Self {
+        Self::Failed(format!("{:#}", e))
+    }
+}
+
+/// Shared by a transfer and the code moving its data.
+#[derive(Debug, Default)]
+pub struct Progress {
+    transferred: AtomicU64,
+    cancelled: AtomicBool,
+    /// Set once, by whichever side ends the transfer first.
+    outcome: Mutex<Option<TransferState>>,
+    finished: Notify,
+}
+
+impl Progress {
+    pub fn add(&self, n: usize) {
+        self.transferred.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Start counting again, e.g. when the data is sent once more.
+    pub fn reset(&self) {
+        self.transferred.store(0, Ordering::Relaxed);
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.finish(TransferState::Cancelled);
+        // Also wakes `cancelled` if the transfer had already ended otherwise.
+        self.finished.notify_waiters();
+    }
+
+    /// Wait until the transfer is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.finished.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Record how the transfer ended, unless it already has.
+    pub fn finish(&self, state: TransferState) {
+        let mut outcome = self.outcome.lock().unwrap();
+        if outcome.is_none() {
+            *outcome = Some(state);
+            self.finished.notify_waiters();
+        }
+    }
+
+    pub fn state(&self) -> TransferState {
+        self.outcome
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or(TransferState::Running)
+    }
+
+    async fn wait(&self) -> TransferState {
+        loop {
+            let finished = self.finished.notified();
+            if let Some(state) = self.outcome.lock().unwrap().clone() {
+                return state;
+            }
+            finished.await;
+        }
+    }
+}
+
+/// The progress of an outgoing payload, attached to its packet. The transfer fails if it is
+/// dropped before the payload was downloaded, e.g. because the connection closed.
+pub struct PayloadProgress {
+    progress: Arc<Progress>,
+    throttle: Arc<Throttle>,
+}
+
+impl std::fmt::Debug for PayloadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PayloadProgress")
+            .field(&self.progress)
+            .finish()
+    }
+}
+
+impl PayloadProgress {
+    pub fn new(progress: Arc<Progress>, throttle: Arc<Throttle>) -> Self {
+        Self { progress, throttle }
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Wait until `n` more bytes may be sent, as capped in the settings.
+    pub async fn throttle(&self, n: usize) {
+        self.throttle.consume(n).await;
+    }
+}
+
+impl Drop for PayloadProgress {
+    fn drop(&mut self) {
+        self.progress.finish(TransferState::Failed(
+            "The device did not download it".to_string(),
+        ));
+    }
+}
+
+/// Starts the transfer again, as a new one.
+type RetryFn = Box<dyn Fn() + Send + Sync>;
+
+struct Entry {
+    id: u64,
+    device_name: String,
+    name: String,
+    direction: Direction,
+    kind: Kind,
+    size: u64,
+    progress: Arc<Progress>,
+    retry: Option<RetryFn>,
+}
+
+impl Entry {
+    fn cancel_menu_id(&self) -> MenuId {
+        MenuId::new(&format!("transfers:{}:cancel", self.id))
+    }
+
+    fn retry_menu_id(&self) -> MenuId {
+        MenuId::new(&format!("transfers:{}:retry", self.id))
+    }
+
+    fn label(&self) -> String {
+        let arrow = match self.direction {
+            Direction::Incoming => "↓",
+            Direction::Outgoing => "↑",
+        };
+        let mut label = format!("{} {} ({}): ", arrow, self.name, self.device_name);
+        match self.progress.state() {
+            TransferState::Running if self.size > 0 => {
+                let percent = self.progress.transferred().min(self.size) * 100 / self.size;
+                write!(label, "{}% of {}", percent, utils::format_size(self.size)).ok();
+            }
+            TransferState::Running => label.push_str("starting"),
+            TransferState::Completed => label.push_str("done"),
+            TransferState::Cancelled => label.push_str("cancelled"),
+            TransferState::Failed(_) => label.push_str("failed"),
+        }
+        label
+    }
+}
+
+/// The transfers in progress and the recently finished ones, oldest first.
+#[derive(Default)]
+pub struct TransferManager {
+    entries: Mutex<Vec<Entry>>,
+    next_id: AtomicU64,
+}
+
+impl TransferManager {
+    fn register(
+        &self,
+        device_name: &str,
+        direction: Direction,
+        kind: Kind,
+        name: &str,
+        size: u64,
+    ) -> (u64, Arc<Progress>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(Progress::default());
+        self.entries.lock().unwrap().push(Entry {
+            id,
+            device_name: device_name.to_string(),
+            name: name.to_string(),
+            direction,
+            kind,
+            size,
+            progress: progress.clone(),
+            retry: None,
+        });
+        (id, progress)
+    }
+
+    fn set_retry(&self, id: u64, retry: RetryFn) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().find(|e| e.id == id) {
+            entry.retry = Some(retry);
+        }
+    }
+
+    /// Forget finished transfers that are not worth listing.
+    fn prune(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| {
+            e.kind == Kind::File
+                || !matches!(
+                    e.progress.state(),
+                    TransferState::Completed | TransferState::Cancelled
+                )
+        });
+
+        let finished = entries
+            .iter()
+            .filter(|e| e.progress.state() != TransferState::Running)
+            .count();
+        let mut excess = finished.saturating_sub(MAX_FINISHED);
+        entries.retain(|e| {
+            if excess > 0 && e.progress.state() != TransferState::Running {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn has_running(&self) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.progress.state() == TransferState::Running)
+    }
+
+    /// Add the "Transfers" submenu, if there are any.
+    pub fn add_menu(&self, menu: &mut TrayMenu) {
+        let entries = self.entries.lock().unwrap();
+        let mut submenu = TrayMenu::new();
+        for entry in entries.iter().rev() {
+            let mut entry_menu = TrayMenu::new();
+            match entry.progress.state() {
+                TransferState::Running => {
+                    entry_menu.add_action(entry.cancel_menu_id(), "Cancel");
+                }
+                state => {
+                    if let TransferState::Failed(e) = state {
+                        entry_menu.add_label(e);
+                    }
+                    if entry.retry.is_some() {
+                        entry_menu.add_action(entry.retry_menu_id(), "Retry");
+                    }
+                }
+            }
+
+            if entry_menu.is_empty() {
+                submenu.add_label(entry.label());
+            } else {
+                submenu.add_submenu(entry.label(), entry_menu);
+            }
+        }
+
+        if entries
+            .iter()
+            .any(|e| e.progress.state() != TransferState::Running)
+        {
+            submenu.add_separator();
+            submenu.add_action(clear_menu_id(), "Clear finished");
+        }
+        menu.add_submenu("Transfers", submenu);
+    }
+
+    /// Act on a click in the submenu, returns whether the menu should be updated.
+    fn handle_click(&self, menu_id: MenuId) -> bool {
+        if menu_id == clear_menu_id() {
+            self.entries
+                .lock()
+                .unwrap()
+                .retain(|e| e.progress.state() == TransferState::Running);
+            return true;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter().find(|e| e.cancel_menu_id() == menu_id) {
+            log::info!("Cancelling transfer of {}", entry.name);
+            entry.progress.cancel();
+            return true;
+        }
+
+        let index = entries
+            .iter()
+            .position(|e| e.retry.is_some() && e.retry_menu_id() == menu_id);
+        if let Some(index) = index {
+            // The retry is listed as a new transfer.
+            let entry = entries.remove(index);
+            drop(entries);
+            log::info!("Retrying transfer of {}", entry.name);
+            if let Some(retry) = entry.retry {
+                retry();
+            }
+            return true;
+        }
+
+        false
+    }
+}
+
+fn clear_menu_id() -> MenuId {
+    MenuId::new("transfers:clear")
+}
+
+/// Handle clicks in the "Transfers" submenu, and refresh the progress shown in it when the tray
+/// menu is opened.
+pub async fn handle_event(event: SystemEvent, ctx: &AppContextRef) {
+    let update = match event {
+        SystemEvent::TrayMenuClicked(menu_id) => ctx.transfers().handle_click(menu_id),
+        SystemEvent::TrayMenuOpened => ctx.transfers().has_running(),
+        _ => false,
+    };
+    if update {
+        ctx.update_tray().await;
+    }
+}
+
+/// A transfer listed in the tray while it is alive. It is marked as failed if it is dropped
+/// before it was finished.
+pub struct Transfer {
+    ctx: AppContextRef,
+    id: u64,
+    progress: Arc<Progress>,
+    throttle: Arc<Throttle>,
+}
+
+impl Transfer {
+    pub async fn start(
+        ctx: &AppContextRef,
+        dev: &DeviceHandle,
+        direction: Direction,
+        kind: Kind,
+        name: &str,
+        size: u64,
+    ) -> Self {
+        let (id, progress) =
+            ctx.transfers()
+                .register(&dev.device_name(), direction, kind, name, size);
+        if kind == Kind::File {
+            ctx.update_tray().await;
+        }
+
+        Self {
+            ctx: ctx.clone(),
+            id,
+            progress,
+            throttle: Arc::new(Throttle::new(ctx, dev.device_id(), direction)),
+        }
+    }
+
+    pub fn progress(&self) -> &Arc<Progress> {
+        &self.progress
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.progress.is_cancelled()
+    }
+
+    /// Wait until `n` more bytes may be transferred, as capped in the settings.
+    pub async fn throttle(&self, n: usize) {
+        self.throttle.consume(n).await;
+    }
+
+    /// Offer to retry the transfer once it failed or was cancelled.
+    pub fn set_retry(&self, retry: impl Fn() + Send + Sync + 'static) {
+        self.ctx.transfers().set_retry(self.id, Box::new(retry));
+    }
+
+    pub async fn finish(self, state: TransferState) {
+        self.progress.finish(state);
+        self.ended().await;
+    }
+
+    /// Wait until the transfer is finished by the code moving its data.
+    pub async fn wait(self) -> TransferState {
+        let state = self.progress.wait().await;
+        self.ended().await;
+        state
+    }
+
+    async fn ended(&self) {
+        self.ctx.transfers().prune();
+        self.ctx.update_tray().await;
+    }
+
+    /// Download a payload of `size` bytes from the device.
+    pub async fn fetch(&self, dev: &DeviceHandle, port: u16, size: usize) -> Result<Vec<u8>> {
+        let mut stream = dev.connect_payload(port).await?;
+        let mut data = Vec::with_capacity(size);
+        let mut buf = vec![0; 64 * 1024];
+        self.progress.reset();
+
+        while data.len() < size {
+            if self.is_cancelled() {
+                bail!("Cancelled");
+            }
+
+            let len = buf.len().min(size - data.len());
+            let n = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf[..len]))
+                .await
+                .context("Timed out reading payload")??;
+            if n == 0 {
+                bail!(
+                    "Payload connection closed with {} bytes remaining",
+                    size - data.len()
+                );
+            }
+
+            data.extend_from_slice(&buf[..n]);
+            dev.stats().add_received(n);
+            self.progress.add(n);
+            self.throttle(n).await;
+        }
+
+        Ok(data)
+    }
+
+    /// Fetch a payload and check it against its hash, as sent by the device in "payloadHash".
+    ///
+    /// A corrupt payload is counted in the stats and fetched once more, in case the device
+    /// still serves it.
+    pub async fn fetch_verified(
+        &self,
+        dev: &DeviceHandle,
+        port: u16,
+        size: usize,
+        expected: ContentHash,
+    ) -> Result<Vec<u8>> {
+        let mut retried = false;
+        loop {
+            let data = self.fetch(dev, port, size).await?;
+            // Hashing a large payload would hold up the other tasks.
+            let (data, actual) = tokio::task::spawn_blocking(move || {
+                let actual = expected.recompute(&data);
+                (data, actual)
+            })
+            .await?;
+            if actual == expected {
+                return Ok(data);
+            }
+
+            dev.stats().add_corrupt_payload();
+            if retried {
+                bail!(
+                    "Payload hash mismatch: {} (fetched) != {} (expected)",
+                    actual,
+                    expected
+                );
+            }
+            log::warn!(
+                "Payload hash mismatch from {}, fetching again",
+                dev.device_name()
+            );
+            retried = true;
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.progress
+            .finish(TransferState::Failed("Interrupted".to_string()));
+        self.ctx.transfers().prune();
+    }
+}
+
+/// Send a packet with a payload, listed as a transfer until the device has downloaded it.
+/// A failed transfer can be retried, which sends the packet again.
+pub async fn send(
+    ctx: &AppContextRef,
+    dev: &DeviceHandle,
+    kind: Kind,
+    name: &str,
+    mut packet: NetworkPacketWithPayload,
+) {
+    let size = packet.payload.as_ref().map_or(0, |p| p.len() as u64);
+    let transfer = Transfer::start(ctx, dev, Direction::Outgoing, kind, name, size).await;
+
+    let (rctx, rdev, rname, rpacket) = (ctx.clone(), dev.clone(), name.to_string(), packet.clone());
+    transfer.set_retry(move || {
+        tokio::spawn(send_boxed(
+            rctx.clone(),
+            rdev.clone(),
+            kind,
+            rname.clone(),
+            rpacket.clone(),
+        ));
+    });
+
+    packet.progress = Some(Arc::new(PayloadProgress::new(
+        transfer.progress().clone(),
+        transfer.throttle.clone(),
+    )));
+    dev.send_packet(packet).await;
+
+    tokio::spawn(async move {
+        if let TransferState::Failed(e) = transfer.wait().await {
+            log::warn!("Failed to send payload: {}", e);
+        }
+    });
+}
+
+/// [`send`] as a boxed future, so that it can be spawned again from the retry action.
+fn send_boxed(
+    ctx: AppContextRef,
+    dev: DeviceHandle,
+    kind: Kind,
+    name: String,
+    packet: NetworkPacketWithPayload,
+) -> BoxFuture<'static, ()> {
+    Box::pin(async move { send(&ctx, &dev, kind, &name, packet).await })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tray::TrayItem;
+
+    use super::*;
+
+    fn labels(manager: &TransferManager) -> Vec<String> {
+        let mut menu = TrayMenu::new();
+        manager.add_menu(&mut menu);
+        let items = match menu.items() {
+            [TrayItem::Submenu { menu, .. }] => menu.items().to_vec(),
+            [] => vec![],
+            items => panic!("Unexpected menu: {:?}", items),
+        };
+        items
+            .into_iter()
+            .filter_map(|item| match item {
+                TrayItem::Label(label) | TrayItem::Submenu { label, .. } => Some(label),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transfers_are_listed_newest_first() {
+        let manager = TransferManager::default();
+        let (_, photo) = manager.register("Phone", Direction::Incoming, Kind::File, "a.jpg", 200);
+        manager.register("Phone", Direction::Outgoing, Kind::File, "b.txt", 0);
+        photo.add(50);
+
+        assert_eq!(
+            labels(&manager),
+            ["↑ b.txt (Phone): starting", "↓ a.jpg (Phone): 25% of 200 B"]
+        );
+
+        photo.cancel();
+        assert!(manager.has_running());
+        assert_eq!(labels(&manager)[1], "↓ a.jpg (Phone): cancelled");
+    }
+
+    #[test]
+    fn finished_background_transfers_are_dropped() {
+        let manager = TransferManager::default();
+        let (_, icon) = manager.register("Phone", Direction::Incoming, Kind::Background, "i", 1);
+        let (_, art) = manager.register("Phone", Direction::Outgoing, Kind::Background, "a", 1);
+
+        icon.finish(TransferState::Completed);
+        art.finish(TransferState::Failed("closed".into()));
+        manager.prune();
+        assert_eq!(labels(&manager), ["↑ a (Phone): failed"]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_wakes_after_the_transfer_ended() {
+        let progress = Arc::new(Progress::default());
+        let waiter = tokio::spawn({
+            let progress = progress.clone();
+            async move { progress.cancelled().await }
+        });
+
+        progress.finish(TransferState::Completed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        progress.cancel();
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(progress.state(), TransferState::Completed);
+    }
+
+    #[test]
+    fn retry_replaces_the_failed_transfer() {
+        let manager = TransferManager::default();
+        let (id, progress) = manager.register("Phone", Direction::Incoming, Kind::File, "f", 1);
+        let retried = Arc::new(AtomicBool::new(false));
+        let flag = retried.clone();
+        manager.set_retry(id, Box::new(move || flag.store(true, Ordering::Relaxed)));
+        progress.finish(TransferState::Failed("closed".into()));
+
+        assert!(manager.handle_click(MenuId::new(&format!("transfers:{}:retry", id))));
+        assert!(retried.load(Ordering::Relaxed));
+        assert!(labels(&manager).is_empty());
+    }
+
+    #[test]
+    fn only_a_few_finished_transfers_are_kept() {
+        let manager = TransferManager::default();
+        let (_, running) = manager.register("Phone", Direction::Incoming, Kind::File, "r", 1);
+        for i in 0..MAX_FINISHED + 5 {
+            let name = format!("{}", i);
+            let (_, p) = manager.register("Phone", Direction::Incoming, Kind::File, &name, 1);
+            p.finish(TransferState::Completed);
+        }
+        manager.prune();
+
+        let labels = labels(&manager);
+        assert_eq!(labels.len(), MAX_FINISHED + 1);
+        assert_eq!(labels.last().unwrap(), "↓ r (Phone): 0% of 1 B");
+        assert!(running.state() == TransferState::Running);
+    }
+}
@@ -0,0 +1,221 @@
+//! Tray icon, composed from the cellphone icon and the state of the connected devices.
+//!
+//! The cellphone icons are white, for the dark taskbar Windows has by default. They are darkened
+//! on a light taskbar.
+
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use tao::system_tray::Icon;
+
+use super::theme::TaskbarTheme;
+
+/// Replaces the white of the cellphone icons on a light taskbar.
+const LIGHT_THEME_COLOR: Rgba<u8> = Rgba([0x20, 0x20, 0x20, 0xff]);
+const BADGE_COLOR: Rgba<u8> = Rgba([0xe5, 0x39, 0x35, 0xff]);
+const BADGE_TEXT_COLOR: Rgba<u8> = Rgba([0xff, 0xff, 0xff, 0xff]);
+const BATTERY_OUTLINE_COLOR: Rgba<u8> = Rgba([0x20, 0x20, 0x20, 0xff]);
+const BATTERY_COLOR: Rgba<u8> = Rgba([0x4c, 0xaf, 0x50, 0xff]);
+const BATTERY_LOW_COLOR: Rgba<u8> = Rgba([0xe5, 0x39, 0x35, 0xff]);
+/// Charge at or below which the battery bar turns red.
+const BATTERY_LOW: u8 = 15;
+
+/// 3x5 glyphs for the digits in the device count badge, one row per byte.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+lazy_static::lazy_static! {
+    static ref ICON_CELLPHONE: RgbaImage = load_png(include_bytes!("../icons/cellphone.png"));
+    static ref ICON_CELLPHONE_OFF: RgbaImage =
+        load_png(include_bytes!("../icons/cellphone-off.png"));
+}
+
+fn load_png(buf: &[u8]) -> RgbaImage {
+    image::load_from_memory(buf).unwrap().into_rgba8()
+}
+
+/// What the tray icon shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IconState {
+    pub device_count: usize,
+    /// Battery charge in percent, only shown when a single device is connected.
+    pub battery: Option<u8>,
+}
+
+impl IconState {
+    pub fn to_icon(self, theme: TaskbarTheme) -> Icon {
+        let image = self.render(theme);
+        let (width, height) = image.dimensions();
+        Icon::from_rgba(image.into_raw(), width, height).unwrap()
+    }
+
+    fn render(self, theme: TaskbarTheme) -> RgbaImage {
+        let base = if self.device_count == 0 {
+            &*ICON_CELLPHONE_OFF
+        } else {
+            &*ICON_CELLPHONE
+        };
+        let mut image = base.clone();
+        if theme == TaskbarTheme::Light {
+            tint(&mut image, LIGHT_THEME_COLOR);
+        }
+
+        match self.device_count {
+            0 => {}
+            1 => {
+                if let Some(charge) = self.battery {
+                    draw_battery(&mut image, charge);
+                }
+            }
+            count => draw_badge(&mut image, count),
+        }
+        image
+    }
+}
+
+/// Paint every pixel with `color`, keeping its transparency.
+fn tint(image: &mut RgbaImage, color: Rgba<u8>) {
+    for pixel in image.pixels_mut() {
+        *pixel = Rgba([color[0], color[1], color[2], pixel[3]]);
+    }
+}
+
+/// Draw a battery bar along the bottom edge.
+fn draw_battery(image: &mut RgbaImage, charge: u8) {
+    let (width, height) = image.dimensions();
+    let bar_height = (height / 8).max(3);
+    let top = height - bar_height;
+
+    fill_rect(image, 0, top, width, bar_height, BATTERY_OUTLINE_COLOR);
+
+    let inner_width = width - 2;
+    let filled = inner_width * u32::from(charge.min(100)) / 100;
+    let color = if charge <= BATTERY_LOW {
+        BATTERY_LOW_COLOR
+    } else {
+        BATTERY_COLOR
+    };
+    fill_rect(image, 1, top + 1, filled, bar_height - 2, color);
+}
+
+/// Draw a round badge with the number of devices in the bottom right corner.
+fn draw_badge(image: &mut RgbaImage, count: usize) {
+    let (width, height) = image.dimensions();
+    let radius = (width.min(height) * 5 / 22) as i64;
+    let cx = width as i64 - radius - 1;
+    let cy = height as i64 - radius - 1;
+
+    for y in (cy - radius).max(0)..=cy + radius {
+        for x in (cx - radius).max(0)..=cx + radius {
+            let (dx, dy) = (x - cx, y - cy);
+            if dx * dx + dy * dy <= radius * radius && in_bounds(image, x, y) {
+                image.put_pixel(x as u32, y as u32, BADGE_COLOR);
+            }
+        }
+    }
+
+    // Counts above 9 do not fit, and are rare enough to not bother.
+    let glyph = &DIGITS[count.min(9)];
+    let scale = (radius / 4).max(1);
+    let left = cx - scale * 3 / 2;
+    let top = cy - scale * 5 / 2;
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) == 0 {
+                continue;
+            }
+            let x = left + col as i64 * scale;
+            let y = top + row as i64 * scale;
+            if in_bounds(image, x, y) {
+                fill_rect(
+                    image,
+                    x as u32,
+                    y as u32,
+                    scale as u32,
+                    scale as u32,
+                    BADGE_TEXT_COLOR,
+                );
+            }
+        }
+    }
+}
+
+fn in_bounds(image: &RgbaImage, x: i64, y: i64) -> bool {
+    x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height()
+}
+
+fn fill_rect(image: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    let x_end = (x + width).min(image.width());
+    let y_end = (y + height).min(image.height());
+    for y in y..y_end {
+        for x in x..x_end {
+            image.put_pixel(x, y, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank() -> RgbaImage {
+        RgbaImage::new(48, 48)
+    }
+
+    #[test]
+    fn battery_bar_width_follows_charge() {
+        let mut image = blank();
+        draw_battery(&mut image, 50);
+
+        let row = 48 - 3;
+        let filled = (0..48)
+            .filter(|&x| *image.get_pixel(x, row) == BATTERY_COLOR)
+            .count();
+        assert_eq!(filled, 23);
+    }
+
+    #[test]
+    fn low_battery_is_red() {
+        let mut image = blank();
+        draw_battery(&mut image, 10);
+
+        assert_eq!(*image.get_pixel(1, 48 - 3), BATTERY_LOW_COLOR);
+    }
+
+    #[test]
+    fn light_theme_darkens_the_icon() {
+        let state = IconState {
+            device_count: 0,
+            battery: None,
+        };
+        let dark = state.render(TaskbarTheme::Dark);
+        let light = state.render(TaskbarTheme::Light);
+
+        let opaque = |p: &&Rgba<u8>| p[3] > 0;
+        assert!(dark.pixels().filter(opaque).all(|p| p[0] == 0xff));
+        assert!(light.pixels().filter(opaque).all(|p| p[0] == 0x20));
+        assert_eq!(
+            dark.pixels().filter(opaque).count(),
+            light.pixels().filter(opaque).count()
+        );
+    }
+
+    #[test]
+    fn badge_is_drawn_in_the_corner() {
+        let mut image = blank();
+        draw_badge(&mut image, 3);
+
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+        assert_ne!(*image.get_pixel(40, 40), Rgba([0, 0, 0, 0]));
+        assert!(image.pixels().any(|p| *p == BADGE_TEXT_COLOR));
+    }
+}
@@ -0,0 +1,360 @@
+//! Tray menu model.
+//!
+//! Plugins and the device manager describe the menu with these types, which are only turned
+//! into a tao [`ContextMenu`] on the event loop. This keeps the layout logic testable.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use tao::menu::{ContextMenu, MenuId, MenuItem, MenuItemAttributes};
+
+use crate::utils::tao_serde::MenuIdDef;
+
+mod icon;
+pub mod theme;
+pub use icon::IconState;
+
+/// Windows cuts tooltips longer than this, in UTF-16 units.
+const MAX_TOOLTIP_LEN: usize = 127;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TrayItem {
+    /// A clickable item, shown with a check mark if `checked` is `Some(true)`.
+    Action {
+        #[serde(with = "MenuIdDef")]
+        id: MenuId,
+        label: String,
+        checked: Option<bool>,
+        enabled: bool,
+    },
+    /// A disabled item that only shows some information.
+    Label(String),
+    Submenu {
+        label: String,
+        menu: TrayMenu,
+    },
+    Separator,
+    Quit,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrayMenu {
+    items: Vec<TrayItem>,
+}
+
+impl TrayMenu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn items(&self) -> &[TrayItem] {
+        &self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn add_action(&mut self, id: MenuId, label: impl Into<String>) {
+        self.items.push(TrayItem::Action {
+            id,
+            label: label.into(),
+            checked: None,
+            enabled: true,
+        });
+    }
+
+    pub fn add_toggle(&mut self, id: MenuId, label: impl Into<String>, checked: bool) {
+        self.items.push(TrayItem::Action {
+            id,
+            label: label.into(),
+            checked: Some(checked),
+            enabled: true,
+        });
+    }
+
+    pub fn add_label(&mut self, label: impl Into<String>) {
+        self.items.push(TrayItem::Label(label.into()));
+    }
+
+    /// Add a submenu, unless it has no items.
+    pub fn add_submenu(&mut self, label: impl Into<String>, menu: TrayMenu) {
+        if !menu.is_empty() {
+            self.items.push(TrayItem::Submenu {
+                label: label.into(),
+                menu,
+            });
+        }
+    }
+
+    /// Add a separator. Separators at the start of the menu or right after another one
+    /// are dropped, so sections can be added without checking whether they are empty.
+    pub fn add_separator(&mut self) {
+        if !matches!(self.items.last(), None | Some(TrayItem::Separator)) {
+            self.items.push(TrayItem::Separator);
+        }
+    }
+
+    pub fn add_quit(&mut self) {
+        self.items.push(TrayItem::Quit);
+    }
+
+    /// Append all items of another menu.
+    pub fn extend(&mut self, other: TrayMenu) {
+        for item in other.items {
+            match item {
+                TrayItem::Separator => self.add_separator(),
+                item => self.items.push(item),
+            }
+        }
+    }
+
+    /// Build the native menu. Trailing separators are left out.
+    pub fn to_context_menu(&self) -> ContextMenu {
+        let mut menu = ContextMenu::new();
+
+        let end = self
+            .items
+            .iter()
+            .rposition(|item| !matches!(item, TrayItem::Separator))
+            .map_or(0, |i| i + 1);
+
+        for item in &self.items[..end] {
+            match item {
+                TrayItem::Action {
+                    id,
+                    label,
+                    checked,
+                    enabled,
+                } => {
+                    let mut attrs = MenuItemAttributes::new(label)
+                        .with_id(*id)
+                        .with_enabled(*enabled);
+                    if let Some(checked) = checked {
+                        attrs = attrs.with_selected(*checked);
+                    }
+                    menu.add_item(attrs);
+                }
+                TrayItem::Label(label) => {
+                    menu.add_item(MenuItemAttributes::new(label).with_enabled(false));
+                }
+                TrayItem::Submenu { label, menu: sub } => {
+                    menu.add_submenu(label, true, sub.to_context_menu());
+                }
+                TrayItem::Separator => {
+                    menu.add_native_item(MenuItem::Separator);
+                }
+                TrayItem::Quit => {
+                    menu.add_native_item(MenuItem::Quit);
+                }
+            }
+        }
+
+        menu
+    }
+}
+
+/// Items contributed by the plugins of a device, grouped by where they go in its submenu.
+#[derive(Debug, Default)]
+pub struct DeviceMenu {
+    /// Information shown under the connection status, like the battery level.
+    pub status: TrayMenu,
+    /// Things to do with the device.
+    pub actions: TrayMenu,
+    /// Plugin settings, collected in a "Settings" submenu.
+    pub settings: TrayMenu,
+    /// Cellular network of the device, shown next to the connection status.
+    pub network: Option<String>,
+    /// Battery charge of the device in percent, shown on the tray icon.
+    pub battery: Option<u8>,
+    /// How the charge changed recently, shown in the tooltip.
+    pub battery_trend: Option<String>,
+}
+
+impl DeviceMenu {
+    /// Lay out the submenu of a connected device.
+    pub fn into_submenu(
+        self,
+        remote_ip: IpAddr,
+        disconnect_id: MenuId,
+        forget_id: MenuId,
+    ) -> TrayMenu {
+        let mut menu = TrayMenu::new();
+
+        match &self.network {
+            Some(network) => menu.add_label(format!("Connected ({}) - {}", remote_ip, network)),
+            None => menu.add_label(format!("Connected ({})", remote_ip)),
+        }
+        menu.extend(self.status);
+        menu.add_separator();
+        menu.extend(self.actions);
+        menu.add_separator();
+        menu.add_submenu("Settings", self.settings);
+        menu.add_separator();
+        menu.add_action(disconnect_id, "Disconnect");
+        menu.add_action(forget_id, "Forget");
+
+        menu
+    }
+
+    /// The line about the device in the tooltip, if there is anything to say.
+    pub fn tooltip_line(&self, name: &str) -> Option<String> {
+        let battery = self.battery?;
+        Some(match &self.battery_trend {
+            Some(trend) => format!("{}: {}%, {}", name, battery, trend),
+            None => format!("{}: {}%", name, battery),
+        })
+    }
+}
+
+/// The tray tooltip, with a line for each device. Lines that do not fit are left out.
+pub fn tooltip(lines: impl IntoIterator<Item = String>) -> String {
+    let mut tooltip = "KDE Connect".to_string();
+    let mut len = tooltip.encode_utf16().count();
+    for line in lines {
+        let line_len = line.encode_utf16().count() + 1;
+        if len + line_len > MAX_TOOLTIP_LEN {
+            break;
+        }
+        tooltip.push('\n');
+        tooltip.push_str(&line);
+        len += line_len;
+    }
+    tooltip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip() -> IpAddr {
+        "192.168.1.2".parse().unwrap()
+    }
+
+    #[test]
+    fn separators_are_not_doubled() {
+        let mut menu = TrayMenu::new();
+        menu.add_separator();
+        menu.add_label("a");
+        menu.add_separator();
+        menu.add_separator();
+        menu.add_label("b");
+
+        assert_eq!(
+            menu.items(),
+            &[
+                TrayItem::Label("a".into()),
+                TrayItem::Separator,
+                TrayItem::Label("b".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_submenus_are_skipped() {
+        let mut menu = TrayMenu::new();
+        menu.add_submenu("Empty", TrayMenu::new());
+
+        assert!(menu.is_empty());
+    }
+
+    #[test]
+    fn device_submenu_layout() {
+        let ping = MenuId::new("dev:ping");
+        let mute = MenuId::new("dev:mute");
+        let disconnect = MenuId::new("dev:disconnect");
+        let forget = MenuId::new("dev:forget");
+
+        let mut device = DeviceMenu {
+            network: Some("LTE 3/4".into()),
+            ..Default::default()
+        };
+        device.status.add_label("Battery: 50%");
+        device.actions.add_action(ping, "Ping");
+        device.settings.add_toggle(mute, "Mute", true);
+
+        let menu = device.into_submenu(ip(), disconnect, forget);
+
+        let mut settings = TrayMenu::new();
+        settings.add_toggle(mute, "Mute", true);
+        assert_eq!(
+            menu.items(),
+            &[
+                TrayItem::Label("Connected (192.168.1.2) - LTE 3/4".into()),
+                TrayItem::Label("Battery: 50%".into()),
+                TrayItem::Separator,
+                TrayItem::Action {
+                    id: ping,
+                    label: "Ping".into(),
+                    checked: None,
+                    enabled: true,
+                },
+                TrayItem::Separator,
+                TrayItem::Submenu {
+                    label: "Settings".into(),
+                    menu: settings,
+                },
+                TrayItem::Separator,
+                TrayItem::Action {
+                    id: disconnect,
+                    label: "Disconnect".into(),
+                    checked: None,
+                    enabled: true,
+                },
+                TrayItem::Action {
+                    id: forget,
+                    label: "Forget".into(),
+                    checked: None,
+                    enabled: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tooltip_lines_fit() {
+        let device = DeviceMenu {
+            battery: Some(54),
+            battery_trend: Some("dropped 12% in the last hour".into()),
+            ..Default::default()
+        };
+        let line = device.tooltip_line("Phone").unwrap();
+        assert_eq!(line, "Phone: 54%, dropped 12% in the last hour");
+        assert_eq!(DeviceMenu::default().tooltip_line("Phone"), None);
+
+        assert_eq!(
+            tooltip([line.clone(), "Tablet: 80%".into()]),
+            "KDE Connect\nPhone: 54%, dropped 12% in the last hour\nTablet: 80%"
+        );
+        let long = tooltip(vec![line; 5]);
+        assert_eq!(long.lines().count(), 3);
+        assert!(long.encode_utf16().count() <= MAX_TOOLTIP_LEN);
+    }
+
+    #[test]
+    fn device_submenu_without_plugin_items() {
+        let disconnect = MenuId::new("dev:disconnect");
+        let forget = MenuId::new("dev:forget");
+        let menu = DeviceMenu::default().into_submenu(ip(), disconnect, forget);
+
+        assert_eq!(
+            menu.items(),
+            &[
+                TrayItem::Label("Connected (192.168.1.2)".into()),
+                TrayItem::Separator,
+                TrayItem::Action {
+                    id: disconnect,
+                    label: "Disconnect".into(),
+                    checked: None,
+                    enabled: true,
+                },
+                TrayItem::Action {
+                    id: forget,
+                    label: "Forget".into(),
+                    checked: None,
+                    enabled: true,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Theme of the taskbar, which the tray icon follows to stay visible.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use windows::{
+    core::{HSTRING, PCWSTR},
+    Win32::System::Registry::{
+        RegCloseKey, RegGetValueW, RegNotifyChangeKeyValue, RegOpenKeyExW, HKEY, HKEY_CURRENT_USER,
+        KEY_NOTIFY, KEY_QUERY_VALUE, REG_NOTIFY_CHANGE_LAST_SET, RRF_RT_REG_DWORD,
+    },
+};
+
+const PERSONALIZE_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Themes\Personalize";
+const LIGHT_THEME_VALUE: &str = "SystemUsesLightTheme";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskbarTheme {
+    /// Also the theme of versions of Windows without a light taskbar.
+    #[default]
+    Dark,
+    Light,
+}
+
+/// The personalization key, opened for reading and change notifications.
+struct PersonalizeKey(HKEY);
+
+impl PersonalizeKey {
+    fn open() -> Result<Self> {
+        let mut key = HKEY::default();
+        unsafe {
+            RegOpenKeyExW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(PERSONALIZE_KEY),
+                0,
+                KEY_QUERY_VALUE | KEY_NOTIFY,
+                &mut key,
+            )
+            .to_hresult()
+            .ok()?;
+        }
+        Ok(Self(key))
+    }
+
+    fn theme(&self) -> Result<TaskbarTheme> {
+        let mut value = 0u32;
+        let mut size = std::mem::size_of::<u32>() as u32;
+        unsafe {
+            RegGetValueW(
+                self.0,
+                PCWSTR::null(),
+                &HSTRING::from(LIGHT_THEME_VALUE),
+                RRF_RT_REG_DWORD,
+                None,
+                Some(&mut value as *mut u32 as _),
+                Some(&mut size),
+            )
+            .to_hresult()
+            .ok()?;
+        }
+        Ok(if value != 0 {
+            TaskbarTheme::Light
+        } else {
+            TaskbarTheme::Dark
+        })
+    }
+
+    /// Block until a value of the key changes.
+    fn wait_for_change(&self) -> Result<()> {
+        unsafe {
+            RegNotifyChangeKeyValue(self.0, false, REG_NOTIFY_CHANGE_LAST_SET, None, false)
+                .to_hresult()
+                .ok()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PersonalizeKey {
+    fn drop(&mut self) {
+        unsafe {
+            RegCloseKey(self.0);
+        }
+    }
+}
+
+/// Call `on_change` with the current theme, then again whenever it changes.
+///
+/// Changes are waited for on a dedicated thread, which runs until the process exits.
+pub fn watch(on_change: impl Fn(TaskbarTheme) + Send + 'static) -> Result<()> {
+    let key = PersonalizeKey::open()?;
+    let mut theme = key.theme().unwrap_or_default();
+    on_change(theme);
+
+    std::thread::Builder::new()
+        .name("taskbar-theme".into())
+        .spawn(move || loop {
+            if let Err(e) = key.wait_for_change() {
+                log::warn!("Stopped following the taskbar theme: {:?}", e);
+                return;
+            }
+            // Other values of the key change too, e.g. the accent color.
+            let new_theme = key.theme().unwrap_or_default();
+            if new_theme != theme {
+                log::info!("Taskbar theme changed to {:?}", new_theme);
+                theme = new_theme;
+                on_change(theme);
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! Capping the bandwidth of payload transfers, see [`BandwidthSettings`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{config::BandwidthSettings, context::AppContextRef, transfer::Direction};
+
+/// How often a [`Throttle`] looks at the settings again, so that changes apply to the transfers
+/// in progress.
+const SETTINGS_REFRESH: Duration = Duration::from_secs(1);
+
+/// A device id, or `None` for the global caps.
+type BucketKey = (Option<String>, Direction);
+
+lazy_static::lazy_static! {
+    /// Buckets shared by the transfers they cap.
+    static ref BUCKETS: Mutex<HashMap<BucketKey, Arc<TokenBucket>>> = Default::default();
+}
+
+/// Lets bytes through at a given rate on average, with bursts of up to a second worth.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes that may go through without waiting, negative when others are already waiting.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self {
+            tokens: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Take `n` bytes from the bucket, returning how long to wait before sending them at `rate`
+    /// bytes per second.
+    fn reserve(&self, n: usize, rate: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let mut tokens = self.tokens.lock().unwrap();
+        let (available, last) = *tokens;
+
+        let refill = now.saturating_duration_since(last).as_secs_f64() * rate;
+        let available = (available + refill).min(rate) - n as f64;
+        *tokens = (available, now.max(last));
+
+        if available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-available / rate)
+        }
+    }
+
+    /// Wait until `n` bytes may be sent at `rate` bytes per second, or not at all if `rate` is 0.
+    pub async fn consume(&self, n: usize, rate: u64) {
+        if rate == 0 {
+            return;
+        }
+        let wait = self.reserve(n, rate, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The cap on the transfers with a device in one direction, following changes to the settings.
+pub struct Throttle {
+    ctx: AppContextRef,
+    device_id: String,
+    direction: Direction,
+    /// When the settings were last read, the rate in bytes per second and its bucket.
+    current: Mutex<Option<(Instant, u64, Arc<TokenBucket>)>>,
+}
+
+impl Throttle {
+    pub fn new(ctx: &AppContextRef, device_id: &str, direction: Direction) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            device_id: device_id.to_string(),
+            direction,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Wait until `n` more bytes may be transferred.
+    pub async fn consume(&self, n: usize) {
+        let (rate, bucket) = self.current();
+        bucket.consume(n, rate).await;
+    }
+
+    fn current(&self) -> (u64, Arc<TokenBucket>) {
+        let mut current = self.current.lock().unwrap();
+        match &*current {
+            Some((read_at, rate, bucket)) if read_at.elapsed() < SETTINGS_REFRESH => {
+                return (*rate, bucket.clone());
+            }
+            _ => {}
+        }
+
+        let device = self.ctx.settings().device(&self.device_id).bandwidth;
+        let (settings, key) = match device {
+            Some(settings) => (settings, Some(self.device_id.clone())),
+            None => (self.ctx.settings().network().bandwidth, None),
+        };
+        let rate = max_rate(&settings, self.direction);
+        let bucket = BUCKETS
+            .lock()
+            .unwrap()
+            .entry((key, self.direction))
+            .or_default()
+            .clone();
+
+        *current = Some((Instant::now(), rate, bucket.clone()));
+        (rate, bucket)
+    }
+}
+
+/// In bytes per second, 0 if unlimited.
+fn max_rate(settings: &BandwidthSettings, direction: Direction) -> u64 {
+    let kib = match direction {
+        Direction::Incoming => settings.max_download,
+        Direction::Outgoing => settings.max_upload,
+    };
+    kib.saturating_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn empty_bucket(now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: Mutex::new((0.0, now)),
+        }
+    }
+
+    #[test]
+    fn bursts_are_limited_to_a_second_worth() {
+        let start = Instant::now();
+        let bucket = empty_bucket(start);
+
+        // Idle for a long time, which does not allow more than a second worth.
+        let later = start + 10_000 * MS;
+        assert_eq!(bucket.reserve(1000, 1000, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, 1000, later), 500 * MS);
+    }
+
+    #[test]
+    fn waiting_senders_queue_up() {
+        let start = Instant::now();
+        let bucket = empty_bucket(start);
+
+        assert_eq!(bucket.reserve(100, 1000, start), 100 * MS);
+        assert_eq!(bucket.reserve(100, 1000, start), 200 * MS);
+        // The first 100 ms refill what the first sender used.
+        assert_eq!(bucket.reserve(100, 1000, start + 100 * MS), 200 * MS);
+    }
+
+    #[test]
+    fn rates_are_in_kib() {
+        let settings = BandwidthSettings {
+            max_upload: 2,
+            max_download: 0,
+        };
+        assert_eq!(max_rate(&settings, Direction::Outgoing), 2048);
+        assert_eq!(max_rate(&settings, Direction::Incoming), 0);
+    }
+}
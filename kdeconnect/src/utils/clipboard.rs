@@ -1,15 +1,50 @@
 use std::collections::HashSet;
 
 use anyhow::Result;
-use clipboard_win::{formats, Clipboard, Getter, Setter};
+use clipboard_win::{formats, register_format, Clipboard, Getter, Setter};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+use crate::service::{self, ServiceMessage};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ClipboardContent {
     Text(String),
     Files(Vec<String>),
+    /// Marked by the source application (e.g. a password manager) as not to be shared.
+    Sensitive,
     Unsupported,
 }
 
+/// Whether the clipboard has one of the formats that password managers use to keep content
+/// out of clipboard monitors, history and cloud sync.
+///
+/// See <https://learn.microsoft.com/en-us/windows/win32/dataxchg/clipboard-formats#cloud-clipboard-and-clipboard-history-formats>.
+fn is_sensitive(formats: &HashSet<u32>) -> bool {
+    let format_id = |name| register_format(name).map_or(0, |f| f.get());
+
+    let exclude = format_id("ExcludeClipboardContentFromMonitorProcessing");
+    if exclude != 0 && formats.contains(&exclude) {
+        return true;
+    }
+
+    // These are a DWORD, only 0 excludes the content.
+    for name in ["CanIncludeInClipboardHistory", "CanUploadToCloudClipboard"] {
+        let format = format_id(name);
+        if format == 0 || !formats.contains(&format) {
+            continue;
+        }
+        let mut data = vec![];
+        if formats::RawData(format).read_clipboard(&mut data).is_ok()
+            && data.len() >= 4
+            && data[..4] == [0; 4]
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
 /// Attempt to open (and lock) the global clipboard with a 100ms attempt timeout.
 fn try_open_clipboard() -> Result<Clipboard> {
     let mut clipboard = None;
@@ -32,6 +67,10 @@ pub fn read() -> Result<ClipboardContent> {
 
     let formats = clipboard_win::EnumFormats::new().collect::<HashSet<_>>();
 
+    if is_sensitive(&formats) {
+        return Ok(ClipboardContent::Sensitive);
+    }
+
     if formats.contains(&formats::CF_UNICODETEXT) {
         let mut text = String::new();
         formats::Unicode.read_clipboard(&mut text)?;
@@ -47,6 +86,29 @@ pub fn read() -> Result<ClipboardContent> {
     Ok(ClipboardContent::Unsupported)
 }
 
+/// Read the clipboard, through the UI helper when running as a service.
+pub async fn read_async() -> Result<ClipboardContent> {
+    match service::link::get() {
+        Some(link) => link
+            .request(|id| ServiceMessage::ReadClipboard { id })
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("UI helper returned no clipboard content")),
+        None => tokio::task::spawn_blocking(read).await?,
+    }
+}
+
+/// Put text on the clipboard, through the UI helper when running as a service.
+pub async fn write_text_async(text: String) -> Result<()> {
+    match service::link::get() {
+        Some(link) => {
+            link.request(|id| ServiceMessage::WriteClipboard { id, text })
+                .await?;
+            Ok(())
+        }
+        None => tokio::task::spawn_blocking(move || write(ClipboardContent::Text(text))).await?,
+    }
+}
+
 pub fn write(content: ClipboardContent) -> Result<()> {
     let _clip = try_open_clipboard()?;
 
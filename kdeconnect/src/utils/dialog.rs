@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::sync::oneshot;
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{ERROR_CANCELLED, HWND},
+        System::Com::{
+            CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_INPROC_SERVER,
+            COINIT_APARTMENTTHREADED, COINIT_DISABLE_OLE1DDE,
+        },
+        UI::Shell::{
+            FileOpenDialog, IFileOpenDialog, IShellItem, SHCreateItemFromParsingName,
+            FOS_FORCEFILESYSTEM, FOS_PICKFOLDERS, SIGDN_FILESYSPATH,
+        },
+    },
+};
+
+/// Let the user pick a folder, starting in `initial` if it exists.
+///
+/// Returns `None` if the dialog was cancelled.
+pub async fn pick_folder(title: &str, initial: Option<&Path>) -> Result<Option<PathBuf>> {
+    let title = title.to_string();
+    let initial = initial.map(Path::to_path_buf);
+    let (tx, rx) = oneshot::channel();
+
+    // The dialog is modal and needs a single-threaded apartment.
+    std::thread::spawn(move || {
+        let res = unsafe {
+            match CoInitializeEx(None, COINIT_APARTMENTTHREADED | COINIT_DISABLE_OLE1DDE) {
+                Ok(_) => {
+                    let res = show_folder_dialog(&title, initial.as_deref());
+                    CoUninitialize();
+                    res
+                }
+                Err(e) => Err(e.into()),
+            }
+        };
+        let _ = tx.send(res);
+    });
+
+    rx.await?
+}
+
+unsafe fn show_folder_dialog(title: &str, initial: Option<&Path>) -> Result<Option<PathBuf>> {
+    let dialog: IFileOpenDialog = CoCreateInstance(&FileOpenDialog, None, CLSCTX_INPROC_SERVER)?;
+    dialog.SetOptions(dialog.GetOptions()? | FOS_PICKFOLDERS | FOS_FORCEFILESYSTEM)?;
+    dialog.SetTitle(&HSTRING::from(title))?;
+
+    if let Some(initial) = initial.filter(|p| p.exists()) {
+        let item: IShellItem =
+            SHCreateItemFromParsingName(&HSTRING::from(initial.as_os_str()), None)?;
+        dialog.SetFolder(&item)?;
+    }
+
+    if let Err(e) = dialog.Show(HWND::default()) {
+        if e.code() == ERROR_CANCELLED.to_hresult() {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+
+    let name = dialog.GetResult()?.GetDisplayName(SIGDN_FILESYSPATH)?;
+    let path = name.to_string();
+    CoTaskMemFree(Some(name.0 as *const _));
+
+    Ok(Some(PathBuf::from(path?)))
+}
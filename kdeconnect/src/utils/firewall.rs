@@ -0,0 +1,248 @@
+//! Checking whether Windows Firewall keeps devices from connecting to us, and adding the rules
+//! that let them.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use windows::{
+    core::{Interface, HSTRING},
+    Win32::{
+        Foundation::{CloseHandle, ERROR_CANCELLED, WAIT_OBJECT_0},
+        NetworkManagement::WindowsFirewall::*,
+        System::{
+            Com::{
+                CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER,
+                COINIT_MULTITHREADED, VARIANT,
+            },
+            Ole::{IEnumVARIANT, VariantClear},
+            Threading::{GetExitCodeProcess, WaitForSingleObject},
+        },
+        UI::{
+            Shell::{
+                ShellExecuteExW, SEE_MASK_NOASYNC, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW,
+            },
+            WindowsAndMessaging::SW_HIDE,
+        },
+    },
+};
+use winrt_toast::{Action, Toast};
+
+use crate::{config::NetworkSettings, context::AppContextRef};
+
+use super::toast::{ToastCallbacks, TOASTS};
+
+const RULE_TCP: &str = "KDE Connect (TCP)";
+const RULE_UDP: &str = "KDE Connect (UDP)";
+
+/// `INFINITE`, for waiting on netsh.
+const WAIT_FOREVER: u32 = u32::MAX;
+
+const ACTION_ALLOW: &str = "allow";
+const ACTION_IGNORE: &str = "ignore";
+
+/// Whether incoming connections to `exe` are blocked in one of the current firewall profiles:
+/// the firewall is on, blocks by default, and no enabled rule allows the executable.
+pub fn is_blocked(exe: &Path) -> Result<bool> {
+    unsafe {
+        CoInitializeEx(None, COINIT_MULTITHREADED)?;
+        let res = check_rules(&exe.to_string_lossy());
+        CoUninitialize();
+        res
+    }
+}
+
+unsafe fn check_rules(exe: &str) -> Result<bool> {
+    let policy: INetFwPolicy2 = CoCreateInstance(&NetFwPolicy2, None, CLSCTX_INPROC_SERVER)?;
+    let profiles = policy.CurrentProfileTypes()?;
+
+    let mut blocking = false;
+    for profile in [
+        NET_FW_PROFILE2_DOMAIN,
+        NET_FW_PROFILE2_PRIVATE,
+        NET_FW_PROFILE2_PUBLIC,
+    ] {
+        if profiles & profile.0 != 0
+            && policy.get_FirewallEnabled(profile)? != 0
+            && policy.get_DefaultInboundAction(profile)? == NET_FW_ACTION_BLOCK
+        {
+            blocking = true;
+        }
+    }
+    if !blocking {
+        return Ok(false);
+    }
+
+    let rules: IEnumVARIANT = policy.Rules()?._NewEnum()?.cast()?;
+    let mut allowed = false;
+    loop {
+        let mut item = [VARIANT::default()];
+        let mut fetched = 0;
+        rules.Next(&mut item, &mut fetched).ok()?;
+        if fetched == 0 {
+            break;
+        }
+        let rule = item[0]
+            .Anonymous
+            .Anonymous
+            .Anonymous
+            .pdispVal
+            .as_ref()
+            .and_then(|dispatch| dispatch.cast::<INetFwRule>().ok());
+        VariantClear(&mut item[0])?;
+
+        let rule = match rule {
+            Some(rule) => rule,
+            None => continue,
+        };
+        if rule.Direction()? != NET_FW_RULE_DIR_IN
+            || rule.Enabled()? == 0
+            || rule.Profiles()? & profiles == 0
+            || !rule
+                .ApplicationName()?
+                .to_string()
+                .eq_ignore_ascii_case(exe)
+        {
+            continue;
+        }
+        // Block rules win over allow rules, e.g. when access was denied in the prompt of
+        // Windows.
+        match rule.Action()? {
+            NET_FW_ACTION_BLOCK => return Ok(true),
+            NET_FW_ACTION_ALLOW => allowed = true,
+            _ => {}
+        }
+    }
+
+    Ok(!allowed)
+}
+
+/// Add inbound rules allowing `exe` to receive discovery packets and connections on the
+/// configured ports, replacing those added before. Asks the user for administrator rights.
+pub fn allow(exe: &Path, network: &NetworkSettings) -> Result<()> {
+    let program = exe.display();
+    let parameters = format!(
+        "/c netsh advfirewall firewall delete rule name=\"{tcp}\" \
+         & netsh advfirewall firewall delete rule name=\"{udp}\" \
+         & netsh advfirewall firewall add rule name=\"{tcp}\" dir=in action=allow \
+         program=\"{program}\" protocol=TCP localport={},{} enable=yes \
+         && netsh advfirewall firewall add rule name=\"{udp}\" dir=in action=allow \
+         program=\"{program}\" protocol=UDP localport={} enable=yes",
+        network.discovery_ports,
+        network.transfer_ports,
+        crate::config::DISCOVERY_UDP_PORT,
+        tcp = RULE_TCP,
+        udp = RULE_UDP,
+        program = program,
+    );
+
+    let verb = HSTRING::from("runas");
+    let file = HSTRING::from("cmd.exe");
+    let parameters = HSTRING::from(parameters);
+    let mut info = SHELLEXECUTEINFOW {
+        cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
+        fMask: SEE_MASK_NOCLOSEPROCESS | SEE_MASK_NOASYNC,
+        lpVerb: (&verb).into(),
+        lpFile: (&file).into(),
+        lpParameters: (&parameters).into(),
+        nShow: SW_HIDE.0 as i32,
+        ..Default::default()
+    };
+
+    unsafe {
+        if !ShellExecuteExW(&mut info).as_bool() {
+            let e = windows::core::Error::from_win32();
+            if e.code() == ERROR_CANCELLED.to_hresult() {
+                bail!("Administrator rights were not granted");
+            }
+            return Err(e.into());
+        }
+
+        let mut exit_code = 0;
+        let res = if WaitForSingleObject(info.hProcess, WAIT_FOREVER) == WAIT_OBJECT_0
+            && GetExitCodeProcess(info.hProcess, &mut exit_code).as_bool()
+        {
+            Ok(exit_code)
+        } else {
+            Err(windows::core::Error::from_win32())
+        };
+        CloseHandle(info.hProcess);
+
+        match res? {
+            0 => Ok(()),
+            code => bail!("netsh failed with exit code {}", code),
+        }
+    }
+}
+
+/// Offer to add the firewall rules if they seem to be needed, until the user has answered once.
+pub async fn check_on_startup(ctx: AppContextRef) {
+    if !ctx.settings().network().firewall_check {
+        return;
+    }
+
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            log::warn!("Failed to get executable path: {:?}", e);
+            return;
+        }
+    };
+
+    let check_exe = exe.clone();
+    match tokio::task::spawn_blocking(move || is_blocked(&check_exe)).await {
+        Ok(Ok(false)) => return,
+        Ok(Ok(true)) => {}
+        res => {
+            log::warn!("Failed to check firewall rules: {:?}", res);
+            return;
+        }
+    }
+
+    log::info!("The firewall may block incoming connections");
+    show_offer(ctx, exe).await;
+}
+
+async fn show_offer(ctx: AppContextRef, exe: PathBuf) {
+    let mut toast = Toast::new();
+    toast
+        .text1("Windows Firewall may block your devices")
+        .text2("Allow KDE Connect to receive connections from devices on your network?")
+        .action(Action::new("Allow", ACTION_ALLOW, ""))
+        .action(Action::new("Don't ask again", ACTION_IGNORE, ""));
+
+    let rt_handle = tokio::runtime::Handle::current();
+    let on_activated = move |arg: winrt_toast::Result<String>| {
+        let ctx = ctx.clone();
+        let exe = exe.clone();
+        match arg.as_deref() {
+            Ok(ACTION_ALLOW) => {
+                rt_handle.spawn(async move {
+                    let network = ctx.settings().network();
+                    let res = tokio::task::spawn_blocking(move || allow(&exe, &network)).await;
+                    match res {
+                        Ok(Ok(())) => {
+                            log::info!("Firewall rules added");
+                            stop_asking(&ctx);
+                        }
+                        res => log::error!("Failed to add firewall rules: {:?}", res),
+                    }
+                });
+            }
+            Ok(ACTION_IGNORE) => stop_asking(&ctx),
+            _ => {}
+        }
+    };
+
+    let callbacks = ToastCallbacks::new().on_activated(on_activated);
+    if let Err(e) = TOASTS.show_with_callbacks(toast, callbacks).await {
+        log::error!("Failed to show toast: {:?}", e);
+    }
+}
+
+fn stop_asking(ctx: &AppContextRef) {
+    super::log_if_error(
+        "Failed to save settings",
+        ctx.settings()
+            .update_network(|network| network.firewall_check = false),
+    );
+}
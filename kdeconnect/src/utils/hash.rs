@@ -0,0 +1,115 @@
+//! Hashes of content, e.g. to check payloads or to name cached files.
+
+use std::fmt::Display;
+
+use sha2::{Digest, Sha256};
+
+/// A hash of some content, displayed as lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentHash {
+    /// Used by the protocol, e.g. for "payloadHash", and where short names are needed.
+    Md5([u8; 16]),
+    /// Used for our own identifiers.
+    Sha256([u8; 32]),
+}
+
+impl ContentHash {
+    pub fn md5(data: impl AsRef<[u8]>) -> Self {
+        Self::Md5(md5::compute(data).0)
+    }
+
+    pub fn sha256(data: impl AsRef<[u8]>) -> Self {
+        Self::Sha256(Sha256::digest(data).into())
+    }
+
+    /// Parse a hash in hex, telling MD5 and SHA-256 apart by their length.
+    pub fn parse(hex: &str) -> Option<Self> {
+        match hex.len() {
+            32 => decode_hex(hex).map(Self::Md5),
+            64 => decode_hex(hex).map(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Parse an MD5 hash in hex, as sent by devices in "payloadHash".
+    pub fn parse_md5(hex: &str) -> Option<Self> {
+        decode_hex(hex).map(Self::Md5)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Md5(b) => b,
+            Self::Sha256(b) => b,
+        }
+    }
+
+    /// Hash `data` with the same algorithm as this hash.
+    pub fn recompute(&self, data: impl AsRef<[u8]>) -> Self {
+        match self {
+            Self::Md5(_) => Self::md5(data),
+            Self::Sha256(_) => Self::sha256(data),
+        }
+    }
+}
+
+impl From<md5::Digest> for ContentHash {
+    fn from(digest: md5::Digest) -> Self {
+        Self::Md5(digest.0)
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    // `from_str_radix` alone would accept a sign.
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut out = [0; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_of_both_lengths() {
+        let md5 = ContentHash::md5(b"data");
+        let sha256 = ContentHash::sha256(b"data");
+        assert_eq!(ContentHash::parse(&md5.to_string()), Some(md5));
+        assert_eq!(ContentHash::parse(&sha256.to_string()), Some(sha256));
+        assert_eq!(ContentHash::parse_md5(&md5.to_string()), Some(md5));
+        assert_eq!(ContentHash::parse_md5(&sha256.to_string()), None);
+    }
+
+    #[test]
+    fn rejects_anything_but_hex() {
+        let hex = ContentHash::md5(b"data").to_string();
+        for bad in [
+            String::new(),
+            hex[..30].to_string(),
+            format!("{}00", hex),
+            format!("+f{}", &hex[2..]),
+            format!("-0{}", &hex[2..]),
+            format!("..{}", &hex[2..]),
+            format!("{}/a", &hex[2..]),
+            format!("{}\\a", &hex[2..]),
+            format!("é{}", &hex[2..]),
+            "g".repeat(32),
+        ] {
+            assert_eq!(ContentHash::parse(&bad), None, "{:?}", bad);
+        }
+    }
+}
@@ -1,20 +1,37 @@
-use std::{iter::once, os::windows::prelude::*};
+use std::{iter::once, os::windows::prelude::*, time::Duration};
 
 use windows::Win32::{
     Foundation::{HWND, LPARAM, LRESULT, WPARAM},
-    UI::WindowsAndMessaging::DefWindowProcW,
+    UI::{
+        Shell::{
+            SHQueryUserNotificationState, QUNS_APP, QUNS_BUSY, QUNS_PRESENTATION_MODE,
+            QUNS_QUIET_TIME, QUNS_RUNNING_D3D_FULL_SCREEN,
+        },
+        WindowsAndMessaging::DefWindowProcW,
+    },
 };
-use winrt_toast::{Text, Toast, ToastManager};
+use winrt_toast::{Text, Toast, ToastTag, WinToastError};
 
+pub mod bandwidth;
 pub mod clipboard;
-pub mod open;
 pub mod debounce;
+pub mod dialog;
+pub mod firewall;
+pub mod hash;
+pub mod monitor;
+pub mod network;
+pub mod open;
+pub mod rate_limit;
+pub mod retry;
+pub mod seen;
+pub mod tao_serde;
+pub mod toast;
 
-lazy_static::lazy_static! {
-    pub static ref TOAST_MANAGER: ToastManager = {
-        ToastManager::new(crate::AUM_ID)
-    };
-}
+/// Showing a toast fails now and then, e.g. while the notification platform is starting up.
+const TOAST_BACKOFF: retry::Backoff = retry::Backoff::new(Duration::from_millis(250), 3);
+
+/// Tells the groups of devices apart from any other toast group.
+const DEVICE_TOAST_GROUP_PREFIX: &str = "device-";
 
 pub fn unix_ts_ms() -> u64 {
     std::time::SystemTime::now()
@@ -23,6 +40,22 @@ pub fn unix_ts_ms() -> u64 {
         .as_millis() as u64
 }
 
+/// Format a number of bytes for display, e.g. "1.5 MB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 pub fn log_if_error<R, E: std::fmt::Debug>(text: &str, res: Result<R, E>) {
     if let Err(e) = res {
         log::error!("{}: {:?}", text, e);
@@ -41,14 +74,89 @@ pub async fn simple_toast(title: &str, content: Option<&str>, attribution: Optio
         toast.text3(Text::new(attr).as_attribution());
     }
 
-    let res = tokio::task::spawn_blocking(move || TOAST_MANAGER.show(&toast)).await;
-    match res {
-        Ok(Ok(_)) => {}
-        Ok(Err(e)) => {
-            log::error!("Failed to show toast: {:?}", e);
-        }
+    show_toast(toast).await;
+}
+
+/// Show a toast, trying again a few times if Windows fails to show it.
+pub async fn show_toast(toast: Toast) {
+    let res = retry::retry_if(
+        &TOAST_BACKOFF,
+        |_| toast::TOASTS.show(toast.clone()),
+        |e: &anyhow::Error| e.is::<WinToastError>(),
+    )
+    .await;
+    log_if_error("Failed to show toast", res);
+}
+
+/// Group of all toasts about a device, so that they can be removed when it is forgotten.
+pub fn device_toast_group(device_id: &str) -> ToastTag {
+    // The hash keeps it in the limit whatever the id is.
+    let group = format!(
+        "{}{}",
+        DEVICE_TOAST_GROUP_PREFIX,
+        ToastTag::hashed(device_id)
+    );
+    ToastTag::new(group).expect("Device toast group is too long")
+}
+
+/// Whether `group` is the toast group of a device that is not in `known` anymore, or of an
+/// older version, which grouped the toasts of a device under a bare MD5 hash.
+fn is_stale_toast_group(group: &str, known: &[ToastTag]) -> bool {
+    let is_legacy = group.len() == 32
+        && group
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    (group.starts_with(DEVICE_TOAST_GROUP_PREFIX) || is_legacy)
+        && !known.iter().any(|g| g.as_str() == group)
+}
+
+/// Remove all toasts about a device from the Action Center.
+pub async fn remove_device_toasts(device_id: &str) {
+    let group = device_toast_group(device_id);
+    if let Err(e) = toast::TOASTS.remove_group(group.as_str()).await {
+        log::error!("Failed to remove toasts of {}: {:?}", device_id, e);
+    }
+}
+
+/// Remove toasts left in the Action Center by devices that are not known anymore, and by
+/// older versions that grouped them differently. Groups of anything else are kept.
+pub async fn remove_stale_toasts(known_devices: Vec<String>) {
+    let res = toast::TOASTS
+        .run(move |manager| {
+            let known: Vec<_> = known_devices
+                .iter()
+                .map(|id| device_toast_group(id))
+                .collect();
+
+            for group in manager.groups()? {
+                if is_stale_toast_group(&group, &known) {
+                    log::info!("Removing stale toast group {}", group);
+                    manager.remove_group(&group)?;
+                }
+            }
+            Ok(())
+        })
+        .await;
+    if let Err(e) = res {
+        log::error!("Failed to remove stale toasts: {:?}", e);
+    }
+}
+
+/// Whether the user does not want to be disturbed right now, i.e. Focus Assist is on,
+/// or a full screen application or presentation is running.
+pub fn is_do_not_disturb() -> bool {
+    match unsafe { SHQueryUserNotificationState() } {
+        Ok(state) => matches!(
+            state,
+            QUNS_BUSY
+                | QUNS_RUNNING_D3D_FULL_SCREEN
+                | QUNS_PRESENTATION_MODE
+                | QUNS_QUIET_TIME
+                | QUNS_APP
+        ),
         Err(e) => {
-            log::error!("Failed to show toast: {:?}", e);
+            log::warn!("Failed to query user notification state: {:?}", e);
+            false
         }
     }
 }
@@ -65,3 +173,33 @@ pub unsafe extern "system" fn call_default_window_proc(
 ) -> LRESULT {
     DefWindowProcW(hwnd, msg, wparam, lparam)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_toast_groups_fit() {
+        let group = device_toast_group(&"x".repeat(1000));
+        assert!(group.as_str().starts_with(DEVICE_TOAST_GROUP_PREFIX));
+        assert_eq!(device_toast_group(&"x".repeat(1000)), group);
+    }
+
+    #[test]
+    fn only_device_groups_are_stale() {
+        let known = [device_toast_group("known")];
+
+        assert!(!is_stale_toast_group(known[0].as_str(), &known));
+        assert!(is_stale_toast_group(
+            device_toast_group("forgotten").as_str(),
+            &known
+        ));
+        // Grouped by an older version.
+        let legacy = format!("{:x}", md5::compute("receive_notifications:known"));
+        assert!(is_stale_toast_group(&legacy, &known));
+
+        assert!(!is_stale_toast_group("transfers", &known));
+        assert!(!is_stale_toast_group(&"A".repeat(32), &known));
+        assert!(!is_stale_toast_group(&"a".repeat(31), &known));
+    }
+}
@@ -0,0 +1,265 @@
+//! Monitor brightness over DDC/CI, display power, and the layout of the displays.
+//!
+//! DDC/CI calls take tens of milliseconds per monitor, so these functions should be called with
+//! `spawn_blocking`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Result;
+use windows::Win32::{
+    Devices::Display::{
+        DestroyPhysicalMonitors, GetNumberOfPhysicalMonitorsFromHMONITOR,
+        GetPhysicalMonitorsFromHMONITOR, GetVCPFeatureAndVCPFeatureReply, SetVCPFeature,
+        PHYSICAL_MONITOR,
+    },
+    Foundation::{BOOL, HWND, LPARAM, RECT, WPARAM},
+    Graphics::Gdi::{
+        EnumDisplayMonitors, GetMonitorInfoW, HDC, HMONITOR, MONITORINFO, MONITORINFOEXW,
+    },
+    UI::WindowsAndMessaging::{
+        GetSystemMetrics, PostMessageW, SC_MONITORPOWER, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN,
+        SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN, WM_SYSCOMMAND,
+    },
+};
+
+/// VCP code of the luminance control.
+const VCP_BRIGHTNESS: u8 = 0x10;
+
+const HWND_BROADCAST: HWND = HWND(0xffff);
+/// `lParam` of `SC_MONITORPOWER` to turn the displays off.
+const MONITOR_OFF: isize = 2;
+
+/// Incremented by [`displays_changed`].
+static DISPLAY_LAYOUT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    /// Rectangles found by [`display_rect`], and the layout they were found in.
+    static ref DISPLAY_RECTS: Mutex<(u64, HashMap<u32, Option<RECT>>)> = Default::default();
+}
+
+/// Physical monitors of all displays, released when dropped.
+struct PhysicalMonitors(Vec<PHYSICAL_MONITOR>);
+
+impl Drop for PhysicalMonitors {
+    fn drop(&mut self) {
+        unsafe {
+            DestroyPhysicalMonitors(&self.0);
+        }
+    }
+}
+
+fn display_monitors() -> Result<Vec<HMONITOR>> {
+    unsafe extern "system" fn collect(
+        monitor: HMONITOR,
+        _hdc: HDC,
+        _rect: *mut RECT,
+        data: LPARAM,
+    ) -> BOOL {
+        (*(data.0 as *mut Vec<HMONITOR>)).push(monitor);
+        true.into()
+    }
+
+    let mut handles: Vec<HMONITOR> = vec![];
+    unsafe {
+        EnumDisplayMonitors(
+            HDC::default(),
+            None,
+            Some(collect),
+            LPARAM(&mut handles as *mut _ as isize),
+        )
+        .ok()?;
+    }
+
+    Ok(handles)
+}
+
+fn physical_monitors() -> Result<PhysicalMonitors> {
+    let mut monitors = vec![];
+
+    unsafe {
+        for handle in display_monitors()? {
+            let mut count = 0;
+            if GetNumberOfPhysicalMonitorsFromHMONITOR(handle, &mut count) == 0 {
+                continue;
+            }
+            let mut physical = vec![PHYSICAL_MONITOR::default(); count as usize];
+            if GetPhysicalMonitorsFromHMONITOR(handle, &mut physical) == 0 {
+                continue;
+            }
+            monitors.extend(physical);
+        }
+    }
+
+    Ok(PhysicalMonitors(monitors))
+}
+
+/// Set the brightness of every monitor supporting DDC/CI, computed from its current and
+/// maximum value.
+fn update_brightness(f: impl Fn(u32, u32) -> u32) -> Result<()> {
+    let monitors = physical_monitors()?;
+    let mut updated = 0;
+
+    for monitor in &monitors.0 {
+        let handle = monitor.hPhysicalMonitor;
+        let (mut current, mut max) = (0, 0);
+
+        unsafe {
+            // Fails for monitors without DDC/CI, e.g. most laptop panels.
+            if GetVCPFeatureAndVCPFeatureReply(
+                handle,
+                VCP_BRIGHTNESS,
+                None,
+                &mut current,
+                Some(&mut max),
+            ) == 0
+                || max == 0
+            {
+                continue;
+            }
+            if SetVCPFeature(handle, VCP_BRIGHTNESS, f(current, max)) != 0 {
+                updated += 1;
+            }
+        }
+    }
+
+    if updated == 0 {
+        anyhow::bail!("No monitor supports brightness control over DDC/CI");
+    }
+    Ok(())
+}
+
+/// Set the brightness of all monitors, in percent.
+pub fn set_brightness(percent: u8) -> Result<()> {
+    let percent = percent.min(100) as u32;
+    update_brightness(|_, max| percent * max / 100)
+}
+
+/// Change the brightness of all monitors by `delta` percent.
+pub fn adjust_brightness(delta: i32) -> Result<()> {
+    update_brightness(|current, max| adjusted_brightness(current, max, delta))
+}
+
+/// `current` changed by `delta` percent of `max`, within `0..=max`.
+fn adjusted_brightness(current: u32, max: u32, delta: i32) -> u32 {
+    let delta = i64::from(delta.clamp(-100, 100));
+    let max = i64::from(max);
+    (i64::from(current) + delta * max / 100).clamp(0, max) as u32
+}
+
+/// Put all displays into power saving mode. They turn on again on input.
+pub fn power_off() -> Result<()> {
+    unsafe {
+        PostMessageW(
+            HWND_BROADCAST,
+            WM_SYSCOMMAND,
+            WPARAM(SC_MONITORPOWER as usize),
+            LPARAM(MONITOR_OFF),
+        )
+        .ok()?;
+    }
+    Ok(())
+}
+
+/// The bounding rectangle of all displays, in pixels.
+pub fn virtual_desktop() -> RECT {
+    unsafe {
+        let left = GetSystemMetrics(SM_XVIRTUALSCREEN);
+        let top = GetSystemMetrics(SM_YVIRTUALSCREEN);
+        RECT {
+            left,
+            top,
+            right: left + GetSystemMetrics(SM_CXVIRTUALSCREEN),
+            bottom: top + GetSystemMetrics(SM_CYVIRTUALSCREEN),
+        }
+    }
+}
+
+/// Forget the rectangles of the displays, after a display was added, removed, moved or had its
+/// resolution changed.
+pub fn displays_changed() {
+    DISPLAY_LAYOUT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The rectangle of the display with the device name `\\.\DISPLAY<number>`, in virtual desktop
+/// pixels.
+///
+/// This number is assigned by Windows when a display is first connected. It often matches the
+/// number shown in the display settings, but not always, e.g. after displays were replaced.
+///
+/// Displays are only enumerated again after [`displays_changed`].
+pub fn display_rect(number: u32) -> Result<Option<RECT>> {
+    let layout = DISPLAY_LAYOUT.load(Ordering::Relaxed);
+    {
+        let mut cache = DISPLAY_RECTS.lock().unwrap();
+        if cache.0 != layout {
+            *cache = (layout, HashMap::new());
+        }
+        if let Some(rect) = cache.1.get(&number) {
+            return Ok(*rect);
+        }
+    }
+
+    let rect = find_display_rect(number)?;
+    let mut cache = DISPLAY_RECTS.lock().unwrap();
+    if cache.0 == layout {
+        cache.1.insert(number, rect);
+    }
+    Ok(rect)
+}
+
+fn find_display_rect(number: u32) -> Result<Option<RECT>> {
+    let name = format!(r"\\.\DISPLAY{}", number);
+    let mut found = vec![];
+
+    for handle in display_monitors()? {
+        let mut info = MONITORINFOEXW::default();
+        info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+        let ok = unsafe {
+            GetMonitorInfoW(handle, &mut info as *mut MONITORINFOEXW as *mut MONITORINFO)
+        };
+        if !ok.as_bool() {
+            continue;
+        }
+
+        let len = info
+            .szDevice
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(info.szDevice.len());
+        let device = String::from_utf16_lossy(&info.szDevice[..len]);
+        if device == name {
+            return Ok(Some(info.monitorInfo.rcMonitor));
+        }
+        found.push(device);
+    }
+
+    log::warn!("{} not found, the displays are {:?}", name, found);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brightness_changes_by_percent_of_max() {
+        assert_eq!(adjusted_brightness(50, 100, 10), 60);
+        assert_eq!(adjusted_brightness(100, 200, -25), 50);
+        assert_eq!(adjusted_brightness(90, 100, 20), 100);
+        assert_eq!(adjusted_brightness(10, 100, -20), 0);
+    }
+
+    #[test]
+    fn extreme_deltas_do_not_overflow() {
+        assert_eq!(adjusted_brightness(50, 100, i32::MAX), 100);
+        assert_eq!(adjusted_brightness(50, 100, i32::MIN), 0);
+        assert_eq!(adjusted_brightness(u32::MAX, u32::MAX, -100), 0);
+        assert_eq!(adjusted_brightness(0, u32::MAX, 100), u32::MAX);
+    }
+}
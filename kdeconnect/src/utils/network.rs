@@ -0,0 +1,75 @@
+//! Network interfaces, for discovery.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use anyhow::Result;
+use windows::Win32::{
+    Foundation::NO_ERROR,
+    NetworkManagement::IpHelper::{
+        GetIpAddrTable, MIB_IPADDRROW_XP, MIB_IPADDRTABLE, MIB_IPADDR_DELETED,
+        MIB_IPADDR_DISCONNECTED,
+    },
+};
+
+/// Addresses and subnet masks of all connected IPv4 interfaces.
+pub fn interface_addresses() -> Result<Vec<(Ipv4Addr, Ipv4Addr)>> {
+    let mut size = 0;
+    unsafe {
+        // Fails with the required size.
+        GetIpAddrTable(None, &mut size, false);
+    }
+
+    // Backed by u32s, for the alignment of the table.
+    let mut buf = vec![0u32; (size as usize).div_ceil(4)];
+    let table = buf.as_mut_ptr() as *mut MIB_IPADDRTABLE;
+    let result = unsafe { GetIpAddrTable(Some(table), &mut size, false) };
+    if result != NO_ERROR.0 {
+        anyhow::bail!("GetIpAddrTable failed with error {}", result);
+    }
+
+    let rows: &[MIB_IPADDRROW_XP] = unsafe {
+        std::slice::from_raw_parts((*table).table.as_ptr(), (*table).dwNumEntries as usize)
+    };
+
+    Ok(rows
+        .iter()
+        .filter(|row| row.wType as u32 & (MIB_IPADDR_DISCONNECTED | MIB_IPADDR_DELETED) == 0)
+        // Addresses are in network byte order.
+        .map(|row| {
+            (
+                Ipv4Addr::from(row.dwAddr.to_ne_bytes()),
+                Ipv4Addr::from(row.dwMask.to_ne_bytes()),
+            )
+        })
+        .collect())
+}
+
+/// Broadcast addresses of the subnets of all connected IPv4 interfaces, except loopback.
+pub fn broadcast_addresses() -> Result<Vec<Ipv4Addr>> {
+    let mut addresses = vec![];
+    for (ip, mask) in interface_addresses()? {
+        if ip.is_loopback() || ip.is_unspecified() {
+            continue;
+        }
+
+        let broadcast = Ipv4Addr::from(u32::from(ip) | !u32::from(mask));
+        if !addresses.contains(&broadcast) {
+            addresses.push(broadcast);
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Whether `ip` belongs to this computer.
+pub fn is_local_address(ip: IpAddr) -> Result<bool> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return Ok(ip.is_loopback()),
+        },
+    };
+
+    Ok(ip.is_loopback() || interface_addresses()?.iter().any(|(a, _)| *a == ip))
+}
@@ -1,9 +1,13 @@
-use anyhow::Result;
+use std::path::Path;
+
+use anyhow::{Context, Result};
 use tokio::sync::{mpsc, oneshot};
 use windows::Win32::System::Com::COINIT_MULTITHREADED;
 
 enum RequestType {
     OpenItem(String),
+    /// Open Explorer with the item selected
+    ShowInFolder(String),
 }
 
 struct WindowsApiRequest {
@@ -39,31 +43,41 @@ fn create_windows_api_thread() -> mpsc::Sender<WindowsApiRequest> {
         }
 
         let hs_open = HSTRING::from("open");
+        let hs_explorer = HSTRING::from("explorer.exe");
 
         while let Some(req) = receiver.blocking_recv() {
             use RequestType::*;
 
-            let res = match req.ty {
-                OpenItem(item) => {
-                    let ret = unsafe {
-                        ShellExecuteW(
-                            HWND::default(),
-                            &hs_open,
-                            &HSTRING::from(item),
-                            PCWSTR::null(),
-                            PCWSTR::null(),
-                            SW_SHOWNORMAL,
-                        )
-                    };
-                    // If the function succeeds, it returns a value greater than 32.
-                    // If the function fails, it returns an error value that indicates the cause of the failure.
-                    // The return value is cast as an HINSTANCE for backward compatibility with 16-bit Windows applications.
-                    if ret.0 > 32 {
-                        Ok(())
-                    } else {
-                        Err(windows::core::Error::from_win32().into())
-                    }
-                }
+            let ret = match req.ty {
+                OpenItem(item) => unsafe {
+                    ShellExecuteW(
+                        HWND::default(),
+                        &hs_open,
+                        &HSTRING::from(item),
+                        PCWSTR::null(),
+                        PCWSTR::null(),
+                        SW_SHOWNORMAL,
+                    )
+                },
+                ShowInFolder(item) => unsafe {
+                    ShellExecuteW(
+                        HWND::default(),
+                        &hs_open,
+                        &hs_explorer,
+                        &HSTRING::from(format!("/select,\"{}\"", item)),
+                        PCWSTR::null(),
+                        SW_SHOWNORMAL,
+                    )
+                },
+            };
+
+            // If the function succeeds, it returns a value greater than 32.
+            // If the function fails, it returns an error value that indicates the cause of the failure.
+            // The return value is cast as an HINSTANCE for backward compatibility with 16-bit Windows applications.
+            let res = if ret.0 > 32 {
+                Ok(())
+            } else {
+                Err(windows::core::Error::from_win32().into())
             };
 
             let _ = req.response.send(res);
@@ -79,8 +93,8 @@ lazy_static::lazy_static! {
     };
 }
 
-pub async fn open_url(url: impl Into<String>) -> Result<()> {
-    let (req, rx) = WindowsApiRequest::new(RequestType::OpenItem(url.into()));
+async fn request(ty: RequestType) -> Result<()> {
+    let (req, rx) = WindowsApiRequest::new(ty);
     match WINDOWS_API_SENDER.send(req).await {
         Ok(_) => rx.await?,
         Err(_) => Err(anyhow::anyhow!(
@@ -88,3 +102,82 @@ pub async fn open_url(url: impl Into<String>) -> Result<()> {
         )),
     }
 }
+
+/// Check that `url` is valid and that its scheme is one of `allowed_schemes`.
+pub fn check_url(url: &str, allowed_schemes: &[String]) -> Result<()> {
+    let parsed = url::Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    // The scheme is always lowercase after parsing.
+    if !allowed_schemes
+        .iter()
+        .any(|s| s.eq_ignore_ascii_case(parsed.scheme()))
+    {
+        anyhow::bail!("URL scheme {:?} is not allowed", parsed.scheme());
+    }
+    Ok(())
+}
+
+/// Open a URL with the default application of its scheme, if the scheme is allowed.
+pub async fn open_url(url: impl Into<String>, allowed_schemes: &[String]) -> Result<()> {
+    let url = url.into();
+    check_url(&url, allowed_schemes)?;
+    request(RequestType::OpenItem(url)).await
+}
+
+/// Open a file with its default application.
+pub async fn open_path(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref().to_string_lossy().into_owned();
+    request(RequestType::OpenItem(path)).await
+}
+
+/// Open the folder containing `path` in Explorer, with the item selected.
+pub async fn show_in_folder(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref().to_string_lossy().into_owned();
+    request(RequestType::ShowInFolder(path)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schemes(schemes: &[&str]) -> Vec<String> {
+        schemes.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_listed_schemes() {
+        let allowed = schemes(&["http", "https", "mailto"]);
+        assert!(check_url("https://kde.org/", &allowed).is_ok());
+        assert!(check_url("http://192.168.1.2:8080/path?q=1", &allowed).is_ok());
+        assert!(check_url("mailto:someone@example.com", &allowed).is_ok());
+    }
+
+    #[test]
+    fn schemes_are_case_insensitive() {
+        assert!(check_url("HTTPS://kde.org/", &schemes(&["https"])).is_ok());
+        assert!(check_url("https://kde.org/", &schemes(&["HTTPS"])).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        let allowed = schemes(&["http", "https"]);
+        for url in [
+            "file:///C:/Windows/System32/calc.exe",
+            "javascript:alert(1)",
+            "ms-settings:privacy",
+            "search-ms:query=secret",
+            // A drive letter parses as a scheme.
+            r"C:\Windows\System32\calc.exe",
+        ] {
+            assert!(check_url(url, &allowed).is_err(), "{} was allowed", url);
+        }
+        assert!(check_url("https://kde.org/", &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        let allowed = schemes(&["http", "https"]);
+        assert!(check_url("", &allowed).is_err());
+        assert!(check_url("kde.org", &allowed).is_err());
+        assert!(check_url("/relative/path", &allowed).is_err());
+    }
+}
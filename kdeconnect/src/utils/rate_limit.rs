@@ -0,0 +1,151 @@
+//! Rate limiting by key, e.g. the source address of packets.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// Keys tracked at most, so that packets with spoofed sources cannot use up memory.
+const MAX_KEYS: usize = 1024;
+
+/// Outcome of [`RateLimiter::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    Allowed,
+    /// The first event over the limit in the current window.
+    Exceeded,
+    /// Over the limit, which has already been reported.
+    Limited,
+}
+
+/// Allows `limit` events per key in fixed windows of `window`.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    limit: u32,
+    window: Duration,
+    /// Start of the current window of each key, and the events counted in it.
+    windows: HashMap<K, (Instant, u32)>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Count an event for `key`.
+    pub fn check(&mut self, key: K) -> Rate {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: K, now: Instant) -> Rate {
+        if self.windows.len() >= MAX_KEYS && !self.windows.contains_key(&key) {
+            let window = self.window;
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < window);
+            if self.windows.len() >= MAX_KEYS {
+                self.windows.clear();
+            }
+        }
+
+        let (start, count) = self.windows.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+
+        *count = count.saturating_add(1);
+        match (*count).cmp(&(self.limit + 1)) {
+            std::cmp::Ordering::Less => Rate::Allowed,
+            std::cmp::Ordering::Equal => Rate::Exceeded,
+            std::cmp::Ordering::Greater => Rate::Limited,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn reports_the_first_event_over_the_limit() {
+        let mut limiter = RateLimiter::new(3, WINDOW);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("a", now), Rate::Allowed);
+        }
+        assert_eq!(limiter.check_at("a", now), Rate::Exceeded);
+        assert_eq!(limiter.check_at("a", now), Rate::Limited);
+        assert_eq!(limiter.check_at("a", now + WINDOW / 2), Rate::Limited);
+    }
+
+    #[test]
+    fn limits_each_key_on_its_own() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("a", now), Rate::Allowed);
+        assert_eq!(limiter.check_at("a", now), Rate::Exceeded);
+        assert_eq!(limiter.check_at("b", now), Rate::Allowed);
+    }
+
+    #[test]
+    fn windows_start_over() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("a", now), Rate::Allowed);
+        assert_eq!(limiter.check_at("a", now), Rate::Exceeded);
+        assert_eq!(limiter.check_at("a", now + WINDOW), Rate::Allowed);
+        assert_eq!(limiter.check_at("a", now + WINDOW), Rate::Exceeded);
+        // The window starts at the first event in it, not at a multiple of its length.
+        assert_eq!(limiter.check_at("a", now + WINDOW * 2), Rate::Allowed);
+    }
+
+    #[test]
+    fn zero_limit_allows_nothing() {
+        let mut limiter = RateLimiter::new(0, WINDOW);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at((), now), Rate::Exceeded);
+        assert_eq!(limiter.check_at((), now), Rate::Limited);
+    }
+
+    #[test]
+    fn expired_keys_are_dropped_when_full() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        for key in 0..MAX_KEYS - 1 {
+            limiter.check_at(key, now);
+        }
+        limiter.check_at(MAX_KEYS, now + WINDOW / 2);
+        assert_eq!(limiter.windows.len(), MAX_KEYS);
+
+        // Only the key of the later window is still live.
+        assert_eq!(limiter.check_at(MAX_KEYS + 1, now + WINDOW), Rate::Allowed);
+        assert_eq!(limiter.windows.len(), 2);
+        assert_eq!(limiter.check_at(MAX_KEYS, now + WINDOW), Rate::Exceeded);
+    }
+
+    #[test]
+    fn live_keys_are_forgotten_when_full() {
+        let mut limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        for key in 0..MAX_KEYS {
+            limiter.check_at(key, now);
+        }
+        assert_eq!(limiter.check_at(MAX_KEYS, now), Rate::Allowed);
+        assert_eq!(limiter.windows.len(), 1);
+        // The state of the other keys is lost, which lets them through again.
+        assert_eq!(limiter.check_at(0, now), Rate::Allowed);
+    }
+}
@@ -0,0 +1,214 @@
+//! Retrying operations that may fail for a moment, with a bounded, jittered exponential backoff.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// How often and how long to wait before trying again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first retry, doubled for each following one.
+    initial: Duration,
+    max_delay: Duration,
+    /// Attempts in total, including the first one.
+    max_attempts: u32,
+    /// Fraction of each delay that is randomly taken off, so that clients failing together do
+    /// not retry together.
+    jitter: f64,
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max_attempts: u32) -> Self {
+        Self {
+            initial,
+            max_delay: Duration::from_secs(30),
+            max_attempts,
+            jitter: 0.5,
+        }
+    }
+
+    pub const fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Clamped to `0.0..=1.0`.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The delay before retry number `retry`, counted from 0, without jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry);
+        self.initial
+            .checked_mul(factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_delay)
+    }
+
+    /// `delay` shortened by up to `jitter` of it, `sample` being in `0.0..1.0`.
+    fn jittered(&self, retry: u32, sample: f64) -> Duration {
+        self.delay(retry).mul_f64(1.0 - self.jitter * sample)
+    }
+
+    /// The delays to wait between attempts, one less than the attempts.
+    pub fn delays(&self) -> Delays {
+        Delays {
+            backoff: *self,
+            retry: 0,
+        }
+    }
+}
+
+/// See [`Backoff::delays`].
+#[derive(Debug, Clone)]
+pub struct Delays {
+    backoff: Backoff,
+    retry: u32,
+}
+
+impl Iterator for Delays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.retry + 1 >= self.backoff.max_attempts {
+            return None;
+        }
+        let delay = self.backoff.jittered(self.retry, random_sample());
+        self.retry += 1;
+        Some(delay)
+    }
+}
+
+/// A random number in `0.0..1.0`, good enough for jitter.
+fn random_sample() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Run `f` until it succeeds or `backoff` runs out of attempts, returning the last error.
+/// `f` is given the number of the attempt, counted from 0.
+pub async fn retry<T, E, F, Fut>(backoff: &Backoff, f: F) -> Result<T, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Debug,
+{
+    retry_if(backoff, f, |_| true).await
+}
+
+/// Like [`retry`], but stops at the first error for which `should_retry` returns `false`.
+pub async fn retry_if<T, E, F, Fut>(
+    backoff: &Backoff,
+    mut f: F,
+    mut should_retry: impl FnMut(&E) -> bool,
+) -> Result<T, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Debug,
+{
+    let mut delays = backoff.delays();
+    let mut attempt = 0;
+    loop {
+        let e = match f(attempt).await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        match delays.next() {
+            Some(delay) if should_retry(&e) => {
+                log::debug!(
+                    "Attempt {} failed, retrying in {:?}: {:?}",
+                    attempt,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), 10).max_delay(Duration::from_secs(1));
+
+        let delays = (0..6).map(|r| backoff.delay(r)).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_only_shortens_delays() {
+        let backoff = Backoff::new(Duration::from_millis(100), 10).jitter(0.25);
+
+        assert_eq!(backoff.jittered(1, 0.0), Duration::from_millis(200));
+        assert_eq!(backoff.jittered(1, 0.5), Duration::from_millis(175));
+        assert!(backoff.jittered(1, 0.999) > Duration::from_millis(150));
+
+        for (retry, delay) in backoff.delays().enumerate() {
+            let base = backoff.delay(retry as u32);
+            assert!(delay <= base && delay >= base.mul_f64(0.75), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn delays_are_bounded_by_attempts() {
+        assert_eq!(Backoff::new(Duration::ZERO, 4).delays().count(), 3);
+        assert_eq!(Backoff::new(Duration::ZERO, 1).delays().count(), 0);
+        assert_eq!(Backoff::new(Duration::ZERO, 0).delays().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_stops_on_success_or_after_max_attempts() {
+        let backoff = Backoff::new(Duration::from_millis(1), 3);
+
+        let calls = AtomicU32::new(0);
+        let res = retry(&backoff, |attempt| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match attempt {
+                    0 => Err("first"),
+                    n => Ok(n),
+                }
+            }
+        })
+        .await;
+        assert_eq!(res, Ok(1));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = retry(&backoff, |attempt| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { Err(attempt) }
+        })
+        .await;
+        assert_eq!(res, Err(2));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_if_stops_on_permanent_errors() {
+        let backoff = Backoff::new(Duration::from_millis(1), 5);
+
+        let res: Result<(), _> =
+            retry_if(&backoff, |attempt| async move { Err(attempt) }, |&e| e < 1).await;
+        assert_eq!(res, Err(1));
+    }
+}
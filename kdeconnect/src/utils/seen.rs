@@ -0,0 +1,175 @@
+//! Keys seen recently, kept on disk so that they are still known after a restart, e.g. the
+//! notifications that have already been shown.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use super::unix_ts_ms;
+
+/// How long changes are collected before they are written, as keys often come in bursts.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct SeenCache {
+    path: PathBuf,
+    ttl: Duration,
+    max_entries: usize,
+    /// When each key was last seen, in Unix milliseconds.
+    entries: Mutex<HashMap<String, u64>>,
+    /// Whether a save is scheduled, which will also write the changes made until it starts.
+    save_pending: AtomicBool,
+    /// Held while writing, so that an older copy of the keys never replaces a newer one.
+    writing: tokio::sync::Mutex<()>,
+}
+
+impl SeenCache {
+    /// Load the keys saved at `path`. Keys are forgotten after `ttl`, or once there are more
+    /// than `max_entries`, oldest first.
+    pub fn load(path: impl AsRef<Path>, ttl: Duration, max_entries: usize) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let entries = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                log::warn!("Failed to read {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+
+        let cache = Self {
+            path,
+            ttl,
+            max_entries,
+            entries: Mutex::new(entries),
+            save_pending: AtomicBool::new(false),
+            writing: tokio::sync::Mutex::new(()),
+        };
+        cache.expire(unix_ts_ms());
+        cache
+    }
+
+    /// Record `key` as seen now. Returns `true` if it had not been seen within the TTL.
+    pub fn insert(&self, key: &str) -> bool {
+        self.insert_at(key, unix_ts_ms())
+    }
+
+    fn insert_at(&self, key: &str, now: u64) -> bool {
+        self.expire(now);
+        let previous = self.entries.lock().unwrap().insert(key.to_string(), now);
+        self.expire(now);
+        previous.is_none()
+    }
+
+    /// Forget expired keys, then the oldest ones over `max_entries`.
+    fn expire(&self, now: u64) {
+        let ttl = self.ttl.as_millis() as u64;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, seen| now.saturating_sub(*seen) < ttl);
+
+        if entries.len() > self.max_entries {
+            let mut times = entries.values().copied().collect::<Vec<_>>();
+            times.sort_unstable();
+            let cutoff = times[entries.len() - self.max_entries];
+            entries.retain(|_, seen| *seen >= cutoff);
+        }
+    }
+
+    /// Write the keys to disk after [`SAVE_DELAY`], together with any other changes made by
+    /// then. Changes made right before the app exits may be lost.
+    pub fn save_soon(&'static self) {
+        if self.save_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::spawn(async move {
+            tokio::time::sleep(SAVE_DELAY).await;
+            // Later changes schedule another save.
+            self.save_pending.store(false, Ordering::SeqCst);
+            if let Err(e) = self.save().await {
+                log::error!("Failed to save {}: {:?}", self.path.display(), e);
+            }
+        });
+    }
+
+    /// Write the keys to disk.
+    pub async fn save(&self) -> Result<()> {
+        let _writing = self.writing.lock().await;
+        let data = serde_json::to_vec(&*self.entries.lock().unwrap())?;
+        tokio::fs::write(&self.path, data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: Duration, max_entries: usize) -> SeenCache {
+        SeenCache {
+            path: PathBuf::new(),
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+            save_pending: AtomicBool::new(false),
+            writing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn keys_are_seen_until_they_expire() {
+        let cache = cache(Duration::from_secs(10), 100);
+
+        assert!(cache.insert_at("a", 1_000));
+        assert!(!cache.insert_at("a", 5_000));
+        // Seeing a key again extends its life.
+        assert!(!cache.insert_at("a", 14_000));
+        assert!(cache.insert_at("a", 24_000));
+        assert!(cache.insert_at("b", 24_000));
+    }
+
+    #[test]
+    fn oldest_keys_are_dropped_over_the_limit() {
+        let cache = cache(Duration::from_secs(3600), 2);
+
+        assert!(cache.insert_at("a", 1));
+        assert!(cache.insert_at("b", 2));
+        assert!(cache.insert_at("c", 3));
+        assert!(!cache.insert_at("c", 4));
+        assert!(!cache.insert_at("b", 5));
+        assert!(cache.insert_at("a", 6));
+    }
+
+    #[tokio::test]
+    async fn changes_are_saved_together() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("kdeconnect-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("seen.json");
+        let ttl = Duration::from_secs(3600);
+        let cache: &'static SeenCache = Box::leak(Box::new(SeenCache::load(&path, ttl, 100)));
+
+        for key in ["a", "b", "c"] {
+            cache.insert(key);
+            cache.save_soon();
+        }
+        assert!(!path.exists());
+
+        tokio::time::sleep(SAVE_DELAY + Duration::from_secs(1)).await;
+        let loaded = SeenCache::load(&path, ttl, 100);
+        assert!(!loaded.insert("a"));
+        assert!(!loaded.insert("c"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
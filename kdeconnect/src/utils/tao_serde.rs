@@ -0,0 +1,12 @@
+//! Serde support for tao identifiers, which are sent to the UI helper in service mode.
+
+use serde::{Deserialize, Serialize};
+use tao::{accelerator::AcceleratorId, menu::MenuId};
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "MenuId")]
+pub struct MenuIdDef(pub u16);
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "AcceleratorId")]
+pub struct AcceleratorIdDef(pub u16);
@@ -0,0 +1,331 @@
+//! Showing toasts from async code, see [`ToastService`].
+//!
+//! Calls to the toast manager block while Windows processes them, so they are all made on a
+//! dedicated thread instead of tying up the threads of the runtime.
+//!
+//! In service mode, toasts are shown by the UI helper instead, see [`crate::service`].
+
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, oneshot};
+use windows::Win32::Foundation::E_FAIL;
+use winrt_toast::{
+    DismissalReason, Progress, ProgressTemplate, ShownToast, Text, Toast, ToastManager,
+    WinToastError,
+};
+
+use crate::service::{self, ServiceMessage, ToastEvent};
+
+type OnActivated = Box<dyn FnMut(winrt_toast::Result<String>) + Send + 'static>;
+type OnDismissed = Box<dyn FnMut(winrt_toast::Result<DismissalReason>) + Send + 'static>;
+type OnFailed = Box<dyn FnMut(WinToastError) + Send + 'static>;
+type Job = Box<dyn FnOnce(&ToastManager) + Send + 'static>;
+
+lazy_static::lazy_static! {
+    pub static ref TOASTS: ToastService = ToastService::start();
+}
+
+/// The toast manager with the defaults of the app.
+///
+/// Only for code that cannot wait for [`TOASTS`], e.g. while crashing.
+pub fn new_manager() -> winrt_toast::Result<ToastManager> {
+    // The user interface is in English only, whatever the language of the system.
+    ToastManager::builder(crate::AUM_ID)
+        .attribution(Text::new("KDE Connect").with_language("en"))
+        .expires_in(Duration::from_secs(60 * 60 * 12))
+        .build()
+}
+
+/// Where the events of a toast go. They are called on threads of Windows.
+#[derive(Default)]
+pub struct ToastCallbacks {
+    on_activated: Option<OnActivated>,
+    on_dismissed: Option<OnDismissed>,
+    on_failed: Option<OnFailed>,
+}
+
+impl ToastCallbacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called with the arguments of the clicked action, or of the toast itself.
+    pub fn on_activated(
+        mut self,
+        f: impl FnMut(winrt_toast::Result<String>) + Send + 'static,
+    ) -> Self {
+        self.on_activated = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_dismissed(
+        mut self,
+        f: impl FnMut(winrt_toast::Result<DismissalReason>) + Send + 'static,
+    ) -> Self {
+        self.on_dismissed = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_failed(mut self, f: impl FnMut(WinToastError) + Send + 'static) -> Self {
+        self.on_failed = Some(Box::new(f));
+        self
+    }
+
+    /// Call the callback of an event sent by the UI helper. Returns whether the toast is gone,
+    /// so that no more events will come.
+    pub(crate) fn dispatch(&mut self, event: ToastEvent) -> bool {
+        match event {
+            ToastEvent::Activated { arguments, error } => {
+                if let Some(f) = &mut self.on_activated {
+                    f(arguments.ok_or_else(|| helper_error(error)));
+                }
+                true
+            }
+            ToastEvent::Dismissed { reason, error } => {
+                // Toasts that timed out are still in the Action Center, and can be clicked.
+                let gone = reason != Some(DismissalReason::TimedOut);
+                if let Some(f) = &mut self.on_dismissed {
+                    f(reason.ok_or_else(|| helper_error(error)));
+                }
+                gone
+            }
+            ToastEvent::Failed { error } => {
+                if let Some(f) = &mut self.on_failed {
+                    f(helper_error(Some(error)));
+                }
+                true
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ToastCallbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToastCallbacks")
+            .field("on_activated", &self.on_activated.is_some())
+            .field("on_dismissed", &self.on_dismissed.is_some())
+            .field("on_failed", &self.on_failed.is_some())
+            .finish()
+    }
+}
+
+/// An error that the UI helper got from Windows.
+fn helper_error(message: Option<String>) -> WinToastError {
+    let message = message.unwrap_or_else(|| "Unknown error in the UI helper".into());
+    WinToastError::Os(windows::core::Error::new(E_FAIL, message.into()))
+}
+
+enum Request {
+    Show {
+        toast: Box<Toast>,
+        callbacks: ToastCallbacks,
+        reply: oneshot::Sender<Result<ShownToast>>,
+    },
+    Run(Job),
+}
+
+/// Hands toasts to the thread owning the toast manager.
+///
+/// Requests never block, and are handled in order.
+#[derive(Debug, Clone)]
+pub struct ToastService {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl ToastService {
+    fn start() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        std::thread::Builder::new()
+            .name("toasts".into())
+            .spawn(move || run(receiver))
+            .expect("Failed to start toast thread");
+
+        Self { sender }
+    }
+
+    pub async fn show(&self, toast: Toast) -> Result<()> {
+        self.show_with_callbacks(toast, ToastCallbacks::new()).await
+    }
+
+    pub async fn show_with_callbacks(&self, toast: Toast, callbacks: ToastCallbacks) -> Result<()> {
+        if let Some(link) = service::link::get() {
+            return link.show_toast(&toast, callbacks).await;
+        }
+
+        let (reply, rx) = oneshot::channel();
+        self.send(Request::Show {
+            toast: Box::new(toast),
+            callbacks,
+            reply,
+        })?;
+        rx.await.map_err(|_| stopped())??;
+        Ok(())
+    }
+
+    /// Update the progress bar of a toast shown with a [`Progress`].
+    ///
+    /// Returns `false` if the toast could not be found, e.g. because the user dismissed it.
+    /// In service mode, the update is sent to the UI helper without waiting for it, and always
+    /// returns `true`.
+    pub async fn update_progress(
+        &self,
+        tag: &str,
+        group: Option<&str>,
+        progress: Progress,
+    ) -> Result<bool> {
+        if let Some(link) = service::link::get() {
+            link.send(ServiceMessage::UpdateProgress {
+                tag: tag.into(),
+                group: group.map(Into::into),
+                progress: ProgressTemplate::from(&progress),
+            });
+            return Ok(true);
+        }
+
+        let tag = tag.to_string();
+        let group = group.map(str::to_string);
+        self.run(move |manager| manager.update_progress(&tag, group.as_deref(), &progress))
+            .await
+    }
+
+    pub async fn remove_grouped_tag(&self, group: &str, tag: &str) -> Result<()> {
+        if let Some(link) = service::link::get() {
+            link.send(ServiceMessage::RemoveToasts {
+                group: group.into(),
+                tag: Some(tag.into()),
+            });
+            return Ok(());
+        }
+
+        let group = group.to_string();
+        let tag = tag.to_string();
+        self.run(move |manager| manager.remove_grouped_tag(&group, &tag))
+            .await
+    }
+
+    pub async fn remove_group(&self, group: &str) -> Result<()> {
+        if let Some(link) = service::link::get() {
+            link.send(ServiceMessage::RemoveToasts {
+                group: group.into(),
+                tag: None,
+            });
+            return Ok(());
+        }
+
+        let group = group.to_string();
+        self.run(move |manager| manager.remove_group(&group)).await
+    }
+
+    /// Call `f` with the toast manager on its thread.
+    ///
+    /// This is always the manager of this process, i.e. of session 0 in service mode.
+    pub async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&ToastManager) -> winrt_toast::Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Request::Run(Box::new(move |manager| {
+            let _ = reply.send(f(manager));
+        })))?;
+        Ok(rx.await.map_err(|_| stopped())??)
+    }
+
+    fn send(&self, request: Request) -> Result<()> {
+        self.sender.send(request).map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> anyhow::Error {
+    anyhow!("Toasts are unavailable")
+}
+
+fn run(mut receiver: mpsc::UnboundedReceiver<Request>) {
+    // Requests fail once the receiver is dropped.
+    let manager = match new_manager() {
+        Ok(manager) => manager,
+        Err(e) => {
+            log::error!("Failed to create toast manager: {:?}", e);
+            return;
+        }
+    };
+
+    while let Some(request) = receiver.blocking_recv() {
+        match request {
+            Request::Show {
+                toast,
+                callbacks,
+                reply,
+            } => {
+                let _ = reply.send(show(&manager, &toast, callbacks));
+            }
+            Request::Run(job) => job(&manager),
+        }
+    }
+}
+
+/// Every toast is shown, as some of them ask the user something. Floods of mirrored
+/// notifications are limited by their plugin.
+fn show(manager: &ToastManager, toast: &Toast, callbacks: ToastCallbacks) -> Result<ShownToast> {
+    let ToastCallbacks {
+        on_activated,
+        on_dismissed,
+        on_failed,
+    } = callbacks;
+    Ok(manager.show_with_callbacks(toast, on_activated, on_dismissed, on_failed)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn events_from_the_helper_reach_the_callbacks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut callbacks = ToastCallbacks::new()
+            .on_activated({
+                let events = events.clone();
+                move |r| events.lock().unwrap().push(format!("{:?}", r.ok()))
+            })
+            .on_dismissed({
+                let events = events.clone();
+                move |r| events.lock().unwrap().push(format!("{:?}", r.ok()))
+            });
+
+        let timed_out = ToastEvent::dismissed(Ok(DismissalReason::TimedOut));
+        assert!(!callbacks.dispatch(timed_out));
+        let failed = ToastEvent::Activated {
+            arguments: None,
+            error: Some("Failed".into()),
+        };
+        assert!(callbacks.dispatch(failed));
+        assert!(callbacks.dispatch(ToastEvent::activated(Ok("accept".into()))));
+        // Nothing to call.
+        assert!(callbacks.dispatch(ToastEvent::Failed {
+            error: "Failed".into()
+        }));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["Some(TimedOut)", "None", "Some(\"accept\")"]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "Shows a toast"]
+    async fn floods_of_toasts_are_all_shown() -> Result<()> {
+        // More than any device may mirror at once, prompts must still get through.
+        for i in 0..30 {
+            let mut toast = Toast::new();
+            toast
+                .tag(format!("flood-{}", i))
+                .text1(format!("Toast {}", i));
+            TOASTS.show(toast).await?;
+        }
+        TOASTS.run(|manager| manager.clear()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+//! Passing events between COM threads, the manager thread and subscribers without blocking.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::{mpsc, Notify};
+
+/// Volume changes reported by the endpoint callbacks. Only the last change of each device is
+/// kept until the manager takes them, so bursts of notifications (e.g. dragging a slider)
+/// neither block the COM threads nor pile up.
+#[derive(Debug, Default)]
+pub(crate) struct PendingVolumes {
+    volumes: Mutex<HashMap<Arc<String>, (u8, bool)>>,
+    notify: Notify,
+}
+
+impl PendingVolumes {
+    /// Record the new volume of a device. Called from COM threads, this never waits for the
+    /// manager.
+    pub fn push(&self, id: Arc<String>, volume: u8, muted: bool) {
+        self.volumes.lock().unwrap().insert(id, (volume, muted));
+        self.notify.notify_one();
+    }
+
+    /// Wait until a volume has been pushed since the last call.
+    pub async fn changed(&self) {
+        self.notify.notified().await;
+    }
+
+    /// The last volume and mute state of each device changed since the last call.
+    pub fn take(&self) -> Vec<(Arc<String>, u8, bool)> {
+        self.volumes
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(id, (volume, muted))| (id, volume, muted))
+            .collect()
+    }
+}
+
+/// A notification sent through [`Subscribers`].
+pub(crate) trait Notification: Clone + Send + 'static {
+    /// Whether a lagging subscriber may miss it, e.g. a volume change that the next one makes
+    /// out of date.
+    fn is_droppable(&self) -> bool;
+
+    /// Sent to a subscriber that missed a notification that is not droppable, once its queue
+    /// has room again. It must bring the subscriber up to date with everything it missed.
+    fn resync() -> Self;
+}
+
+#[derive(Debug)]
+struct Subscriber<T> {
+    tx: mpsc::Sender<T>,
+    /// Set while a resync waits for room in the queue. Notifications until then are covered
+    /// by it, so they are not queued.
+    resyncing: Arc<AtomicBool>,
+}
+
+impl<T: Notification> Subscriber<T> {
+    fn resync(&self) {
+        self.resyncing.store(true, Ordering::SeqCst);
+        let tx = self.tx.clone();
+        let resyncing = self.resyncing.clone();
+        tokio::spawn(async move {
+            tx.send(T::resync()).await.ok();
+            resyncing.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Receivers of notifications, which must not hold up the manager: a subscriber that waits for
+/// the manager while its queue is full would otherwise deadlock it.
+#[derive(Debug)]
+pub(crate) struct Subscribers<T> {
+    subscribers: Vec<Subscriber<T>>,
+}
+
+impl<T: Notification> Subscribers<T> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: vec![],
+        }
+    }
+
+    pub fn add(&mut self, sender: mpsc::Sender<T>) {
+        self.subscribers.push(Subscriber {
+            tx: sender,
+            resyncing: Arc::default(),
+        });
+    }
+
+    /// Queue `item` for every subscriber, and remove those who went away.
+    ///
+    /// If the queue of a subscriber is full, a droppable `item` is dropped. Otherwise the
+    /// subscriber gets a [`Notification::resync`] as soon as there is room, without waiting
+    /// for it here.
+    pub fn emit(&mut self, item: T) {
+        self.subscribers.retain(|sub| {
+            if sub.resyncing.load(Ordering::SeqCst) {
+                return !sub.tx.is_closed();
+            }
+            match sub.tx.try_send(item.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    if item.is_droppable() {
+                        log::warn!(
+                            "Audio notification subscriber is lagging, dropping notification"
+                        );
+                    } else {
+                        log::warn!("Audio notification subscriber is lagging, resyncing it");
+                        sub.resync();
+                    }
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Event {
+        Volume(u32),
+        List,
+        Resync,
+    }
+
+    impl Notification for Event {
+        fn is_droppable(&self) -> bool {
+            matches!(self, Event::Volume(_))
+        }
+
+        fn resync() -> Self {
+            Event::Resync
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_volumes_keep_last_change_per_device() {
+        const THREADS: usize = 8;
+        const CHANGES: usize = 10_000;
+
+        let pending = Arc::new(PendingVolumes::default());
+
+        // Simulate the COM threads calling the callbacks of several devices as fast as they can.
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let pending = pending.clone();
+                std::thread::spawn(move || {
+                    let id = Arc::new(format!("device-{}", t));
+                    for i in 0..CHANGES {
+                        pending.push(id.clone(), (i % 101) as u8, i >= CHANGES / 2);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), pending.changed())
+            .await
+            .expect("No wake-up after pushes");
+
+        let mut changes = pending.take();
+        changes.sort();
+        assert_eq!(changes.len(), THREADS);
+        for (t, (id, volume, muted)) in changes.into_iter().enumerate() {
+            assert_eq!(*id, format!("device-{}", t));
+            assert_eq!(volume, ((CHANGES - 1) % 101) as u8);
+            assert!(muted);
+        }
+
+        assert!(pending.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_volumes_wake_up_consumer() {
+        let pending = Arc::new(PendingVolumes::default());
+        let consumer = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                let mut last = None;
+                while last != Some(100) {
+                    pending.changed().await;
+                    if let Some((_, volume, _)) = pending.take().pop() {
+                        last = Some(volume);
+                    }
+                }
+            })
+        };
+
+        let producer = std::thread::spawn(move || {
+            let id = Arc::new("device".to_string());
+            for volume in 0..=100 {
+                pending.push(id.clone(), volume, false);
+                if volume % 10 == 0 {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), consumer)
+            .await
+            .expect("Consumer missed the last change")
+            .unwrap();
+        producer.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribers_do_not_block_when_full() {
+        let mut subscribers = Subscribers::new();
+        let (tx, mut rx) = mpsc::channel(4);
+        subscribers.add(tx);
+
+        for i in 0..1000 {
+            subscribers.emit(Event::Volume(i));
+        }
+
+        assert_eq!(subscribers.len(), 1);
+        for i in 0..4 {
+            assert_eq!(rx.try_recv().unwrap(), Event::Volume(i));
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_resynced() {
+        let mut subscribers = Subscribers::new();
+        let (tx, mut rx) = mpsc::channel(2);
+        subscribers.add(tx);
+
+        subscribers.emit(Event::Volume(0));
+        subscribers.emit(Event::Volume(1));
+        // Missed while the queue is full, which does not block.
+        subscribers.emit(Event::List);
+        subscribers.emit(Event::List);
+        subscribers.emit(Event::Volume(2));
+
+        assert_eq!(rx.recv().await, Some(Event::Volume(0)));
+        assert_eq!(rx.recv().await, Some(Event::Volume(1)));
+        let resync = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert_eq!(resync.unwrap(), Some(Event::Resync));
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+
+        subscribers.emit(Event::List);
+        assert_eq!(rx.try_recv().unwrap(), Event::List);
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_removed_when_closed() {
+        let mut subscribers = Subscribers::new();
+        let (open_tx, mut open_rx) = mpsc::channel(4);
+        let (closed_tx, closed_rx) = mpsc::channel(4);
+        subscribers.add(open_tx);
+        subscribers.add(closed_tx);
+        drop(closed_rx);
+
+        subscribers.emit(Event::Volume(1));
+
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(open_rx.try_recv().unwrap(), Event::Volume(1));
+    }
+}
@@ -0,0 +1,96 @@
+//! Metadata of audio endpoints: what kind of device they are and whether something is plugged in.
+
+use std::ffi::c_void;
+
+use windows::{
+    core::{Interface, Vtable},
+    Win32::{
+        Media::{Audio::*, KernelStreaming::IKsJackDescription},
+        System::Com::CLSCTX_ALL,
+        UI::Shell::PropertiesSystem::IPropertyStore,
+    },
+};
+
+/// The physical kind of an endpoint, from `PKEY_AudioEndpoint_FormFactor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormFactor {
+    Speakers,
+    Headphones,
+    Headset,
+    Handset,
+    LineLevel,
+    /// A monitor or TV connected with HDMI or DisplayPort.
+    Hdmi,
+    Spdif,
+    /// E.g. a remote desktop session.
+    Network,
+    Unknown,
+}
+
+impl FormFactor {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(value: EndpointFormFactor) -> Self {
+        match value {
+            Speakers => FormFactor::Speakers,
+            Headphones => FormFactor::Headphones,
+            Headset => FormFactor::Headset,
+            Handset => FormFactor::Handset,
+            LineLevel => FormFactor::LineLevel,
+            DigitalAudioDisplayDevice => FormFactor::Hdmi,
+            SPDIF => FormFactor::Spdif,
+            RemoteNetworkDevice => FormFactor::Network,
+            _ => FormFactor::Unknown,
+        }
+    }
+
+    /// A short name to show to users, `None` if unknown.
+    pub fn label(&self) -> Option<&'static str> {
+        match self {
+            FormFactor::Speakers => Some("Speakers"),
+            FormFactor::Headphones => Some("Headphones"),
+            FormFactor::Headset => Some("Headset"),
+            FormFactor::Handset => Some("Handset"),
+            FormFactor::LineLevel => Some("Line out"),
+            FormFactor::Hdmi => Some("HDMI"),
+            FormFactor::Spdif => Some("S/PDIF"),
+            FormFactor::Network => Some("Network"),
+            FormFactor::Unknown => None,
+        }
+    }
+}
+
+pub(crate) fn form_factor(store: &IPropertyStore) -> FormFactor {
+    match unsafe { store.GetValue(&PKEY_AudioEndpoint_FormFactor) } {
+        Ok(value) => {
+            let raw = unsafe { value.Anonymous.Anonymous.Anonymous.ulVal };
+            FormFactor::from_raw(EndpointFormFactor(raw as i32))
+        }
+        Err(_) => FormFactor::Unknown,
+    }
+}
+
+/// Whether a jack of the endpoint has something plugged in, `None` if the device has no jacks
+/// (e.g. USB or Bluetooth) or its driver does not report them.
+pub(crate) fn jack_connected(device: &IMMDevice) -> Option<bool> {
+    unsafe {
+        let topology = device.Activate::<IDeviceTopology>(CLSCTX_ALL, None).ok()?;
+        // The endpoint has a single connector, leading to the part of the adapter with the jacks.
+        let connector = topology.GetConnector(0).ok()?.GetConnectedTo().ok()?;
+        let part: IPart = connector.cast().ok()?;
+
+        let mut raw: *mut c_void = std::ptr::null_mut();
+        part.Activate(CLSCTX_ALL.0, &IKsJackDescription::IID, Some(&mut raw))
+            .ok()?;
+        let jacks = IKsJackDescription::from_raw(raw);
+
+        let count = jacks.GetJackCount().ok()?;
+        if count == 0 {
+            return None;
+        }
+        let mut connected = false;
+        for i in 0..count {
+            connected |= jacks.GetJackDescription(i).ok()?.IsConnected.as_bool();
+        }
+        Some(connected)
+    }
+}
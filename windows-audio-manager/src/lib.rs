@@ -2,11 +2,15 @@ use std::{
     collections::{HashMap, HashSet},
     ptr::null,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Result;
 
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use windows::{
     core::PCWSTR,
     Win32::{
@@ -23,33 +27,47 @@ use windows::{
     },
 };
 
+use channel::{Notification, PendingVolumes, Subscribers};
+
+pub use endpoint::FormFactor;
+
+mod channel;
+mod endpoint;
+mod session;
+
+/// Queued commands, beyond which callers wait.
+const COMMAND_CAPACITY: usize = 32;
+/// Queued notifications per subscriber, beyond which they are dropped.
+const NOTIFICATION_CAPACITY: usize = 64;
+/// Volume changes within this window after the first one are sent together, only the last one
+/// of each device.
+const VOLUME_COALESCE_WINDOW: Duration = Duration::from_millis(50);
+
+/// Changes of the devices, reported by COM threads.
 #[derive(Debug)]
 enum AudioEvent {
     SendSinkList,
-    ReleaseDevice {
-        id: String,
-    },
-    VolumeUpdated {
-        id: Arc<String>,
-        volume: u8,
-        muted: bool,
-    },
+    ReleaseDevice { id: String },
+    DefaultChanged { id: String },
 }
 
 #[windows::core::implement(IMMNotificationClient)]
 struct NotificationClient {
-    sender: mpsc::Sender<AudioEvent>,
+    /// Unbounded, so that COM threads never wait for the manager.
+    sender: mpsc::UnboundedSender<AudioEvent>,
 }
 
 impl NotificationClient {
     fn send_sink_list(&self) {
-        self.sender.blocking_send(AudioEvent::SendSinkList).ok();
+        self.sender.send(AudioEvent::SendSinkList).ok();
     }
 
     fn send_release_device(&self, id: String) {
-        self.sender
-            .blocking_send(AudioEvent::ReleaseDevice { id })
-            .ok();
+        self.sender.send(AudioEvent::ReleaseDevice { id }).ok();
+    }
+
+    fn send_default_changed(&self, id: String) {
+        self.sender.send(AudioEvent::DefaultChanged { id }).ok();
     }
 }
 
@@ -61,7 +79,11 @@ impl IMMNotificationClient_Impl for NotificationClient {
         dwnewstate: u32,
     ) -> windows::core::Result<()> {
         unsafe {
-            log::debug!("OnDeviceStateChanged: {} to {}", pwstrdeviceid.display(), dwnewstate);
+            log::debug!(
+                "OnDeviceStateChanged: {} to {}",
+                pwstrdeviceid.display(),
+                dwnewstate
+            );
         }
 
         if dwnewstate == DEVICE_STATE_UNPLUGGED {
@@ -100,13 +122,30 @@ impl IMMNotificationClient_Impl for NotificationClient {
     fn OnDefaultDeviceChanged(
         &self,
         flow: EDataFlow,
-        _role: ERole,
-        _pwstrdefaultdeviceid: &PCWSTR,
+        role: ERole,
+        pwstrdefaultdeviceid: &PCWSTR,
     ) -> windows::core::Result<()> {
-        log::debug!("Default device changed: {:?}", flow);
+        log::debug!("Default device changed: {:?} {:?}", flow, role);
+
+        // This is called once for every role, only the one used by `update_sink_list` matters.
+        if flow != eRender || role != eMultimedia {
+            return Ok(());
+        }
 
-        if flow == eRender {
+        if pwstrdefaultdeviceid.is_null() {
+            // No render device is left
             self.send_sink_list();
+            return Ok(());
+        }
+
+        match unsafe { pwstrdefaultdeviceid.to_string() } {
+            Ok(s) => {
+                self.send_default_changed(s);
+            }
+            Err(e) => {
+                log::warn!("Failed to decode device ID: {:?}", e);
+                self.send_sink_list();
+            }
         }
         Ok(())
     }
@@ -126,7 +165,7 @@ impl IMMNotificationClient_Impl for NotificationClient {
 #[windows::core::implement(IAudioEndpointVolumeCallback)]
 struct AudioEndpointVolumeCb {
     id: Arc<String>,
-    sender: mpsc::Sender<AudioEvent>,
+    volumes: Arc<PendingVolumes>,
 }
 
 #[allow(non_snake_case)]
@@ -135,13 +174,11 @@ impl IAudioEndpointVolumeCallback_Impl for AudioEndpointVolumeCb {
         log::debug!("AudioEndpointVolumeCb OnNotify: {}", self.id);
 
         if let Some(p) = unsafe { pnotify.as_ref() } {
-            self.sender
-                .blocking_send(AudioEvent::VolumeUpdated {
-                    id: Arc::clone(&self.id),
-                    volume: (p.fMasterVolume * 100.0) as u8,
-                    muted: p.bMuted.as_bool(),
-                })
-                .ok();
+            self.volumes.push(
+                Arc::clone(&self.id),
+                (p.fMasterVolume * 100.0) as u8,
+                p.bMuted.as_bool(),
+            );
         }
         Ok(())
     }
@@ -150,9 +187,11 @@ impl IAudioEndpointVolumeCallback_Impl for AudioEndpointVolumeCb {
 struct AudioSink {
     name: String,
     description: String,
+    device: IMMDevice,
     endpoint: IAudioEndpointVolume,
     callback: IAudioEndpointVolumeCallback,
     is_active: bool,
+    form_factor: FormFactor,
 }
 
 impl AudioSink {
@@ -186,13 +225,14 @@ pub struct AudioManager {
     enumerator: IMMDeviceEnumerator,
     sinks: HashMap<String, AudioSink>,
     command_rx: mpsc::Receiver<AudioCommand>,
-    subscribers: Vec<mpsc::Sender<AudioNotification>>,
+    subscribers: Subscribers<AudioNotification>,
+    volumes: Arc<PendingVolumes>,
 }
 
 impl AudioManager {
     #[allow(clippy::new_ret_no_self)]
     pub fn new() -> AudioManagerHandle {
-        let (command_tx, command_rx) = mpsc::channel(1);
+        let (command_tx, command_rx) = mpsc::channel(COMMAND_CAPACITY);
 
         std::thread::spawn(move || {
             let enumerator = unsafe {
@@ -204,7 +244,8 @@ impl AudioManager {
                 enumerator,
                 sinks: HashMap::new(),
                 command_rx,
-                subscribers: Vec::new(),
+                subscribers: Subscribers::new(),
+                volumes: Arc::new(PendingVolumes::default()),
             };
 
             if let Err(e) = this.manager_main() {
@@ -215,7 +256,7 @@ impl AudioManager {
         AudioManagerHandle { command_tx }
     }
 
-    fn update_sink_list(&mut self, event_tx: mpsc::Sender<AudioEvent>) -> Result<()> {
+    fn update_sink_list(&mut self) -> Result<()> {
         let mut found_devices = HashSet::new();
 
         unsafe {
@@ -253,6 +294,8 @@ impl AudioManager {
                     .display()
                     .to_string();
 
+                let form_factor = endpoint::form_factor(&property_store);
+
                 if let Some(sink) = self.sinks.get_mut(&id) {
                     sink.is_active = default_device_id == id;
                 } else {
@@ -266,7 +309,7 @@ impl AudioManager {
 
                     let callback = IAudioEndpointVolumeCallback::from(AudioEndpointVolumeCb {
                         id: Arc::new(id.clone()),
-                        sender: event_tx.clone(),
+                        volumes: self.volumes.clone(),
                     });
                     if let Err(e) = endpoint.RegisterControlChangeNotify(&callback) {
                         log::warn!("Failed to register volume callback: {:?}", e);
@@ -278,9 +321,11 @@ impl AudioManager {
                         AudioSink {
                             name,
                             description: desc,
+                            device,
                             endpoint,
                             callback,
                             is_active: default_device_id == id,
+                            form_factor,
                         },
                     );
                 }
@@ -301,6 +346,9 @@ impl AudioManager {
                 .as_bool();
             let volume =
                 unsafe { sink.endpoint.GetMasterVolumeLevelScalar() }.unwrap_or(0.0) * 100.0;
+            // Plugging something into a jack changes no property of the endpoint, so this is
+            // read every time.
+            let jack_connected = endpoint::jack_connected(&sink.device);
 
             ret.insert(
                 id.clone(),
@@ -310,6 +358,8 @@ impl AudioManager {
                     is_active: sink.is_active,
                     is_muted,
                     volume: volume as u8,
+                    form_factor: sink.form_factor,
+                    jack_connected,
                 },
             );
         }
@@ -317,31 +367,32 @@ impl AudioManager {
         ret
     }
 
-    fn update_sink_list_or_log(&mut self, notify_tx: mpsc::Sender<AudioEvent>) {
-        if let Err(e) = self.update_sink_list(notify_tx) {
+    fn update_sink_list_or_log(&mut self) {
+        if let Err(e) = self.update_sink_list() {
             log::warn!("Failed to update sink list: {:?}", e);
         }
     }
 
-    async fn emit_notification(&mut self, notify: AudioNotification) {
-        let mut failed = vec![];
-
-        for tx in self.subscribers.iter() {
-            if (tx.send(notify.clone()).await).is_err() {
-                failed.push(tx.clone());
+    /// Send the volume changes gathered since the last call.
+    fn flush_volumes(&mut self) {
+        for (id, volume, muted) in self.volumes.take() {
+            // The device may have been removed in the meantime.
+            if let Some(sink) = self.sinks.get(id.as_str()) {
+                let name = sink.name.clone();
+                self.subscribers.emit(AudioNotification::VolumeUpdated {
+                    id,
+                    name,
+                    volume,
+                    muted,
+                });
             }
         }
-
-        // Remove any failed subscribers
-        for tx in failed {
-            self.subscribers.retain(|x| !x.same_channel(&tx));
-        }
     }
 
-    async fn handle_command(&mut self, command: AudioCommand) {
+    fn handle_command(&mut self, command: AudioCommand) {
         match command {
             AudioCommand::SubscribeNotification { sender } => {
-                self.subscribers.push(sender);
+                self.subscribers.add(sender);
             }
             AudioCommand::RequestAudioSinkInfo { reply } => {
                 reply.send(self.gather_sink_info()).ok();
@@ -375,42 +426,77 @@ impl AudioManager {
                     }
                 }
             }
+            AudioCommand::GetAppVolume { app, reply } => {
+                let volume =
+                    session::app_sessions(&self.enumerator, &app).and_then(
+                        |sessions| match sessions.first() {
+                            Some(session) => Ok(Some(unsafe { session.GetMasterVolume() }?)),
+                            None => Ok(None),
+                        },
+                    );
+                reply
+                    .send(volume.map(|v| v.map(|v| (v * 100.0).round() as u8)))
+                    .ok();
+            }
+            AudioCommand::SetAppVolume { app, volume, reply } => {
+                let res = session::app_sessions(&self.enumerator, &app).and_then(|sessions| {
+                    let volume = volume as f32 / 100.0;
+                    for session in &sessions {
+                        unsafe { session.SetMasterVolume(volume, null()) }?;
+                    }
+                    Ok(!sessions.is_empty())
+                });
+                reply.send(res).ok();
+            }
         }
     }
 
-    async fn handle_event(&mut self, event: AudioEvent, event_tx: &mpsc::Sender<AudioEvent>) {
+    fn handle_event(&mut self, event: AudioEvent) {
         match event {
             AudioEvent::SendSinkList => {
-                self.update_sink_list_or_log(event_tx.clone());
-                self.emit_notification(AudioNotification::SinkListUpdated)
-                    .await;
+                self.update_sink_list_or_log();
+                self.subscribers.emit(AudioNotification::SinkListUpdated);
             }
             AudioEvent::ReleaseDevice { id } => {
                 self.sinks.remove(&id);
-                self.emit_notification(AudioNotification::SinkListUpdated)
-                    .await;
+                self.subscribers.emit(AudioNotification::SinkListUpdated);
             }
-            AudioEvent::VolumeUpdated { id, volume, muted } => {
-                if let Some(sink) = self.sinks.get(id.as_str()) {
-                    self.emit_notification(AudioNotification::VolumeUpdated {
-                        id,
-                        name: sink.name.clone(),
-                        volume,
-                        muted,
-                    })
-                    .await;
+            AudioEvent::DefaultChanged { id } => {
+                if !self.sinks.contains_key(&id) {
+                    // A device we have not seen yet
+                    self.update_sink_list_or_log();
+                    self.subscribers.emit(AudioNotification::SinkListUpdated);
+                    return;
+                }
+
+                let mut previous = None;
+                for (sink_id, sink) in self.sinks.iter_mut() {
+                    if sink.is_active && sink_id != &id {
+                        previous = Some(Arc::new(sink_id.clone()));
+                    }
+                    sink.is_active = sink_id == &id;
                 }
+
+                self.subscribers.emit(AudioNotification::DefaultChanged {
+                    id: Arc::new(id),
+                    previous,
+                });
             }
         }
     }
 
     #[tokio::main(flavor = "current_thread")]
     async fn manager_main(mut self) -> Result<()> {
-        let (event_tx, mut event_rx) = mpsc::channel(1);
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let client = IMMNotificationClient::from(NotificationClient { sender: event_tx });
+        // When the volume changes gathered so far are sent.
+        let mut flush_at: Option<Instant> = None;
 
         unsafe {
             CoInitializeEx(None, COINIT_MULTITHREADED | COINIT_DISABLE_OLE1DDE)?;
-            self.update_sink_list_or_log(event_tx.clone());
+            self.enumerator
+                .RegisterEndpointNotificationCallback(&client)?;
+            self.update_sink_list_or_log();
         }
 
         loop {
@@ -419,20 +505,35 @@ impl AudioManager {
                     let event = if let Some(e) = event {
                         e
                     } else {
-                        return Ok(());
+                        break;
                     };
-                    self.handle_event(event, &event_tx).await;
+                    self.handle_event(event);
+                }
+                _ = self.volumes.changed() => {
+                    flush_at.get_or_insert_with(|| Instant::now() + VOLUME_COALESCE_WINDOW);
+                }
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)),
+                    if flush_at.is_some() =>
+                {
+                    flush_at = None;
+                    self.flush_volumes();
                 }
                 command = self.command_rx.recv() => {
                     let command = if let Some(c) = command {
                         c
                     } else {
-                        return Ok(());
+                        break;
                     };
-                    self.handle_command(command).await;
+                    self.handle_command(command);
                 }
             }
         }
+
+        unsafe {
+            self.enumerator
+                .UnregisterEndpointNotificationCallback(&client)?;
+        }
+        Ok(())
     }
 }
 
@@ -443,6 +544,9 @@ pub struct AudioSinkInfo {
     pub is_active: bool,
     pub is_muted: bool,
     pub volume: u8,
+    pub form_factor: FormFactor,
+    /// Whether something is plugged into the jack of the device, `None` if it has none.
+    pub jack_connected: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -454,6 +558,24 @@ pub enum AudioNotification {
         volume: u8,
         muted: bool,
     },
+    /// The default render device has changed to `id`.
+    DefaultChanged {
+        id: Arc<String>,
+        /// The device that was the default before, and is no longer active.
+        previous: Option<Arc<String>>,
+    },
+}
+
+impl Notification for AudioNotification {
+    /// The next change, or the sink list, has the volume too.
+    fn is_droppable(&self) -> bool {
+        matches!(self, AudioNotification::VolumeUpdated { .. })
+    }
+
+    /// The sink list has the volumes and the default device as well.
+    fn resync() -> Self {
+        AudioNotification::SinkListUpdated
+    }
 }
 
 #[derive(Debug)]
@@ -472,6 +594,15 @@ enum AudioCommand {
         id: String,
         muted: bool,
     },
+    GetAppVolume {
+        app: String,
+        reply: oneshot::Sender<Result<Option<u8>>>,
+    },
+    SetAppVolume {
+        app: String,
+        volume: u8,
+        reply: oneshot::Sender<Result<bool>>,
+    },
 }
 
 #[derive(Clone)]
@@ -491,7 +622,7 @@ impl AudioManagerHandle {
     }
 
     pub async fn subscribe_notification(&self) -> Result<mpsc::Receiver<AudioNotification>> {
-        let (sender, receiver) = mpsc::channel(1);
+        let (sender, receiver) = mpsc::channel(NOTIFICATION_CAPACITY);
 
         self.command_tx
             .send(AudioCommand::SubscribeNotification { sender })
@@ -521,4 +652,38 @@ impl AudioManagerHandle {
 
         Ok(())
     }
+
+    /// The volume (0-100) of the application `app` on the default output, relative to the
+    /// volume of the output, or `None` if it has no audio session there.
+    ///
+    /// `app` is the AppUserModelID of the application, e.g. of its media session.
+    pub async fn get_app_volume(&self, app: &str) -> Result<Option<u8>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.command_tx
+            .send(AudioCommand::GetAppVolume {
+                app: app.to_owned(),
+                reply: reply_tx,
+            })
+            .await?;
+
+        reply_rx.await?
+    }
+
+    /// Set the volume of all audio sessions of `app` on the default output.
+    ///
+    /// Returns `false` if it has none.
+    pub async fn set_app_volume(&self, app: &str, volume: u8) -> Result<bool> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        self.command_tx
+            .send(AudioCommand::SetAppVolume {
+                app: app.to_owned(),
+                volume,
+                reply: reply_tx,
+            })
+            .await?;
+
+        reply_rx.await?
+    }
 }
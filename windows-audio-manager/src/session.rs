@@ -0,0 +1,139 @@
+//! Volume of the audio sessions of applications, e.g. to control the volume of a media player
+//! without changing the volume of everything else.
+
+use anyhow::Result;
+use windows::{
+    core::{Interface, PWSTR},
+    Win32::{
+        Foundation::CloseHandle,
+        Media::Audio::*,
+        System::{Com::CLSCTX_ALL, Threading::*},
+    },
+};
+
+/// The sessions of `app` on the default output, active ones first.
+///
+/// `app` is an AppUserModelID as found in media sessions: the executable name for desktop
+/// applications, or the package family name followed by the application id for packaged ones.
+pub(crate) fn app_sessions(
+    enumerator: &IMMDeviceEnumerator,
+    app: &str,
+) -> Result<Vec<ISimpleAudioVolume>> {
+    let mut active = vec![];
+    let mut inactive = vec![];
+
+    unsafe {
+        let device = enumerator.GetDefaultAudioEndpoint(eRender, eMultimedia)?;
+        let manager = device.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None)?;
+        let sessions = manager.GetSessionEnumerator()?;
+
+        for i in 0..sessions.GetCount()? {
+            let control: IAudioSessionControl2 = sessions.GetSession(i)?.cast()?;
+            let image = match process_image(control.GetProcessId()?) {
+                Some(image) => image,
+                // E.g. system sounds, or a process that has exited.
+                None => continue,
+            };
+            if !matches_app(app, &image) {
+                continue;
+            }
+
+            let volume = control.cast::<ISimpleAudioVolume>()?;
+            if control.GetState()? == AudioSessionStateActive {
+                active.push(volume);
+            } else {
+                inactive.push(volume);
+            }
+        }
+    }
+
+    active.append(&mut inactive);
+    Ok(active)
+}
+
+/// The path of the executable of a process.
+fn process_image(pid: u32) -> Option<String> {
+    if pid == 0 {
+        return None;
+    }
+
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let mut buf = [0u16; 1024];
+        let mut size = buf.len() as u32;
+        let ok = QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buf.as_mut_ptr()),
+            &mut size,
+        )
+        .as_bool();
+        CloseHandle(process);
+
+        ok.then(|| String::from_utf16_lossy(&buf[..size as usize]))
+    }
+}
+
+/// Whether the executable at `image` belongs to the application `app`.
+fn matches_app(app: &str, image: &str) -> bool {
+    let image = image.to_lowercase();
+    let app = app.to_lowercase();
+
+    if let Some((family, _)) = app.split_once('!') {
+        // Packaged executables are in a folder named after the full name of their package,
+        // which is made of the name and publisher of the family, with the version in between.
+        return match family.rsplit_once('_') {
+            Some((name, publisher)) => {
+                image.contains(&format!("\\{}_", name))
+                    && image.contains(&format!("__{}\\", publisher))
+            }
+            None => false,
+        };
+    }
+
+    // Desktop applications are identified by their executable, sometimes without extension,
+    // or by its whole path.
+    let file_name = |path: &str| {
+        path.rsplit(['\\', '/'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let app_file = file_name(&app);
+    let image_file = file_name(&image);
+    let image_stem = image_file
+        .rsplit_once('.')
+        .map_or(&*image_file, |(stem, _)| stem);
+    image_file == app_file || image_stem == app_file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_apps_match_their_executable() {
+        let image = r"C:\Program Files\Google\Chrome\Application\chrome.exe";
+        assert!(matches_app("chrome.exe", image));
+        assert!(matches_app("Chrome", image));
+        assert!(matches_app(
+            r"C:\Program Files\Google\Chrome\Application\chrome.exe",
+            image
+        ));
+        assert!(!matches_app("msedge.exe", image));
+        assert!(!matches_app("chrome.exe", r"C:\Tools\notchrome.exe"));
+    }
+
+    #[test]
+    fn packaged_apps_match_their_package() {
+        let image = r"C:\Program Files\WindowsApps\SpotifyAB.SpotifyMusic_1.200.0.0_x86__zpdnekdrzrea0\Spotify.exe";
+        assert!(matches_app(
+            "SpotifyAB.SpotifyMusic_zpdnekdrzrea0!Spotify",
+            image
+        ));
+        assert!(!matches_app(
+            "Microsoft.ZuneMusic_8wekyb3d8bbwe!Microsoft.ZuneMusic",
+            image
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::HashMap, ffi::OsStr, os::windows::prelude::*, sync::Arc};
+
+use windows::{
+    core::{implement, IUnknown, Interface, GUID, HSTRING, PCWSTR},
+    Win32::{
+        Foundation::{BOOL, CLASS_E_NOAGGREGATION, ERROR_FILE_NOT_FOUND, E_POINTER, WIN32_ERROR},
+        System::{
+            Com::{
+                CoRegisterClassObject, CoRevokeClassObject, IClassFactory, IClassFactory_Impl,
+                CLSCTX_LOCAL_SERVER, REGCLS_MULTIPLEUSE,
+            },
+            Registry::{
+                RegDeleteKeyValueW, RegDeleteTreeW, RegSetKeyValueW, HKEY_CURRENT_USER, REG_SZ,
+            },
+        },
+        UI::Notifications::{
+            INotificationActivationCallback, INotificationActivationCallback_Impl,
+            NOTIFICATION_USER_INPUT_DATA,
+        },
+    },
+};
+
+use crate::Result;
+
+/// The argument that COM adds to the command line when it starts the application to deliver
+/// an activation.
+pub const EMBEDDING_ARG: &str = "-Embedding";
+
+/// A toast (or one of its actions) that was activated by the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Activation {
+    /// The AUMID of the toast.
+    pub aum_id: String,
+    /// The launch argument of the toast, or the arguments of the clicked action.
+    pub arguments: String,
+    /// Values of the inputs of the toast, by their id.
+    pub user_input: HashMap<String, String>,
+}
+
+type Handler = Arc<dyn Fn(Activation) + Send + Sync>;
+
+/// Receives activations of toasts through COM, including the ones clicked in the Action Center
+/// after the process that showed them has exited.
+///
+/// Windows starts the current executable with [`EMBEDDING_ARG`] to deliver such activations,
+/// which should then create a `ToastActivator` as early as possible. While the process is
+/// running, activations are delivered to it directly, in addition to the callbacks given to
+/// [`ToastManager::show_with_callbacks`](crate::ToastManager::show_with_callbacks).
+///
+/// Activations stop being received when it is dropped.
+///
+/// # Example
+/// ```no_run
+/// use winrt_toast::ToastActivator;
+///
+/// let clsid = windows::core::GUID::from_u128(0x6f6e0e1c_7c0d_4a5e_9a39_6d0d5d2f0b11);
+/// let activator = ToastActivator::register("YourCompany.YourApp", clsid, |activation| {
+///     println!("Activated with {:?}", activation.arguments);
+/// })
+/// .expect("Failed to register activator");
+/// ```
+pub struct ToastActivator {
+    clsid: GUID,
+    cookie: u32,
+}
+
+impl std::fmt::Debug for ToastActivator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ToastActivator({:?})", self.clsid)
+    }
+}
+
+impl ToastActivator {
+    /// Register `clsid` as the activator of toasts shown with `aum_id`, and start receiving
+    /// activations with `handler`.
+    ///
+    /// The CLSID should be unique to the application and never change. It is registered to
+    /// the current executable in the registry of the current user, along with the AUMID
+    /// (see [`register`](crate::register)).
+    ///
+    /// COM must have been initialized on the calling thread. For a single-threaded apartment,
+    /// activations are delivered through its message loop.
+    pub fn register<F>(aum_id: &str, clsid: GUID, handler: F) -> Result<Self>
+    where
+        F: Fn(Activation) + Send + Sync + 'static,
+    {
+        register_server(aum_id, &clsid)?;
+
+        let factory: IClassFactory = ActivatorFactory {
+            handler: Arc::new(handler),
+        }
+        .into();
+        let cookie = unsafe {
+            CoRegisterClassObject(
+                &clsid,
+                &factory.cast::<IUnknown>()?,
+                CLSCTX_LOCAL_SERVER,
+                REGCLS_MULTIPLEUSE,
+            )?
+        };
+
+        Ok(Self { clsid, cookie })
+    }
+
+    /// Remove what [`register`](Self::register) wrote to the registry, e.g. when the
+    /// application is uninstalled, so that activating older toasts starts nothing.
+    ///
+    /// Keys and values that do not exist are skipped.
+    pub fn unregister(aum_id: &str, clsid: GUID) -> Result<()> {
+        let clsid = format!("{{{:?}}}", clsid);
+
+        unsafe {
+            ignore_not_found(RegDeleteTreeW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(format!("SOFTWARE\\Classes\\CLSID\\{}", clsid)),
+            ))?;
+            ignore_not_found(RegDeleteKeyValueW(
+                HKEY_CURRENT_USER,
+                &HSTRING::from(format!("SOFTWARE\\Classes\\AppUserModelId\\{}", aum_id)),
+                &HSTRING::from("CustomActivator"),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the process has been started by COM to deliver an activation, in which case
+    /// it may e.g. not show any window.
+    pub fn is_com_launch() -> bool {
+        std::env::args().skip(1).any(|a| a == EMBEDDING_ARG)
+    }
+}
+
+impl Drop for ToastActivator {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CoRevokeClassObject(self.cookie);
+        }
+    }
+}
+
+/// Point the CLSID to the current executable, and the AUMID to the CLSID.
+fn register_server(aum_id: &str, clsid: &GUID) -> Result<()> {
+    let clsid = format!("{{{:?}}}", clsid);
+    let exe = std::env::current_exe()?;
+
+    set_string_value(
+        &format!("SOFTWARE\\Classes\\CLSID\\{}\\LocalServer32", clsid),
+        None,
+        &format!("\"{}\"", exe.display()),
+    )?;
+    set_string_value(
+        &format!("SOFTWARE\\Classes\\AppUserModelId\\{}", aum_id),
+        Some("CustomActivator"),
+        &clsid,
+    )?;
+
+    Ok(())
+}
+
+fn set_string_value(key: &str, name: Option<&str>, value: &str) -> Result<()> {
+    let data: Vec<u16> = OsStr::new(value).encode_wide().chain(Some(0)).collect();
+    let name = name.map(HSTRING::from);
+
+    unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            &HSTRING::from(key),
+            name.as_ref().map_or(PCWSTR::null(), |n| n.into()),
+            REG_SZ.0,
+            Some(data.as_ptr() as *const _),
+            (data.len() * std::mem::size_of::<u16>()) as u32,
+        )
+        .ok()?;
+    }
+
+    Ok(())
+}
+
+fn ignore_not_found(res: WIN32_ERROR) -> Result<()> {
+    if res != ERROR_FILE_NOT_FOUND {
+        res.ok()?;
+    }
+    Ok(())
+}
+
+#[implement(IClassFactory)]
+struct ActivatorFactory {
+    handler: Handler,
+}
+
+#[allow(non_snake_case)]
+impl IClassFactory_Impl for ActivatorFactory {
+    fn CreateInstance(
+        &self,
+        punkouter: &Option<IUnknown>,
+        riid: *const GUID,
+        ppvobject: *mut *mut std::ffi::c_void,
+    ) -> windows::core::Result<()> {
+        if punkouter.is_some() {
+            return Err(CLASS_E_NOAGGREGATION.into());
+        }
+        if riid.is_null() || ppvobject.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        let callback: INotificationActivationCallback = ActivationCallback {
+            handler: self.handler.clone(),
+        }
+        .into();
+        unsafe { callback.query(&*riid, ppvobject as *mut _).ok() }
+    }
+
+    fn LockServer(&self, _flock: BOOL) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+#[implement(INotificationActivationCallback)]
+struct ActivationCallback {
+    handler: Handler,
+}
+
+#[allow(non_snake_case)]
+impl INotificationActivationCallback_Impl for ActivationCallback {
+    fn Activate(
+        &self,
+        appusermodelid: &PCWSTR,
+        invokedargs: &PCWSTR,
+        data: *const NOTIFICATION_USER_INPUT_DATA,
+        count: u32,
+    ) -> windows::core::Result<()> {
+        let inputs = if data.is_null() {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(data, count as usize) }
+        };
+
+        let activation = unsafe {
+            Activation {
+                aum_id: pcwstr_to_string(*appusermodelid),
+                arguments: pcwstr_to_string(*invokedargs),
+                user_input: inputs
+                    .iter()
+                    .map(|i| (pcwstr_to_string(i.Key), pcwstr_to_string(i.Value)))
+                    .collect(),
+            }
+        };
+        (self.handler)(activation);
+
+        Ok(())
+    }
+}
+
+/// # Safety
+/// `s` must be null or point to a null-terminated string.
+unsafe fn pcwstr_to_string(s: PCWSTR) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        String::from_utf16_lossy(s.as_wide())
+    }
+}
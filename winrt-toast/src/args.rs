@@ -0,0 +1,145 @@
+use std::fmt::Display;
+
+/// Key-value arguments of a toast, its actions or its header, which are given back as a string
+/// when the toast is activated.
+///
+/// They are written as `key=value` pairs separated by `;`, the same format as the
+/// `ToastArguments` class of the Windows Community Toolkit. `%`, `;` and `=` in keys and values
+/// are percent-encoded, so any string round-trips.
+///
+/// # Example
+/// ```rust
+/// use winrt_toast::ToastArgs;
+///
+/// let args = ToastArgs::new().with("action", "reply").with("id", "a;b=c");
+/// assert_eq!(args.to_string(), "action=reply;id=a%3Bb%3Dc");
+///
+/// let parsed = ToastArgs::parse(&args.to_string());
+/// assert_eq!(parsed.get("id"), Some("a;b=c"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToastArgs {
+    pairs: Vec<(String, String)>,
+}
+
+impl ToastArgs {
+    /// Create empty arguments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the arguments given to an activation callback.
+    ///
+    /// Pairs without `=` are keys with an empty value. Parsing never fails, so that
+    /// arguments not built with `ToastArgs` can still be inspected.
+    pub fn parse(s: &str) -> Self {
+        let mut args = Self::new();
+        for pair in s.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            args.set(decode(key), decode(value));
+        }
+        args
+    }
+
+    /// Add a pair, replacing the value of the key if it is already present.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Set the value of a key, replacing it if it is already present.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let key = key.into();
+        let value = value.into();
+        match self.pairs.iter_mut().find(|(k, _)| *k == key) {
+            Some(pair) => pair.1 = value,
+            None => self.pairs.push((key, value)),
+        }
+        self
+    }
+
+    /// The value of a key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the key is present.
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The pairs, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str("%25"),
+            ';' => out.push_str("%3B"),
+            '=' => out.push_str("%3D"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn decode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..i]);
+        let escaped = match rest.get(i + 1..i + 3) {
+            Some("25") => Some('%'),
+            Some("3B") | Some("3b") => Some(';'),
+            Some("3D") | Some("3d") => Some('='),
+            _ => None,
+        };
+        match escaped {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[i + 3..];
+            }
+            // Not one of our escapes, keep it as is.
+            None => {
+                out.push('%');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+impl Display for ToastArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (key, value)) in self.pairs.iter().enumerate() {
+            if i > 0 {
+                f.write_str(";")?;
+            }
+            f.write_str(&encode(key))?;
+            if !value.is_empty() {
+                write!(f, "={}", encode(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<ToastArgs> for String {
+    fn from(args: ToastArgs) -> Self {
+        args.to_string()
+    }
+}
+
+impl From<&ToastArgs> for String {
+    fn from(args: &ToastArgs) -> Self {
+        args.to_string()
+    }
+}
@@ -5,11 +5,11 @@ use crate::hs;
 /// Specifies a button shown in a toast.
 #[derive(Debug, Clone)]
 pub struct Action {
-    content: String,
-    arguments: String,
-    r#type: String,
-    activation_type: Option<ActivationType>,
-    placement: Option<ActionPlacement>,
+    pub(crate) content: String,
+    pub(crate) arguments: String,
+    pub(crate) r#type: String,
+    pub(crate) activation_type: Option<ActivationType>,
+    pub(crate) placement: Option<ActionPlacement>,
 }
 
 impl Action {
@@ -64,6 +64,8 @@ impl Action {
 
 /// The type of activation that will be used when the user interacts with a specific action
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ActivationType {
     /// Default value. Your foreground app is launched.
     Foreground,
@@ -85,6 +87,8 @@ impl ActivationType {
 
 /// The location of the action
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ActionPlacement {
     /// The action becomes a context menu action added to the toast notification's
     /// context menu rather than a traditional toast button.
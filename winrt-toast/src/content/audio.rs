@@ -0,0 +1,103 @@
+use windows::Data::Xml::Dom::XmlElement;
+
+use crate::hs;
+
+/// A sound provided by the system.
+///
+/// See <https://docs.microsoft.com/en-us/uwp/schemas/tiles/toastschema/element-audio>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum Sound {
+    /// The default notification sound.
+    Default,
+    /// The sound of an instant message.
+    Im,
+    /// The sound of an email.
+    Mail,
+    /// The sound of a calendar reminder.
+    Reminder,
+    /// The sound of a text message.
+    Sms,
+    /// The default alarm sound, which should be looped.
+    Alarm,
+    /// The default ringtone, which should be looped.
+    Call,
+}
+
+impl Sound {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sound::Default => "ms-winsoundevent:Notification.Default",
+            Sound::Im => "ms-winsoundevent:Notification.IM",
+            Sound::Mail => "ms-winsoundevent:Notification.Mail",
+            Sound::Reminder => "ms-winsoundevent:Notification.Reminder",
+            Sound::Sms => "ms-winsoundevent:Notification.SMS",
+            Sound::Alarm => "ms-winsoundevent:Notification.Looping.Alarm",
+            Sound::Call => "ms-winsoundevent:Notification.Looping.Call",
+        }
+    }
+}
+
+/// The sound played when the toast is shown.
+///
+/// Without it, the default notification sound is played, or the sound of the
+/// [`Scenario`](crate::Scenario) of the toast.
+///
+/// # Example
+/// ```rust
+/// # use winrt_toast::{Audio, Toast};
+/// # use winrt_toast::content::audio::Sound;
+/// let mut toast = Toast::new();
+/// toast.text1("Quiet").audio(Audio::silent());
+///
+/// let mut toast = Toast::new();
+/// toast.text1("Wake up").audio(Audio::new(Sound::Alarm).with_looping(true));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Audio {
+    pub(crate) src: Option<Sound>,
+    pub(crate) looping: bool,
+    pub(crate) silent: bool,
+}
+
+impl Audio {
+    /// Play `sound` instead of the default one.
+    pub fn new(sound: Sound) -> Self {
+        Self {
+            src: Some(sound),
+            looping: false,
+            silent: false,
+        }
+    }
+
+    /// Play no sound at all.
+    pub fn silent() -> Self {
+        Self {
+            src: None,
+            looping: false,
+            silent: true,
+        }
+    }
+
+    /// Repeat the sound for as long as the toast is shown.
+    ///
+    /// The toast should then also have a long duration, or an alarm or incoming call scenario.
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub(crate) fn write_to_element(&self, el: &XmlElement) -> crate::Result<()> {
+        if let Some(src) = &self.src {
+            el.SetAttribute(&hs("src"), &hs(src.as_str()))?;
+        }
+        if self.looping {
+            el.SetAttribute(&hs("loop"), &hs("true"))?;
+        }
+        if self.silent {
+            el.SetAttribute(&hs("silent"), &hs("true"))?;
+        }
+        Ok(())
+    }
+}
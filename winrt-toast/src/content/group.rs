@@ -0,0 +1,162 @@
+use windows::Data::Xml::Dom::{XmlDocument, XmlElement};
+
+use crate::{hs, Image, Localizer, Text};
+
+/// The vertical alignment of the content of a subgroup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextStacking {
+    /// The content is aligned to the top. This is the default.
+    Top,
+    /// The content is vertically centered.
+    Center,
+    /// The content is aligned to the bottom.
+    Bottom,
+}
+
+impl TextStacking {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TextStacking::Top => "top",
+            TextStacking::Center => "center",
+            TextStacking::Bottom => "bottom",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SubgroupItem {
+    Text(Text),
+    Image(Image),
+}
+
+/// A column in a [`Group`], with text and images stacked vertically.
+///
+/// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/toast-schema#adaptivesubgroup>
+#[derive(Debug, Clone, Default)]
+pub struct Subgroup {
+    items: Vec<SubgroupItem>,
+    weight: Option<u32>,
+    text_stacking: Option<TextStacking>,
+}
+
+impl Subgroup {
+    /// Create an empty subgroup.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a line of text.
+    pub fn text(mut self, text: impl Into<Text>) -> Self {
+        self.items.push(SubgroupItem::Text(text.into()));
+        self
+    }
+
+    /// Add an image. Its placement is ignored inside of a subgroup.
+    pub fn image(mut self, image: Image) -> Self {
+        self.items.push(SubgroupItem::Image(image));
+        self
+    }
+
+    /// The width of this column, relative to the weights of the other subgroups of the group.
+    ///
+    /// Columns without a weight share the remaining space equally.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// The vertical alignment of the content.
+    pub fn with_text_stacking(mut self, text_stacking: TextStacking) -> Self {
+        self.text_stacking = Some(text_stacking);
+        self
+    }
+
+    fn localize(&mut self, localizer: &dyn Localizer) {
+        for item in &mut self.items {
+            if let SubgroupItem::Text(text) = item {
+                text.localize(localizer);
+            }
+        }
+    }
+
+    fn write_to_element(&self, doc: &XmlDocument, el: &XmlElement) -> crate::Result<()> {
+        if let Some(weight) = self.weight {
+            el.SetAttribute(&hs("hint-weight"), &hs(weight.to_string()))?;
+        }
+        if let Some(text_stacking) = self.text_stacking {
+            el.SetAttribute(&hs("hint-textStacking"), &hs(text_stacking.as_str()))?;
+        }
+
+        for item in &self.items {
+            match item {
+                SubgroupItem::Text(text) => {
+                    let child = doc.CreateElement(&hs("text"))?;
+                    el.AppendChild(&child)?;
+                    text.write_content(&child)?;
+                }
+                SubgroupItem::Image(image) => {
+                    let child = doc.CreateElement(&hs("image"))?;
+                    el.AppendChild(&child)?;
+                    image.write_content(&child)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A row of columns, for layouts that the generic toast template does not support.
+///
+/// Groups are shown below the text of the toast, in the order they are added.
+///
+/// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/adaptive-interactive-toasts#adaptive-content>
+///
+/// # Example
+/// ```rust
+/// # use winrt_toast::{Group, Subgroup, Toast};
+/// # use winrt_toast::content::group::TextStacking;
+/// let mut toast = Toast::new();
+/// toast.text1("Now playing").adaptive_group(
+///     Group::new()
+///         .subgroup(Subgroup::new().text("Song").text("Artist").with_weight(2))
+///         .subgroup(
+///             Subgroup::new()
+///                 .text("3:14")
+///                 .with_text_stacking(TextStacking::Center),
+///         ),
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Group {
+    subgroups: Vec<Subgroup>,
+}
+
+impl Group {
+    /// Create an empty group.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a column to the group.
+    pub fn subgroup(mut self, subgroup: Subgroup) -> Self {
+        self.subgroups.push(subgroup);
+        self
+    }
+
+    pub(crate) fn localize(&mut self, localizer: &dyn Localizer) {
+        for subgroup in &mut self.subgroups {
+            subgroup.localize(localizer);
+        }
+    }
+
+    pub(crate) fn write_to_element(&self, doc: &XmlDocument, el: &XmlElement) -> crate::Result<()> {
+        for subgroup in &self.subgroups {
+            let child = doc.CreateElement(&hs("subgroup"))?;
+            el.AppendChild(&child)?;
+            subgroup.write_to_element(doc, &child)?;
+        }
+
+        Ok(())
+    }
+}
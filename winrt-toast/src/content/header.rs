@@ -4,6 +4,8 @@ use crate::hs;
 
 /// The type of activation this header will use when clicked.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ActivationType {
     /// The activation event is sent to a foreground app.
     Foreground,
@@ -25,10 +27,10 @@ impl ActivationType {
 /// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/toast-headers>
 #[derive(Debug, Clone)]
 pub struct Header {
-    id: String,
-    title: String,
-    arguments: String,
-    activation_type: Option<ActivationType>,
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) arguments: String,
+    pub(crate) activation_type: Option<ActivationType>,
 }
 
 impl Header {
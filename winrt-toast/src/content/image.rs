@@ -7,6 +7,8 @@ use crate::hs;
 
 /// The placement of the image.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ImagePlacement {
     /// The image replaces your app's logo in the toast notification.
     AppLogoOverride,
@@ -25,6 +27,8 @@ impl ImagePlacement {
 
 /// The cropping of the image.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ImageHintCrop {
     /// The image is cropped into a circle.
     Circle,
@@ -41,10 +45,10 @@ impl ImageHintCrop {
 /// Specifies an image used in the toast template.
 #[derive(Debug, Clone)]
 pub struct Image {
-    src: url::Url,
-    placement: Option<ImagePlacement>,
-    hint_crop: Option<ImageHintCrop>,
-    alt: Option<String>,
+    pub(crate) src: url::Url,
+    pub(crate) placement: Option<ImagePlacement>,
+    pub(crate) hint_crop: Option<ImageHintCrop>,
+    pub(crate) alt: Option<String>,
 }
 
 impl Image {
@@ -78,6 +82,13 @@ impl Image {
         self
     }
 
+    /// Show the image as a circular avatar in place of the app logo, e.g. for the photo of
+    /// the sender of a message.
+    pub fn as_avatar(self) -> Self {
+        self.with_placement(ImagePlacement::AppLogoOverride)
+            .with_hint_crop(ImageHintCrop::Circle)
+    }
+
     /// A description of the image, for users of assistive technologies.
     pub fn with_alt(mut self, alt: impl Into<String>) -> Self {
         self.alt = Some(alt.into());
@@ -86,6 +97,11 @@ impl Image {
 
     pub(crate) fn write_to_element(&self, id: u8, el: &XmlElement) -> crate::Result<()> {
         el.SetAttribute(&hs("id"), &hs(&format!("{}", id)))?;
+        self.write_content(el)
+    }
+
+    /// Write everything but the id, which images in a subgroup do not have.
+    pub(crate) fn write_content(&self, el: &XmlElement) -> crate::Result<()> {
         el.SetAttribute(&hs("src"), &hs(&self.src))?;
         if let Some(placement) = self.placement {
             el.SetAttribute(&hs("placement"), &hs(placement.as_str()))?;
@@ -1,8 +1,14 @@
 /// Action element
 pub mod action;
+/// Audio element
+pub mod audio;
+/// Group and subgroup elements
+pub mod group;
 /// Header element
 pub mod header;
 /// Image element
 pub mod image;
+/// Progress bar element
+pub mod progress;
 /// Text element
 pub mod text;
@@ -0,0 +1,109 @@
+use windows::{
+    core::HSTRING, Data::Xml::Dom::XmlElement, Foundation::Collections::IMap,
+    UI::Notifications::NotificationData,
+};
+
+use crate::hs;
+
+const KEY_TITLE: &str = "progressTitle";
+const KEY_VALUE: &str = "progressValue";
+const KEY_VALUE_STRING: &str = "progressValueString";
+const KEY_STATUS: &str = "progressStatus";
+
+/// The value of a progress bar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum ProgressValue {
+    /// A value between 0.0 and 1.0.
+    Determinate(f64),
+    /// Show an animation instead of a value.
+    Indeterminate,
+}
+
+impl ProgressValue {
+    fn to_data_string(self) -> String {
+        match self {
+            ProgressValue::Determinate(v) => format!("{}", v.clamp(0.0, 1.0)),
+            ProgressValue::Indeterminate => "indeterminate".to_string(),
+        }
+    }
+}
+
+/// A progress bar shown in the toast.
+///
+/// The values are data bound, so they can be changed after the toast is shown with
+/// [`ToastManager::update_progress`](crate::ToastManager::update_progress).
+///
+/// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/toast-progress-bar>
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub(crate) title: Option<String>,
+    pub(crate) status: String,
+    pub(crate) value: ProgressValue,
+    pub(crate) value_string: Option<String>,
+}
+
+impl Progress {
+    /// Create a new progress bar.
+    ///
+    /// `status`: A status string displayed underneath the progress bar on the left, like "Downloading...".
+    pub fn new(status: impl Into<String>, value: ProgressValue) -> Self {
+        Self {
+            title: None,
+            status: status.into(),
+            value,
+            value_string: None,
+        }
+    }
+
+    /// A title string displayed above the progress bar.
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// A string displayed underneath the progress bar on the right, instead of the percentage.
+    pub fn with_value_string(mut self, value_string: impl Into<String>) -> Self {
+        self.value_string = Some(value_string.into());
+        self
+    }
+
+    pub(crate) fn write_to_element(&self, el: &XmlElement) -> crate::Result<()> {
+        if self.title.is_some() {
+            el.SetAttribute(&hs("title"), &hs(format!("{{{}}}", KEY_TITLE)))?;
+        }
+        el.SetAttribute(&hs("status"), &hs(format!("{{{}}}", KEY_STATUS)))?;
+        el.SetAttribute(&hs("value"), &hs(format!("{{{}}}", KEY_VALUE)))?;
+        if self.value_string.is_some() {
+            el.SetAttribute(
+                &hs("valueStringOverride"),
+                &hs(format!("{{{}}}", KEY_VALUE_STRING)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn to_notification_data(&self) -> crate::Result<NotificationData> {
+        let data = NotificationData::new()?;
+        self.insert_values(&data.Values()?)?;
+        // 0 means the data always replaces what is shown.
+        data.SetSequenceNumber(0)?;
+
+        Ok(data)
+    }
+
+    pub(crate) fn insert_values(&self, values: &IMap<HSTRING, HSTRING>) -> crate::Result<()> {
+        if let Some(title) = &self.title {
+            values.Insert(&hs(KEY_TITLE), &hs(title))?;
+        }
+        values.Insert(&hs(KEY_STATUS), &hs(&self.status))?;
+        values.Insert(&hs(KEY_VALUE), &hs(self.value.to_data_string()))?;
+        if let Some(value_string) = &self.value_string {
+            values.Insert(&hs(KEY_VALUE_STRING), &hs(value_string))?;
+        }
+
+        Ok(())
+    }
+}
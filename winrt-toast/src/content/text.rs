@@ -1,6 +1,10 @@
+use std::fmt::Display;
+
 use windows::Data::Xml::Dom::XmlElement;
 
-use crate::hs;
+use crate::{hs, Localizer};
+
+const RESOURCE_PREFIX: &str = "ms-resource:";
 
 /// The placement of the text.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -23,11 +27,43 @@ impl TextPlacement {
     }
 }
 
+/// The horizontal alignment of the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum TextAlign {
+    /// Aligned according to the language of the text. This is the default.
+    Auto,
+    /// Aligned to the left.
+    Left,
+    /// Centered.
+    Center,
+    /// Aligned to the right.
+    Right,
+}
+
+impl TextAlign {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TextAlign::Auto => "auto",
+            TextAlign::Left => "left",
+            TextAlign::Center => "center",
+            TextAlign::Right => "right",
+        }
+    }
+}
+
 /// Specifies text used in the toast template.
 #[derive(Debug, Clone)]
 pub struct Text {
-    content: String,
-    placement: Option<TextPlacement>,
+    pub(crate) content: String,
+    pub(crate) placement: Option<TextPlacement>,
+    pub(crate) language: Option<String>,
+    /// `content` is the name of a resource.
+    pub(crate) is_resource: bool,
+    pub(crate) max_lines: Option<u32>,
+    pub(crate) wrap: Option<bool>,
+    pub(crate) align: Option<TextAlign>,
 }
 
 impl Text {
@@ -36,9 +72,43 @@ impl Text {
         Self {
             content: content.into(),
             placement: None,
+            language: None,
+            is_resource: false,
+            max_lines: None,
+            wrap: None,
+            align: None,
         }
     }
 
+    /// Create a text element with a localized string, e.g. `Resources/Greeting`.
+    ///
+    /// The string is looked up with the [`Localizer`] of the manager, if it has one. Otherwise
+    /// Windows resolves the `ms-resource:` URI, which only works for packaged apps.
+    pub fn resource(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self {
+            content: name
+                .strip_prefix(RESOURCE_PREFIX)
+                .map(str::to_string)
+                .unwrap_or(name),
+            is_resource: true,
+            ..Self::new("")
+        }
+    }
+
+    /// Create a text element whose content is the value bound to `key` with
+    /// [`Toast::bind`](crate::Toast::bind).
+    pub fn binding(key: &str) -> Self {
+        Self::new(format!("{{{}}}", key))
+    }
+
+    /// The language of the text as a BCP-47 tag, e.g. `en-US`, if it differs from the language
+    /// of the system. This selects the right fonts and formatting.
+    pub fn with_language(mut self, language: impl Into<String>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
     /// The placement of the text.
     pub fn with_placement(mut self, placement: TextPlacement) -> Self {
         self.placement = Some(placement);
@@ -50,15 +120,76 @@ impl Text {
         self.with_placement(TextPlacement::Attribution)
     }
 
+    /// Show up to `max_lines` lines of the text before truncating it.
+    ///
+    /// Windows still limits the lines of each text, e.g. the body of a toast has at most four
+    /// lines, shared by its second and third text.
+    pub fn with_max_lines(mut self, max_lines: u32) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+
+    /// Whether the text in a [`Subgroup`](crate::Subgroup) wraps onto more lines instead of
+    /// being truncated, which it does not by default.
+    ///
+    /// Other texts always wrap, use [`with_max_lines`](Self::with_max_lines) to limit them.
+    pub fn with_wrap(mut self, wrap: bool) -> Self {
+        self.wrap = Some(wrap);
+        self
+    }
+
+    /// The horizontal alignment of the text, which only applies in a
+    /// [`Subgroup`](crate::Subgroup).
+    pub fn with_align(mut self, align: TextAlign) -> Self {
+        self.align = Some(align);
+        self
+    }
+
     pub(crate) fn write_to_element(&self, id: u8, el: &XmlElement) -> crate::Result<()> {
         el.SetAttribute(&hs("id"), &hs(&format!("{}", id)))?;
-        el.SetInnerText(&hs(&self.content))?;
+        self.write_content(el)
+    }
+
+    /// Write everything but the id, which text in a subgroup does not have.
+    pub(crate) fn write_content(&self, el: &XmlElement) -> crate::Result<()> {
+        el.SetInnerText(&hs(self.to_string()))?;
         if let Some(placement) = self.placement {
             el.SetAttribute(&hs("placement"), &hs(placement.as_str()))?;
         }
+        if let Some(language) = &self.language {
+            el.SetAttribute(&hs("lang"), &hs(language))?;
+        }
+        if let Some(max_lines) = self.max_lines {
+            el.SetAttribute(&hs("hint-maxLines"), &hs(max_lines.to_string()))?;
+        }
+        if let Some(wrap) = self.wrap {
+            el.SetAttribute(&hs("hint-wrap"), &hs(wrap.to_string()))?;
+        }
+        if let Some(align) = self.align {
+            el.SetAttribute(&hs("hint-align"), &hs(align.as_str()))?;
+        }
 
         Ok(())
     }
+
+    /// Replace the name of a resource with its string, if the localizer has one.
+    pub(crate) fn localize(&mut self, localizer: &dyn Localizer) {
+        if self.is_resource {
+            if let Some(s) = localizer.localize(&self.content) {
+                self.content = s;
+                self.is_resource = false;
+            }
+        }
+    }
+}
+
+impl Display for Text {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_resource {
+            f.write_str(RESOURCE_PREFIX)?;
+        }
+        f.write_str(&self.content)
+    }
 }
 
 impl<T> From<T> for Text
@@ -33,18 +33,38 @@
 /// Contents in a toast notification.
 pub mod content;
 pub use content::action::Action;
+pub use content::audio::Audio;
+pub use content::group::{Group, Subgroup};
 pub use content::header::Header;
 pub use content::image::Image;
+pub use content::progress::{Progress, ProgressValue};
 pub use content::text::Text;
 
 mod manager;
-pub use manager::{DismissalReason, ToastManager};
+pub use manager::{DismissalReason, Localizer, ShownToast, ToastManager, ToastManagerBuilder};
 
 mod toast;
-pub use toast::{Scenario, Toast, ToastDuration};
+pub use toast::{Mirroring, Person, Scenario, Toast, ToastBuilder, ToastDuration, ToastPriority};
+
+#[cfg(feature = "serde")]
+mod template;
+#[cfg(feature = "serde")]
+pub use template::{
+    ActionTemplate, AudioTemplate, HeaderTemplate, ImageTemplate, ProgressTemplate, TextTemplate,
+    ToastTemplate,
+};
 
 mod register;
-pub use register::register;
+pub use register::{is_registered, register, unregister};
+
+mod activator;
+pub use activator::{Activation, ToastActivator, EMBEDDING_ARG};
+
+mod tag;
+pub use tag::{ToastTag, MAX_TAG_LENGTH};
+
+mod args;
+pub use args::ToastArgs;
 
 /// Re-export of the `url` crate.
 pub use url;
@@ -72,6 +92,12 @@ pub enum WinToastError {
     /// The dismissal reason from OS is unknown
     #[error("The dismissal reason from OS is unknown")]
     InvalidDismissalReason,
+    /// The AUMID is not registered, and no registration info was given.
+    #[error("The AUMID is not registered")]
+    UnregisteredAumId,
+    /// The tag or group is longer than [`MAX_TAG_LENGTH`].
+    #[error("The tag or group is longer than {} characters", MAX_TAG_LENGTH)]
+    TagTooLong,
 }
 
 /// The result type used in this crate.
@@ -1,20 +1,29 @@
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
 use windows::{
     core::{IInspectable, Interface, HSTRING},
-    Data::Xml::Dom::XmlDocument,
     Foundation::{PropertyValue, TypedEventHandler},
     Globalization::Calendar,
     UI::Notifications::{
-        ToastActivatedEventArgs, ToastDismissalReason, ToastDismissedEventArgs,
-        ToastFailedEventArgs, ToastNotification, ToastNotificationManager,
+        NotificationData, NotificationUpdateResult, ToastActivatedEventArgs, ToastDismissalReason,
+        ToastDismissedEventArgs, ToastFailedEventArgs, ToastNotification, ToastNotificationManager,
+        ToastNotifier,
     },
 };
 
-use crate::{hs, Result, Toast, WinToastError};
+use crate::{hs, tag::validate, Header, Progress, Result, Text, Toast, WinToastError};
 
 /// Specifies the reason that a toast notification is no longer being shown
 ///
 /// See <https://docs.microsoft.com/en-us/uwp/api/windows.ui.notifications.toastdismissalreason>
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum DismissalReason {
     /// The user dismissed the toast notification.
     UserCanceled,
@@ -37,6 +46,33 @@ impl DismissalReason {
     }
 }
 
+/// Supplies the strings of texts created with [`Text::resource`], for apps that are not
+/// packaged and therefore have no resources that Windows could look up.
+///
+/// Closures taking the name of the resource can be used as a localizer.
+pub trait Localizer: Send + Sync {
+    /// The string for the resource `name`, or `None` to leave it to Windows.
+    fn localize(&self, name: &str) -> Option<String>;
+}
+
+impl<F> Localizer for F
+where
+    F: Fn(&str) -> Option<String> + Send + Sync,
+{
+    fn localize(&self, name: &str) -> Option<String> {
+        self(name)
+    }
+}
+
+#[derive(Clone)]
+struct LocalizerRef(Arc<dyn Localizer>);
+
+impl std::fmt::Debug for LocalizerRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Localizer")
+    }
+}
+
 /// An interface that provides access to the toast notification manager.
 ///
 /// This does not actually hold any Windows resource, but is used to
@@ -49,6 +85,16 @@ impl DismissalReason {
 #[derive(Clone)]
 pub struct ToastManager {
     app_id: HSTRING,
+    defaults: ToastDefaults,
+}
+
+/// Values applied to every toast shown by a [`ToastManager`], unless the toast sets its own.
+#[derive(Debug, Clone, Default)]
+struct ToastDefaults {
+    attribution: Option<Text>,
+    header: Option<Header>,
+    expires_in: Option<Duration>,
+    localizer: Option<LocalizerRef>,
 }
 
 impl std::fmt::Debug for ToastManager {
@@ -62,11 +108,54 @@ impl ToastManager {
     pub fn new(aum_id: impl AsRef<str>) -> Self {
         Self {
             app_id: hs(aum_id.as_ref()),
+            defaults: ToastDefaults::default(),
         }
     }
 
+    /// Create a [`ToastManagerBuilder`] to validate the AUMID and configure defaults.
+    pub fn builder(aum_id: impl Into<String>) -> ToastManagerBuilder {
+        ToastManagerBuilder::new(aum_id)
+    }
+
+    /// Fill in the defaults of this manager for anything the toast does not set, and localize
+    /// its texts.
+    fn apply_defaults<'a>(&self, toast: &'a Toast) -> Cow<'a, Toast> {
+        let defaults = &self.defaults;
+        let needs_header = toast.header.is_none() && defaults.header.is_some();
+        let needs_attribution = toast.text.2.is_none() && defaults.attribution.is_some();
+        let needs_expiration = toast.expires_in.is_none() && defaults.expires_in.is_some();
+        let localizer = defaults.localizer.as_ref();
+
+        if !(needs_header || needs_attribution || needs_expiration || localizer.is_some()) {
+            return Cow::Borrowed(toast);
+        }
+
+        let mut toast = toast.clone();
+        if needs_header {
+            toast.header = defaults.header.clone();
+        }
+        if let (true, Some(attribution)) = (needs_attribution, &defaults.attribution) {
+            toast.text.2 = Some(attribution.clone().as_attribution());
+        }
+        if needs_expiration {
+            toast.expires_in = defaults.expires_in;
+        }
+        if let Some(LocalizerRef(localizer)) = localizer {
+            let (t1, t2, t3) = &mut toast.text;
+            for text in [t1, t2, t3].into_iter().flatten() {
+                text.localize(localizer.as_ref());
+            }
+            for group in &mut toast.adaptive_groups {
+                group.localize(localizer.as_ref());
+            }
+        }
+
+        Cow::Owned(toast)
+    }
+
     /// Remove all notifications in `group`.
     pub fn remove_group(&self, group: &str) -> Result<()> {
+        validate(group)?;
         let history = ToastNotificationManager::History()?;
 
         history.RemoveGroupWithId(&hs(group), &self.app_id)?;
@@ -76,6 +165,8 @@ impl ToastManager {
 
     /// Remove a notification in `group` with `tag`.
     pub fn remove_grouped_tag(&self, group: &str, tag: &str) -> Result<()> {
+        validate(group)?;
+        validate(tag)?;
         let history = ToastNotificationManager::History()?;
 
         history.RemoveGroupedTagWithId(&hs(tag), &hs(group), &self.app_id)?;
@@ -85,6 +176,7 @@ impl ToastManager {
 
     /// Remove a notification with the specified `tag`.
     pub fn remove(&self, tag: &str) -> Result<()> {
+        validate(tag)?;
         let history = ToastNotificationManager::History()?;
 
         history.Remove(&hs(tag))?;
@@ -101,6 +193,22 @@ impl ToastManager {
         Ok(())
     }
 
+    /// The distinct groups of the notifications from this application that are still in the
+    /// Action Center. Notifications without a group are left out.
+    pub fn groups(&self) -> Result<Vec<String>> {
+        let history = ToastNotificationManager::History()?;
+
+        let mut groups = vec![];
+        for toast in history.GetHistoryWithId(&self.app_id)? {
+            let group = toast.Group()?.to_string_lossy();
+            if !group.is_empty() && !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+
+        Ok(groups)
+    }
+
     /// Send a toast to Windows for display.
     pub fn show_with_callbacks(
         &self,
@@ -108,92 +216,47 @@ impl ToastManager {
         on_activated: Option<Box<dyn FnMut(Result<String>) + Send + 'static>>,
         on_dismissed: Option<Box<dyn FnMut(Result<DismissalReason>) + Send + 'static>>,
         on_failed: Option<Box<dyn FnMut(WinToastError) + Send + 'static>>,
-    ) -> Result<()> {
+    ) -> Result<ShownToast> {
+        let in_toast = self.apply_defaults(in_toast);
         let notifier = ToastNotificationManager::CreateToastNotifierWithId(&self.app_id)?;
 
-        let toast_doc = XmlDocument::new()?;
-
-        let toast_el = toast_doc.CreateElement(&hs("toast"))?;
-        toast_doc.AppendChild(&toast_el)?;
-
-        if let Some(scenario) = &in_toast.scenario {
-            toast_el.SetAttribute(&hs("scenario"), &hs(scenario.as_str()))?;
-        }
-
-        if let Some(launch) = &in_toast.launch {
-            toast_el.SetAttribute(&hs("launch"), &hs(launch))?;
-        }
-
-        if let Some(duration) = &in_toast.duration {
-            toast_el.SetAttribute(&hs("duration"), &hs(duration.as_str()))?;
-        }
-
-        // <header>
-        if let Some(header) = &in_toast.header {
-            let el = toast_doc.CreateElement(&hs("header"))?;
-            toast_el.AppendChild(&el)?;
-            header.write_to_element(&el)?;
-        }
-        // </header>
-        // <visual>
-        {
-            let visual_el = toast_doc.CreateElement(&hs("visual"))?;
-            toast_el.AppendChild(&visual_el)?;
-            // <binding>
-            {
-                let binding_el = toast_doc.CreateElement(&hs("binding"))?;
-                visual_el.AppendChild(&binding_el)?;
-                binding_el.SetAttribute(&hs("template"), &hs("ToastGeneric"))?;
-                {
-                    if let Some(text) = &in_toast.text.0 {
-                        let el = toast_doc.CreateElement(&hs("text"))?;
-                        binding_el.AppendChild(&el)?;
-                        text.write_to_element(1, &el)?;
-                    }
-                    if let Some(text) = &in_toast.text.1 {
-                        let el = toast_doc.CreateElement(&hs("text"))?;
-                        binding_el.AppendChild(&el)?;
-                        text.write_to_element(2, &el)?;
-                    }
-                    if let Some(text) = &in_toast.text.2 {
-                        let el = toast_doc.CreateElement(&hs("text"))?;
-                        binding_el.AppendChild(&el)?;
-                        text.write_to_element(3, &el)?;
-                    }
-
-                    for (id, image) in &in_toast.images {
-                        let el = toast_doc.CreateElement(&hs("image"))?;
-                        binding_el.AppendChild(&el)?;
-                        image.write_to_element(*id, &el)?;
-                    }
-                }
-            }
-            // </binding>
-        }
-        // </visual>
-        // <actions>
-        if !in_toast.actions.is_empty() {
-            let actions_el = toast_doc.CreateElement(&hs("actions"))?;
-            toast_el.AppendChild(&actions_el)?;
-            for action in &in_toast.actions {
-                let el = toast_doc.CreateElement(&hs("action"))?;
-                actions_el.AppendChild(&el)?;
-                action.write_to_element(&el)?;
-            }
-        }
-        // </actions>
+        let toast_doc = in_toast.to_document()?;
 
         let toast = ToastNotification::CreateToastNotification(&toast_doc)?;
 
         if let Some(group) = &in_toast.group {
+            validate(group)?;
             toast.SetGroup(&hs(group))?;
         }
         if let Some(tag) = &in_toast.tag {
+            validate(tag)?;
             toast.SetTag(&hs(tag))?;
         }
         if let Some(remote_id) = &in_toast.remote_id {
             toast.SetRemoteId(&hs(remote_id))?;
         }
+        if in_toast.progress.is_some() || !in_toast.bindings.is_empty() {
+            let data = NotificationData::new()?;
+            let values = data.Values()?;
+            for (key, value) in &in_toast.bindings {
+                values.Insert(&hs(key), &hs(value))?;
+            }
+            if let Some(progress) = &in_toast.progress {
+                progress.insert_values(&values)?;
+            }
+            // 0 means the data always replaces what is shown.
+            data.SetSequenceNumber(0)?;
+            toast.SetData(&data)?;
+        }
+        if in_toast.suppress_popup {
+            toast.SetSuppressPopup(true)?;
+        }
+        if let Some(priority) = in_toast.priority {
+            toast.SetPriority(priority.as_winrt())?;
+        }
+        if let Some(mirroring) = in_toast.mirroring {
+            toast.SetNotificationMirroring(mirroring.as_winrt())?;
+        }
         if let Some(exp) = in_toast.expires_in {
             let now = Calendar::new()?;
             now.AddSeconds(exp.as_secs() as i32)?;
@@ -252,11 +315,178 @@ impl ToastManager {
 
         notifier.Show(&toast)?;
 
-        Ok(())
+        Ok(ShownToast {
+            notifier,
+            toast,
+            tag: in_toast.tag.clone(),
+            group: in_toast.group.clone(),
+        })
     }
 
     /// Send a toast to Windows for display without any callbacks.
-    pub fn show(&self, in_toast: &Toast) -> Result<()> {
+    pub fn show(&self, in_toast: &Toast) -> Result<ShownToast> {
         self.show_with_callbacks(in_toast, None, None, None)
     }
+
+    /// Update the progress bar of a toast that has been shown with a [`Progress`].
+    ///
+    /// Returns `false` if the toast could not be found, e.g. because the user dismissed it.
+    pub fn update_progress(
+        &self,
+        tag: &str,
+        group: Option<&str>,
+        progress: &Progress,
+    ) -> Result<bool> {
+        validate(tag)?;
+        if let Some(group) = group {
+            validate(group)?;
+        }
+        let notifier = ToastNotificationManager::CreateToastNotifierWithId(&self.app_id)?;
+        update_progress(&notifier, tag, group, progress)
+    }
+}
+
+fn update_progress(
+    notifier: &ToastNotifier,
+    tag: &str,
+    group: Option<&str>,
+    progress: &Progress,
+) -> Result<bool> {
+    let data = progress.to_notification_data()?;
+
+    let result = match group {
+        Some(group) => notifier.UpdateWithTagAndGroup(&data, &hs(tag), &hs(group))?,
+        None => notifier.UpdateWithTag(&data, &hs(tag))?,
+    };
+
+    Ok(result == NotificationUpdateResult::Succeeded)
+}
+
+/// A toast that has been shown by a [`ToastManager`].
+///
+/// It can be kept around to hide the toast once it is no longer relevant, e.g. when the
+/// call it is about has ended, without having to give it a unique tag.
+#[derive(Debug, Clone)]
+pub struct ShownToast {
+    notifier: ToastNotifier,
+    toast: ToastNotification,
+    tag: Option<String>,
+    group: Option<String>,
+}
+
+impl ShownToast {
+    /// The tag of the toast, if it has one.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// The group of the toast, if it has one.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// Remove the toast from the screen.
+    ///
+    /// It is dismissed with [`DismissalReason::ApplicationHidden`]. Hiding a toast that has
+    /// already been dismissed does nothing.
+    pub fn hide(&self) -> Result<()> {
+        self.notifier.Hide(&self.toast)?;
+        Ok(())
+    }
+
+    /// Update the progress bar of the toast.
+    ///
+    /// Windows can only update toasts by their tag, so this returns `false` if the toast has
+    /// no tag, or if it could not be found, e.g. because the user dismissed it.
+    pub fn update(&self, progress: &Progress) -> Result<bool> {
+        match &self.tag {
+            Some(tag) => update_progress(&self.notifier, tag, self.group.as_deref(), progress),
+            None => Ok(false),
+        }
+    }
+}
+
+/// A builder for [`ToastManager`] that validates the AUMID and configures defaults
+/// applied to every toast shown through the manager.
+///
+/// # Example
+/// ```no_run
+/// use std::time::Duration;
+/// use winrt_toast::ToastManager;
+///
+/// let manager = ToastManager::builder("YourCompany.YourApp")
+///     .register("Your App", None)
+///     .attribution("Via Your App")
+///     .expires_in(Duration::from_secs(60 * 60))
+///     .build()
+///     .expect("Failed to create manager");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ToastManagerBuilder {
+    aum_id: String,
+    registration: Option<(String, Option<PathBuf>)>,
+    defaults: ToastDefaults,
+}
+
+impl ToastManagerBuilder {
+    /// Create a new builder for the given AUMID.
+    pub fn new(aum_id: impl Into<String>) -> Self {
+        Self {
+            aum_id: aum_id.into(),
+            registration: None,
+            defaults: ToastDefaults::default(),
+        }
+    }
+
+    /// Register the AUMID with [`register`](crate::register) if it is not registered yet.
+    ///
+    /// Without this, [`build`](Self::build) fails if the AUMID is not registered.
+    pub fn register(mut self, display_name: impl Into<String>, icon_path: Option<&Path>) -> Self {
+        self.registration = Some((display_name.into(), icon_path.map(Path::to_path_buf)));
+        self
+    }
+
+    /// Attribution text used for toasts that do not have a third text element.
+    pub fn attribution(mut self, attribution: impl Into<Text>) -> Self {
+        self.defaults.attribution = Some(attribution.into());
+        self
+    }
+
+    /// Look up the strings of texts created with [`Text::resource`] with `localizer`.
+    pub fn localizer(mut self, localizer: impl Localizer + 'static) -> Self {
+        self.defaults.localizer = Some(LocalizerRef(Arc::new(localizer)));
+        self
+    }
+
+    /// Header used for toasts that do not have one.
+    pub fn header(mut self, header: Header) -> Self {
+        self.defaults.header = Some(header);
+        self
+    }
+
+    /// Expiration time used for toasts that do not have one.
+    pub fn expires_in(mut self, duration: Duration) -> Self {
+        self.defaults.expires_in = Some(duration);
+        self
+    }
+
+    /// Validate the AUMID, registering it if requested, and create the manager.
+    ///
+    /// Returns [`WinToastError::UnregisteredAumId`] if the AUMID is not registered
+    /// and [`register`](Self::register) was not called.
+    pub fn build(self) -> Result<ToastManager> {
+        if !crate::is_registered(&self.aum_id)? {
+            match &self.registration {
+                Some((display_name, icon_path)) => {
+                    crate::register(&self.aum_id, display_name, icon_path.as_deref())?;
+                }
+                None => return Err(WinToastError::UnregisteredAumId),
+            }
+        }
+
+        Ok(ToastManager {
+            app_id: hs(&self.aum_id),
+            defaults: self.defaults,
+        })
+    }
 }
@@ -3,11 +3,12 @@ use std::{ffi::OsStr, os::windows::prelude::*, path::Path, ptr::null_mut};
 use windows::{
     core::{HSTRING, PCWSTR},
     Win32::{
-        Foundation::CloseHandle,
+        Foundation::{CloseHandle, ERROR_FILE_NOT_FOUND},
         Storage::FileSystem::{CommitTransaction, CreateTransaction},
         System::Registry::{
-            RegCreateKeyTransactedW, RegDeleteValueW, RegSetValueExW, HKEY, HKEY_CURRENT_USER,
-            KEY_ALL_ACCESS, REG_OPTION_NON_VOLATILE, REG_SZ,
+            RegCloseKey, RegCreateKeyTransactedW, RegDeleteTreeW, RegDeleteValueW, RegOpenKeyExW,
+            RegSetValueExW, HKEY, HKEY_CURRENT_USER, KEY_ALL_ACCESS, KEY_READ,
+            REG_OPTION_NON_VOLATILE, REG_SZ,
         },
     },
 };
@@ -79,6 +80,41 @@ pub fn register(aum_id: &str, display_name: &str, icon_path: Option<&Path>) -> c
     Ok(())
 }
 
+/// Remove the registration of the application from Windows registry. Nothing happens if it is
+/// not registered.
+///
+/// The CLSID of a [`ToastActivator`](crate::ToastActivator) is removed by
+/// [`ToastActivator::unregister`](crate::ToastActivator::unregister).
+pub fn unregister(aum_id: &str) -> crate::Result<()> {
+    let registry_path = HSTRING::from(format!("SOFTWARE\\Classes\\AppUserModelId\\{}", aum_id));
+
+    let res = unsafe { RegDeleteTreeW(HKEY_CURRENT_USER, &registry_path) };
+    if res != ERROR_FILE_NOT_FOUND {
+        res.ok()?;
+    }
+
+    Ok(())
+}
+
+/// Check whether the application has been registered to Windows registry, e.g. by [`register`].
+///
+/// Note that AUMIDs registered by other means (like a Start Menu shortcut) are not detected.
+pub fn is_registered(aum_id: &str) -> crate::Result<bool> {
+    let registry_path = HSTRING::from(format!("SOFTWARE\\Classes\\AppUserModelId\\{}", aum_id));
+
+    unsafe {
+        let mut hkey = HKEY::default();
+        let res = RegOpenKeyExW(HKEY_CURRENT_USER, &registry_path, 0, KEY_READ, &mut hkey);
+        if res == ERROR_FILE_NOT_FOUND {
+            return Ok(false);
+        }
+        res.ok()?;
+        RegCloseKey(hkey);
+    }
+
+    Ok(true)
+}
+
 /// Convert to null-terminated UTF-16 bytes
 fn to_utf16<P: AsRef<OsStr>>(s: P) -> Vec<u8> {
     s.as_ref()
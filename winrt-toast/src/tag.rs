@@ -0,0 +1,86 @@
+use std::fmt::Display;
+
+use crate::{Result, WinToastError};
+
+/// The maximum length of a tag or group, in UTF-16 code units.
+///
+/// Windows silently truncates longer identifiers, which makes it impossible to
+/// remove the toast by its tag or group later.
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// A tag or group identifier that fits in the platform limit.
+///
+/// See <https://docs.microsoft.com/en-us/uwp/api/windows.ui.notifications.toastnotification.tag>
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ToastTag(String);
+
+impl ToastTag {
+    /// Create a tag from a string, failing with [`WinToastError::TagTooLong`]
+    /// if it is longer than [`MAX_TAG_LENGTH`].
+    pub fn new(tag: impl Into<String>) -> Result<Self> {
+        let tag = tag.into();
+        validate(&tag)?;
+        Ok(Self(tag))
+    }
+
+    /// Create a tag from the hash of an arbitrary string, which always fits in the limit.
+    ///
+    /// The same input always results in the same tag.
+    pub fn hashed(s: impl AsRef<str>) -> Self {
+        Self(format!("{:x}", md5::compute(s.as_ref())))
+    }
+
+    /// The tag as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for ToastTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for ToastTag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<ToastTag> for String {
+    fn from(tag: ToastTag) -> Self {
+        tag.0
+    }
+}
+
+impl From<&ToastTag> for String {
+    fn from(tag: &ToastTag) -> Self {
+        tag.0.clone()
+    }
+}
+
+impl TryFrom<String> for ToastTag {
+    type Error = WinToastError;
+
+    fn try_from(tag: String) -> Result<Self> {
+        Self::new(tag)
+    }
+}
+
+impl TryFrom<&str> for ToastTag {
+    type Error = WinToastError;
+
+    fn try_from(tag: &str) -> Result<Self> {
+        Self::new(tag)
+    }
+}
+
+/// Check that a tag or group fits in [`MAX_TAG_LENGTH`].
+pub(crate) fn validate(tag: &str) -> Result<()> {
+    if tag.encode_utf16().count() > MAX_TAG_LENGTH {
+        Err(WinToastError::TagTooLong)
+    } else {
+        Ok(())
+    }
+}
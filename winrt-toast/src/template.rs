@@ -0,0 +1,472 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    content::{
+        action::{ActionPlacement, ActivationType},
+        audio::Sound,
+        header,
+        image::{ImageHintCrop, ImagePlacement},
+        text::{TextAlign, TextPlacement},
+    },
+    Action, Audio, Header, Image, Mirroring, Progress, ProgressValue, Scenario, Text, Toast,
+    ToastDuration, ToastPriority,
+};
+
+/// A toast stored in a file, e.g. a template in a config file.
+///
+/// The fields form a schema of their own, which does not change with the internals of
+/// [`Toast`]. Every field may be left out, and unknown fields are rejected.
+///
+/// A toast can also be turned back into a template, e.g. to show it from another process.
+/// Its adaptive groups and people have no template yet, and are left out.
+///
+/// # Example
+/// ```rust
+/// # use winrt_toast::{ToastTemplate, TextTemplate};
+/// let template = ToastTemplate {
+///     tag: Some("reminder".into()),
+///     text1: Some(TextTemplate::new("Stand up")),
+///     ..Default::default()
+/// };
+/// let toast = template.to_toast();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ToastTemplate {
+    /// See [`Toast::tag`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// See [`Toast::group`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// See [`Toast::launch`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub launch: Option<String>,
+    /// See [`Toast::header`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<HeaderTemplate>,
+    /// See [`Toast::remote_id`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_id: Option<String>,
+    /// See [`Toast::scenario`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<Scenario>,
+    /// See [`Toast::duration`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<ToastDuration>,
+    /// See [`Toast::priority`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<ToastPriority>,
+    /// See [`Toast::mirroring`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirroring: Option<Mirroring>,
+    /// See [`Toast::suppress_popup`].
+    pub suppress_popup: bool,
+    /// See [`Toast::expires_in`], in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    /// See [`Toast::text1`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text1: Option<TextTemplate>,
+    /// See [`Toast::text2`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text2: Option<TextTemplate>,
+    /// See [`Toast::text3`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text3: Option<TextTemplate>,
+    /// See [`Toast::progress`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ProgressTemplate>,
+    /// See [`Toast::audio`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioTemplate>,
+    /// See [`Toast::bind`].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub bindings: BTreeMap<String, String>,
+    /// See [`Toast::image`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageTemplate>,
+    /// See [`Toast::action`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<ActionTemplate>,
+}
+
+impl ToastTemplate {
+    /// A new toast with the contents of the template.
+    pub fn to_toast(&self) -> Toast {
+        let mut toast = Toast::new();
+        if let Some(tag) = &self.tag {
+            toast.tag(tag);
+        }
+        if let Some(group) = &self.group {
+            toast.group(group);
+        }
+        if let Some(launch) = &self.launch {
+            toast.launch(launch);
+        }
+        if let Some(header) = &self.header {
+            toast.header(header.to_header());
+        }
+        if let Some(remote_id) = &self.remote_id {
+            toast.remote_id(remote_id);
+        }
+        if let Some(scenario) = self.scenario {
+            toast.scenario(scenario);
+        }
+        if let Some(duration) = self.duration {
+            toast.duration(duration);
+        }
+        if let Some(priority) = self.priority {
+            toast.priority(priority);
+        }
+        if let Some(mirroring) = self.mirroring {
+            toast.mirroring(mirroring);
+        }
+        toast.suppress_popup(self.suppress_popup);
+        if let Some(secs) = self.expires_in_secs {
+            toast.expires_in(Duration::from_secs(secs));
+        }
+        if let Some(text) = &self.text1 {
+            toast.text1(text.to_text());
+        }
+        if let Some(text) = &self.text2 {
+            toast.text2(text.to_text());
+        }
+        if let Some(text) = &self.text3 {
+            toast.text3(text.to_text());
+        }
+        if let Some(progress) = &self.progress {
+            toast.progress(progress.to_progress());
+        }
+        if let Some(audio) = &self.audio {
+            toast.audio(audio.to_audio());
+        }
+        for (key, value) in &self.bindings {
+            toast.bind(key, value);
+        }
+        for image in &self.images {
+            toast.image(image.id, image.to_image());
+        }
+        for action in &self.actions {
+            toast.action(action.to_action());
+        }
+        toast
+    }
+}
+
+impl From<&Toast> for ToastTemplate {
+    fn from(toast: &Toast) -> Self {
+        let mut images: Vec<_> = toast
+            .images
+            .iter()
+            .map(|(&id, image)| ImageTemplate::from_image(id, image))
+            .collect();
+        images.sort_by_key(|image| image.id);
+
+        Self {
+            tag: toast.tag.clone(),
+            group: toast.group.clone(),
+            launch: toast.launch.clone(),
+            header: toast.header.as_ref().map(HeaderTemplate::from),
+            remote_id: toast.remote_id.clone(),
+            scenario: toast.scenario,
+            duration: toast.duration,
+            priority: toast.priority,
+            mirroring: toast.mirroring,
+            suppress_popup: toast.suppress_popup,
+            expires_in_secs: toast.expires_in.map(|d| d.as_secs()),
+            text1: toast.text.0.as_ref().map(TextTemplate::from),
+            text2: toast.text.1.as_ref().map(TextTemplate::from),
+            text3: toast.text.2.as_ref().map(TextTemplate::from),
+            progress: toast.progress.as_ref().map(ProgressTemplate::from),
+            audio: toast.audio.as_ref().map(AudioTemplate::from),
+            bindings: toast.bindings.clone().into_iter().collect(),
+            images,
+            actions: toast.actions.iter().map(ActionTemplate::from).collect(),
+        }
+    }
+}
+
+/// The header of a [`ToastTemplate`], see [`Header`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct HeaderTemplate {
+    /// See [`Header::new`].
+    pub id: String,
+    /// See [`Header::new`].
+    pub title: String,
+    /// See [`Header::new`].
+    pub arguments: String,
+    /// See [`Header::with_activation_type`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activation_type: Option<header::ActivationType>,
+}
+
+impl HeaderTemplate {
+    fn to_header(&self) -> Header {
+        let header = Header::new(&self.id, &self.title, &self.arguments);
+        match self.activation_type {
+            Some(activation_type) => header.with_activation_type(activation_type),
+            None => header,
+        }
+    }
+}
+
+impl From<&Header> for HeaderTemplate {
+    fn from(header: &Header) -> Self {
+        Self {
+            id: header.id.clone(),
+            title: header.title.clone(),
+            arguments: header.arguments.clone(),
+            activation_type: header.activation_type,
+        }
+    }
+}
+
+/// The progress bar of a [`ToastTemplate`], see [`Progress`].
+///
+/// Also the data of [`ToastManager::update_progress`](crate::ToastManager::update_progress),
+/// so that updates can be sent along with the toast.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProgressTemplate {
+    /// See [`Progress::with_title`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// See [`Progress::new`].
+    pub status: String,
+    /// See [`Progress::new`].
+    pub value: ProgressValue,
+    /// See [`Progress::with_value_string`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_string: Option<String>,
+}
+
+impl ProgressTemplate {
+    /// A new progress bar with the contents of the template.
+    pub fn to_progress(&self) -> Progress {
+        let mut progress = Progress::new(&self.status, self.value);
+        if let Some(title) = &self.title {
+            progress = progress.with_title(title);
+        }
+        if let Some(value_string) = &self.value_string {
+            progress = progress.with_value_string(value_string);
+        }
+        progress
+    }
+}
+
+impl From<&Progress> for ProgressTemplate {
+    fn from(progress: &Progress) -> Self {
+        Self {
+            title: progress.title.clone(),
+            status: progress.status.clone(),
+            value: progress.value,
+            value_string: progress.value_string.clone(),
+        }
+    }
+}
+
+/// A text of a [`ToastTemplate`], see [`Text`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct TextTemplate {
+    /// See [`Text::new`].
+    pub content: String,
+    /// See [`Text::as_attribution`].
+    pub attribution: bool,
+    /// `content` is the name of a resource, see [`Text::resource`].
+    pub resource: bool,
+    /// See [`Text::with_language`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// See [`Text::with_max_lines`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_lines: Option<u32>,
+    /// See [`Text::with_wrap`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrap: Option<bool>,
+    /// See [`Text::with_align`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub align: Option<TextAlign>,
+}
+
+impl TextTemplate {
+    /// A text with `content` and nothing else set.
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    fn to_text(&self) -> Text {
+        let mut text = if self.resource {
+            Text::resource(&self.content)
+        } else {
+            Text::new(&self.content)
+        };
+        if self.attribution {
+            text = text.as_attribution();
+        }
+        if let Some(language) = &self.language {
+            text = text.with_language(language);
+        }
+        if let Some(max_lines) = self.max_lines {
+            text = text.with_max_lines(max_lines);
+        }
+        if let Some(wrap) = self.wrap {
+            text = text.with_wrap(wrap);
+        }
+        if let Some(align) = self.align {
+            text = text.with_align(align);
+        }
+        text
+    }
+}
+
+impl From<&Text> for TextTemplate {
+    fn from(text: &Text) -> Self {
+        Self {
+            content: text.content.clone(),
+            attribution: text.placement == Some(TextPlacement::Attribution),
+            resource: text.is_resource,
+            language: text.language.clone(),
+            max_lines: text.max_lines,
+            wrap: text.wrap,
+            align: text.align,
+        }
+    }
+}
+
+/// The sound of a [`ToastTemplate`], see [`Audio`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct AudioTemplate {
+    /// See [`Audio::new`]. Without it, the default sound is played unless `silent` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound: Option<Sound>,
+    /// See [`Audio::silent`].
+    pub silent: bool,
+    /// See [`Audio::with_looping`].
+    pub looping: bool,
+}
+
+impl AudioTemplate {
+    fn to_audio(&self) -> Audio {
+        let audio = match (self.silent, self.sound) {
+            (true, _) => Audio::silent(),
+            (false, Some(sound)) => Audio::new(sound),
+            (false, None) => Audio::new(Sound::Default),
+        };
+        audio.with_looping(self.looping)
+    }
+}
+
+impl From<&Audio> for AudioTemplate {
+    fn from(audio: &Audio) -> Self {
+        Self {
+            sound: audio.src,
+            silent: audio.silent,
+            looping: audio.looping,
+        }
+    }
+}
+
+/// An image of a [`ToastTemplate`], see [`Image`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ImageTemplate {
+    /// See [`Toast::image`].
+    #[serde(default = "default_image_id")]
+    pub id: u8,
+    /// See [`Image::new`].
+    pub src: Url,
+    /// See [`Image::with_placement`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placement: Option<ImagePlacement>,
+    /// See [`Image::with_hint_crop`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint_crop: Option<ImageHintCrop>,
+    /// See [`Image::with_alt`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+}
+
+/// The first image of a template, as most templates have only one.
+fn default_image_id() -> u8 {
+    1
+}
+
+impl ImageTemplate {
+    fn from_image(id: u8, image: &Image) -> Self {
+        Self {
+            id,
+            src: image.src.clone(),
+            placement: image.placement,
+            hint_crop: image.hint_crop,
+            alt: image.alt.clone(),
+        }
+    }
+
+    fn to_image(&self) -> Image {
+        let mut image = Image::new(self.src.clone());
+        if let Some(placement) = self.placement {
+            image = image.with_placement(placement);
+        }
+        if let Some(crop) = self.hint_crop {
+            image = image.with_hint_crop(crop);
+        }
+        if let Some(alt) = &self.alt {
+            image = image.with_alt(alt);
+        }
+        image
+    }
+}
+
+/// A button of a [`ToastTemplate`], see [`Action`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct ActionTemplate {
+    /// The label of the button, see [`Action::new`].
+    pub content: String,
+    /// See [`Action::new`].
+    pub arguments: String,
+    /// See [`Action::new`].
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// See [`Action::with_activation_type`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activation_type: Option<ActivationType>,
+    /// See [`Action::with_placement`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placement: Option<ActionPlacement>,
+}
+
+impl ActionTemplate {
+    fn to_action(&self) -> Action {
+        let mut action = Action::new(&self.content, &self.arguments, &self.typ);
+        if let Some(activation_type) = self.activation_type {
+            action = action.with_activation_type(activation_type);
+        }
+        if let Some(placement) = self.placement {
+            action = action.with_placement(placement);
+        }
+        action
+    }
+}
+
+impl From<&Action> for ActionTemplate {
+    fn from(action: &Action) -> Self {
+        Self {
+            content: action.content.clone(),
+            arguments: action.arguments.clone(),
+            typ: action.r#type.clone(),
+            activation_type: action.activation_type,
+            placement: action.placement,
+        }
+    }
+}
@@ -1,6 +1,11 @@
 use std::{collections::HashMap, time::Duration};
 
-use crate::{Action, Header, Image, Text};
+use windows::{
+    Data::Xml::Dom::XmlDocument,
+    UI::Notifications::{NotificationMirroring, ToastNotificationPriority},
+};
+
+use crate::{hs, Action, Audio, Group, Header, Image, Progress, Result, Text};
 
 /// Represents a Windows toast.
 ///
@@ -11,6 +16,8 @@ pub struct Toast {
     pub(crate) header: Option<Header>,
     pub(crate) text: (Option<Text>, Option<Text>, Option<Text>),
     pub(crate) images: HashMap<u8, Image>,
+    pub(crate) adaptive_groups: Vec<Group>,
+    pub(crate) progress: Option<Progress>,
     pub(crate) tag: Option<String>,
     pub(crate) group: Option<String>,
     pub(crate) remote_id: Option<String>,
@@ -19,6 +26,12 @@ pub struct Toast {
     pub(crate) launch: Option<String>,
     pub(crate) duration: Option<ToastDuration>,
     pub(crate) actions: Vec<Action>,
+    pub(crate) audio: Option<Audio>,
+    pub(crate) suppress_popup: bool,
+    pub(crate) priority: Option<ToastPriority>,
+    pub(crate) mirroring: Option<Mirroring>,
+    pub(crate) people: Option<Person>,
+    pub(crate) bindings: HashMap<String, String>,
 }
 
 impl Toast {
@@ -27,6 +40,22 @@ impl Toast {
         Self::default()
     }
 
+    /// Build a toast by value, e.g. to return it from a function.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use winrt_toast::{Text, Toast};
+    /// fn reminder(title: &str) -> Toast {
+    ///     Toast::builder()
+    ///         .text1(title)
+    ///         .text3(Text::new("Calendar").as_attribution())
+    ///         .build()
+    /// }
+    /// ```
+    pub fn builder() -> ToastBuilder {
+        ToastBuilder::default()
+    }
+
     /// Add a [`Header`] to this toast.
     pub fn header(&mut self, header: Header) -> &mut Toast {
         self.header = header.into();
@@ -76,6 +105,27 @@ impl Toast {
         self
     }
 
+    /// Add a [`Group`] of columns below the text of the toast.
+    pub fn adaptive_group(&mut self, group: Group) -> &mut Toast {
+        self.adaptive_groups.push(group);
+        self
+    }
+
+    /// Set the value of `key`, for texts created with [`Text::binding`].
+    pub fn bind(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Toast {
+        self.bindings.insert(key.into(), value.into());
+        self
+    }
+
+    /// Add a progress bar to the toast.
+    ///
+    /// Give the toast a tag, so that the progress can be updated later with
+    /// [`ToastManager::update_progress`](crate::ToastManager::update_progress).
+    pub fn progress(&mut self, progress: Progress) -> &mut Toast {
+        self.progress = Some(progress);
+        self
+    }
+
     /// Add a new action to the toast.
     pub fn action(&mut self, action: Action) -> &mut Toast {
         self.actions.push(action);
@@ -84,6 +134,9 @@ impl Toast {
 
     /// Set the tag of this toast.
     ///
+    /// The tag must not be longer than [`MAX_TAG_LENGTH`](crate::MAX_TAG_LENGTH),
+    /// use [`ToastTag::hashed`](crate::ToastTag::hashed) for arbitrary strings.
+    ///
     /// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/send-local-toast-cpp-uwp?tabs=xml#provide-a-primary-key-for-your-toast>
     pub fn tag(&mut self, tag: impl Into<String>) -> &mut Toast {
         self.tag = Some(tag.into());
@@ -92,6 +145,9 @@ impl Toast {
 
     /// Set the group of this toast.
     ///
+    /// The group must not be longer than [`MAX_TAG_LENGTH`](crate::MAX_TAG_LENGTH),
+    /// use [`ToastTag::hashed`](crate::ToastTag::hashed) for arbitrary strings.
+    ///
     /// See <https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/send-local-toast-cpp-uwp?tabs=xml#provide-a-primary-key-for-your-toast>
     pub fn group(&mut self, group: impl Into<String>) -> &mut Toast {
         self.group = Some(group.into());
@@ -113,6 +169,12 @@ impl Toast {
         self
     }
 
+    /// Set the sound played when this toast is shown, see [`Audio`].
+    pub fn audio(&mut self, audio: Audio) -> &mut Toast {
+        self.audio = Some(audio);
+        self
+    }
+
     /// A string that is passed to the application when it is activated by the toast.
     ///
     /// The format and contents of this string are defined by the app for its own use.
@@ -130,6 +192,38 @@ impl Toast {
         self
     }
 
+    /// Whether to suppress the popup UI of this toast.
+    ///
+    /// If set to `true`, the toast is placed silently in the Action Center without being displayed.
+    pub fn suppress_popup(&mut self, suppress: bool) -> &mut Toast {
+        self.suppress_popup = suppress;
+        self
+    }
+
+    /// Set the priority of this toast.
+    ///
+    /// High priority toasts may be shown even if the system is in a state where
+    /// default priority toasts would be hidden.
+    pub fn priority(&mut self, priority: ToastPriority) -> &mut Toast {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Set whether this toast may be mirrored to other devices of the user.
+    pub fn mirroring(&mut self, mirroring: Mirroring) -> &mut Toast {
+        self.mirroring = Some(mirroring);
+        self
+    }
+
+    /// Set the contact this toast is about, so that it can also be shown on their contact card
+    /// in the People app.
+    ///
+    /// See <https://docs.microsoft.com/en-us/windows/uwp/contacts-and-calendar/my-people-notifications>
+    pub fn hint_people(&mut self, person: Person) -> &mut Toast {
+        self.people = Some(person);
+        self
+    }
+
     /// Set the expiration time of this toats, starting from the moment it is shown.
     ///
     /// After expiration, the toast will be removed from the Notification Center.
@@ -137,12 +231,193 @@ impl Toast {
         self.expires_in = Some(duration);
         self
     }
+
+    /// Render the toast as the XML document given to Windows.
+    ///
+    /// The defaults of a [`ToastManager`](crate::ToastManager) are not applied. This is mostly
+    /// useful to debug and test the content of toasts, without showing them.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use winrt_toast::Toast;
+    /// let mut toast = Toast::new();
+    /// toast.text1("Title");
+    /// assert_eq!(
+    ///     toast.to_xml().unwrap(),
+    ///     r#"<toast><visual><binding template="ToastGeneric"><text id="1">Title</text></binding></visual></toast>"#
+    /// );
+    /// ```
+    pub fn to_xml(&self) -> Result<String> {
+        let doc = self.to_document()?;
+        Ok(doc.DocumentElement()?.GetXml()?.to_string_lossy())
+    }
+
+    pub(crate) fn to_document(&self) -> Result<XmlDocument> {
+        let toast_doc = XmlDocument::new()?;
+
+        let toast_el = toast_doc.CreateElement(&hs("toast"))?;
+        toast_doc.AppendChild(&toast_el)?;
+
+        if let Some(scenario) = &self.scenario {
+            toast_el.SetAttribute(&hs("scenario"), &hs(scenario.as_str()))?;
+        }
+
+        if let Some(launch) = &self.launch {
+            toast_el.SetAttribute(&hs("launch"), &hs(launch))?;
+        }
+
+        if let Some(duration) = &self.duration {
+            toast_el.SetAttribute(&hs("duration"), &hs(duration.as_str()))?;
+        }
+
+        if let Some(person) = &self.people {
+            toast_el.SetAttribute(&hs("hint-people"), &hs(person.to_hint()))?;
+        }
+
+        // <header>
+        if let Some(header) = &self.header {
+            let el = toast_doc.CreateElement(&hs("header"))?;
+            toast_el.AppendChild(&el)?;
+            header.write_to_element(&el)?;
+        }
+        // </header>
+        // <visual>
+        {
+            let visual_el = toast_doc.CreateElement(&hs("visual"))?;
+            toast_el.AppendChild(&visual_el)?;
+            // <binding>
+            {
+                let binding_el = toast_doc.CreateElement(&hs("binding"))?;
+                visual_el.AppendChild(&binding_el)?;
+                binding_el.SetAttribute(&hs("template"), &hs("ToastGeneric"))?;
+                {
+                    if let Some(text) = &self.text.0 {
+                        let el = toast_doc.CreateElement(&hs("text"))?;
+                        binding_el.AppendChild(&el)?;
+                        text.write_to_element(1, &el)?;
+                    }
+                    if let Some(text) = &self.text.1 {
+                        let el = toast_doc.CreateElement(&hs("text"))?;
+                        binding_el.AppendChild(&el)?;
+                        text.write_to_element(2, &el)?;
+                    }
+                    if let Some(text) = &self.text.2 {
+                        let el = toast_doc.CreateElement(&hs("text"))?;
+                        binding_el.AppendChild(&el)?;
+                        text.write_to_element(3, &el)?;
+                    }
+
+                    for group in &self.adaptive_groups {
+                        let el = toast_doc.CreateElement(&hs("group"))?;
+                        binding_el.AppendChild(&el)?;
+                        group.write_to_element(&toast_doc, &el)?;
+                    }
+
+                    // Sorted, so that the document does not depend on the order of the map.
+                    let mut images: Vec<_> = self.images.iter().collect();
+                    images.sort_by_key(|(id, _)| **id);
+                    for (id, image) in images {
+                        let el = toast_doc.CreateElement(&hs("image"))?;
+                        binding_el.AppendChild(&el)?;
+                        image.write_to_element(*id, &el)?;
+                    }
+
+                    if let Some(progress) = &self.progress {
+                        let el = toast_doc.CreateElement(&hs("progress"))?;
+                        binding_el.AppendChild(&el)?;
+                        progress.write_to_element(&el)?;
+                    }
+                }
+            }
+            // </binding>
+        }
+        // </visual>
+        // <actions>
+        if !self.actions.is_empty() {
+            let actions_el = toast_doc.CreateElement(&hs("actions"))?;
+            toast_el.AppendChild(&actions_el)?;
+            for action in &self.actions {
+                let el = toast_doc.CreateElement(&hs("action"))?;
+                actions_el.AppendChild(&el)?;
+                action.write_to_element(&el)?;
+            }
+        }
+        // </actions>
+        // <audio>
+        if let Some(audio) = &self.audio {
+            let el = toast_doc.CreateElement(&hs("audio"))?;
+            toast_el.AppendChild(&el)?;
+            audio.write_to_element(&el)?;
+        }
+        // </audio>
+
+        Ok(toast_doc)
+    }
+}
+
+/// Generates the methods of [`ToastBuilder`], which call the setter of [`Toast`] with the same
+/// name.
+macro_rules! builder_methods {
+    ($($name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            #[doc = concat!("See [`Toast::", stringify!($name), "`].")]
+            pub fn $name(mut self, $($arg: $ty),*) -> Self {
+                self.toast.$name($($arg),*);
+                self
+            }
+        )*
+    };
+}
+
+/// Builds a [`Toast`] by value, see [`Toast::builder`].
+#[derive(Debug, Clone, Default)]
+pub struct ToastBuilder {
+    toast: Toast,
+}
+
+impl ToastBuilder {
+    builder_methods! {
+        header(header: Header);
+        text1(text: impl Into<Text>);
+        text2(text: impl Into<Text>);
+        text3(text: impl Into<Text>);
+        image(id: u8, image: Image);
+        adaptive_group(group: Group);
+        bind(key: impl Into<String>, value: impl Into<String>);
+        progress(progress: Progress);
+        action(action: Action);
+        tag(tag: impl Into<String>);
+        group(group: impl Into<String>);
+        remote_id(remote_id: impl Into<String>);
+        scenario(scenario: Scenario);
+        audio(audio: Audio);
+        launch(launch: impl Into<String>);
+        duration(duration: ToastDuration);
+        suppress_popup(suppress: bool);
+        priority(priority: ToastPriority);
+        mirroring(mirroring: Mirroring);
+        hint_people(person: Person);
+        expires_in(duration: Duration);
+    }
+
+    /// The toast with everything set so far.
+    pub fn build(self) -> Toast {
+        self.toast
+    }
+}
+
+impl From<ToastBuilder> for Toast {
+    fn from(builder: ToastBuilder) -> Self {
+        builder.build()
+    }
 }
 
 /// The scenario your toast is used for, like an alarm or reminder.
 ///
 /// See [Microsoft documentation](https://docs.microsoft.com/en-us/windows/apps/design/shell/tiles-and-notifications/adaptive-interactive-toasts?tabs=xml#scenarios).
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum Scenario {
     /// A reminder notification. This will be displayed pre-expanded and stay on the user's screen till dismissed.
     Reminder,
@@ -170,8 +445,31 @@ impl Scenario {
     }
 }
 
+/// A contact, identified the same way as in the People app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Person {
+    /// An email address.
+    Email(String),
+    /// A phone number.
+    Phone(String),
+    /// An id that the app assigned to the contact when adding it.
+    RemoteId(String),
+}
+
+impl Person {
+    pub(crate) fn to_hint(&self) -> String {
+        match self {
+            Person::Email(email) => format!("mailto:{}", email),
+            Person::Phone(phone) => format!("tel:{}", phone),
+            Person::RemoteId(id) => format!("remoteid:{}", id),
+        }
+    }
+}
+
 /// The amount of time the toast should display
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub enum ToastDuration {
     /// The toast will display for a longer period of time.
     Long,
@@ -187,3 +485,47 @@ impl ToastDuration {
         }
     }
 }
+
+/// The priority of a toast.
+///
+/// See <https://docs.microsoft.com/en-us/uwp/api/windows.ui.notifications.toastnotificationpriority>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum ToastPriority {
+    /// The toast is shown with the default priority.
+    Default,
+    /// The toast is shown with a higher priority, e.g. it may be shown on the lock screen.
+    High,
+}
+
+impl ToastPriority {
+    pub(crate) fn as_winrt(&self) -> ToastNotificationPriority {
+        match self {
+            ToastPriority::Default => ToastNotificationPriority::Default,
+            ToastPriority::High => ToastNotificationPriority::High,
+        }
+    }
+}
+
+/// Whether a toast may be mirrored to other devices.
+///
+/// See <https://docs.microsoft.com/en-us/uwp/api/windows.ui.notifications.notificationmirroring>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum Mirroring {
+    /// The toast may be mirrored to other devices. This is the default.
+    Allowed,
+    /// The toast is only shown on this device.
+    Disabled,
+}
+
+impl Mirroring {
+    pub(crate) fn as_winrt(&self) -> NotificationMirroring {
+        match self {
+            Mirroring::Allowed => NotificationMirroring::Allowed,
+            Mirroring::Disabled => NotificationMirroring::Disabled,
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Building and parsing of activation arguments.
+
+use winrt_toast::ToastArgs;
+
+#[test]
+fn single_pair() {
+    let args = ToastArgs::new().with("action", "headerClick");
+    assert_eq!(args.to_string(), "action=headerClick");
+}
+
+#[test]
+fn escapes_separators() {
+    let args = ToastArgs::new().with("a;b", "1=2").with("percent", "100%");
+    assert_eq!(args.to_string(), "a%3Bb=1%3D2;percent=100%25");
+}
+
+#[test]
+fn round_trips() {
+    let args = ToastArgs::new()
+        .with("action", "reply")
+        .with("id", "0|com.example|42|null|10086")
+        .with("odd", "%3B;=%")
+        .with("flag", "");
+    let parsed = ToastArgs::parse(&args.to_string());
+    assert_eq!(parsed, args);
+    assert_eq!(parsed.get("odd"), Some("%3B;=%"));
+    assert!(parsed.contains("flag"));
+}
+
+#[test]
+fn set_replaces_value() {
+    let mut args = ToastArgs::new().with("action", "open");
+    args.set("action", "dismiss");
+    assert_eq!(args.iter().collect::<Vec<_>>(), vec![("action", "dismiss")]);
+}
+
+#[test]
+fn parses_foreign_arguments() {
+    let args = ToastArgs::parse("headerClick;;progress=50%");
+    assert_eq!(args.get("headerClick"), Some(""));
+    assert_eq!(args.get("progress"), Some("50%"));
+    assert_eq!(args.get("missing"), None);
+}
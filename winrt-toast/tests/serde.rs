@@ -0,0 +1,171 @@
+//! Toasts loaded from files with the `serde` feature.
+#![cfg(feature = "serde")]
+
+use winrt_toast::{
+    content::{audio::Sound, image::ImagePlacement, text::TextAlign},
+    Action, ActionTemplate, Audio, AudioTemplate, Header, Image, ImageTemplate, Progress,
+    ProgressValue, Scenario, Text, TextTemplate, Toast, ToastTemplate,
+};
+
+const TEMPLATE: &str = r#"
+tag = "reminder"
+scenario = "reminder"
+expiresInSecs = 60
+
+[text1]
+content = "Stand up"
+
+[text2]
+content = "{minutes} minutes of sitting"
+maxLines = 2
+align = "center"
+
+[text3]
+content = "Timer"
+attribution = true
+
+[audio]
+sound = "reminder"
+
+[bindings]
+minutes = "45"
+
+[[images]]
+src = "https://example.com/icon.png"
+placement = "appLogoOverride"
+
+[[actions]]
+content = "Snooze"
+arguments = "snooze"
+"#;
+
+fn expected() -> ToastTemplate {
+    ToastTemplate {
+        tag: Some("reminder".into()),
+        scenario: Some(Scenario::Reminder),
+        expires_in_secs: Some(60),
+        text1: Some(TextTemplate::new("Stand up")),
+        text2: Some(TextTemplate {
+            max_lines: Some(2),
+            align: Some(TextAlign::Center),
+            ..TextTemplate::new("{minutes} minutes of sitting")
+        }),
+        text3: Some(TextTemplate {
+            attribution: true,
+            ..TextTemplate::new("Timer")
+        }),
+        audio: Some(AudioTemplate {
+            sound: Some(Sound::Reminder),
+            ..Default::default()
+        }),
+        bindings: [("minutes".to_string(), "45".to_string())].into(),
+        images: vec![ImageTemplate {
+            id: 1,
+            src: "https://example.com/icon.png".parse().unwrap(),
+            placement: Some(ImagePlacement::AppLogoOverride),
+            hint_crop: None,
+            alt: None,
+        }],
+        actions: vec![ActionTemplate {
+            content: "Snooze".into(),
+            arguments: "snooze".into(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn loads_from_toml() {
+    let loaded: ToastTemplate = toml::from_str(TEMPLATE).unwrap();
+    assert_eq!(loaded, expected());
+}
+
+#[test]
+fn round_trips_through_toml() {
+    let saved = toml::to_string(&expected()).unwrap();
+    let loaded: ToastTemplate = toml::from_str(&saved).unwrap();
+    assert_eq!(loaded, expected());
+}
+
+#[test]
+fn missing_fields_are_defaults() {
+    let loaded: ToastTemplate = toml::from_str("[text1]\ncontent = \"Title\"").unwrap();
+    assert_eq!(
+        loaded,
+        ToastTemplate {
+            text1: Some(TextTemplate {
+                content: "Title".into(),
+                attribution: false,
+                resource: false,
+                language: None,
+                max_lines: None,
+                wrap: None,
+                align: None,
+            }),
+            ..Default::default()
+        }
+    );
+    assert!(!loaded.suppress_popup);
+    assert!(loaded.bindings.is_empty());
+
+    let empty: ToastTemplate = toml::from_str("").unwrap();
+    assert_eq!(empty, ToastTemplate::default());
+}
+
+#[test]
+fn unknown_fields_are_rejected() {
+    assert!(toml::from_str::<ToastTemplate>("title = \"Title\"").is_err());
+    assert!(toml::from_str::<ToastTemplate>("[text1]\ntext = \"Title\"").is_err());
+    // Images have no default source.
+    assert!(toml::from_str::<ToastTemplate>("[[images]]\nid = 2").is_err());
+}
+
+#[test]
+fn builds_the_same_toast() {
+    let built = Toast::builder()
+        .tag("reminder")
+        .scenario(Scenario::Reminder)
+        .expires_in(std::time::Duration::from_secs(60))
+        .text1("Stand up")
+        .text2(
+            Text::new("{minutes} minutes of sitting")
+                .with_max_lines(2)
+                .with_align(TextAlign::Center),
+        )
+        .text3(Text::new("Timer").as_attribution())
+        .audio(Audio::new(Sound::Reminder))
+        .bind("minutes", "45")
+        .image(
+            1,
+            Image::new("https://example.com/icon.png".parse().unwrap())
+                .with_placement(ImagePlacement::AppLogoOverride),
+        )
+        .action(Action::new("Snooze", "snooze", ""))
+        .build();
+
+    let loaded: ToastTemplate = toml::from_str(TEMPLATE).unwrap();
+    assert_eq!(loaded.to_toast().to_xml().unwrap(), built.to_xml().unwrap());
+}
+
+#[test]
+fn toasts_turn_back_into_templates() {
+    let loaded: ToastTemplate = toml::from_str(TEMPLATE).unwrap();
+    assert_eq!(ToastTemplate::from(&loaded.to_toast()), expected());
+
+    let mut toast = Toast::new();
+    toast
+        .header(Header::new("transfers", "Transfers", "open"))
+        .remote_id("42")
+        .text1(Text::resource("Title").with_wrap(false))
+        .progress(Progress::new("Receiving", ProgressValue::Determinate(0.5)).with_title("a.txt"))
+        .image(2, Image::new("https://example.com/a.png".parse().unwrap()))
+        .image(1, Image::new("https://example.com/b.png".parse().unwrap()));
+    let template = ToastTemplate::from(&toast);
+    assert_eq!(template.images[0].id, 1);
+
+    let saved = toml::to_string(&template).unwrap();
+    let loaded: ToastTemplate = toml::from_str(&saved).unwrap();
+    assert_eq!(loaded, template);
+    assert_eq!(loaded.to_toast().to_xml().unwrap(), toast.to_xml().unwrap());
+}
@@ -0,0 +1,283 @@
+//! Snapshots of the XML generated for toasts, checked without showing them.
+
+use winrt_toast::{
+    content::{
+        action::{ActionPlacement, ActivationType},
+        audio::Sound,
+        group::TextStacking,
+        header::ActivationType as HeaderActivationType,
+        image::ImagePlacement,
+        text::{TextAlign, TextPlacement},
+    },
+    url::Url,
+    Action, Audio, Group, Header, Image, Person, Progress, ProgressValue, Scenario, Subgroup, Text,
+    Toast, ToastDuration,
+};
+
+/// Wrap the content of a binding in the rest of a toast without attributes.
+fn generic(binding: &str) -> String {
+    format!(
+        r#"<toast><visual><binding template="ToastGeneric">{}</binding></visual></toast>"#,
+        binding
+    )
+}
+
+fn image(name: &str) -> Image {
+    Image::new(Url::parse(&format!("https://example.com/{}.png", name)).unwrap())
+}
+
+#[test]
+fn empty() {
+    assert_eq!(Toast::new().to_xml().unwrap(), generic(""));
+}
+
+#[test]
+fn texts() {
+    let mut toast = Toast::new();
+    toast
+        .text1("Title")
+        .text2(Text::new("Body").with_language("en-US"))
+        .text3(Text::new("Via SMS").with_placement(TextPlacement::Attribution));
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<text id="1">Title</text>"#,
+            r#"<text id="2" lang="en-US">Body</text>"#,
+            r#"<text id="3" placement="attribution">Via SMS</text>"#,
+        ))
+    );
+}
+
+#[test]
+fn text_is_escaped() {
+    let mut toast = Toast::new();
+    toast.text1("Tom & Jerry <3");
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(r#"<text id="1">Tom &amp; Jerry &lt;3</text>"#)
+    );
+}
+
+#[test]
+fn resource_and_binding_texts() {
+    let mut toast = Toast::new();
+    toast
+        .text1(Text::resource("Resources/Greeting"))
+        .text2(Text::binding("name"))
+        .bind("name", "World");
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<text id="1">ms-resource:Resources/Greeting</text>"#,
+            r#"<text id="2">{name}</text>"#,
+        ))
+    );
+}
+
+#[test]
+fn images_are_sorted_by_id() {
+    let mut toast = Toast::new();
+    toast
+        .image(2, image("hero").with_placement(ImagePlacement::Hero))
+        .image(1, image("avatar").as_avatar().with_alt("Avatar"));
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<image id="1" src="https://example.com/avatar.png" placement="appLogoOverride" hint-crop="circle" alt="Avatar"/>"#,
+            r#"<image id="2" src="https://example.com/hero.png" placement="hero"/>"#,
+        ))
+    );
+}
+
+#[test]
+fn adaptive_groups() {
+    let mut toast = Toast::new();
+    toast.text1("Now playing").adaptive_group(
+        Group::new()
+            .subgroup(Subgroup::new().text("Song").text("Artist").with_weight(2))
+            .subgroup(
+                Subgroup::new()
+                    .image(image("cover"))
+                    .with_text_stacking(TextStacking::Center),
+            ),
+    );
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<text id="1">Now playing</text>"#,
+            r#"<group>"#,
+            r#"<subgroup hint-weight="2"><text>Song</text><text>Artist</text></subgroup>"#,
+            r#"<subgroup hint-textStacking="center"><image src="https://example.com/cover.png"/></subgroup>"#,
+            r#"</group>"#,
+        ))
+    );
+}
+
+#[test]
+fn text_hints() {
+    let mut toast = Toast::new();
+    toast
+        .text1("Message")
+        .text2(Text::new("A long body").with_max_lines(4))
+        .adaptive_group(
+            Group::new().subgroup(
+                Subgroup::new().text(
+                    Text::new("12:00")
+                        .with_wrap(true)
+                        .with_align(TextAlign::Right),
+                ),
+            ),
+        );
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<text id="1">Message</text>"#,
+            r#"<text id="2" hint-maxLines="4">A long body</text>"#,
+            r#"<group><subgroup><text hint-wrap="true" hint-align="right">12:00</text></subgroup></group>"#,
+        ))
+    );
+}
+
+#[test]
+fn progress() {
+    let mut toast = Toast::new();
+    toast.text1("Download").progress(
+        Progress::new("Downloading...", ProgressValue::Determinate(0.5))
+            .with_title("file.zip")
+            .with_value_string("1/2 files"),
+    );
+
+    // The values are data bound, so that they can be updated.
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(concat!(
+            r#"<text id="1">Download</text>"#,
+            r#"<progress title="{progressTitle}" status="{progressStatus}" value="{progressValue}" valueStringOverride="{progressValueString}"/>"#,
+        ))
+    );
+
+    let mut toast = Toast::new();
+    toast.progress(Progress::new("Waiting", ProgressValue::Indeterminate));
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(r#"<progress status="{progressStatus}" value="{progressValue}"/>"#)
+    );
+}
+
+#[test]
+fn actions() {
+    let mut toast = Toast::new();
+    toast
+        .text1("Message")
+        .action(
+            Action::new("Reply", "action=reply", "")
+                .with_activation_type(ActivationType::Background),
+        )
+        .action(
+            Action::new("Mute", "action=mute", "").with_placement(ActionPlacement::ContextMenu),
+        );
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        concat!(
+            r#"<toast><visual><binding template="ToastGeneric"><text id="1">Message</text></binding></visual>"#,
+            r#"<actions>"#,
+            r#"<action content="Reply" arguments="action=reply" type="" activationType="background"/>"#,
+            r#"<action content="Mute" arguments="action=mute" type="" placement="contextMenu"/>"#,
+            r#"</actions></toast>"#,
+        )
+    );
+}
+
+#[test]
+fn header_and_toast_attributes() {
+    let mut toast = Toast::new();
+    toast
+        .header(
+            Header::new("chat", "Chat", "action=header")
+                .with_activation_type(HeaderActivationType::Foreground),
+        )
+        .scenario(Scenario::Reminder)
+        .launch("action=open")
+        .duration(ToastDuration::Long)
+        .hint_people(Person::Phone("+1555".into()))
+        .text1("Hello");
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        concat!(
+            r#"<toast scenario="reminder" launch="action=open" duration="long" hint-people="tel:+1555">"#,
+            r#"<header id="chat" title="Chat" arguments="action=header" activationType="foreground"/>"#,
+            r#"<visual><binding template="ToastGeneric"><text id="1">Hello</text></binding></visual>"#,
+            r#"</toast>"#,
+        )
+    );
+}
+
+#[test]
+fn audio() {
+    let mut toast = Toast::new();
+    toast.text1("Quiet").audio(Audio::silent());
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        concat!(
+            r#"<toast><visual><binding template="ToastGeneric"><text id="1">Quiet</text></binding></visual>"#,
+            r#"<audio silent="true"/></toast>"#,
+        )
+    );
+
+    let mut toast = Toast::new();
+    toast
+        .text1("Wake up")
+        .scenario(Scenario::Alarm)
+        .audio(Audio::new(Sound::Alarm).with_looping(true));
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        concat!(
+            r#"<toast scenario="alarm"><visual><binding template="ToastGeneric"><text id="1">Wake up</text></binding></visual>"#,
+            r#"<audio src="ms-winsoundevent:Notification.Looping.Alarm" loop="true"/></toast>"#,
+        )
+    );
+}
+
+#[test]
+fn properties_outside_of_the_document() {
+    // Tags, groups and the like are set on the notification, not in its content.
+    let mut toast = Toast::new();
+    toast
+        .text1("Hello")
+        .tag("tag")
+        .group("group")
+        .remote_id("remote")
+        .suppress_popup(true);
+
+    assert_eq!(
+        toast.to_xml().unwrap(),
+        generic(r#"<text id="1">Hello</text>"#)
+    );
+}
+
+#[test]
+fn builder_matches_setters() {
+    let mut toast = Toast::new();
+    toast
+        .text1("Title")
+        .text3(Text::new("Via SMS").as_attribution())
+        .action(Action::new("Open", "open", ""))
+        .scenario(Scenario::Reminder);
+
+    let built = Toast::builder()
+        .text1("Title")
+        .text3(Text::new("Via SMS").as_attribution())
+        .action(Action::new("Open", "open", ""))
+        .scenario(Scenario::Reminder)
+        .build();
+
+    assert_eq!(built.to_xml().unwrap(), toast.to_xml().unwrap());
+}